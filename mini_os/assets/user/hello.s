@@ -0,0 +1,35 @@
+# user program loaded by tests/elf_loader.rs, rebuild hello.elf with:
+#   as --64 -o /tmp/hello.o hello.s && ld -T user.ld -o hello.elf /tmp/hello.o
+# syscalls (src/process/syscall.rs): 0 write, 1 exit
+    .intel_syntax noprefix
+    .text
+    .global _start
+_start:
+    # .bss has to be zeroed by the loader
+    lea rbx, [rip + counter]
+    mov rax, [rbx]
+    or rax, [rbx + 8192]
+    jnz fail
+    mov qword ptr [rbx + 8192], 1
+
+    mov eax, 0
+    mov edi, 1
+    lea rsi, [rip + message]
+    mov edx, message_end - message
+    int 0x80
+    mov rdi, rax
+    mov eax, 1
+    int 0x80
+fail:
+    mov edi, 99
+    mov eax, 1
+    int 0x80
+
+    .data
+message:
+    .ascii "hello from an ELF binary\n"
+message_end:
+
+    .bss
+counter:
+    .space 8192 + 8
@@ -0,0 +1,261 @@
+// ACPI tables, as far as the kernel needs them: the MADT (signature "APIC") lists the local APICs
+// of the processors, the IOAPICs and how the ISA interrupts are wired to them. The firmware's tables
+// are read through the mapping of the physical memory, so "memory::init_global" has to run first.
+use crate::cpu::MAX_CPUS;
+use crate::memory;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const MAX_IO_APICS: usize = 4;
+pub const MAX_OVERRIDES: usize = 16;
+pub const MAX_NMIS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NotInitialized,       // "memory::init_global" wasn't called
+    NoRsdp,               // no root pointer in the EBDA or the BIOS area
+    NotMapped(u64),       // a table lies outside of the physical memory mapping
+    BadChecksum([u8; 4]), // table with this signature is corrupt
+    NoMadt,               // the root table doesn't list a MADT
+}
+
+impl core::fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            AcpiError::NotInitialized => f.write_str("memory manager is not initialized"),
+            AcpiError::NoRsdp => f.write_str("no ACPI root system description pointer found"),
+            AcpiError::NotMapped(addr) => write!(f, "ACPI table at {:#x} is not mapped", addr),
+            AcpiError::BadChecksum(signature) => write!(
+                f,
+                "ACPI table {} has a bad checksum",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+            AcpiError::NoMadt => f.write_str("no MADT (APIC table) in the ACPI tables"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub uid: u8, // ACPI processor id, the NMI entries refer to it
+    pub apic_id: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32, // global system interrupt of its first input
+}
+
+// ISA interrupt "irq" arrives at global system interrupt "gsi" instead of the input with its number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16, // MPS INTI flags, polarity in bits 0-1, trigger mode in bits 2-3
+}
+
+// local APIC input "lint" of processor "uid" (0xff for all of them) is connected to the NMI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub uid: u8,
+    pub lint: u8,
+    pub flags: u16,
+}
+
+// polarity and trigger mode of MPS INTI flags, "bus default" is active high and edge triggered for
+// ISA interrupts
+pub fn active_low(flags: u16) -> bool {
+    flags & 0b11 == 0b11
+}
+
+pub fn level_triggered(flags: u16) -> bool {
+    (flags >> 2) & 0b11 == 0b11
+}
+
+// what the kernel uses of the MADT, in fixed arrays (it's parsed before the heap exists)
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    pub legacy_pics: bool, // 8259 PICs are installed and have to be masked
+    processors: [Option<Processor>; MAX_CPUS],
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+    nmis: [Option<LocalApicNmi>; MAX_NMIS],
+}
+
+impl Madt {
+    // enabled processors, the first MAX_CPUS of them
+    pub fn processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors.iter().flatten()
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApic> {
+        self.io_apics.iter().flatten()
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = &InterruptOverride> {
+        self.overrides.iter().flatten()
+    }
+
+    pub fn nmis(&self) -> impl Iterator<Item = &LocalApicNmi> {
+        self.nmis.iter().flatten()
+    }
+
+    // global system interrupt and INTI flags of ISA interrupt "irq", identity mapped with the bus
+    // defaults unless there's an override
+    pub fn isa_irq(&self, irq: u8) -> (u32, u16) {
+        self.overrides()
+            .find(|entry| entry.irq == irq)
+            .map_or((irq.into(), 0), |entry| (entry.gsi, entry.flags))
+    }
+}
+
+// first free entry of a fixed array, entries past its end are dropped
+fn push<T>(entries: &mut [Option<T>], entry: T) {
+    if let Some(free) = entries.iter_mut().find(|entry| entry.is_none()) {
+        *free = Some(entry);
+    }
+}
+
+fn virt(addr: u64, len: u64) -> Result<VirtAddr, AcpiError> {
+    let start = memory::phys_to_virt(PhysAddr::new(addr)).ok_or(AcpiError::NotInitialized)?;
+    if memory::is_mapped(start) && memory::is_mapped(start + (len.max(1) - 1)) {
+        Ok(start)
+    } else {
+        Err(AcpiError::NotMapped(addr))
+    }
+}
+
+// the tables are byte packed, nothing in there is aligned
+fn read<T: Copy>(addr: VirtAddr, offset: u64) -> T {
+    unsafe { (addr + offset).as_ptr::<T>().read_unaligned() }
+}
+
+// the bytes of a table add up to 0
+fn checksum_ok(addr: VirtAddr, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read(addr, i))) == 0
+}
+
+// the root system description pointer is on a 16 byte boundary in the first KiB of the extended
+// BIOS data area or in the BIOS area below 1MiB
+fn find_rsdp() -> Result<VirtAddr, AcpiError> {
+    let ebda = u64::from(read::<u16>(virt(0x40e, 2)?, 0)) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    for &(start, end) in areas.iter().filter(|(start, _)| *start != 0) {
+        let area = virt(start, end - start)?;
+        for offset in (0..end - start).step_by(16) {
+            if read::<[u8; 8]>(area, offset) == *b"RSD PTR " && checksum_ok(area + offset, 20) {
+                return Ok(area + offset);
+            }
+        }
+    }
+    Err(AcpiError::NoRsdp)
+}
+
+// a system description table after checking its length and checksum: (address, length)
+fn table(addr: u64) -> Result<(VirtAddr, u64), AcpiError> {
+    let header = virt(addr, 36)?;
+    let signature = read::<[u8; 4]>(header, 0);
+    let len = u64::from(read::<u32>(header, 4));
+    let table = virt(addr, len)?;
+    if len < 36 || !checksum_ok(table, len) {
+        return Err(AcpiError::BadChecksum(signature));
+    }
+    Ok((table, len))
+}
+
+// finds the MADT through the RSDT (ACPI 1.0) or the XSDT (2.0 and later, 64 bit pointers)
+fn find_madt() -> Result<(VirtAddr, u64), AcpiError> {
+    let rsdp = find_rsdp()?;
+    let revision = read::<u8>(rsdp, 15);
+    let (root, entry_size) = if revision >= 2 && checksum_ok(rsdp, 36) {
+        (read::<u64>(rsdp, 24), 8)
+    } else {
+        (u64::from(read::<u32>(rsdp, 16)), 4)
+    };
+    let (root, len) = table(root)?;
+    for offset in (36..len).step_by(entry_size) {
+        let entry = if entry_size == 8 {
+            read::<u64>(root, offset)
+        } else {
+            u64::from(read::<u32>(root, offset))
+        };
+        // only the MADT has to be intact
+        if read::<[u8; 4]>(virt(entry, 36)?, 0) == *b"APIC" {
+            return table(entry);
+        }
+    }
+    Err(AcpiError::NoMadt)
+}
+
+fn parse_madt(madt: VirtAddr, len: u64) -> Madt {
+    let mut parsed = Madt {
+        local_apic: PhysAddr::new(read::<u32>(madt, 36).into()),
+        legacy_pics: read::<u32>(madt, 40) & 1 != 0,
+        processors: [None; MAX_CPUS],
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+        nmis: [None; MAX_NMIS],
+    };
+    // interrupt controller structures: type, length, then the fields
+    let mut offset = 44;
+    while offset + 2 <= len {
+        let entry = madt + offset;
+        let entry_len = u64::from(read::<u8>(entry, 1));
+        if entry_len < 2 || offset + entry_len > len {
+            break;
+        }
+        match read::<u8>(entry, 0) {
+            // processor local APIC, only the enabled ones
+            0 if read::<u32>(entry, 4) & 1 != 0 => push(
+                &mut parsed.processors,
+                Processor {
+                    uid: read(entry, 2),
+                    apic_id: read(entry, 3),
+                },
+            ),
+            1 => push(
+                &mut parsed.io_apics,
+                IoApic {
+                    id: read(entry, 2),
+                    address: PhysAddr::new(read::<u32>(entry, 4).into()),
+                    gsi_base: read(entry, 8),
+                },
+            ),
+            // bus 0 is ISA, the only one with overrides
+            2 => push(
+                &mut parsed.overrides,
+                InterruptOverride {
+                    irq: read(entry, 3),
+                    gsi: read(entry, 4),
+                    flags: read(entry, 8),
+                },
+            ),
+            4 => push(
+                &mut parsed.nmis,
+                LocalApicNmi {
+                    uid: read(entry, 2),
+                    flags: read(entry, 3),
+                    lint: read(entry, 5),
+                },
+            ),
+            // 64 bit local APIC address
+            5 => parsed.local_apic = PhysAddr::new(read(entry, 4)),
+            _ => {}
+        }
+        offset += entry_len;
+    }
+    parsed
+}
+
+static MADT: spin::Once<Result<Madt, AcpiError>> = spin::Once::new();
+
+// the MADT, looked up and parsed on the first call
+pub fn madt() -> Result<&'static Madt, AcpiError> {
+    // don't remember the failure of a call before "init_global"
+    memory::phys_to_virt(PhysAddr::new(0)).ok_or(AcpiError::NotInitialized)?;
+    MADT.call_once(|| find_madt().map(|(madt, len)| parse_madt(madt, len)))
+        .as_ref()
+        .map_err(|error| *error)
+}
@@ -1,16 +1,24 @@
+pub mod buddy;
 pub mod bump;
+pub mod debug;
 pub mod fixed_size_block;
 pub mod linked_list;
-// use bump::BumpAllocator;
-use fixed_size_block::FixedSizeBlockAllocator;
-// use linked_list::LinkedListAllocator;
+pub mod magazine;
+pub mod slab;
+pub mod stats;
 
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use magazine::Magazines;
+use stats::{HeapStats, Tracked};
 // use linked_list_allocator::LockedHeap;
 
 pub const HEAP_START: /*usize*/ *mut u8 = 0x_4444_4444_0000 as *mut u8; // any address can be chosen untill the address
-pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB...will increase in future when needed.
+pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB initially, grows on demand (see grow_heap)
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // default limit for the growth
 
 pub struct Dummy; // zero-sized type [not of any use: just to learn how GlobalAlloc trait works]
 unsafe impl GlobalAlloc for Dummy {
@@ -23,48 +31,154 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
+// the global allocator is chosen with cargo features, exactly one of "alloc-fixed-size-block"
+// (default), "alloc-linked-list", "alloc-buddy" and "alloc-bump" has to be enabled:
+//   cargo test --no-default-features --features alloc-linked-list
+#[cfg(feature = "alloc-bump")]
+type SelectedAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type SelectedAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-size-block")]
+type SelectedAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-buddy")]
+type SelectedAllocator = buddy::BuddyAllocator;
+
+const SELECTED_ALLOCATORS: usize = cfg!(feature = "alloc-bump") as usize
+    + cfg!(feature = "alloc-linked-list") as usize
+    + cfg!(feature = "alloc-fixed-size-block") as usize
+    + cfg!(feature = "alloc-buddy") as usize;
+const _: () = assert!(
+    SELECTED_ALLOCATORS == 1,
+    "enable exactly one allocator feature: alloc-fixed-size-block, alloc-linked-list, alloc-buddy or alloc-bump"
+);
+
+// small allocations are served from per-CPU magazines, the selected allocator is behind them
+type GlobalHeap = Magazines<Locked<SelectedAllocator>>;
+
+#[cfg(not(feature = "heap-debug"))]
 #[global_allocator]
 // this attribute tells Rust Compiler what allocator instance to be used for global heap
 // allocation (this attribute is only applicable to STATICS)...
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
-
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags as PTF, Size4KiB,
-    },
-    VirtAddr,
-};
-
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        // -1 because we need last byte address of heap (inclusive)...
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PTF::PRESENT | PTF::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-        // flush is necessary to get rid of old translations from TLB...
-    }
+static ALLOCATOR: Tracked<GlobalHeap> =
+    Tracked::new(Magazines::new(Locked::new(SelectedAllocator::new())));
+
+// feature "heap-debug": redzones, poisoning, dealloc checks and a registry of live allocations
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static ALLOCATOR: Tracked<debug::Debugged<GlobalHeap>> = Tracked::new(debug::Debugged::new(
+    Magazines::new(Locked::new(SelectedAllocator::new())),
+));
+
+fn magazines() -> &'static GlobalHeap {
+    #[cfg(feature = "heap-debug")]
+    return ALLOCATOR.inner().inner();
+    #[cfg(not(feature = "heap-debug"))]
+    ALLOCATOR.inner()
+}
+
+// the selected allocator below the wrappers
+fn heap() -> &'static Locked<SelectedAllocator> {
+    magazines().inner()
+}
+
+// prints the live allocations of the global heap over serial, if heap debugging is enabled
+pub fn dump_live_allocations() {
+    #[cfg(feature = "heap-debug")]
+    ALLOCATOR.inner().dump();
+    #[cfg(not(feature = "heap-debug"))]
+    crate::serial_println!("heap debug: not enabled (cargo feature \"heap-debug\")");
+}
+
+// what every allocator provides next to GlobalAlloc (implemented on Locked<Self>), so the rest of
+// the kernel doesn't care which one was selected
+pub trait KernelHeap {
+    fn name(&self) -> &'static str;
+    /// # Safety
+    /// the heap range must be valid and unused, must only be called once
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+    fn heap_size(&self) -> usize;
+    fn free_bytes(&self) -> usize;
+    fn largest_free_block(&self) -> usize;
+}
+
+// allocation counters of the global allocator and the state of its heap
+pub fn stats() -> HeapStats {
+    let mut stats = ALLOCATOR.stats(&*heap().lock());
+    stats.lock = heap().lock_stats();
+    stats.magazines = magazines().stats();
+    stats
+}
+
+use crate::memory::{self, PagingError};
+use x86_64::{structures::paging::PageTableFlags as PTF, VirtAddr};
+
+// the heap is a lazy region: its pages are only backed by frames once touched (see the page fault
+// handler), so an unused heap costs no physical memory. "memory::init_global" must be called first
+pub fn init_heap() -> Result<(), PagingError> {
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    memory::register_lazy_region(heap_start, HEAP_SIZE as u64, PTF::PRESENT | PTF::WRITABLE)?;
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        KernelHeap::init(&mut *heap().lock(), HEAP_START as usize, HEAP_SIZE);
+        // "init" writes the first free block header, which already faults the first page in
     }
     Ok(())
 }
 
-// own wrapper type around spin::Mutex to get mutable references to allocators
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+// the kernel stacks follow the heap in its level 4 slot, it can't grow into them
+fn heap_space() -> usize {
+    (memory::stack::STACKS_START - HEAP_START as u64) as usize
+}
+
+// the heap doesn't grow beyond "limit" bytes (it doesn't shrink if it's bigger already). Limits
+// past the start of the kernel stacks are cut down to the space in between
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(heap_space()), Ordering::Relaxed);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+const PAGE_SIZE: usize = 4096;
+
+// called by an allocator that ran out of memory (with its lock held): extends the lazy heap region
+// that starts at "heap_start" and is "heap_size" bytes big so that "layout" fits, returns the number
+// of bytes added, 0 at the limit. The heap at least doubles, so this stays rare
+pub(crate) fn grow_heap(heap_start: usize, heap_size: usize, layout: &Layout) -> usize {
+    let room = heap_limit().min(heap_space()).saturating_sub(heap_size);
+    // enough for the allocation wherever the alignment puts it
+    let needed = align_up(layout.size() + layout.align(), PAGE_SIZE);
+    let by = needed.max(heap_size).min(room);
+    if by < needed {
+        return 0;
+    }
+    let start = VirtAddr::new(heap_start as u64);
+    match memory::grow_lazy_region(start, by as u64) {
+        Ok(()) => by,
+        Err(_) => 0,
+    }
+}
+
+// runs when an allocation that can't fail (Box, Vec, ...) gets null from the global allocator
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!(
+        "allocation error: {:?} (heap limit {} bytes)\n{}",
+        layout,
+        heap_limit(),
+        stats()
+    )
+}
+
+// own wrapper type around spin::Mutex to get mutable references to allocators. Interrupts are
+// disabled while the lock is held: an interrupt handler that allocates would otherwise spin
+// forever on a lock its own CPU holds
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
+    acquisitions: AtomicU64,
+    contended: AtomicU64, // acquisitions that had to wait for another holder
 }
 impl<A> Locked<A> {
     /// Creates a new [`Locked<A>`].
@@ -72,11 +186,72 @@ impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
             inner: spin::Mutex::new(inner),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
         }
     }
     /// Returns the lock of this [`Locked<A>`].
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+    pub fn lock(&self) -> LockedGuard<'_, A> {
+        use x86_64::instructions::interrupts;
+
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let guard = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                loop {
+                    // the holder may be waiting for this CPU's TLB flush (memory::shootdown)
+                    crate::memory::shootdown::acknowledge();
+                    core::hint::spin_loop();
+                    if let Some(guard) = self.inner.try_lock() {
+                        break guard;
+                    }
+                }
+            }
+        };
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        LockedGuard {
+            guard: ManuallyDrop::new(guard),
+            interrupts_enabled,
+        }
+    }
+    pub fn lock_stats(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    pub contended: u64,
+}
+
+// unlocks, then turns interrupts back on if they were on before "lock"
+pub struct LockedGuard<'a, A> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, A>>,
+    interrupts_enabled: bool,
+}
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            x86_64::instructions::interrupts::enable();
+        }
     }
 }
 /*adding an align_up method to align address to alignment 'align' [not an efficient function]
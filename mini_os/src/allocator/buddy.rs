@@ -0,0 +1,177 @@
+// binary buddy allocator: memory is handed out in blocks of PAGE_SIZE << order bytes (order 0 up to
+// MAX_ORDER), every block is aligned to its size. A block is split in halves ("buddies") until it
+// has the requested order; freeing a block merges it with its buddy again as long as the buddy is
+// free too. Made for page runs, as the global allocator every allocation takes at least a page.
+use super::{align_up, KernelHeap, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+pub const PAGE_SIZE: usize = 4096;
+pub const MAX_ORDER: usize = 10; // 4MiB blocks
+const ORDERS: usize = MAX_ORDER + 1;
+
+// free blocks store the pointer to the next free block of their order in their first bytes
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+pub fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+// smallest order whose blocks can hold "size" bytes
+pub fn order_for(size: usize) -> Option<usize> {
+    (0..ORDERS).find(|&order| block_size(order) >= size)
+}
+
+pub struct BuddyAllocator {
+    free_lists: [Option<&'static mut ListNode>; ORDERS],
+    heap_size: usize,
+    free: usize, // bytes in the free lists
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        BuddyAllocator {
+            free_lists: [EMPTY; ORDERS],
+            heap_size: 0,
+            free: 0,
+        }
+    }
+
+    /// # Safety
+    /// the memory range must be valid and unused, should only be called once. Only the page aligned
+    /// part of the range is used
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let mut addr = align_up(heap_start, PAGE_SIZE);
+        let end = (heap_start + heap_size) & !(PAGE_SIZE - 1);
+        self.heap_size = end.saturating_sub(addr);
+        while addr < end {
+            // the biggest block that is aligned at "addr" and fits (a page always does)
+            let order = (0..ORDERS)
+                .rev()
+                .find(|&order| addr % block_size(order) == 0 && addr + block_size(order) <= end)
+                .unwrap();
+            self.push(order, addr);
+            addr += block_size(order);
+        }
+    }
+
+    unsafe fn push(&mut self, order: usize, addr: usize) {
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(ListNode {
+            next: self.free_lists[order].take(),
+        });
+        self.free_lists[order] = Some(&mut *node_ptr);
+        self.free += block_size(order);
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let node = self.free_lists[order].take()?;
+        self.free_lists[order] = node.next.take();
+        self.free -= block_size(order);
+        Some(node.start_addr())
+    }
+
+    // takes the block at "addr" out of the free list of "order", false if it isn't free
+    fn remove(&mut self, order: usize, addr: usize) -> bool {
+        let mut current = &mut self.free_lists[order];
+        while current
+            .as_ref()
+            .map_or(false, |node| node.start_addr() != addr)
+        {
+            current = &mut current.as_mut().unwrap().next;
+        }
+        match current.take() {
+            Some(node) => {
+                *current = node.next.take();
+                self.free -= block_size(order);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // a block of "block_size(order)" bytes, aligned to its size
+    pub fn allocate(&mut self, order: usize) -> Option<NonNull<u8>> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let (found, addr) = (order..ORDERS).find_map(|found| Some((found, self.pop(found)?)))?;
+        // split the block down to "order", the upper halves stay free
+        for split in (order..found).rev() {
+            unsafe { self.push(split, addr + block_size(split)) };
+        }
+        NonNull::new(addr as *mut u8)
+    }
+
+    /// # Safety
+    /// "block" must come from "allocate" with the same order and must not be used afterwards
+    pub unsafe fn deallocate(&mut self, block: NonNull<u8>, order: usize) {
+        let mut addr = block.as_ptr() as usize;
+        let mut order = order;
+        // the buddy is the other half of the block of the next order
+        while order < MAX_ORDER && self.remove(order, addr ^ block_size(order)) {
+            addr &= !block_size(order);
+            order += 1;
+        }
+        self.push(order, addr);
+    }
+
+    // number of free blocks of "order"
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let head = self.free_lists[order].as_deref();
+        core::iter::successors(head, |node| node.next.as_deref()).count()
+    }
+}
+
+impl KernelHeap for BuddyAllocator {
+    fn name(&self) -> &'static str {
+        "buddy"
+    }
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        BuddyAllocator::init(self, heap_start, heap_size);
+    }
+    fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+    fn free_bytes(&self) -> usize {
+        self.free
+    }
+    fn largest_free_block(&self) -> usize {
+        (0..ORDERS)
+            .rev()
+            .find(|&order| self.free_lists[order].is_some())
+            .map_or(0, block_size)
+    }
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn layout_order(layout: &Layout) -> Option<usize> {
+    order_for(layout.size().max(layout.align()))
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match layout_order(&layout).and_then(|order| self.lock().allocate(order)) {
+            Some(block) => block.as_ptr(),
+            None => ptr::null_mut(),
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let order = layout_order(&layout).unwrap();
+        self.lock().deallocate(NonNull::new(ptr).unwrap(), order);
+    }
+}
@@ -29,6 +29,26 @@ impl Default for BumpAllocator {
     }
 }
 
+use super::KernelHeap;
+impl KernelHeap for BumpAllocator {
+    fn name(&self) -> &'static str {
+        "bump"
+    }
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        BumpAllocator::init(self, heap_start, heap_size);
+    }
+    fn heap_size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+    // freed memory only comes back once everything is freed, so only the rest counts
+    fn free_bytes(&self) -> usize {
+        self.heap_end - self.heap_next
+    }
+    fn largest_free_block(&self) -> usize {
+        self.free_bytes()
+    }
+}
+
 // every allocator must implement the GlobalAlloc trait
 use super::{align_up, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
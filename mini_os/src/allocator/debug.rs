@@ -0,0 +1,254 @@
+// heap debugging (feature "heap-debug" wraps the global allocator in "Debugged"): every allocation
+// gets a header and redzones of guard bytes in front of and behind it, freed memory is poisoned and
+// every live allocation is kept in a registry together with the return addresses of its callers.
+// dealloc checks pointer, layout and redzones and panics on double frees, wrong layouts and
+// overflows, so these bugs show up where they happen instead of as random page faults later.
+//
+//   inner block: [padding][header][front redzone][user data][back redzone]
+use crate::interrupts::exceptions::Backtrace;
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem::size_of;
+use spin::Mutex;
+
+pub const REDZONE: usize = 16;
+pub const REDZONE_BYTE: u8 = 0xfd;
+pub const POISON_BYTE: u8 = 0xdd; // freed memory
+const LIVE: u64 = 0x4c49_5645_4c49_5645;
+const FREED: u64 = 0x4652_4545_4652_4545;
+
+// the magic number comes last: the inner allocator may put its free list node at the start of a
+// freed block, the FREED mark has to survive that
+#[repr(C)]
+struct Header {
+    size: usize,
+    align: usize,
+    _reserved: usize,
+    magic: u64,
+}
+
+const HEADER_SIZE: usize = size_of::<Header>();
+pub const CALLER_DEPTH: usize = 6;
+const REGISTRY_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    DoubleFree,          // the block was freed already
+    UnknownPointer,      // not allocated by this allocator
+    WrongLayout(Layout), // dealloc with another layout than alloc, this one
+    Underflow,           // front redzone overwritten
+    Overflow,            // back redzone overwritten
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeapError::DoubleFree => f.write_str("double free"),
+            HeapError::UnknownPointer => f.write_str("pointer was not allocated on this heap"),
+            HeapError::WrongLayout(layout) => {
+                write!(f, "freed with a wrong layout, allocated as {:?}", layout)
+            }
+            HeapError::Underflow => f.write_str("buffer underflow: front redzone overwritten"),
+            HeapError::Overflow => f.write_str("buffer overflow: back redzone overwritten"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveAllocation {
+    pub ptr: usize,
+    pub size: usize,
+    pub align: usize,
+    pub callers: [usize; CALLER_DEPTH], // return addresses, innermost first, 0 = none
+}
+
+impl fmt::Display for LiveAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}: {} bytes (align {}), callers",
+            self.ptr, self.size, self.align
+        )?;
+        for &caller in self.callers.iter().take_while(|&&caller| caller != 0) {
+            write!(f, " {:#x}", caller)?;
+        }
+        Ok(())
+    }
+}
+
+// fixed size, allocating in here would recurse into the allocator
+struct Registry {
+    entries: [Option<LiveAllocation>; REGISTRY_SIZE],
+    untracked: usize, // live allocations that didn't fit
+}
+
+impl Registry {
+    fn insert(&mut self, allocation: LiveAllocation) {
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => *entry = Some(allocation),
+            None => self.untracked += 1,
+        }
+    }
+
+    fn find(&self, ptr: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.map_or(false, |allocation| allocation.ptr == ptr))
+    }
+}
+
+pub struct Debugged<A> {
+    inner: A,
+    registry: Mutex<Registry>,
+}
+
+// return addresses of the calling frames
+#[inline(always)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    for (caller, return_address) in callers.iter_mut().zip(Backtrace::here()) {
+        *caller = return_address as usize;
+    }
+    callers
+}
+
+// distance from the start of the inner block to the user data
+fn data_offset(align: usize) -> usize {
+    super::align_up(HEADER_SIZE + REDZONE, align.max(8))
+}
+
+fn inner_layout(layout: &Layout) -> Option<Layout> {
+    let size = data_offset(layout.align()) + layout.size() + REDZONE;
+    Layout::from_size_align(size, layout.align().max(8)).ok()
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.sub(REDZONE + HEADER_SIZE) as *mut Header
+}
+
+impl<A> Debugged<A> {
+    pub const fn new(inner: A) -> Self {
+        const EMPTY: Option<LiveAllocation> = None;
+        Debugged {
+            inner,
+            registry: Mutex::new(Registry {
+                entries: [EMPTY; REGISTRY_SIZE],
+                untracked: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    fn with_registry<R>(&self, f: impl FnOnce(&mut Registry) -> R) -> R {
+        x86_64::instructions::interrupts::without_interrupts(|| f(&mut self.registry.lock()))
+    }
+
+    // what dealloc(ptr, layout) would complain about, Ok if it is a valid free
+    /// # Safety
+    /// "ptr" must point into the heap, the header in front of it is read
+    pub unsafe fn validate(&self, ptr: *mut u8, layout: Layout) -> Result<(), HeapError> {
+        let (registered, untracked) = self
+            .with_registry(|registry| (registry.find(ptr as usize).is_some(), registry.untracked));
+        let header = &*header(ptr);
+        if !registered && header.magic == FREED {
+            return Err(HeapError::DoubleFree);
+        }
+        // without a registry entry it can only be valid if some allocations weren't registered
+        if header.magic != LIVE || (!registered && untracked == 0) {
+            return Err(HeapError::UnknownPointer);
+        }
+        let allocated = Layout::from_size_align_unchecked(header.size, header.align);
+        if allocated != layout {
+            return Err(HeapError::WrongLayout(allocated));
+        }
+        let front = core::slice::from_raw_parts(ptr.sub(REDZONE), REDZONE);
+        if front.iter().any(|&byte| byte != REDZONE_BYTE) {
+            return Err(HeapError::Underflow);
+        }
+        let back = core::slice::from_raw_parts(ptr.add(layout.size()), REDZONE);
+        if back.iter().any(|&byte| byte != REDZONE_BYTE) {
+            return Err(HeapError::Overflow);
+        }
+        Ok(())
+    }
+
+    // calls "f" for every live allocation in the registry, returns how many weren't registered
+    pub fn for_each_live(&self, mut f: impl FnMut(&LiveAllocation)) -> usize {
+        self.with_registry(|registry| {
+            registry.entries.iter().flatten().for_each(&mut f);
+            registry.untracked
+        })
+    }
+
+    // prints the live allocations over serial. Skipped if the registry is locked (e.g. when
+    // called from a panic inside the allocator)
+    pub fn dump(&self) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let registry = match self.registry.try_lock() {
+                Some(registry) => registry,
+                None => {
+                    serial_println!("heap debug: registry is locked, no dump");
+                    return;
+                }
+            };
+            let live = registry.entries.iter().flatten().count();
+            serial_println!(
+                "heap debug: {} live allocations ({} more not registered)",
+                live,
+                registry.untracked
+            );
+            for allocation in registry.entries.iter().flatten() {
+                serial_println!("  {}", allocation);
+            }
+        })
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Debugged<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let inner_layout = match inner_layout(&layout) {
+            Some(inner_layout) => inner_layout,
+            None => return core::ptr::null_mut(),
+        };
+        let block = self.inner.alloc(inner_layout);
+        if block.is_null() {
+            return block;
+        }
+        let ptr = block.add(data_offset(layout.align()));
+        header(ptr).write(Header {
+            size: layout.size(),
+            align: layout.align(),
+            _reserved: 0,
+            magic: LIVE,
+        });
+        ptr.sub(REDZONE).write_bytes(REDZONE_BYTE, REDZONE);
+        ptr.add(layout.size()).write_bytes(REDZONE_BYTE, REDZONE);
+        let allocation = LiveAllocation {
+            ptr: ptr as usize,
+            size: layout.size(),
+            align: layout.align(),
+            callers: callers(),
+        };
+        self.with_registry(|registry| registry.insert(allocation));
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(error) = self.validate(ptr, layout) {
+            panic!("heap debug: dealloc of {:#x}: {}", ptr as usize, error);
+        }
+        self.with_registry(|registry| match registry.find(ptr as usize) {
+            Some(index) => registry.entries[index] = None,
+            None => registry.untracked -= 1,
+        });
+        let inner_layout = inner_layout(&layout).unwrap();
+        let block = ptr.sub(data_offset(layout.align()));
+        block.write_bytes(POISON_BYTE, inner_layout.size());
+        (*header(ptr)).magic = FREED;
+        self.inner.dealloc(block, inner_layout);
+    }
+}
@@ -33,17 +33,58 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
-    // creating a helper function that allocates using fallback allocator
+    // creating a helper function that allocates using fallback allocator, the heap grows when
+    // it's exhausted (up to allocator::heap_limit)
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        loop {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            let heap_start = self.fallback_allocator.bottom() as usize;
+            let heap_size = self.fallback_allocator.top() as usize - heap_start;
+            match super::grow_heap(heap_start, heap_size, &layout) {
+                0 => return ptr::null_mut(),
+                // the new pages directly follow the heap and are faulted in on first use
+                added => unsafe { self.fallback_allocator.extend(added) },
+            }
         }
     }
 
-    // this helper function returns appropriate index from BLOCK_SIZES array according to requested
-    // allocation
+    // number of free blocks in the list of BLOCK_SIZES[index]
+    fn free_blocks(&self, index: usize) -> usize {
+        let head = self.list_heads[index].as_deref();
+        core::iter::successors(head, |node| node.next.as_deref()).count()
+    }
+}
+use super::KernelHeap;
+impl KernelHeap for FixedSizeBlockAllocator {
+    fn name(&self) -> &'static str {
+        "fixed size block"
+    }
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        FixedSizeBlockAllocator::init(self, heap_start as *mut u8, heap_size);
+    }
+    fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+    // free blocks in the lists plus what the fallback heap has left
+    fn free_bytes(&self) -> usize {
+        let listed: usize = (0..BLOCK_SIZES.len())
+            .map(|index| self.free_blocks(index) * BLOCK_SIZES[index])
+            .sum();
+        listed + self.fallback_allocator.free()
+    }
+    // linked_list_allocator doesn't expose its holes, so its free bytes are taken as one block
+    // (an upper bound)
+    fn largest_free_block(&self) -> usize {
+        let largest_listed = (0..BLOCK_SIZES.len())
+            .rev()
+            .find(|&index| self.free_blocks(index) > 0)
+            .map_or(0, |index| BLOCK_SIZES[index]);
+        largest_listed.max(self.fallback_allocator.free())
+    }
 }
+
 impl Default for FixedSizeBlockAllocator {
     fn default() -> Self {
         Self::new()
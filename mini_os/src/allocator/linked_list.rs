@@ -1,5 +1,6 @@
 // using a linked list to keep track of freed memory regions in the for of nodes. Such allocators
-// are also called POOL ALLOCATORS.
+// are also called POOL ALLOCATORS. The list is sorted by address and neighbouring free regions are
+// merged on dealloc, so the heap doesn't fall apart into small pieces over time.
 use super::align_up;
 use core::mem;
 
@@ -21,36 +22,131 @@ impl ListNode {
     }
 }
 
+// which free region an allocation is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    FirstFit, // the first one that fits (default)
+    BestFit,  // the smallest one that fits, keeps big regions intact
+    NextFit,  // the first one that fits behind the previous allocation, wraps around
+}
+
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_size: usize,
+    strategy: FitStrategy,
+    next_fit: usize, // end of the previous allocation
 }
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_size: 0,
+            strategy: FitStrategy::FirstFit,
+            next_fit: 0,
         }
     }
 
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
+    // number of free regions, 1 means the free memory is in one piece
+    pub fn free_regions(&self) -> usize {
+        self.regions().count()
+    }
+
+    // iterates over the free regions (the dummy head is skipped)
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(self.head.next.as_deref(), |node| node.next.as_deref())
+    }
+
     /// # Safety
     /// this function is unsafe because caller should give valid heap_start address and heap size.
     /// Also this function should only be called once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_regions(heap_start, heap_size);
+        self.heap_size = heap_size;
     }
 
     /// # Safety
-    /// adds given memory address in front of the list. Should only be called once.
+    /// the region must be unused memory of the heap that isn't in the list yet
     unsafe fn add_free_regions(&mut self, addr: usize, size: usize) {
         // check to see if the freed region can hold ListNode
         assert_eq!(addr, align_up(addr, mem::align_of::<ListNode>()));
         assert!(size >= mem::size_of::<ListNode>());
 
-        // creating a new list node and appending to start of list
+        // find the last region in front of "addr" (or the dummy head), the list is sorted
+        let mut current = &mut self.head;
+        let mut at_head = true;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+            at_head = false;
+        }
+        assert!(
+            at_head || current.end_addr() <= addr,
+            "freed region overlaps a free region"
+        );
+
+        // merge with the region behind
+        let mut size = size;
+        if let Some(next) = current.next.take() {
+            assert!(
+                addr + size <= next.start_addr(),
+                "freed region overlaps a free region"
+            );
+            if addr + size == next.start_addr() {
+                size += next.size;
+                current.next = next.next.take();
+            } else {
+                current.next = Some(next);
+            }
+        }
+        // merge with the region in front
+        if !at_head && current.end_addr() == addr {
+            current.size += size;
+            return;
+        }
+
+        // creating a new list node and linking it in behind "current"
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
         let node_ptr = addr as *mut ListNode;
         node_ptr.write(node);
-        self.head.next = Some(unsafe { &mut *node_ptr });
+        current.next = Some(unsafe { &mut *node_ptr });
+    }
+
+    // start address of the free region an allocation should come from, depends on the strategy
+    fn choose_region(&self, size: usize, align: usize) -> Option<usize> {
+        let fits = |region: &&ListNode| Self::alloc_from_region(region, size, align).is_ok();
+        let region = match self.strategy {
+            FitStrategy::FirstFit => self.regions().find(fits),
+            FitStrategy::BestFit => self.regions().filter(fits).min_by_key(|region| region.size),
+            FitStrategy::NextFit => self
+                .regions()
+                .filter(|region| region.start_addr() >= self.next_fit)
+                .find(fits)
+                .or_else(|| self.regions().find(fits)),
+        };
+        region.map(|region| region.start_addr())
+    }
+
+    // takes the free region starting at "start" out of the list
+    fn take_region(&mut self, start: usize) -> Option<&'static mut ListNode> {
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() != start)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        let region = current.next.take()?;
+        current.next = region.next.take();
+        Some(region)
     }
 
     // this is the central operation of this allocator. To find the region with it's entry and remove
@@ -60,27 +156,21 @@ impl LinkedListAllocator {
         size: usize,
         align: usize,
     ) -> Option<(&'static mut ListNode, usize)> {
-        // reference to current list node
-        let mut current = &mut self.head;
-        // look for large enough memory in linked list
-        while let Some(ref mut region) = current.next {
-            if let Ok(start_alloc) = Self::alloc_from_region(region, size, align) {
-                // region suitable for allocation -> remove from list
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), start_alloc));
-                current.next = next;
-                return ret;
-            }
-            // region not suitable -> continue with next region
-            current = current.next.as_mut().unwrap();
-        }
-        // no suitable region found
-        None
+        let start = self.choose_region(size, align)?;
+        let region = self.take_region(start)?;
+        let start_alloc = Self::alloc_from_region(region, size, align).ok()?;
+        Some((region, start_alloc))
     }
 
     // this function tries to use given regions for allocation with given size and align
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let start_alloc = align_up(region.start_addr(), align);
+        let mut start_alloc = align_up(region.start_addr(), align);
+        if start_alloc != region.start_addr()
+            && start_alloc - region.start_addr() < mem::size_of::<ListNode>()
+        {
+            // the gap in front has to stay a free region as well, so it must hold a ListNode
+            start_alloc = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let end_alloc = start_alloc.checked_add(size).ok_or(())?;
 
         if end_alloc > region.end_addr() {
@@ -106,7 +196,59 @@ impl LinkedListAllocator {
         let size = layout.size().max(mem::size_of::<ListNode>());
         (size, layout.align())
     }
+
+    // grows or shrinks the allocation [start, start + old_size) without moving it, false if that
+    // isn't possible. Sizes are adjusted ones (size_align)
+    unsafe fn resize_in_place(&mut self, start: usize, old_size: usize, new_size: usize) -> bool {
+        let node_size = mem::size_of::<ListNode>();
+        if new_size <= old_size {
+            let tail = old_size - new_size;
+            if tail > 0 && tail < node_size {
+                return false; // too small to be a free region, it would be lost
+            }
+            if tail > 0 {
+                self.add_free_regions(start + new_size, tail);
+            }
+            return true;
+        }
+        // grow into the free region directly behind the allocation
+        let end = start + old_size;
+        let needed = new_size - old_size;
+        let following = self
+            .regions()
+            .find(|region| region.start_addr() == end)
+            .map(|region| region.size);
+        match following {
+            Some(size) if size == needed || size >= needed + node_size => {
+                self.take_region(end);
+                if size > needed {
+                    self.add_free_regions(start + new_size, size - needed);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
 }
+use super::KernelHeap;
+impl KernelHeap for LinkedListAllocator {
+    fn name(&self) -> &'static str {
+        "linked list"
+    }
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        LinkedListAllocator::init(self, heap_start, heap_size);
+    }
+    fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+    fn free_bytes(&self) -> usize {
+        self.regions().map(|region| region.size).sum()
+    }
+    fn largest_free_block(&self) -> usize {
+        self.regions().map(|region| region.size).max().unwrap_or(0)
+    }
+}
+
 impl Default for LinkedListAllocator {
     fn default() -> Self {
         Self::new()
@@ -127,11 +269,16 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
 
         if let Some((region, start_alloc)) = allocator.find_regions(size, align) {
             let end_alloc = start_alloc.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - end_alloc;
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
 
-            if excess_size > 0 {
-                allocator.add_free_regions(end_alloc, excess_size);
+            // the parts of the region in front of and behind the allocation stay free
+            if start_alloc > region_start {
+                allocator.add_free_regions(region_start, start_alloc - region_start);
+            }
+            if region_end > end_alloc {
+                allocator.add_free_regions(end_alloc, region_end - end_alloc);
             }
+            allocator.next_fit = end_alloc;
             start_alloc as *mut u8
         } else {
             ptr::null_mut()
@@ -142,4 +289,22 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         let (size, _) = LinkedListAllocator::size_align(layout);
         self.lock().add_free_regions(ptr as usize, size);
     }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_adjusted, _) = LinkedListAllocator::size_align(layout);
+        let (new_adjusted, _) = LinkedListAllocator::size_align(new_layout);
+        if self
+            .lock()
+            .resize_in_place(ptr as usize, old_adjusted, new_adjusted)
+        {
+            return ptr;
+        }
+        // no room behind it: move the allocation
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
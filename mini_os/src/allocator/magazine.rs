@@ -0,0 +1,160 @@
+// per-CPU magazines in front of an allocator: every CPU keeps a small stack ("magazine") of free
+// blocks for each size class of the fixed size block allocator. Freed blocks go into the magazine
+// of the running CPU and are handed out from there again, without taking the allocator's lock.
+// Only an empty magazine (alloc) or a full one (dealloc, half of it goes back) reaches the shared
+// allocator. A CPU only ever touches its own magazines, with interrupts disabled, so they need no
+// lock at all.
+use super::stats::SIZE_CLASSES;
+use crate::cpu::{self, MAX_CPUS};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+pub const MAGAZINE_SIZE: usize = 16;
+const CLASSES: usize = SIZE_CLASSES.len();
+
+struct Magazine {
+    blocks: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+pub struct Magazines<A> {
+    inner: A,
+    cpus: [UnsafeCell<[Magazine; CLASSES]>; MAX_CPUS],
+    cached: AtomicUsize, // bytes in all magazines
+    hits: AtomicU64,     // allocations served from a magazine
+    misses: AtomicU64,   // allocations that went to the inner allocator
+}
+
+// every CPU only accesses its own magazines
+unsafe impl<A: Sync> Sync for Magazines<A> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MagazineStats {
+    pub cached_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// size class of an allocation, None if it is too big for the magazines
+fn class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+// cached blocks are allocated from the inner allocator with the layout of their class, so any
+// allocation of the class can use them
+fn class_layout(class: usize) -> Layout {
+    Layout::from_size_align(SIZE_CLASSES[class], SIZE_CLASSES[class]).unwrap()
+}
+
+impl<A> Magazines<A> {
+    pub const fn new(inner: A) -> Self {
+        const EMPTY: Magazine = Magazine {
+            blocks: [ptr::null_mut(); MAGAZINE_SIZE],
+            count: 0,
+        };
+        #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+        const CPU: UnsafeCell<[Magazine; CLASSES]> = UnsafeCell::new([EMPTY; CLASSES]);
+        Magazines {
+            inner,
+            cpus: [CPU; MAX_CPUS],
+            cached: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> MagazineStats {
+        MagazineStats {
+            cached_bytes: self.cached.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    // runs "f" on the running CPU's magazine for "class" with interrupts disabled, so no interrupt
+    // handler on this CPU can use it meanwhile. None if the CPU has no magazines
+    fn with_magazine<R>(&self, class: usize, f: impl FnOnce(&mut Magazine) -> R) -> Option<R> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let cpu = cpu::id()?;
+            let magazines = unsafe { &mut *self.cpus[cpu].get() };
+            Some(f(&mut magazines[class]))
+        })
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Magazines<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = match class(&layout) {
+            Some(class) => class,
+            None => return self.inner.alloc(layout),
+        };
+        let cached = self.with_magazine(class, |magazine| {
+            if magazine.count == 0 {
+                return None;
+            }
+            magazine.count -= 1;
+            Some(magazine.blocks[magazine.count])
+        });
+        if let Some(Some(block)) = cached {
+            self.cached
+                .fetch_sub(SIZE_CLASSES[class], Ordering::Relaxed);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return block;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.inner.alloc(class_layout(class))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let class = match class(&layout) {
+            Some(class) => class,
+            None => return self.inner.dealloc(ptr, layout),
+        };
+        // a full magazine gives its older half back
+        let mut flushed = [ptr::null_mut(); MAGAZINE_SIZE / 2];
+        let stored = self.with_magazine(class, |magazine| {
+            if magazine.count == MAGAZINE_SIZE {
+                flushed.copy_from_slice(&magazine.blocks[..MAGAZINE_SIZE / 2]);
+                magazine.blocks.copy_within(MAGAZINE_SIZE / 2.., 0);
+                magazine.count -= MAGAZINE_SIZE / 2;
+            }
+            magazine.blocks[magazine.count] = ptr;
+            magazine.count += 1;
+        });
+        if stored.is_none() {
+            return self.inner.dealloc(ptr, class_layout(class));
+        }
+        let class_size = SIZE_CLASSES[class];
+        if flushed[0].is_null() {
+            self.cached.fetch_add(class_size, Ordering::Relaxed);
+            return;
+        }
+        self.cached
+            .fetch_sub(class_size * (MAGAZINE_SIZE / 2 - 1), Ordering::Relaxed);
+        for &block in flushed.iter() {
+            self.inner.dealloc(block, class_layout(class));
+        }
+    }
+
+    // allocations too big for the magazines keep the inner allocator's realloc (in place for the
+    // linked list allocator)
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if class(&layout).is_none() && class(&new_layout).is_none() {
+            return self.inner.realloc(ptr, layout, new_size);
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
@@ -0,0 +1,225 @@
+// slab caches: objects of one type are carved out of slabs (buddy blocks), freed objects go back
+// to the free list of their slab, so allocating is taking the head of a list and objects of a type
+// are packed together. A slab starts with its header, the objects follow. Slabs are aligned to
+// their size (buddy blocks are), so the slab of an object is found by masking its address.
+use super::buddy::{self, BuddyAllocator};
+use super::{align_up, Locked};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::NonNull;
+
+// slabs are made big enough for at least this many objects
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+struct FreeObject {
+    next: Option<&'static mut FreeObject>,
+}
+
+struct SlabHeader {
+    next: Option<&'static mut SlabHeader>,
+    free: Option<&'static mut FreeObject>,
+    in_use: usize,
+}
+
+pub struct SlabCache<T> {
+    name: &'static str,
+    pages: &'static Locked<BuddyAllocator>,
+    constructor: fn() -> T,
+    order: usize, // buddy order of the slabs
+    object_size: usize,
+    first_object: usize, // offset in the slab, behind the header
+    objects_per_slab: usize,
+    slabs: Option<&'static mut SlabHeader>,
+    slab_count: usize,
+    live: usize,
+    allocations: u64,
+    failures: u64,
+    _objects: PhantomData<T>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize, // with padding
+    pub slab_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub live_objects: usize,
+    pub free_objects: usize, // in the slabs, without allocating another one
+    pub allocations: u64,    // successful allocations, ever
+    pub failures: u64,       // no slab could be allocated
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} live, {} free objects of {} bytes in {} slabs of {} bytes ({} allocations, {} failed)",
+            self.name,
+            self.live_objects,
+            self.free_objects,
+            self.object_size,
+            self.slabs,
+            self.slab_size,
+            self.allocations,
+            self.failures
+        )
+    }
+}
+
+impl<T> SlabCache<T> {
+    // a cache that takes its slabs from "pages" and initializes every allocated object with
+    // "constructor". Panics if T doesn't fit MIN_OBJECTS_PER_SLAB times into the biggest slab
+    pub fn new(
+        name: &'static str,
+        pages: &'static Locked<BuddyAllocator>,
+        constructor: fn() -> T,
+    ) -> Self {
+        let align = align_of::<T>().max(align_of::<FreeObject>());
+        let object_size = align_up(size_of::<T>().max(size_of::<FreeObject>()), align);
+        let first_object = align_up(size_of::<SlabHeader>(), align);
+        let order = (0..=buddy::MAX_ORDER)
+            .find(|&order| {
+                (buddy::block_size(order) - first_object) / object_size >= MIN_OBJECTS_PER_SLAB
+            })
+            .expect("object is too big for a slab cache");
+        SlabCache {
+            name,
+            pages,
+            constructor,
+            order,
+            object_size,
+            first_object,
+            objects_per_slab: (buddy::block_size(order) - first_object) / object_size,
+            slabs: None,
+            slab_count: 0,
+            live: 0,
+            allocations: 0,
+            failures: 0,
+            _objects: PhantomData,
+        }
+    }
+
+    fn slab_size(&self) -> usize {
+        buddy::block_size(self.order)
+    }
+
+    // a new object made by the constructor, None if no slab could be allocated
+    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+        let object = match self.take_free_object() {
+            Some(object) => object,
+            None => {
+                if !self.grow() {
+                    self.failures += 1;
+                    return None;
+                }
+                self.take_free_object().unwrap()
+            }
+        };
+        self.live += 1;
+        self.allocations += 1;
+        let object = object.cast::<T>();
+        unsafe { object.as_ptr().write((self.constructor)()) };
+        Some(object)
+    }
+
+    /// # Safety
+    /// "object" must come from "alloc" of this cache and must not be used afterwards. It is dropped
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        object.as_ptr().drop_in_place();
+        let addr = object.as_ptr() as usize;
+        let slab = &mut *((addr & !(self.slab_size() - 1)) as *mut SlabHeader);
+        let node_ptr = addr as *mut FreeObject;
+        node_ptr.write(FreeObject {
+            next: slab.free.take(),
+        });
+        slab.free = Some(&mut *node_ptr);
+        slab.in_use -= 1;
+        self.live -= 1;
+    }
+
+    // gives the slabs without live objects back to the buddy allocator, returns how many
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        let mut current = &mut self.slabs;
+        while current.is_some() {
+            if current.as_ref().unwrap().in_use == 0 {
+                let slab = current.take().unwrap();
+                *current = slab.next.take();
+                let block = NonNull::from(slab).cast::<u8>();
+                unsafe { self.pages.lock().deallocate(block, self.order) };
+                released += 1;
+            } else {
+                current = &mut current.as_mut().unwrap().next;
+            }
+        }
+        self.slab_count -= released;
+        released
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            slab_size: self.slab_size(),
+            objects_per_slab: self.objects_per_slab,
+            slabs: self.slab_count,
+            live_objects: self.live,
+            free_objects: self.slab_count * self.objects_per_slab - self.live,
+            allocations: self.allocations,
+            failures: self.failures,
+        }
+    }
+
+    // first free object of the first slab that has one
+    fn take_free_object(&mut self) -> Option<NonNull<u8>> {
+        let mut slab = self.slabs.as_deref_mut();
+        while let Some(header) = slab {
+            if let Some(object) = header.free.take() {
+                header.free = object.next.take();
+                header.in_use += 1;
+                return Some(NonNull::from(object).cast());
+            }
+            slab = header.next.as_deref_mut();
+        }
+        None
+    }
+
+    // adds a slab with all objects free, false if the buddy allocator is out of memory
+    fn grow(&mut self) -> bool {
+        let block = match self.pages.lock().allocate(self.order) {
+            Some(block) => block.as_ptr() as usize,
+            None => return false,
+        };
+        // thread the objects into the free list, the lowest address ends up first
+        let mut free = None;
+        for index in (0..self.objects_per_slab).rev() {
+            let node_ptr =
+                (block + self.first_object + index * self.object_size) as *mut FreeObject;
+            unsafe {
+                node_ptr.write(FreeObject { next: free });
+                free = Some(&mut *node_ptr);
+            }
+        }
+        let header_ptr = block as *mut SlabHeader;
+        unsafe {
+            header_ptr.write(SlabHeader {
+                next: self.slabs.take(),
+                free,
+                in_use: 0,
+            });
+            self.slabs = Some(&mut *header_ptr);
+        }
+        self.slab_count += 1;
+        true
+    }
+}
+
+// empty slabs go back to the buddy allocator, slabs with live objects are leaked (the objects stay
+// valid)
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        self.shrink();
+    }
+}
@@ -0,0 +1,186 @@
+// allocation statistics: "Tracked" wraps the global allocator and counts every successful alloc
+// and dealloc, whatever allocator was selected. Free memory and fragmentation come from the
+// allocator itself (KernelHeap).
+use super::magazine::MagazineStats;
+use super::{KernelHeap, LockStats};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// live allocations are counted per size class, the last class holds everything bigger
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const CLASS_COUNT: usize = SIZE_CLASSES.len() + 1;
+
+fn size_class(layout: &Layout) -> usize {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES
+        .iter()
+        .position(|&class| class >= size)
+        .unwrap_or(SIZE_CLASSES.len())
+}
+
+pub struct Tracked<A> {
+    inner: A,
+    allocations: AtomicU64, // alloc calls that succeeded, ever
+    failures: AtomicU64,
+    live: AtomicUsize,
+    in_use: AtomicUsize, // requested bytes of the live allocations
+    peak: AtomicUsize,
+    classes: [AtomicUsize; CLASS_COUNT],
+}
+
+impl<A> Tracked<A> {
+    pub const fn new(inner: A) -> Self {
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        Tracked {
+            inner,
+            allocations: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            live: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            classes: [ZERO; CLASS_COUNT],
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    fn record_alloc(&self, layout: &Layout) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.live.fetch_add(1, Ordering::Relaxed);
+        let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        self.classes[size_class(layout)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_dealloc(&self, layout: &Layout) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.classes[size_class(layout)].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // counters and the heap, see "allocator::stats" for the full picture
+    pub fn stats(&self, heap: &impl KernelHeap) -> HeapStats {
+        let mut size_classes = [0; CLASS_COUNT];
+        for (count, class) in size_classes.iter_mut().zip(self.classes.iter()) {
+            *count = class.load(Ordering::Relaxed);
+        }
+        HeapStats {
+            allocator: heap.name(),
+            heap_size: heap.heap_size(),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            live_allocations: self.live.load(Ordering::Relaxed),
+            bytes_in_use: self.in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak.load(Ordering::Relaxed),
+            free_bytes: heap.free_bytes(),
+            largest_free_block: heap.largest_free_block(),
+            size_classes,
+            magazines: MagazineStats::default(),
+            lock: LockStats::default(),
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.record_alloc(&layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.record_dealloc(&layout);
+    }
+
+    // forwarded, allocators may resize in place
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.record_dealloc(&layout);
+            self.record_alloc(&Layout::from_size_align_unchecked(new_size, layout.align()));
+        }
+        new_ptr
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub allocator: &'static str,
+    pub heap_size: usize,
+    pub allocations: u64, // successful allocations since boot
+    pub failures: u64,
+    pub live_allocations: usize,
+    pub bytes_in_use: usize, // as requested, without the allocator's padding
+    pub peak_bytes_in_use: usize,
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+    pub size_classes: [usize; CLASS_COUNT], // live allocations per SIZE_CLASSES entry (+ bigger)
+    pub magazines: MagazineStats,           // blocks cached per CPU count as used above
+    pub lock: LockStats,
+}
+
+impl HeapStats {
+    // share of the free memory that can't be used for one big allocation (0 = all in one block)
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        100 - self.largest_free_block.min(self.free_bytes) * 100 / self.free_bytes
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "allocator: {}, heap size {} bytes",
+            self.allocator, self.heap_size
+        )?;
+        writeln!(
+            f,
+            "allocations: {} live, {} total, {} failed",
+            self.live_allocations, self.allocations, self.failures
+        )?;
+        writeln!(
+            f,
+            "in use: {} bytes (peak {}), free: {} bytes",
+            self.bytes_in_use, self.peak_bytes_in_use, self.free_bytes
+        )?;
+        writeln!(
+            f,
+            "largest free block: {} bytes, fragmentation {}%",
+            self.largest_free_block,
+            self.fragmentation_percent()
+        )?;
+        writeln!(
+            f,
+            "per-CPU magazines: {} bytes cached, {} hits, {} misses",
+            self.magazines.cached_bytes, self.magazines.hits, self.magazines.misses
+        )?;
+        writeln!(
+            f,
+            "lock: {} acquisitions, {} contended",
+            self.lock.acquisitions, self.lock.contended
+        )?;
+        write!(f, "live per size class:")?;
+        for (size, count) in SIZE_CLASSES.iter().zip(self.size_classes.iter()) {
+            write!(f, " {}:{}", size, count)?;
+        }
+        write!(
+            f,
+            " >{}:{}",
+            SIZE_CLASSES[SIZE_CLASSES.len() - 1],
+            self.size_classes[CLASS_COUNT - 1]
+        )
+    }
+}
@@ -0,0 +1,122 @@
+// CPU identification for per-CPU data. CPUs are numbered in the order they come online, the
+// bootstrap processor is 0. While it is the only one, "id" doesn't even have to ask the CPU. Every
+// CPU keeps its number in IA32_TSC_AUX, which "rdtscp" reads without a VM exit (unlike "cpuid",
+// the fallback on CPUs without "rdtscp"). The GS base would be faster but ring 3 can reset it.
+use core::arch::x86_64::{__cpuid, __rdtscp};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+pub const MAX_CPUS: usize = 8;
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+// local APIC id of every online CPU, indexed by CPU number
+static APIC_IDS: [AtomicUsize; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const UNKNOWN: AtomicUsize = AtomicUsize::new(usize::MAX);
+    [UNKNOWN; MAX_CPUS]
+};
+
+const IA32_TSC_AUX: u32 = 0xc000_0103;
+// CPUID 0x8000_0001, EDX bit 27
+static RDTSCP: AtomicBool = AtomicBool::new(false);
+
+// called once by "init" on the bootstrap processor
+pub fn init_bsp() {
+    APIC_IDS[0].store(apic_id() as usize, Ordering::Relaxed);
+    let extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    let rdtscp = extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & 1 << 27 != 0;
+    RDTSCP.store(rdtscp, Ordering::Relaxed);
+    set_tsc_aux(0);
+}
+
+// called by every application processor before anything uses per-CPU data (smp::ap_main)
+pub(crate) fn init_ap(cpu: usize) {
+    set_tsc_aux(cpu);
+}
+
+fn set_tsc_aux(cpu: usize) {
+    if RDTSCP.load(Ordering::Relaxed) {
+        unsafe { Msr::new(IA32_TSC_AUX).write(cpu as u64) };
+    }
+}
+
+// gives the next CPU number to the processor with local APIC id "apic_id", before it's started so
+// "id" works from its first instruction on. None if MAX_CPUS are online. Only the bootstrap
+// processor adds CPUs (smp::start_application_processors), one after the other
+pub(crate) fn add(apic_id: u8) -> Option<usize> {
+    let cpu = count();
+    if cpu >= MAX_CPUS {
+        return None;
+    }
+    APIC_IDS[cpu].store(apic_id.into(), Ordering::Relaxed);
+    ONLINE.store(cpu + 1, Ordering::Release);
+    Some(cpu)
+}
+
+// takes back the last "add", the processor didn't come up
+pub(crate) fn remove(cpu: usize) {
+    assert_eq!(cpu + 1, count(), "only the last CPU can be removed");
+    ONLINE.store(cpu, Ordering::Release);
+    APIC_IDS[cpu].store(usize::MAX, Ordering::Relaxed);
+}
+
+// initial local APIC id of the running CPU (CPUID leaf 1)
+pub fn apic_id() -> u8 {
+    let leaf = unsafe { __cpuid(1) };
+    (leaf.ebx >> 24) as u8
+}
+
+// local APIC id of CPU "cpu", None if it isn't online
+pub fn apic_id_of(cpu: usize) -> Option<u8> {
+    if cpu < count() {
+        Some(APIC_IDS[cpu].load(Ordering::Relaxed) as u8)
+    } else {
+        None
+    }
+}
+
+pub fn count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+// number of the running CPU, None if it wasn't registered (more than MAX_CPUS CPUs, those are
+// never started)
+pub fn id() -> Option<usize> {
+    let online = count();
+    if online == 1 {
+        return Some(0);
+    }
+    if RDTSCP.load(Ordering::Relaxed) {
+        let mut cpu = 0;
+        unsafe { __rdtscp(&mut cpu) };
+        return Some(cpu as usize);
+    }
+    let apic_id = apic_id() as usize;
+    (0..online).find(|&cpu| APIC_IDS[cpu].load(Ordering::Relaxed) == apic_id)
+}
+
+// PER-CPU DATA
+// one T per CPU, indexed by CPU number. Whatever each CPU changes in its own T needs interior
+// mutability (atomics, locks), another CPU may look at it
+pub struct PerCpu<T> {
+    data: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(data: [T; MAX_CPUS]) -> Self {
+        PerCpu { data }
+    }
+
+    // the running CPU's, panics on an unregistered CPU
+    pub fn get(&self) -> &T {
+        &self.data[id().expect("CPU is not registered")]
+    }
+
+    pub fn of(&self, cpu: usize) -> &T {
+        &self.data[cpu]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.data.iter()
+    }
+}
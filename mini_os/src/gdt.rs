@@ -1,24 +1,54 @@
-use core::ptr::addr_of;
+use core::ptr::{addr_of, addr_of_mut};
 
+use crate::cpu::{PerCpu, MAX_CPUS};
+use crate::memory::stack::KernelStack;
+use crate::memory::PagingError;
 use lazy_static::lazy_static;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_INDEX: u16 = 0;
+const EXCEPTION_STACK_PAGES: u64 = 5; // 20KiB
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; // 20KiB size
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+// the CPU reads the stack pointers from here whenever it switches stacks, every CPU from its own.
+// Until pages can be mapped the bootstrap processor's point to the static boot stacks below
+// (without guard pages), "init_guarded_stacks" replaces them
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [TaskStateSegment::new(); MAX_CPUS];
 
-            let start_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
+fn set_boot_stacks() {
+    const STACK_SIZE: usize = 4096 * EXCEPTION_STACK_PAGES as usize;
+    static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    // stack the CPU switches to when an interrupt or syscall arrives in ring 3
+    static mut PRIVILEGE_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-            start_start + STACK_SIZE
-        };
-        tss
-    };
+    let tss = unsafe { &mut (*addr_of_mut!(TSS))[0] };
+    let index = DOUBLE_FAULT_INDEX as usize;
+    if tss.interrupt_stack_table[index].is_null() {
+        let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(DOUBLE_FAULT_STACK) });
+        tss.interrupt_stack_table[index] = stack_start + STACK_SIZE;
+    }
+    if tss.privilege_stack_table[0].is_null() {
+        let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(PRIVILEGE_STACK) });
+        tss.privilege_stack_table[0] = stack_start + STACK_SIZE;
+    }
+}
+
+// moves the double fault and ring 3 interrupt stacks to kernel stacks with guard pages, an
+// overflow of them then faults instead of running into other statics. Needs
+// "memory::init_global" first
+pub fn init_guarded_stacks() -> Result<(), PagingError> {
+    set_guarded_stacks(0)
+}
+
+fn set_guarded_stacks(cpu: usize) -> Result<(), PagingError> {
+    let double_fault = KernelStack::new("double fault handler", EXCEPTION_STACK_PAGES)?;
+    let privilege = KernelStack::new("interrupts from ring 3", EXCEPTION_STACK_PAGES)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let tss = unsafe { &mut (*addr_of_mut!(TSS))[cpu] };
+        tss.interrupt_stack_table[DOUBLE_FAULT_INDEX as usize] = double_fault.leak();
+        tss.privilege_stack_table[0] = privilege.leak();
+    });
+    Ok(())
 }
 
 use x86_64::structures::gdt::SegmentSelector;
@@ -26,36 +56,79 @@ use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+// the same segments for every CPU, only the TSS descriptor points to the CPU's own TSS
+fn new_gdt(cpu: usize) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // kernel code, kernel data, user data, user code: the order "sysret" expects
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = unsafe { &(*addr_of!(TSS))[cpu] };
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        },
+    )
+}
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        set_boot_stacks();
+        new_gdt(0)
     };
 }
 
+// GDTs of the application processors (CPU 0 uses GDT)
+static AP_GDTS: PerCpu<spin::Once<(GlobalDescriptorTable, Selectors)>> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const NONE: spin::Once<(GlobalDescriptorTable, Selectors)> = spin::Once::new();
+    [NONE; MAX_CPUS]
+});
+
+// code and data (stack) selectors for ring 3, with requested privilege level 3
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
 pub fn init() {
     // GDT.load();
     // just loading won't solve the stack overflow problem. Also need to modify double
     // fault IDT entry so it uses this new GDT.
+    load(&GDT);
+}
+
+// GDT and TSS (with guarded stacks) of an application processor, on the processor itself
+pub fn init_cpu(cpu: usize) -> Result<(), PagingError> {
+    set_guarded_stacks(cpu)?;
+    load(AP_GDTS.of(cpu).call_once(|| new_gdt(cpu)));
+    Ok(())
+}
 
-    use x86_64::instructions::segmentation::{Segment, CS};
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
     // CS here stands for code selector
 
-    GDT.0.load(); // uses the "lgdt" instruction
+    gdt.0.load(); // uses the "lgdt" instruction
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        // iretq reloads SS, so it has to point into this GDT (returning to ring 3 changes it)
+        SS::set_reg(gdt.1.data_selector);
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
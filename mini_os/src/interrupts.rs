@@ -1,66 +1,52 @@
-use crate::gdt;
-use crate::{print, println};
-use lazy_static::lazy_static;
-// lazily initializes a static variable when referenced for the first time
+use crate::cpu::{self, PerCpu, MAX_CPUS};
+use crate::print;
+use crate::process::syscall;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        // breakpoint handler
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_INDEX);
-        /*
-
-        set_stack_index is marked unsafe because caller should ensure used index is valid and
-        not already used for another exception. In this case, it's set to index 0.
-
-        */
+use x86_64::PrivilegeLevel;
+
+pub mod apic;
+pub mod exceptions;
+
+// every CPU loads its own copy, built on its first "init_idt"
+static IDTS: PerCpu<spin::Once<InterruptDescriptorTable>> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const NONE: spin::Once<InterruptDescriptorTable> = spin::Once::new();
+    [NONE; MAX_CPUS]
+});
+
+fn new_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    // every CPU exception goes through an entry stub of the exceptions module
+    exceptions::install(&mut idt);
+    unsafe {
         idt[InterrupIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         // InterruptDescriptorTable implements "IndexMut" trait so we could use array indexing
         // syntax. handler function signarure for interrupts is same as usual exceptions because
         // CPU handles interrupts the same way as exceptions.
         idt[InterrupIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        }
-
-        //page fault handler
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt
-    };
+        idt[InterrupIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+    }
+    // what the masked PICs may still raise, and the local APIC's spurious interrupt
+    idt[usize::from(PIC_1_OFFSET + 7)].set_handler_fn(master_spurious_interrupt_handler);
+    idt[usize::from(PIC_2_OFFSET + 7)].set_handler_fn(slave_spurious_interrupt_handler);
+    idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_interrupt_handler);
+    // other CPUs wake a halted one with this
+    idt[usize::from(apic::WAKEUP_VECTOR)].set_handler_fn(wakeup_interrupt_handler);
+    idt[usize::from(apic::TLB_SHOOTDOWN_VECTOR)].set_handler_fn(tlb_shootdown_interrupt_handler);
+
+    // syscalls: "int 0x80" has to be allowed from ring 3
+    unsafe {
+        idt[usize::from(syscall::INTERRUPT)]
+            .set_handler_addr(syscall::entry_address())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+    idt
 }
 
+// loads the running CPU's IDT
 pub fn init_idt() {
-    IDT.load(); // uses the "lidt" instruction to load interrupt descriptor table...
-}
-
-/// CPU EXCEPTION HANDLERS
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:?}", stack_frame);
-}
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-use crate::hlt_loop;
-use x86_64::structures::idt::PageFaultErrorCode;
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-    // CR2 register is set by CPU on page fault and it holds the virtual address that caused the
-    // page fault...
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    // uses the "lidt" instruction to load interrupt descriptor table...
+    IDTS.get().call_once(new_idt).load();
 }
 
 #[test_case]
@@ -70,6 +56,7 @@ fn test_breakpoint_exception() {
 }
 
 /// HARDWARE INTERRUPTS SECTION
+use core::sync::atomic::{AtomicU64, Ordering};
 use pic8259::ChainedPics;
 // use spin;
 
@@ -82,8 +69,9 @@ pub static PICS: spin::Mutex<ChainedPics> =
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterrupIndex {
-    Timer = PIC_1_OFFSET, // using C-like enum
+    Timer = PIC_1_OFFSET,      // using C-like enum
     Keyboard, // we didn't have to specify value 33, because it defaults to previous value + 1.
+    Serial = PIC_1_OFFSET + 4, // COM1
 }
 impl InterrupIndex {
     fn as_u8(self) -> u8 {
@@ -94,13 +82,24 @@ impl InterrupIndex {
     }
 }
 
+// the PIT isn't reprogrammed, so it fires at its default rate of ~18.2 Hz. The APIC timer that
+// replaces it (apic::init) is calibrated to the same period
+pub const TIMER_TICK_MICROS: u64 = 54_925;
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    // sending and EOI signal that timer interrupt has been processed...
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterrupIndex::Timer.as_u8());
+    // every CPU has its own APIC timer, the ticks are counted by the first one
+    if cpu::id() == Some(0) {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        print!(".");
     }
+    // sending and EOI signal that timer interrupt has been processed...
+    end_of_interrupt(InterrupIndex::Timer);
 }
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
@@ -110,9 +109,42 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode); // new
 
-    // similar to timer interrupt, we send an EOI signal to PICs...
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterrupIndex::Keyboard.as_u8());
+    // similar to timer interrupt, we send an EOI signal...
+    end_of_interrupt(InterrupIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::receive();
+    end_of_interrupt(InterrupIndex::Serial);
+}
+
+// the EOI goes to the local APIC once it took over from the PICs
+fn end_of_interrupt(index: InterrupIndex) {
+    if apic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
     }
 }
+
+// IRQ 7 and 15 without a real interrupt behind them (e.g. one that was masked while the PIC raised
+// it). No EOI, except for the cascade input on the master when the slave's was spurious
+extern "x86-interrupt" fn master_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn slave_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    unsafe { Port::<u8>::new(0x20).write(0x20) };
+}
+
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+// nothing to do, the interrupt only ends a "hlt"
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::memory::shootdown::acknowledge();
+    apic::end_of_interrupt();
+}
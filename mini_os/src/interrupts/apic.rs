@@ -0,0 +1,425 @@
+// local APIC and IOAPIC, found through the ACPI MADT. "init" masks the 8259 PICs, enables the
+// local APIC of the bootstrap processor, starts its timer with the period the PIT had (calibrated
+// against PIT channel 2) and routes the keyboard and COM1 interrupts through the IOAPIC to it.
+// Until then (or if there's no MADT) the PICs stay in charge.
+use super::{InterrupIndex, TIMER_TICK_MICROS};
+use crate::acpi::{self, AcpiError, MAX_IO_APICS};
+use crate::memory::{self, PagingError};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+pub const WAKEUP_VECTOR: u8 = 0xf0; // IPI that ends a "hlt"
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf1; // IPI that flushes the TLB (memory::shootdown)
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const SERIAL_IRQ: u8 = 4; // COM1
+
+// local APIC registers, offsets into its page
+const ID: u64 = 0x20;
+const TASK_PRIORITY: u64 = 0x80;
+const EOI: u64 = 0xb0;
+const SPURIOUS: u64 = 0xf0;
+const ERROR_STATUS: u64 = 0x280;
+const INTERRUPT_COMMAND_LOW: u64 = 0x300;
+const INTERRUPT_COMMAND_HIGH: u64 = 0x310;
+const LVT_TIMER: u64 = 0x320;
+const LVT_LINT0: u64 = 0x350;
+const LVT_LINT1: u64 = 0x360;
+const LVT_ERROR: u64 = 0x370;
+const TIMER_INITIAL: u64 = 0x380;
+const TIMER_CURRENT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const ACTIVE_LOW: u32 = 1 << 13;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+const MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b011;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+// IOAPIC registers: select one with IOREGSEL, then access it through IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10; // two registers per input
+
+// the PIT runs at 1.193182 MHz, the calibration counts 10ms of it
+const PIT_HZ: u64 = 1_193_182;
+const CALIBRATION_MICROS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    Acpi(AcpiError),
+    Mapping(PagingError), // mapping the registers failed
+    NoIoApic(u32),        // no IOAPIC has an input for this global system interrupt
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(error: AcpiError) -> Self {
+        ApicError::Acpi(error)
+    }
+}
+
+impl From<PagingError> for ApicError {
+    fn from(error: PagingError) -> Self {
+        ApicError::Mapping(error)
+    }
+}
+
+impl core::fmt::Display for ApicError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ApicError::Acpi(error) => write!(f, "{}", error),
+            ApicError::Mapping(error) => write!(f, "mapping the APIC registers failed: {}", error),
+            ApicError::NoIoApic(gsi) => write!(f, "no IOAPIC handles interrupt {}", gsi),
+        }
+    }
+}
+
+// virtual address of the local APIC registers, 0 while the PICs are in charge. Every CPU sees its
+// own local APIC at the same address
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+// initial count of the periodic timer, one tick of TIMER_TICK_MICROS
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn local_apic() -> Option<VirtAddr> {
+    match LOCAL_APIC.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(VirtAddr::new(base)),
+    }
+}
+
+unsafe fn read(base: VirtAddr, register: u64) -> u32 {
+    (base + register).as_ptr::<u32>().read_volatile()
+}
+
+unsafe fn write(base: VirtAddr, register: u64, value: u32) {
+    (base + register).as_mut_ptr::<u32>().write_volatile(value)
+}
+
+// true once the local APIC delivers the interrupts instead of the PICs
+pub fn is_active() -> bool {
+    local_apic().is_some()
+}
+
+// acknowledges the interrupt being handled on the running CPU
+pub fn end_of_interrupt() {
+    if let Some(base) = local_apic() {
+        unsafe { write(base, EOI, 0) };
+    }
+}
+
+// local APIC id of the running CPU, read from the APIC itself
+pub fn id() -> Option<u8> {
+    local_apic().map(|base| (unsafe { read(base, ID) } >> 24) as u8)
+}
+
+// initial count the timer is calibrated to (with the divider at 16), None before "init"
+pub fn timer_count() -> Option<u32> {
+    match TIMER_COUNT.load(Ordering::Relaxed) {
+        0 => None,
+        count => Some(count),
+    }
+}
+
+// INTER-PROCESSOR INTERRUPTS
+// writing the low half of the interrupt command register sends the IPI, the destination has to
+// be in the high half before. Waits until the local APIC accepted it. The level bit is set for
+// everything but the (obsolete) INIT deassert
+fn send(apic_id: u8, command: u32) {
+    let base = match local_apic() {
+        Some(base) => base,
+        None => return,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        write(base, INTERRUPT_COMMAND_HIGH, u32::from(apic_id) << 24);
+        write(base, INTERRUPT_COMMAND_LOW, command);
+        while read(base, INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+// interrupt "vector" on the CPU with local APIC id "apic_id"
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send(apic_id, LEVEL_ASSERT | u32::from(vector));
+}
+
+// resets the processor, it waits for a startup IPI then
+pub fn send_init(apic_id: u8) {
+    send(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+// the processor starts in real mode at physical address page * 4KiB (below 1MiB)
+pub fn send_startup(apic_id: u8, page: u8) {
+    send(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | u32::from(page));
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        (self.base + IOREGSEL)
+            .as_mut_ptr::<u32>()
+            .write_volatile(register);
+        (self.base + IOWIN).as_ptr::<u32>().read_volatile()
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        (self.base + IOREGSEL)
+            .as_mut_ptr::<u32>()
+            .write_volatile(register);
+        (self.base + IOWIN)
+            .as_mut_ptr::<u32>()
+            .write_volatile(value)
+    }
+
+    fn entry(&self, input: u32) -> u64 {
+        let register = REDIRECTION_TABLE + 2 * input;
+        unsafe { u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32 }
+    }
+
+    // masked while the halves are written one after the other
+    fn set_entry(&self, input: u32, entry: u64) {
+        let register = REDIRECTION_TABLE + 2 * input;
+        unsafe {
+            self.write(register, MASKED);
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32);
+        }
+    }
+}
+
+// the IOAPICs of the MADT, in its order. Only "init" and the routing functions touch them, never an
+// interrupt handler
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+
+// the IOAPIC of "io_apics" that has an input for "gsi" and the number of that input
+fn find_input(
+    io_apics: &[Option<IoApic>; MAX_IO_APICS],
+    gsi: u32,
+) -> Result<(&IoApic, u32), ApicError> {
+    let io_apic = io_apics
+        .iter()
+        .flatten()
+        .find(|io_apic| gsi >= io_apic.gsi_base && gsi - io_apic.gsi_base < io_apic.inputs)
+        .ok_or(ApicError::NoIoApic(gsi))?;
+    Ok((io_apic, gsi - io_apic.gsi_base))
+}
+
+// runs "f" with the IOAPIC that has an input for "gsi" and the number of that input
+fn with_io_apic<R>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> R) -> Result<R, ApicError> {
+    let io_apics = IO_APICS.lock();
+    let (io_apic, input) = find_input(&io_apics, gsi)?;
+    Ok(f(io_apic, input))
+}
+
+// the input of ISA interrupt "irq" and the redirection entry that delivers it as "vector" to the
+// local APIC with id "apic_id", with the polarity and trigger mode of the MADT's override if there
+// is one
+fn isa_entry(irq: u8, vector: u8, apic_id: u8) -> Result<(u32, u64), ApicError> {
+    let (gsi, flags) = acpi::madt()?.isa_irq(irq);
+    // fixed delivery mode, physical destination
+    let mut entry = u64::from(vector) | u64::from(apic_id) << 56;
+    if acpi::active_low(flags) {
+        entry |= u64::from(ACTIVE_LOW);
+    }
+    if acpi::level_triggered(flags) {
+        entry |= u64::from(LEVEL_TRIGGERED);
+    }
+    Ok((gsi, entry))
+}
+
+// delivers ISA interrupt "irq" as "vector" to the local APIC with id "apic_id"
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u8) -> Result<(), ApicError> {
+    let (gsi, entry) = isa_entry(irq, vector, apic_id)?;
+    with_io_apic(gsi, |io_apic, input| io_apic.set_entry(input, entry))
+}
+
+// vector and destination APIC id of ISA interrupt "irq", None if it's masked or not routed
+pub fn isa_route(irq: u8) -> Option<(u8, u8)> {
+    let (gsi, _) = acpi::madt().ok()?.isa_irq(irq);
+    let entry = with_io_apic(gsi, |io_apic, input| io_apic.entry(input)).ok()?;
+    if entry & u64::from(MASKED) != 0 {
+        None
+    } else {
+        Some((entry as u8, (entry >> 56) as u8))
+    }
+}
+
+// the masks of the two 8259s, 0xff for both once the APICs took over
+pub fn pic_masks() -> [u8; 2] {
+    unsafe { [Port::new(0x21).read(), Port::new(0xa1).read()] }
+}
+
+// software enables the local APIC of the running CPU: spurious vector, no priority threshold, the
+// LINT inputs masked unless the MADT connects the NMI to them
+unsafe fn enable(base: VirtAddr) {
+    write(base, ERROR_STATUS, 0);
+    write(base, LVT_ERROR, MASKED);
+    write(base, LVT_TIMER, MASKED);
+    write(base, LVT_LINT0, MASKED); // the PICs' virtual wire
+    write(base, LVT_LINT1, MASKED);
+    if let Ok(madt) = acpi::madt() {
+        let apic_id = (read(base, ID) >> 24) as u8;
+        let uid = madt
+            .processors()
+            .find(|cpu| cpu.apic_id == apic_id)
+            .map(|cpu| cpu.uid);
+        for nmi in madt
+            .nmis()
+            .filter(|nmi| nmi.uid == 0xff || Some(nmi.uid) == uid)
+        {
+            let mut lvt = DELIVERY_NMI;
+            if acpi::active_low(nmi.flags) {
+                lvt |= ACTIVE_LOW;
+            }
+            write(base, if nmi.lint == 0 { LVT_LINT0 } else { LVT_LINT1 }, lvt);
+        }
+    }
+    write(base, TASK_PRIORITY, 0);
+    write(base, SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+// PIT channel 2 as a one shot of at most ~54ms: "pit_arm" loads the count with the gate low
+// (stopped) and the speaker off, "pit_start" raises the gate, the output goes high at the end of
+// the count (mode 0) and can be polled in port 0x61. Returns the value to restore port 0x61 with
+unsafe fn pit_arm(micros: u64) -> u8 {
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    let count = (PIT_HZ * micros / 1_000_000).clamp(1, 0xffff) as u16;
+
+    let saved = control.read();
+    control.write(saved & !0b11);
+    // channel 2, low byte then high byte, mode 0
+    command.write(0b1011_0000);
+    channel_2.write(count as u8);
+    channel_2.write((count >> 8) as u8);
+    saved
+}
+
+unsafe fn pit_start(saved: u8) {
+    Port::<u8>::new(0x61).write((saved & !0b10) | 0b01);
+}
+
+unsafe fn pit_expired() -> bool {
+    Port::<u8>::new(0x61).read() & 0x20 != 0
+}
+
+// busy waits "micros" (at most ~54ms) on PIT channel 2. There's only one PIT, so it's for the
+// bootstrap processor (starting the others)
+pub fn delay_micros(micros: u64) {
+    unsafe {
+        let saved = pit_arm(micros);
+        pit_start(saved);
+        while !pit_expired() {
+            core::hint::spin_loop();
+        }
+        Port::<u8>::new(0x61).write(saved);
+    }
+}
+
+// APIC timer counts in one TIMER_TICK_MICROS tick: the APIC timer counts down from the top while
+// the PIT counts CALIBRATION_MICROS once
+unsafe fn calibrate(base: VirtAddr) -> u32 {
+    let saved = pit_arm(CALIBRATION_MICROS);
+    write(base, TIMER_DIVIDE, DIVIDE_BY_16);
+    write(base, LVT_TIMER, MASKED);
+    pit_start(saved);
+    write(base, TIMER_INITIAL, u32::MAX);
+    while !pit_expired() {}
+    let elapsed = u32::MAX - read(base, TIMER_CURRENT);
+    write(base, TIMER_INITIAL, 0);
+    Port::<u8>::new(0x61).write(saved);
+
+    let count = u64::from(elapsed) * TIMER_TICK_MICROS / CALIBRATION_MICROS;
+    count.clamp(1, u32::MAX.into()) as u32
+}
+
+unsafe fn start_timer(base: VirtAddr) {
+    write(base, TIMER_DIVIDE, DIVIDE_BY_16);
+    write(
+        base,
+        LVT_TIMER,
+        TIMER_PERIODIC | u32::from(InterrupIndex::Timer.as_u8()),
+    );
+    write(base, TIMER_INITIAL, TIMER_COUNT.load(Ordering::Relaxed));
+}
+
+// switches the bootstrap processor from the PICs to the APICs. Needs the memory manager for the
+// register mappings, call it after "memory::init_global". On an error the PICs stay in charge
+pub fn init() -> Result<(), ApicError> {
+    let madt = acpi::madt()?;
+    let base = memory::map_mmio(madt.local_apic, 4096)?;
+    let mut io_apics = [None; MAX_IO_APICS];
+    for (slot, io_apic) in io_apics.iter_mut().zip(madt.io_apics()) {
+        let mut mapped = IoApic {
+            base: memory::map_mmio(io_apic.address, 0x20)?,
+            gsi_base: io_apic.gsi_base,
+            inputs: 0,
+        };
+        // the version register has the index of the last redirection entry
+        mapped.inputs = ((unsafe { mapped.read(IOAPIC_VERSION) } >> 16) & 0xff) + 1;
+        for input in 0..mapped.inputs {
+            mapped.set_entry(input, MASKED.into());
+        }
+        *slot = Some(mapped);
+    }
+    // everything that can fail comes before the switch, the routes too
+    let apic_id = crate::cpu::apic_id();
+    let mut routes = [None; 2];
+    let isa_irqs = [
+        (KEYBOARD_IRQ, InterrupIndex::Keyboard.as_u8()),
+        (SERIAL_IRQ, InterrupIndex::Serial.as_u8()),
+    ];
+    for (route, &(irq, vector)) in routes.iter_mut().zip(isa_irqs.iter()) {
+        let (gsi, entry) = isa_entry(irq, vector, apic_id)?;
+        let (io_apic, input) = find_input(&io_apics, gsi)?;
+        *route = Some((*io_apic, input, entry));
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // the PICs keep their vectors (32-47), an interrupt they raised just before is spurious
+        if madt.legacy_pics {
+            unsafe {
+                Port::<u8>::new(0x21).write(0xff);
+                Port::<u8>::new(0xa1).write(0xff);
+            }
+        }
+        unsafe {
+            enable(base);
+            TIMER_COUNT.store(calibrate(base), Ordering::Relaxed);
+        }
+        LOCAL_APIC.store(base.as_u64(), Ordering::Release);
+        unsafe { start_timer(base) };
+        for &(io_apic, input, entry) in routes.iter().flatten() {
+            io_apic.set_entry(input, entry);
+        }
+        *IO_APICS.lock() = io_apics;
+    });
+    Ok(())
+}
+
+// enables the local APIC of the running CPU and starts its timer, for CPUs coming online after
+// "init" ran on the bootstrap processor
+pub fn init_local_apic() {
+    if let Some(base) = local_apic() {
+        unsafe {
+            enable(base);
+            start_timer(base);
+        }
+    }
+}
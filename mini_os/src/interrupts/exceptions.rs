@@ -0,0 +1,714 @@
+// CPU exceptions: every architectural exception enters through a small assembly stub that saves all
+// general purpose registers and CR2 next to the interrupt frame (an ExceptionFrame) and calls
+// "exception_dispatch", which adds the control, debug and FPU registers the report decodes.
+// Exceptions of user programs end the process, breakpoints, debug traps and NMIs are reported and
+// execution continues, everything else panics with a report: the exception with its error code
+// decoded, a register dump and a backtrace over the saved frame pointers.
+use crate::cpu::{PerCpu, MAX_CPUS};
+use crate::gdt;
+use crate::memory::{self, stack};
+use crate::println;
+use crate::process::{self, ExitStatus};
+use core::arch::{asm, global_asm};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE_EXCEEDED: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const CONTROL_PROTECTION: u8 = 21;
+pub const HYPERVISOR_INJECTION: u8 = 28;
+pub const VMM_COMMUNICATION: u8 = 29;
+pub const SECURITY: u8 = 30;
+
+// mnemonic and name, None for the reserved vectors
+pub fn name(vector: u8) -> Option<(&'static str, &'static str)> {
+    let name = match vector {
+        DIVIDE_ERROR => ("#DE", "DIVIDE ERROR"),
+        DEBUG => ("#DB", "DEBUG"),
+        NON_MASKABLE_INTERRUPT => ("NMI", "NON-MASKABLE INTERRUPT"),
+        BREAKPOINT => ("#BP", "BREAKPOINT"),
+        OVERFLOW => ("#OF", "OVERFLOW"),
+        BOUND_RANGE_EXCEEDED => ("#BR", "BOUND RANGE EXCEEDED"),
+        INVALID_OPCODE => ("#UD", "INVALID OPCODE"),
+        DEVICE_NOT_AVAILABLE => ("#NM", "DEVICE NOT AVAILABLE"),
+        DOUBLE_FAULT => ("#DF", "DOUBLE FAULT"),
+        INVALID_TSS => ("#TS", "INVALID TSS"),
+        SEGMENT_NOT_PRESENT => ("#NP", "SEGMENT NOT PRESENT"),
+        STACK_SEGMENT_FAULT => ("#SS", "STACK-SEGMENT FAULT"),
+        GENERAL_PROTECTION_FAULT => ("#GP", "GENERAL PROTECTION FAULT"),
+        PAGE_FAULT => ("#PF", "PAGE FAULT"),
+        X87_FLOATING_POINT => ("#MF", "X87 FLOATING POINT"),
+        ALIGNMENT_CHECK => ("#AC", "ALIGNMENT CHECK"),
+        MACHINE_CHECK => ("#MC", "MACHINE CHECK"),
+        SIMD_FLOATING_POINT => ("#XM", "SIMD FLOATING POINT"),
+        VIRTUALIZATION => ("#VE", "VIRTUALIZATION"),
+        CONTROL_PROTECTION => ("#CP", "CONTROL PROTECTION"),
+        HYPERVISOR_INJECTION => ("#HV", "HYPERVISOR INJECTION"),
+        VMM_COMMUNICATION => ("#VC", "VMM COMMUNICATION"),
+        SECURITY => ("#SX", "SECURITY"),
+        _ => return None,
+    };
+    Some(name)
+}
+
+// what the entry stubs leave on the stack, lowest address first
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionFrame {
+    // left free by the stub (5 words), "exception_dispatch" saves the state the report decodes here
+    // before anything can change it
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub dr6: u64,
+    // 0 if SSE can't be used (CR4.OSFXSR off, CR0.TS or CR0.EM on)
+    pub mxcsr: u32,
+    // 0 if the FPU can't be used (CR0.TS or CR0.EM on)
+    pub x87_status: u16,
+    pub cr2: u64, // saved first thing, a nested page fault would change it
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64, // 0 for exceptions without one
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+macro_rules! entry_points {
+    ($($vector:literal $entry:ident),* $(,)?) => {
+        extern "C" {
+            $(fn $entry();)*
+        }
+
+        // address of the assembly entry point for "vector", None for the reserved vectors
+        pub fn entry_address(vector: u8) -> Option<VirtAddr> {
+            match vector {
+                $($vector => Some(VirtAddr::new($entry as usize as u64)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+entry_points!(
+    0 exception_0, 1 exception_1, 2 exception_2, 3 exception_3, 4 exception_4, 5 exception_5,
+    6 exception_6, 7 exception_7, 8 exception_8, 10 exception_10, 11 exception_11,
+    12 exception_12, 13 exception_13, 14 exception_14, 16 exception_16, 17 exception_17,
+    18 exception_18, 19 exception_19, 20 exception_20, 21 exception_21, 28 exception_28,
+    29 exception_29, 30 exception_30,
+);
+
+global_asm!(
+    r#"
+.macro exception_entry vector, has_error_code
+.global exception_\vector
+exception_\vector:
+.if \has_error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp exception_common
+.endm
+
+exception_entry 0, 0
+exception_entry 1, 0
+exception_entry 2, 0
+exception_entry 3, 0
+exception_entry 4, 0
+exception_entry 5, 0
+exception_entry 6, 0
+exception_entry 7, 0
+exception_entry 8, 1
+exception_entry 10, 1
+exception_entry 11, 1
+exception_entry 12, 1
+exception_entry 13, 1
+exception_entry 14, 1
+exception_entry 16, 0
+exception_entry 17, 1
+exception_entry 18, 0
+exception_entry 19, 0
+exception_entry 20, 0
+exception_entry 21, 1
+exception_entry 28, 0
+exception_entry 29, 1
+exception_entry 30, 1
+
+exception_common:
+    cld
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rax, cr2
+    push rax
+    // for the saved CPU state. The CPU aligned the stack to 16 bytes and pushed 5 words, with the
+    // 18 pushed above and these 5 it's aligned again
+    sub rsp, 40
+    mov rdi, rsp
+    call exception_dispatch
+    add rsp, 48
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    // vector and error code
+    add rsp, 16
+    iretq
+"#
+);
+
+// points every exception of "idt" to its entry stub, double faults get their own stack
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let entry = |vector| entry_address(vector).unwrap();
+    unsafe {
+        idt.divide_error.set_handler_addr(entry(DIVIDE_ERROR));
+        idt.debug.set_handler_addr(entry(DEBUG));
+        idt.non_maskable_interrupt
+            .set_handler_addr(entry(NON_MASKABLE_INTERRUPT));
+        idt.breakpoint.set_handler_addr(entry(BREAKPOINT));
+        idt.overflow.set_handler_addr(entry(OVERFLOW));
+        idt.bound_range_exceeded
+            .set_handler_addr(entry(BOUND_RANGE_EXCEEDED));
+        idt.invalid_opcode.set_handler_addr(entry(INVALID_OPCODE));
+        idt.device_not_available
+            .set_handler_addr(entry(DEVICE_NOT_AVAILABLE));
+        idt.double_fault
+            .set_handler_addr(entry(DOUBLE_FAULT))
+            .set_stack_index(gdt::DOUBLE_FAULT_INDEX);
+        idt.invalid_tss.set_handler_addr(entry(INVALID_TSS));
+        idt.segment_not_present
+            .set_handler_addr(entry(SEGMENT_NOT_PRESENT));
+        idt.stack_segment_fault
+            .set_handler_addr(entry(STACK_SEGMENT_FAULT));
+        idt.general_protection_fault
+            .set_handler_addr(entry(GENERAL_PROTECTION_FAULT));
+        idt.page_fault.set_handler_addr(entry(PAGE_FAULT));
+        idt.x87_floating_point
+            .set_handler_addr(entry(X87_FLOATING_POINT));
+        idt.alignment_check.set_handler_addr(entry(ALIGNMENT_CHECK));
+        idt.machine_check.set_handler_addr(entry(MACHINE_CHECK));
+        idt.simd_floating_point
+            .set_handler_addr(entry(SIMD_FLOATING_POINT));
+        idt.virtualization.set_handler_addr(entry(VIRTUALIZATION));
+        idt.cp_protection_exception
+            .set_handler_addr(entry(CONTROL_PROTECTION));
+        idt.hv_injection_exception
+            .set_handler_addr(entry(HYPERVISOR_INJECTION));
+        idt.vmm_communication_exception
+            .set_handler_addr(entry(VMM_COMMUNICATION));
+        idt.security_exception.set_handler_addr(entry(SECURITY));
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    let vector = frame.vector as u8;
+    // user programs only get their own (eagerly mapped) memory, any exception ends the process
+    if frame.from_user_mode() {
+        process::stop_current(match vector {
+            PAGE_FAULT => ExitStatus::PageFault(VirtAddr::new_truncate(frame.cr2)),
+            GENERAL_PROTECTION_FAULT => ExitStatus::GeneralProtection,
+            _ => ExitStatus::Exception(vector),
+        });
+    }
+    // first access to a lazily backed page (e.g. the heap): map it and retry the instruction
+    let mut paging_error = None;
+    if vector == PAGE_FAULT {
+        let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        match memory::handle_page_fault(VirtAddr::new_truncate(frame.cr2), error_code) {
+            Ok(()) => return,
+            Err(error) => paging_error = Some(error),
+        }
+    }
+    // only reports need it, demand paging above stays cheap
+    save_cpu_state(frame);
+    let catch_rsp = CATCH_RSP.get().swap(0, Ordering::SeqCst);
+    if catch_rsp != 0 {
+        *CAUGHT.get().lock() = Some(*frame);
+        unsafe { catch_return(catch_rsp) };
+    }
+    match vector {
+        BREAKPOINT | NON_MASKABLE_INTERRUPT => println!("{}", frame),
+        DEBUG => {
+            println!("{}", frame);
+            frame.rflags &= !TRAP_FLAG; // no single stepping through the rest of the kernel
+        }
+        PAGE_FAULT => panic!("{}\n{}", frame, paging_error.unwrap()),
+        _ => panic!("{}", frame),
+    }
+}
+
+const TRAP_FLAG: u64 = 1 << 8;
+
+// the registers "describe" and the report decode, as they were when the exception happened
+fn save_cpu_state(frame: &mut ExceptionFrame) {
+    let (cr3, dr6): (u64, u64);
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags));
+    }
+    frame.cr0 = Cr0::read_raw();
+    frame.cr3 = cr3;
+    frame.cr4 = Cr4::read_raw();
+    frame.dr6 = dr6;
+    frame.x87_status = 0;
+    frame.mxcsr = 0;
+    // with CR0.TS or CR0.EM the FPU and SSE instructions below would raise #NM
+    let cr0 = Cr0Flags::from_bits_truncate(frame.cr0);
+    if cr0.intersects(Cr0Flags::TASK_SWITCHED | Cr0Flags::EMULATE_COPROCESSOR) {
+        return;
+    }
+    // the non-waiting form doesn't raise the pending exception again
+    unsafe {
+        asm!("fnstsw ax", out("ax") frame.x87_status, options(nomem, nostack, preserves_flags))
+    };
+    // without OSFXSR "stmxcsr" itself would be an invalid opcode
+    if Cr4Flags::from_bits_truncate(frame.cr4).contains(Cr4Flags::OSFXSR) {
+        unsafe {
+            asm!("stmxcsr [{}]", in(reg) &mut frame.mxcsr, options(nostack, preserves_flags))
+        };
+    }
+}
+
+// TESTING HANDLERS
+// "catch" works like "Process::run": "catch_enter" saves the callee-saved registers and the stack
+// pointer, "catch_return" (called by the dispatcher) goes back there from the exception. Exceptions
+// are taken on the CPU that raised them, so every CPU has its own
+static CATCH_RSP: PerCpu<AtomicU64> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const ZERO: AtomicU64 = AtomicU64::new(0); // 0: no "catch" running
+    [ZERO; MAX_CPUS]
+});
+static CAUGHT: PerCpu<Mutex<Option<ExceptionFrame>>> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const NONE: Mutex<Option<ExceptionFrame>> = Mutex::new(None);
+    [NONE; MAX_CPUS]
+});
+
+extern "C" {
+    fn catch_enter(f: extern "C" fn(), rsp: *mut u64);
+    fn catch_return(rsp: u64) -> !;
+}
+
+global_asm!(
+    r#"
+.global catch_enter
+catch_enter:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 8
+    mov [rsi], rsp
+    call rdi
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global catch_return
+catch_return:
+    mov rsp, rdi
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+// runs "f" and returns the frame of the first exception it raises instead of handling it, None if
+// "f" returned. Made for the tests of the handlers. Exceptions in user mode and page faults of
+// lazy regions are handled as usual, whatever "f" left on the stack is dropped
+pub fn catch(f: extern "C" fn()) -> Option<ExceptionFrame> {
+    use x86_64::instructions::interrupts;
+
+    let interrupts_enabled = interrupts::are_enabled();
+    let catch_rsp = CATCH_RSP.get();
+    assert_eq!(catch_rsp.load(Ordering::SeqCst), 0, "nested catch");
+    *CAUGHT.get().lock() = None;
+    unsafe { catch_enter(f, catch_rsp.as_ptr()) };
+    catch_rsp.store(0, Ordering::SeqCst);
+    // exception gates disable interrupts, "catch_return" doesn't turn them on again
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    CAUGHT.get().lock().take()
+}
+
+// BACKTRACE
+// return addresses found by following the saved frame pointers (the target is built with
+// "frame-pointer": "always"), innermost first. Ends at anything that doesn't look like a frame of
+// the same stack or isn't mapped
+pub struct Backtrace {
+    rbp: u64,
+}
+
+impl Backtrace {
+    pub fn new(rbp: u64) -> Self {
+        Backtrace { rbp }
+    }
+
+    // starting at the caller of the current function
+    #[inline(always)]
+    pub fn here() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        Backtrace::new(rbp)
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+        // the saved frame pointer and the return address, both in one page when aligned
+        if rbp == 0 || rbp % 16 != 0 || !memory::is_mapped(VirtAddr::try_new(rbp).ok()?) {
+            return None;
+        }
+        let frame = rbp as *const u64;
+        let (next, return_address) = unsafe { (*frame, *frame.add(1)) };
+        // frames of callers are further up the stack, anything else isn't a frame pointer
+        self.rbp = if next > rbp && next - rbp <= 1024 * 1024 {
+            next
+        } else {
+            0
+        };
+        if return_address == 0 {
+            return None;
+        }
+        Some(return_address)
+    }
+}
+
+// REPORT
+const SELECTOR_TABLES: [&str; 4] = ["GDT", "IDT", "LDT", "IDT"];
+
+// error code of #TS, #NP, #SS and #GP
+fn describe_selector(f: &mut fmt::Formatter, error_code: u64) -> fmt::Result {
+    if error_code == 0 {
+        return f.write_str("not caused by a segment selector");
+    }
+    let table = SELECTOR_TABLES[((error_code >> 1) & 3) as usize];
+    let index = (error_code >> 3) & 0x1fff;
+    if table == "IDT" {
+        write!(f, "selector: IDT vector {:#x}", index)?;
+    } else {
+        write!(f, "selector: {} index {}", table, index)?;
+    }
+    if error_code & 1 != 0 {
+        f.write_str(" (external event)")?;
+    }
+    Ok(())
+}
+
+fn describe_page_fault(f: &mut fmt::Formatter, frame: &ExceptionFrame) -> fmt::Result {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let cause = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "malformed page table"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+        "protection key violation"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    write!(f, "{} {} at {:#x}: {}", mode, access, frame.cr2, cause)
+}
+
+// names of the set bits, "bits" lists (bit, name)
+fn describe_flags(f: &mut fmt::Formatter, value: u64, bits: &[(u32, &str)]) -> fmt::Result {
+    let mut any = false;
+    for &(bit, name) in bits {
+        if value & (1 << bit) != 0 {
+            write!(f, "{}{}", if any { ", " } else { "" }, name)?;
+            any = true;
+        }
+    }
+    if !any {
+        f.write_str("none")?;
+    }
+    Ok(())
+}
+
+// exception flags of the x87 status word and MXCSR
+const FLOATING_POINT_FLAGS: [(u32, &str); 6] = [
+    (0, "invalid operation"),
+    (1, "denormal operand"),
+    (2, "division by zero"),
+    (3, "overflow"),
+    (4, "underflow"),
+    (5, "precision"),
+];
+
+fn describe_debug(f: &mut fmt::Formatter, dr6: u64) -> fmt::Result {
+    f.write_str("debug status: ")?;
+    describe_flags(
+        f,
+        dr6,
+        &[
+            (0, "breakpoint 0"),
+            (1, "breakpoint 1"),
+            (2, "breakpoint 2"),
+            (3, "breakpoint 3"),
+            (13, "debug register access"),
+            (14, "single step"),
+            (15, "task switch"),
+        ],
+    )
+}
+
+// the instruction bytes at "rip", if they are mapped
+fn describe_opcode(f: &mut fmt::Formatter, rip: u64) -> fmt::Result {
+    write!(f, "invalid or undefined instruction at {:#x}", rip)?;
+    let start = match VirtAddr::try_new(rip) {
+        Ok(start) if memory::is_mapped(start) && memory::is_mapped(start + 7u64) => start,
+        _ => return Ok(()),
+    };
+    f.write_str(", bytes")?;
+    let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), 8) };
+    for byte in bytes {
+        write!(f, " {:02x}", byte)?;
+    }
+    Ok(())
+}
+
+// no status word if the FPU couldn't be read, see "save_cpu_state"
+fn describe_fpu_access(f: &mut fmt::Formatter, cr0: u64) -> Option<fmt::Result> {
+    let cr0 = Cr0Flags::from_bits_truncate(cr0);
+    if cr0.contains(Cr0Flags::TASK_SWITCHED) {
+        return Some(f.write_str("FPU state not available (CR0.TS)"));
+    }
+    if cr0.contains(Cr0Flags::EMULATE_COPROCESSOR) {
+        return Some(f.write_str("FPU is emulated (CR0.EM)"));
+    }
+    None
+}
+
+fn describe_x87(f: &mut fmt::Formatter, frame: &ExceptionFrame) -> fmt::Result {
+    if let Some(result) = describe_fpu_access(f, frame.cr0) {
+        return result;
+    }
+    let status = frame.x87_status;
+    write!(f, "x87 status {:#06x}: ", status)?;
+    describe_flags(f, status.into(), &FLOATING_POINT_FLAGS)?;
+    if status & (1 << 6) != 0 {
+        f.write_str(", stack fault")?;
+    }
+    Ok(())
+}
+
+fn describe_simd(f: &mut fmt::Formatter, frame: &ExceptionFrame) -> fmt::Result {
+    if !Cr4Flags::from_bits_truncate(frame.cr4).contains(Cr4Flags::OSFXSR) {
+        return f.write_str("SSE is not enabled (CR4.OSFXSR)");
+    }
+    if let Some(result) = describe_fpu_access(f, frame.cr0) {
+        return result;
+    }
+    let mxcsr = frame.mxcsr;
+    write!(f, "MXCSR {:#06x}: ", mxcsr)?;
+    describe_flags(f, mxcsr.into(), &FLOATING_POINT_FLAGS)
+}
+
+fn describe_control_protection(f: &mut fmt::Formatter, error_code: u64) -> fmt::Result {
+    let cause = match error_code & 0x7fff {
+        1 => "near return to another address than the shadow stack's",
+        2 => "far return or iret to another address than the shadow stack's",
+        3 => "indirect branch without endbranch",
+        4 => "rstorssp with an invalid token",
+        5 => "setssbsy with an invalid token",
+        _ => "unknown cause",
+    };
+    f.write_str(cause)?;
+    if error_code & (1 << 15) != 0 {
+        f.write_str(" (in an enclave)")?;
+    }
+    Ok(())
+}
+
+// the second line of the report: what went wrong, the error code decoded
+fn describe(f: &mut fmt::Formatter, frame: &ExceptionFrame) -> fmt::Result {
+    match frame.vector as u8 {
+        DIVIDE_ERROR => f.write_str("division by zero or quotient too big"),
+        DEBUG => describe_debug(f, frame.dr6),
+        NON_MASKABLE_INTERRUPT => f.write_str("hardware error or watchdog"),
+        BREAKPOINT => write!(f, "int3 at {:#x}", frame.rip.wrapping_sub(1)),
+        OVERFLOW => f.write_str("overflow check failed (into)"),
+        BOUND_RANGE_EXCEEDED => f.write_str("index outside of the bounds (bound)"),
+        INVALID_OPCODE => describe_opcode(f, frame.rip),
+        DEVICE_NOT_AVAILABLE => {
+            let cr0 = Cr0Flags::from_bits_truncate(frame.cr0);
+            write!(
+                f,
+                "x87/SSE instruction without FPU context (CR0.TS {}, CR0.EM {})",
+                cr0.contains(Cr0Flags::TASK_SWITCHED) as u8,
+                cr0.contains(Cr0Flags::EMULATE_COPROCESSOR) as u8
+            )
+        }
+        DOUBLE_FAULT => f.write_str("fault while delivering another exception"),
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            describe_selector(f, frame.error_code)
+        }
+        PAGE_FAULT => describe_page_fault(f, frame),
+        X87_FLOATING_POINT => describe_x87(f, frame),
+        ALIGNMENT_CHECK => f.write_str("misaligned access with alignment checking on"),
+        MACHINE_CHECK => f.write_str("hardware error detected by the CPU"),
+        SIMD_FLOATING_POINT => describe_simd(f, frame),
+        VIRTUALIZATION => f.write_str("EPT violation reported to the guest"),
+        CONTROL_PROTECTION => describe_control_protection(f, frame.error_code),
+        HYPERVISOR_INJECTION => f.write_str("event injected by the hypervisor"),
+        VMM_COMMUNICATION => write!(f, "needs the hypervisor, exit code {:#x}", frame.error_code),
+        SECURITY => write!(f, "security violation, error code {:#x}", frame.error_code),
+        _ => f.write_str("reserved vector"),
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (mnemonic, name) = name(self.vector as u8).unwrap_or(("#??", "UNKNOWN"));
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            name, mnemonic, self.vector
+        )?;
+        describe(f, self)?;
+        writeln!(f, "\nerror code: {:#x}", self.error_code)?;
+        let vector = self.vector as u8;
+        if vector == PAGE_FAULT || vector == DOUBLE_FAULT {
+            if let Some(task) = stack::overflowed_stack(VirtAddr::new_truncate(self.cr2)) {
+                writeln!(f, "stack overflow in task {}", task)?;
+            }
+        }
+        writeln!(
+            f,
+            "rip {:#018x}  cs {:#06x}  rflags {:#010x}  rsp {:#018x}  ss {:#06x}",
+            self.rip, self.cs, self.rflags, self.rsp, self.ss
+        )?;
+        let registers = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8 ", self.r8),
+            ("r9 ", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+            ("cr2", self.cr2),
+        ];
+        for row in registers.chunks(4) {
+            for (name, value) in row {
+                write!(f, "{} {:#018x}  ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "cr0 {:#x}  cr3 {:#x}  cr4 {:#x}",
+            self.cr0, self.cr3, self.cr4
+        )?;
+        f.write_str("backtrace:")?;
+        // user frame pointers point into the program's memory, only kernel stacks are followed
+        if !self.from_user_mode() {
+            for return_address in Backtrace::new(self.rbp).take(16) {
+                write!(f, " {:#x}", return_address)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,21 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![feature(const_mut_refs)] // mutable references in const functions are unstable
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 extern crate alloc; // using alloc which is a subset of "std" like "core"...
+pub mod acpi;
 pub mod allocator;
+pub mod cpu;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod process;
 pub mod serial;
+pub mod smp;
 pub mod task;
 pub mod vga_buffer;
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
@@ -22,6 +27,10 @@ pub enum QemuExitcode {
 pub fn exit_qemu(exit_code: QemuExitcode) {
     use x86_64::instructions::port::Port;
 
+    // leak report at shutdown
+    #[cfg(feature = "heap-debug")]
+    allocator::dump_live_allocations();
+
     unsafe {
         let mut port = Port::new(0xf4);
         // 0xf4 is chosen as it is generally unused on x86 architecture...
@@ -60,6 +69,7 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 }
 
 pub fn init() {
+    cpu::init_bsp();
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() }; // initializing 8259 PIC
@@ -10,8 +10,8 @@ extern crate alloc; // needed here too because main.rs and lib.rs are treated as
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use mini_os::println;
-use mini_os::task::{executor::Executor, keyboard, Task};
+use mini_os::task::{digits, executor, executor::Executor, keyboard, serial, Task};
+use mini_os::{println, smp};
 use x86_64::registers::control::Cr0;
 
 #[cfg(not(test))]
@@ -31,17 +31,22 @@ entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // use mini_os::memory::active_level_4_table;
     use mini_os::allocator;
-    use mini_os::memory::{self, BootInfoFrameAllocator};
+    use mini_os::memory;
     use x86_64::VirtAddr;
 
     // println!("Booting mini_os");
     mini_os::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    // new: initialise a mapper
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    // page tables and frame allocator go into the global memory manager (demand paging)
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    // pages can be mapped now, the stacks in the TSS get guard pages
+    mini_os::gdt::init_guarded_stacks().expect("mapping the exception stacks failed");
+    // and so can the APIC registers: interrupts move from the 8259 PICs to the APICs
+    if let Err(error) = mini_os::interrupts::apic::init() {
+        println!("staying with the 8259 PICs: {}", error);
+    }
 
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     // STACK IMPLEMENTATION CHECK
     println!("physical_memory_offset: {:?}", phys_mem_offset);
     println!("{:?}", Cr0::read());
@@ -79,7 +84,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     }
 
     // HEAP IMPLEMENTATION CHECK
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialisation failed!");
+    allocator::init_heap().expect("heap initialisation failed!");
 
     let a = Box::new(41);
     println!("value located at {:p} is {}", a, *a);
@@ -116,13 +121,38 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         Rc::strong_count(&cloned_reference)
     );
 
+    // SMP: the other CPUs run executors too, they pick up the "spawn_shared" tasks
+    match smp::start_application_processors() {
+        Ok(cpus) => println!("{} CPUs online", cpus),
+        Err(error) => println!("running on one CPU: {}", error),
+    }
+
+    // PHYSICAL MEMORY
+    if let Some(stats) = memory::frame_stats() {
+        println!(
+            "physical memory: {} KiB used, {} KiB free of {} KiB",
+            stats.used / 1024,
+            stats.free() / 1024,
+            stats.total / 1024
+        );
+    }
+
+    // USER MODE: the ELF test program in ring 3 (prints through the write syscall)
+    let hello = include_bytes!("../assets/user/hello.elf");
+    match mini_os::process::Process::from_elf(hello) {
+        Ok(mut process) => println!("user program ended: {:?}", process.run()),
+        Err(error) => println!("loading the user program failed: {}", error),
+    }
+
     #[cfg(test)] // using "cfg(test)" for conditional compiling...
     test_main(); // name of the test framework entry function
 
     // TESTING OUR EXECUTOR FOR ASYNCHRONOUS MULTITASKING
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
+    executor::spawn_shared(example_task());
+    executor.spawn(Task::new(digits::classify())); // no_std rust_cnn inference
     executor.spawn(Task::new(keyboard::key_presses())); // new
+    executor.spawn(Task::new(serial::commands())); // the shell over COM1
     executor.run();
 
     // println!("It did not crash!"); // try running this statement in a for loop from 0 -> 100
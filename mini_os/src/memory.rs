@@ -1,4 +1,9 @@
 // stack frame allocator mapping entire virtual memory to physical memory...
+pub mod address_space;
+pub mod frame_allocator;
+pub mod shootdown;
+pub mod stack;
+
 use x86_64::{
     structures::paging::{OffsetPageTable, PageTable},
     VirtAddr,
@@ -16,82 +21,497 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe (can be called only once[cannot have multiple mut references])
 }
 
-// initialize a new OffsetPageTable
-/// # Safety
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+// wraps the active level 4 table, only "init_global" calls this: the memory manager must own the
+// one OffsetPageTable there is
+unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level4_table, physical_memory_offset)
 }
 
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
-// creating an example mapping for the given page to frame '0xb8000'...using this frame to test if
-// frame allocator works when we write something to this frame and see output on qemu screen
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    use x86_64::structures::paging::PageTableFlags as Flags;
-
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
-
-    let map_to_result = unsafe {
-        // FIXME: this is not safe: we only do it for testing
-        mapper.map_to(page, frame, flags, frame_allocator)
+// DEMAND PAGING
+// the page tables and the frame allocator live in a global so the page fault handler can reach
+// them. Pages of registered "lazy" regions get a (zeroed) frame on their first access instead of
+// being mapped up front...
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use frame_allocator::{FrameStats, FreeListFrameAllocator};
+use spin::{Mutex, MutexGuard};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+
+pub struct MemoryManager {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: FreeListFrameAllocator,
+    physical_memory_offset: VirtAddr,
+}
+
+// spin lock that knows which CPU holds it. It's only taken with interrupts disabled, so a page
+// fault on the CPU that holds it came from the holder itself and waiting would deadlock; another
+// CPU's page fault just waits for it. Waiters answer TLB shootdowns (the holder may wait for them)
+struct CpuMutex<T> {
+    inner: Mutex<T>,
+    owner: AtomicUsize, // CPU number, NO_OWNER while it's free
+}
+
+const NO_OWNER: usize = usize::MAX;
+
+impl<T> CpuMutex<T> {
+    const fn new(value: T) -> Self {
+        CpuMutex {
+            inner: Mutex::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    fn lock(&self) -> CpuMutexGuard<'_, T> {
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            shootdown::acknowledge();
+            core::hint::spin_loop();
+        };
+        self.owner
+            .store(crate::cpu::id().unwrap_or(0), Ordering::Relaxed);
+        CpuMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    // None if the running CPU holds the lock already
+    fn lock_unless_held_here(&self) -> Option<CpuMutexGuard<'_, T>> {
+        if self.owner.load(Ordering::Relaxed) == crate::cpu::id().unwrap_or(0) {
+            None
+        } else {
+            Some(self.lock())
+        }
+    }
+}
+
+struct CpuMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+impl<T> Deref for CpuMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for CpuMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+impl<T> Drop for CpuMutexGuard<'_, T> {
+    // before "guard" unlocks
+    fn drop(&mut self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
+static MEMORY: CpuMutex<Option<MemoryManager>> = CpuMutex::new(None);
+// also kept outside of the manager for "is_mapped", 0 until "init_global"
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// a frame below 1MiB that isn't handed out, 0 if there's none
+static LOW_FRAME: AtomicU64 = AtomicU64::new(0);
+
+// initializes the page tables and the frame allocator and stores them in the global manager
+/// # Safety
+/// same as "init" (the complete physical memory must be mapped at "physical_memory_offset") and
+/// the memory map must be valid. Must only be called once, "init" must not be used next to it.
+pub unsafe fn init_global(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    let usable = memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(|r| r.range.start_addr()..r.range.end_addr());
+    // the first usable frame below 1MiB (not frame 0) is kept from the frame allocator
+    let low_frame = usable.clone().find_map(|range| {
+        let frame = (range.start.max(0x1000) + 0xfff) & !0xfff;
+        Some(frame).filter(|frame| frame + 4096 <= range.end.min(0x10_0000))
+    });
+    let ranges = usable.map(move |range| match low_frame {
+        Some(frame) if range.contains(&frame) => frame + 4096..range.end,
+        _ => range,
+    });
+    if let Some(frame) = low_frame {
+        LOW_FRAME.store(frame, Ordering::Relaxed);
+    }
+    let manager = MemoryManager {
+        mapper: init(physical_memory_offset),
+        frame_allocator: FreeListFrameAllocator::from_ranges(ranges, physical_memory_offset),
+        physical_memory_offset,
+    };
+    *MEMORY.lock() = Some(manager);
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    // pages without the NO_EXECUTE flag are the only executable ones (e.g. ELF segments)
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+// runs "f" with the global memory manager (None if "init_global" wasn't called yet).
+// interrupts are disabled meanwhile and "f" must not touch lazily mapped memory (e.g. the heap),
+// a page fault in there could not be resolved
+pub fn with_memory<R>(f: impl FnOnce(&mut MemoryManager) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| MEMORY.lock().as_mut().map(f))
+}
+
+// total, used and free physical memory (None before "init_global")
+pub fn frame_stats() -> Option<FrameStats> {
+    with_memory(|memory| memory.frame_allocator.stats())
+}
+
+impl MemoryManager {
+    pub fn physical_memory_offset(&self) -> VirtAddr {
+        self.physical_memory_offset
+    }
+
+    // a fresh frame filled with zeros, never hand out what its previous user left behind
+    pub fn allocate_zeroed_frame(&mut self) -> Result<PhysFrame, PagingError> {
+        let frame: PhysFrame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(PagingError::OutOfFrames)?;
+        let frame_ptr: *mut u8 =
+            (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { frame_ptr.write_bytes(0, frame.size() as usize) };
+        Ok(frame)
+    }
+
+    // maps "page" to a fresh frame filled with zeros
+    pub fn map_zeroed(&mut self, page: Page, flags: PageTableFlags) -> Result<(), PagingError> {
+        let frame = self.allocate_zeroed_frame()?;
+        unsafe {
+            self.mapper
+                .map_to(page, frame, flags, &mut self.frame_allocator)
+                .map_err(|_| PagingError::MapFailed)?
+                .flush();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    NotInitialized,    // "init_global" wasn't called
+    Busy,              // fault while the same CPU held the memory manager's lock
+    NotLazy,           // address outside of every lazy region
+    AlreadyPresent,    // the page is mapped, so it's a protection violation
+    OutOfFrames,       // no physical memory left
+    MapFailed,         // page table update failed
+    TooManyRegions,    // registry is full
+    OverlappingRegion, // lazy and VM regions can't overlap (each other, mappings, stacks, MMIO)
+    Unaligned,         // start or length isn't page aligned (or the length is 0)
+    KernelSpace,       // range touches the part shared with the kernel
+    NotMapped,         // range isn't (completely) covered by VM regions
+    OutOfAddressSpace, // no free virtual range is big enough
+    TooManyStacks,     // every kernel stack slot is in use
+}
+
+impl core::fmt::Display for PagingError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let reason = match self {
+            PagingError::NotInitialized => "memory manager is not initialized",
+            PagingError::Busy => "fault while this CPU held the memory manager's lock",
+            PagingError::NotLazy => "address is not part of any mapped or lazy region",
+            PagingError::AlreadyPresent => "page is present, access violates its protection",
+            PagingError::OutOfFrames => "out of physical frames",
+            PagingError::MapFailed => "mapping the page failed",
+            PagingError::TooManyRegions => "too many lazy regions",
+            PagingError::OverlappingRegion => "region overlaps an existing one",
+            PagingError::Unaligned => "range is not page aligned",
+            PagingError::KernelSpace => "range is part of the shared kernel space",
+            PagingError::NotMapped => "range is not mapped",
+            PagingError::OutOfAddressSpace => "no free virtual address range left",
+            PagingError::TooManyStacks => "no kernel stack slot left",
+        };
+        f.write_str(reason)
+    }
+}
+
+// a virtual memory range whose pages are only backed once touched
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+    start: VirtAddr,
+    end: VirtAddr, // exclusive
+    flags: PageTableFlags,
+}
+
+const MAX_LAZY_REGIONS: usize = 16;
+// fixed size array: the heap itself is a lazy region, so no allocations in here
+static LAZY_REGIONS: CpuMutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    CpuMutex::new([None; MAX_LAZY_REGIONS]);
+
+// registers [start, start + size) as lazily backed, PRESENT is added to "flags". An empty range
+// or one that runs past the canonical addresses is rejected
+pub fn register_lazy_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    if size == 0 {
+        return Err(PagingError::Unaligned);
+    }
+    let end = start
+        .as_u64()
+        .checked_add(size)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(PagingError::OutOfAddressSpace)?;
+    let region = LazyRegion {
+        start,
+        end,
+        flags: flags | PageTableFlags::PRESENT,
     };
-    map_to_result.expect("map_to failed").flush();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        let overlaps = regions
+            .iter()
+            .flatten()
+            .any(|r| r.start < region.end && region.start < r.end);
+        if overlaps {
+            return Err(PagingError::OverlappingRegion);
+        }
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(PagingError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(())
+    })
 }
 
-// a frame allocator that always returns None [just for fun, won't be using this anywhere]
-pub struct EmptyFrameAllocator;
+// extends the lazy region starting at "start" by "additional" bytes (the heap grows this way). The
+// new part can't reach into another region, mapped pages or the kernel stack and MMIO windows
+pub fn grow_lazy_region(start: VirtAddr, additional: u64) -> Result<(), PagingError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        let index = regions
+            .iter()
+            .position(|slot| slot.map_or(false, |r| r.start == start))
+            .ok_or(PagingError::NotLazy)?;
+        let old_end = regions[index].unwrap().end;
+        let new_end = old_end
+            .as_u64()
+            .checked_add(additional)
+            .and_then(|end| VirtAddr::try_new(end).ok())
+            .ok_or(PagingError::OutOfAddressSpace)?;
+        let overlaps = regions
+            .iter()
+            .flatten()
+            .any(|r| r.start != start && r.start < new_end && old_end < r.end);
+        // whatever is mapped there already would silently become part of the region
+        if overlaps || in_reserved_window(old_end, new_end) || any_mapped(old_end, new_end) {
+            return Err(PagingError::OverlappingRegion);
+        }
+        regions[index].as_mut().unwrap().end = new_end;
+        Ok(())
+    })
+}
 
-unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        None
+// called by the page fault handler, maps the faulting page if it belongs to a lazy region.
+// Err means the fault is fatal
+pub fn handle_page_fault(
+    address: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), PagingError> {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(PagingError::AlreadyPresent);
+    }
+    // Busy if the interrupted code holds a lock, it can't be waited for
+    let region = LAZY_REGIONS
+        .lock_unless_held_here()
+        .ok_or(PagingError::Busy)?
+        .iter()
+        .flatten()
+        .find(|r| r.start <= address && address < r.end)
+        .copied()
+        .ok_or(PagingError::NotLazy)?;
+
+    let mut memory = MEMORY.lock_unless_held_here().ok_or(PagingError::Busy)?;
+    let memory = memory.as_mut().ok_or(PagingError::NotInitialized)?;
+    match memory.mapper.translate(address) {
+        // another CPU faulted on the same page first
+        TranslateResult::Mapped { flags, .. } if flags.contains(region.flags) => Ok(()),
+        TranslateResult::Mapped { .. } => Err(PagingError::AlreadyPresent),
+        _ => memory.map_zeroed(Page::containing_address(address), region.flags),
     }
 }
 
-use bootloader::bootinfo::MemoryMap;
-// a frame allocator that returns usable frames from the bootloader's memory map
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+// true if [start, end) overlaps the kernel stack slots (with their guard pages) or the MMIO window,
+// both are mapped page by page and must never be backed on demand
+fn in_reserved_window(start: VirtAddr, end: VirtAddr) -> bool {
+    let stacks = stack::STACKS_START
+        ..stack::STACKS_START + stack::MAX_STACKS as u64 * stack::STACK_SLOT_SIZE;
+    let mmio = MMIO_START..MMIO_START + MMIO_SIZE;
+    [stacks, mmio]
+        .iter()
+        .any(|window| window.start < end.as_u64() && start.as_u64() < window.end)
 }
-use bootloader::bootinfo::MemoryRegionType;
 
-impl BootInfoFrameAllocator {
-    // creates a FrameAllocator from the passed memory map
-    /// # Safety
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+// walks the active page table for "addr" without taking the memory manager's lock. Err holds the
+// size of the unmapped range around "addr" that the missing entry covers
+fn walk(addr: VirtAddr) -> Result<(), u64> {
+    use x86_64::registers::control::Cr3;
+
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return Err(1 << 39); // the tables can't be read before "init_global"
+    }
+    let indexes = [
+        (addr.p4_index(), 1 << 39),
+        (addr.p3_index(), 1 << 30),
+        (addr.p2_index(), 1 << 21),
+        (addr.p1_index(), 1 << 12),
+    ];
+    let mut table_addr = Cr3::read().0.start_address().as_u64();
+    for (level, &(index, covers)) in indexes.iter().enumerate() {
+        let table = unsafe { &*((offset + table_addr) as *const PageTable) };
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(covers);
+        }
+        // 1GiB and 2MiB pages end the walk early
+        if level > 0 && flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(());
         }
+        table_addr = table[index].addr().as_u64();
+    }
+    Ok(())
+}
+
+// true if "addr" is mapped in the active page table. Reads the tables without taking the memory
+// manager's lock, so exception handlers can use it whatever the interrupted code held
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    walk(addr).is_ok()
+}
+
+// true if any page of [start, end) is mapped, skips whole tables that aren't there
+fn any_mapped(start: VirtAddr, end: VirtAddr) -> bool {
+    let mut addr = start.align_up(4096u64).as_u64(); // a page around "start" is the caller's
+    while addr < end.as_u64() {
+        let unmapped = match VirtAddr::try_new(addr).map(walk) {
+            Ok(Ok(())) => return true,
+            Ok(Err(unmapped)) => unmapped,
+            Err(_) => return false, // ran past the canonical addresses
+        };
+        addr = match (addr & !(unmapped - 1)).checked_add(unmapped) {
+            Some(next) => next,
+            None => return false,
+        };
     }
-    // returns an iterator over the usable frames specified in a memory map
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+    false
+}
 
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+// USER MEMORY
+// true if [start, start + len) is mapped user accessible (and writable if "write") in the active
+// page table, so syscalls can check the pointers they get from user programs
+pub fn user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::mapper::TranslateResult;
 
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create PhysFrame types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    let end = match start.as_u64().checked_add(len) {
+        Some(end) if end <= 0x0000_8000_0000_0000 => end, // lower half only
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
     }
+    with_memory(|memory| {
+        let offset = memory.physical_memory_offset();
+        let table: &mut PageTable =
+            unsafe { &mut *(offset + Cr3::read().0.start_address().as_u64()).as_mut_ptr() };
+        let mapper = unsafe { OffsetPageTable::new(table, offset) };
+        let mut pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        pages.all(|page| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(required),
+            _ => false,
+        })
+    })
+    .unwrap_or(false)
 }
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+
+// DEVICE MEMORY
+// memory mapped device registers (local APIC, IOAPIC) get uncached mappings in a window of the
+// heap's level 4 slot behind the kernel stacks, so every address space sees them like the stacks
+const MMIO_START: u64 = 0x_4444_c000_0000;
+const MMIO_SIZE: u64 = 16 * 1024 * 1024;
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+// maps the physical range [phys, phys + size) uncached and returns the virtual address of "phys".
+// The window only grows, so map each device once. It's only taken once the mapping worked (under
+// the memory manager's lock), a failure leaves nothing mapped
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, PagingError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    let len = last.start_address().as_u64() - first.start_address().as_u64() + 4096;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let start = with_memory(|memory| {
+        let start = MMIO_NEXT.load(Ordering::Relaxed);
+        if start + len > MMIO_START + MMIO_SIZE {
+            return Err(PagingError::OutOfAddressSpace);
+        }
+        let frames = PhysFrame::range_inclusive(first, last);
+        for (i, frame) in frames.enumerate() {
+            let page = Page::containing_address(VirtAddr::new(start + i as u64 * 4096));
+            let mapped = unsafe {
+                memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)
+            };
+            match mapped {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    // the device's frames aren't the frame allocator's, only the pages go
+                    for i in 0..i as u64 {
+                        let page: Page = Page::containing_address(VirtAddr::new(start + i * 4096));
+                        if let Ok((_, flush)) = memory.mapper.unmap(page) {
+                            flush.flush();
+                        }
+                    }
+                    return Err(PagingError::MapFailed);
+                }
+            }
+        }
+        MMIO_NEXT.store(start + len, Ordering::Relaxed);
+        Ok(start)
+    })
+    .ok_or(PagingError::NotInitialized)??;
+    Ok(VirtAddr::new(start) + (phys - first.start_address()))
+}
+
+// physical frame below 1MiB that "init_global" kept out of the frame allocator, for code that
+// has to run in real mode (the application processors' start, smp)
+pub fn low_frame() -> Option<PhysFrame> {
+    match LOW_FRAME.load(Ordering::Relaxed) {
+        0 => None,
+        frame => Some(PhysFrame::containing_address(PhysAddr::new(frame))),
+    }
+}
+
+// "phys" in the mapping of the complete physical memory, None before "init_global". For memory
+// the firmware left behind (ACPI tables), not for device registers
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset + phys.as_u64())),
     }
 }
@@ -0,0 +1,458 @@
+// address spaces: a level 4 page table plus the list of VM regions mapped in it (the base for
+// processes). A fresh address space shares every level 4 entry the kernel uses at creation time
+// (kernel image, stacks, heap, physical memory mapping), so kernel code keeps running after the
+// switch. Those entries are shared tables, not copies: regions can only be mapped into the unused
+// level 4 slots of the lower half.
+use super::shootdown::{self, FreeAfterShootdown};
+use super::{with_memory, MemoryManager, PagingError};
+use alloc::vec::Vec;
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{CleanUp, MapperFlush, TranslateResult},
+        FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+const SLOT_SIZE: u64 = 1 << 39; // memory covered by one level 4 entry (512GiB)
+const LOWER_HALF_END: u64 = 0x0000_8000_0000_0000;
+// where "mmap" looks for free virtual memory
+pub const MMAP_START: u64 = 0x0000_2000_0000_0000;
+pub const MMAP_END: u64 = 0x0000_4000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    Anonymous,          // zeroed frames, allocated on map and freed on unmap
+    Physical(PhysAddr), // fixed physical memory (e.g. MMIO), never freed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmRegion {
+    pub start: VirtAddr,
+    pub len: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl VmRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.len
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+
+    // the part [start, end) of this region, physical backing is shifted along
+    fn slice(&self, start: VirtAddr, end: VirtAddr) -> VmRegion {
+        let backing = match self.backing {
+            Backing::Physical(base) => Backing::Physical(base + (start - self.start)),
+            Backing::Anonymous => Backing::Anonymous,
+        };
+        VmRegion {
+            start,
+            len: end - start,
+            flags: self.flags,
+            backing,
+        }
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end()),
+        )
+    }
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    kernel_slots: [u64; 8], // bitmap of the level 4 entries shared with the kernel
+    regions: Vec<VmRegion>, // sorted by start address, never overlapping
+}
+
+impl AddressSpace {
+    // creates an address space that only contains the kernel's mappings
+    pub fn new() -> Result<Self, PagingError> {
+        let (level_4_frame, kernel_slots) = with_memory(|memory| {
+            let frame = memory.allocate_zeroed_frame()?;
+            let table = unsafe { table_at(memory, frame) };
+            let mut kernel_slots = [0; 8];
+            for (i, entry) in memory.mapper.level_4_table().iter().enumerate() {
+                if !entry.is_unused() {
+                    table[i] = entry.clone();
+                    kernel_slots[i / 64] |= 1 << (i % 64);
+                }
+            }
+            Ok((frame, kernel_slots))
+        })
+        .ok_or(PagingError::NotInitialized)??;
+        Ok(AddressSpace {
+            level_4_frame,
+            kernel_slots,
+            regions: Vec::new(),
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn regions(&self) -> &[VmRegion] {
+        &self.regions
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // switches to this address space (loads CR3, which flushes the non-global TLB entries)
+    /// # Safety
+    /// the address space must stay alive as long as it's active
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    // physical address and flags of the page "addr" is mapped to
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        self.with_mapper(|mapper, _| match mapper.translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        })
+        .ok()
+        .flatten()
+    }
+
+    // copies "data" to "addr" through the physical memory mapping, so this works for inactive
+    // address spaces and read only pages too. The whole range has to be mapped
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), PagingError> {
+        self.copy_in(addr, data.len(), Some(data))
+    }
+
+    // fills [addr, addr + len) with zeros, like "write"
+    pub fn zero(&mut self, addr: VirtAddr, len: u64) -> Result<(), PagingError> {
+        self.copy_in(addr, len as usize, None)
+    }
+
+    fn copy_in(&self, addr: VirtAddr, len: usize, data: Option<&[u8]>) -> Result<(), PagingError> {
+        self.with_mapper(|mapper, memory| {
+            let mut done = 0;
+            while done < len {
+                let target = addr + done as u64;
+                let phys = mapper
+                    .translate_addr(target)
+                    .ok_or(PagingError::NotMapped)?;
+                let chunk = (PAGE_SIZE - target.as_u64() % PAGE_SIZE) as usize;
+                let chunk = chunk.min(len - done);
+                let dst: *mut u8 = (memory.physical_memory_offset() + phys.as_u64()).as_mut_ptr();
+                match data {
+                    Some(data) => unsafe {
+                        dst.copy_from_nonoverlapping(data[done..].as_ptr(), chunk)
+                    },
+                    None => unsafe { dst.write_bytes(0, chunk) },
+                }
+                done += chunk;
+            }
+            Ok(())
+        })?
+    }
+
+    // maps [start, start + len) with "flags" (PRESENT is added), anonymous memory is zeroed
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<(), PagingError> {
+        self.check_range(start, len)?;
+        if self.regions.iter().any(|r| r.overlaps(start, start + len)) {
+            return Err(PagingError::OverlappingRegion);
+        }
+        let region = VmRegion {
+            start,
+            len,
+            flags: flags | PageTableFlags::PRESENT,
+            backing,
+        };
+        self.regions.reserve(1); // no heap growth while the memory manager is locked
+        let active = self.is_active();
+        self.with_mapper(|mapper, memory| map_pages(mapper, memory, &region, active))??;
+        let index = self.regions.partition_point(|r| r.start < start);
+        self.regions.insert(index, region);
+        Ok(())
+    }
+
+    // like "map", but picks a free address between MMAP_START and MMAP_END
+    pub fn mmap(
+        &mut self,
+        len: u64,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<VirtAddr, PagingError> {
+        if len == 0 || len % PAGE_SIZE != 0 {
+            return Err(PagingError::Unaligned);
+        }
+        let start = self.find_free(len).ok_or(PagingError::OutOfAddressSpace)?;
+        self.map(start, len, flags, backing)?;
+        Ok(start)
+    }
+
+    // unmaps [start, start + len), regions that are only partly inside are split
+    pub fn munmap(&mut self, start: VirtAddr, len: u64) -> Result<(), PagingError> {
+        self.check_range(start, len)?;
+        let end = start + len;
+        if !self.regions.iter().any(|r| r.overlaps(start, end)) {
+            return Err(PagingError::NotMapped);
+        }
+        let (kept, removed) = self.carve(start, end, None);
+        let active = self.is_active();
+        self.with_mapper(|mapper, memory| {
+            for region in removed.iter() {
+                unmap_pages(mapper, memory, region, active);
+            }
+        })?;
+        self.regions = kept;
+        Ok(())
+    }
+
+    // changes the flags of [start, start + len), which has to be mapped completely
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        self.check_range(start, len)?;
+        let end = start + len;
+        let covered: u64 = self
+            .regions
+            .iter()
+            .filter(|r| r.overlaps(start, end))
+            .map(|r| r.end().min(end) - r.start.max(start))
+            .sum();
+        if covered != len {
+            return Err(PagingError::NotMapped);
+        }
+        let flags = flags | PageTableFlags::PRESENT;
+        let (kept, changed) = self.carve(start, end, Some(flags));
+        let active = self.is_active();
+        self.with_mapper(|mapper, _| {
+            for region in changed.iter() {
+                for page in region.pages() {
+                    // parent tables were created with WRITABLE (and USER_ACCESSIBLE if needed)
+                    let flush = unsafe { mapper.update_flags(page, flags) };
+                    if let Ok(flush) = flush {
+                        finish(flush, active);
+                    }
+                }
+            }
+            // other CPUs must not keep using the old flags
+            shootdown::shoot_down();
+        })?;
+        self.regions = kept;
+        Ok(())
+    }
+
+    // rejects unaligned ranges and ranges outside the usable lower half
+    fn check_range(&self, start: VirtAddr, len: u64) -> Result<(), PagingError> {
+        if len == 0 || start.as_u64() % PAGE_SIZE != 0 || len % PAGE_SIZE != 0 {
+            return Err(PagingError::Unaligned);
+        }
+        let end = start
+            .as_u64()
+            .checked_add(len)
+            .ok_or(PagingError::KernelSpace)?;
+        if end > LOWER_HALF_END || self.touches_kernel(start.as_u64(), end) {
+            return Err(PagingError::KernelSpace);
+        }
+        Ok(())
+    }
+
+    fn touches_kernel(&self, start: u64, end: u64) -> bool {
+        let first = (start / SLOT_SIZE) as usize;
+        let last = ((end - 1) / SLOT_SIZE) as usize;
+        (first..=last).any(|slot| self.kernel_slots[slot / 64] & (1 << (slot % 64)) != 0)
+    }
+
+    // first fit between MMAP_START and MMAP_END
+    fn find_free(&self, len: u64) -> Option<VirtAddr> {
+        let mut candidate = MMAP_START;
+        while candidate + len <= MMAP_END {
+            if self.touches_kernel(candidate, candidate + len) {
+                candidate = (candidate / SLOT_SIZE + 1) * SLOT_SIZE;
+                continue;
+            }
+            let start = VirtAddr::new(candidate);
+            match self.regions.iter().find(|r| r.overlaps(start, start + len)) {
+                Some(region) => candidate = region.end().as_u64(),
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    // splits the regions at "start" and "end". Returns the new region list and the parts inside
+    // [start, end), which get "flags" (and stay in the list) if given, or are removed otherwise
+    fn carve(
+        &self,
+        start: VirtAddr,
+        end: VirtAddr,
+        flags: Option<PageTableFlags>,
+    ) -> (Vec<VmRegion>, Vec<VmRegion>) {
+        let mut kept = Vec::with_capacity(self.regions.len() + 2);
+        let mut inside = Vec::new();
+        for region in self.regions.iter() {
+            if !region.overlaps(start, end) {
+                kept.push(*region);
+                continue;
+            }
+            if region.start < start {
+                kept.push(region.slice(region.start, start));
+            }
+            let mut part = region.slice(region.start.max(start), region.end().min(end));
+            if let Some(flags) = flags {
+                part.flags = flags;
+                kept.push(part);
+            }
+            inside.push(part);
+            if end < region.end() {
+                kept.push(region.slice(end, region.end()));
+            }
+        }
+        (kept, inside)
+    }
+
+    // runs "f" with a mapper for this address space's page tables, see "with_memory" for the
+    // restrictions (no heap growth in "f")
+    fn with_mapper<R>(
+        &self,
+        f: impl FnOnce(&mut OffsetPageTable, &mut MemoryManager) -> R,
+    ) -> Result<R, PagingError> {
+        let frame = self.level_4_frame;
+        with_memory(|memory| {
+            let mut mapper = unsafe {
+                OffsetPageTable::new(table_at(memory, frame), memory.physical_memory_offset())
+            };
+            f(&mut mapper, memory)
+        })
+        .ok_or(PagingError::NotInitialized)
+    }
+}
+
+impl Drop for AddressSpace {
+    // unmaps all regions and frees the page tables (the shared kernel tables stay)
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let frame = self.level_4_frame;
+        let regions = &self.regions;
+        let _ = with_memory(|memory| {
+            let mut mapper = unsafe {
+                OffsetPageTable::new(table_at(memory, frame), memory.physical_memory_offset())
+            };
+            for region in regions.iter() {
+                unmap_pages(&mut mapper, memory, region, false);
+            }
+            let mut frames = FreeAfterShootdown::new(&mut memory.frame_allocator);
+            unsafe { frames.deallocate_frame(frame) };
+        });
+    }
+}
+
+// the page table stored in "frame", reached through the physical memory mapping
+unsafe fn table_at(memory: &MemoryManager, frame: PhysFrame) -> &'static mut PageTable {
+    let virt = memory.physical_memory_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+fn finish(flush: MapperFlush<Size4KiB>, active: bool) {
+    if active {
+        flush.flush();
+    } else {
+        flush.ignore(); // not in the TLB, CR3 reload on activation
+    }
+}
+
+// maps all pages of "region", everything mapped so far is rolled back on failure
+fn map_pages(
+    mapper: &mut OffsetPageTable,
+    memory: &mut MemoryManager,
+    region: &VmRegion,
+    active: bool,
+) -> Result<(), PagingError> {
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (region.flags & PageTableFlags::USER_ACCESSIBLE);
+    for (i, page) in region.pages().enumerate() {
+        let frame = match region.backing {
+            Backing::Anonymous => memory.allocate_zeroed_frame(),
+            Backing::Physical(base) => {
+                Ok(PhysFrame::containing_address(base + i as u64 * PAGE_SIZE))
+            }
+        };
+        let result = frame.and_then(|frame| {
+            let mapped = unsafe {
+                mapper.map_to_with_table_flags(
+                    page,
+                    frame,
+                    region.flags,
+                    parent_flags,
+                    &mut memory.frame_allocator,
+                )
+            };
+            mapped.map_err(|_| {
+                if region.backing == Backing::Anonymous {
+                    unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                }
+                PagingError::MapFailed
+            })
+        });
+        match result {
+            Ok(flush) => finish(flush, active),
+            Err(error) => {
+                let done = region.slice(region.start, page.start_address());
+                unmap_pages(mapper, memory, &done, active);
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
+
+// unmaps all pages of "region", frees anonymous frames and page tables that became empty
+fn unmap_pages(
+    mapper: &mut OffsetPageTable,
+    memory: &mut MemoryManager,
+    region: &VmRegion,
+    active: bool,
+) {
+    if region.len == 0 {
+        return;
+    }
+    // other CPUs may run in this address space too, the frames are only freed after they flushed
+    let mut frames = FreeAfterShootdown::new(&mut memory.frame_allocator);
+    for page in region.pages() {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            finish(flush, active);
+            if region.backing == Backing::Anonymous {
+                unsafe { frames.deallocate_frame(frame) };
+            }
+        }
+    }
+    let pages = Page::range_inclusive(
+        Page::containing_address(region.start),
+        Page::containing_address(region.end() - 1u64),
+    );
+    unsafe { mapper.clean_up_addr_range(pages, &mut frames) };
+    if active {
+        tlb::flush_all(); // freed page tables may still be cached
+    }
+}
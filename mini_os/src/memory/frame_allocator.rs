@@ -0,0 +1,244 @@
+// O(1) physical frame allocator with deallocation and 2MiB huge frames.
+// Usable memory is split into 2MiB aligned chunks (huge ranges) and the unaligned leftovers at the
+// region borders (small ranges). Fresh frames are bumped out of these ranges, freed frames go to an
+// intrusive free list (the next pointer is stored in the free frame itself, reached through the
+// physical memory offset), so nothing has to be scanned. When the small frames run out, a huge frame
+// is split into 512 small ones. Small frames are not merged back into huge frames.
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const SMALL: u64 = Size4KiB::SIZE;
+const HUGE: u64 = Size2MiB::SIZE;
+// the bootloader's memory map holds up to 64 regions, each gives at most 1 huge and 2 small ranges
+const MAX_REGIONS: usize = 64;
+const NO_FRAME: u64 = u64::MAX; // end of a free list
+
+// [next, end) of physical memory that was never handed out
+#[derive(Debug, Clone, Copy)]
+struct BumpRange {
+    next: u64,
+    end: u64,
+}
+
+impl BumpRange {
+    const EMPTY: BumpRange = BumpRange { next: 0, end: 0 };
+
+    fn take(&mut self, size: u64) -> Option<u64> {
+        if self.end - self.next < size {
+            return None;
+        }
+        let addr = self.next;
+        self.next += size;
+        Some(addr)
+    }
+}
+
+// ranges are used up one after another, "current" only moves forward
+struct BumpRanges<const N: usize> {
+    ranges: [BumpRange; N],
+    len: usize,
+    current: usize,
+}
+
+impl<const N: usize> BumpRanges<N> {
+    const fn new() -> Self {
+        BumpRanges {
+            ranges: [BumpRange::EMPTY; N],
+            len: 0,
+            current: 0,
+        }
+    }
+
+    fn push(&mut self, next: u64, end: u64) {
+        if next < end && self.len < N {
+            self.ranges[self.len] = BumpRange { next, end };
+            self.len += 1;
+        }
+    }
+
+    fn take(&mut self, size: u64) -> Option<u64> {
+        while self.current < self.len {
+            if let Some(addr) = self.ranges[self.current].take(size) {
+                return Some(addr);
+            }
+            self.current += 1;
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: u64, // bytes of usable physical memory
+    pub used: u64,  // bytes currently handed out
+}
+
+impl FrameStats {
+    pub fn free(&self) -> u64 {
+        self.total - self.used
+    }
+}
+
+pub struct FreeListFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    small_free: u64, // heads of the free lists (NO_FRAME if empty)
+    huge_free: u64,
+    small_ranges: BumpRanges<{ 2 * MAX_REGIONS }>,
+    huge_ranges: BumpRanges<MAX_REGIONS>,
+    split: BumpRange, // the huge frame currently split into small frames
+    stats: FrameStats,
+}
+
+impl FreeListFrameAllocator {
+    // creates an allocator over the usable regions of the bootloader's memory map
+    /// # Safety
+    /// the memory map must be valid (usable frames are really unused) and the complete physical
+    /// memory must be mapped at "physical_memory_offset". Must only be created once.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.start_addr()..r.range.end_addr());
+        Self::from_ranges(usable, physical_memory_offset)
+    }
+
+    // creates an allocator over the given physical address ranges
+    /// # Safety
+    /// same as "init", the ranges must be unused memory and must not overlap
+    pub unsafe fn from_ranges(
+        ranges: impl Iterator<Item = Range<u64>>,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        let mut allocator = FreeListFrameAllocator {
+            physical_memory_offset,
+            small_free: NO_FRAME,
+            huge_free: NO_FRAME,
+            small_ranges: BumpRanges::new(),
+            huge_ranges: BumpRanges::new(),
+            split: BumpRange::EMPTY,
+            stats: FrameStats { total: 0, used: 0 },
+        };
+        for range in ranges.take(MAX_REGIONS) {
+            let start = align_up(range.start, SMALL);
+            let end = range.end & !(SMALL - 1);
+            if start >= end {
+                continue;
+            }
+            allocator.stats.total += end - start;
+            let huge_start = align_up(start, HUGE);
+            let huge_end = end & !(HUGE - 1);
+            if huge_start < huge_end {
+                allocator.small_ranges.push(start, huge_start);
+                allocator.huge_ranges.push(huge_start, huge_end);
+                allocator.small_ranges.push(huge_end, end);
+            } else {
+                allocator.small_ranges.push(start, end);
+            }
+        }
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    // the "next" pointer stored in the first 8 bytes of a free frame
+    fn link(&self, frame_addr: u64) -> *mut u64 {
+        (self.physical_memory_offset + frame_addr).as_mut_ptr()
+    }
+
+    fn pop(&mut self, huge: bool) -> Option<u64> {
+        let head = if huge {
+            self.huge_free
+        } else {
+            self.small_free
+        };
+        if head == NO_FRAME {
+            return None;
+        }
+        let next = unsafe { self.link(head).read() };
+        if huge {
+            self.huge_free = next;
+        } else {
+            self.small_free = next;
+        }
+        Some(head)
+    }
+
+    unsafe fn push(&mut self, addr: u64, huge: bool) {
+        let next = if huge {
+            self.huge_free
+        } else {
+            self.small_free
+        };
+        self.link(addr).write(next);
+        if huge {
+            self.huge_free = addr;
+        } else {
+            self.small_free = addr;
+        }
+    }
+
+    fn allocate_huge(&mut self) -> Option<u64> {
+        self.pop(true).or_else(|| self.huge_ranges.take(HUGE))
+    }
+
+    fn allocate_small(&mut self) -> Option<u64> {
+        if let Some(addr) = self.pop(false) {
+            return Some(addr);
+        }
+        if let Some(addr) = self.small_ranges.take(SMALL) {
+            return Some(addr);
+        }
+        if let Some(addr) = self.split.take(SMALL) {
+            return Some(addr);
+        }
+        let huge = self.allocate_huge()?;
+        self.split = BumpRange {
+            next: huge,
+            end: huge + HUGE,
+        };
+        self.split.take(SMALL)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for FreeListFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let addr = self.allocate_small()?;
+        self.stats.used += SMALL;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for FreeListFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let addr = self.allocate_huge()?;
+        self.stats.used += HUGE;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FreeListFrameAllocator {
+    // the frame must have been allocated by this allocator and must not be in use anymore
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.push(frame.start_address().as_u64(), false);
+        self.stats.used -= SMALL;
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for FreeListFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.push(frame.start_address().as_u64(), true);
+        self.stats.used -= HUGE;
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,122 @@
+// TLB shootdown: a CPU keeps the translations it used in its TLB (and paging structure caches)
+// until it flushes them, unmapping a page only flushes the running CPU. Before the frame of an
+// unmapped page (or a freed page table) goes back to the frame allocator every other online CPU has
+// to flush, or it could still reach the frame's next user through the old translation.
+// FreeAfterShootdown collects the frames, sends apic::TLB_SHOOTDOWN_VECTOR to the other CPUs, waits
+// until each of them flushed and only then frees the frames.
+// The requester waits with the memory manager's lock held and interrupts disabled. A CPU that spins
+// on a lock with interrupts disabled (memory's CpuMutex, allocator::Locked) can't take the
+// interrupt, so those spin loops call "acknowledge" themselves.
+use super::frame_allocator::FreeListFrameAllocator;
+use crate::cpu::{self, PerCpu, MAX_CPUS};
+use crate::interrupts::apic;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame, Size4KiB};
+
+// shootdowns requested so far
+static REQUESTED: AtomicU64 = AtomicU64::new(0);
+// the number of shootdowns each CPU's last flush covered
+static FLUSHED: PerCpu<AtomicU64> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+});
+// bit n: CPU n takes the interrupt (its local APIC is enabled). A processor that is still
+// starting isn't waited for, it flushes once it gets here ("cpu_ready")
+static READY: AtomicU64 = AtomicU64::new(1);
+
+// flushes the running CPU's TLB if a shootdown asked for it. Called by the interrupt handler and
+// by spin loops that run with interrupts disabled
+pub fn acknowledge() {
+    let cpu = match cpu::id() {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    let requested = REQUESTED.load(Ordering::SeqCst);
+    if FLUSHED.of(cpu).load(Ordering::SeqCst) < requested {
+        tlb::flush_all();
+        FLUSHED.of(cpu).store(requested, Ordering::SeqCst);
+    }
+}
+
+// called by every application processor once its local APIC is enabled (smp::ap_main)
+pub(crate) fn cpu_ready(cpu: usize) {
+    READY.fetch_or(1 << cpu, Ordering::SeqCst);
+    // whatever was unmapped before the bit was set wasn't shot down here
+    let requested = REQUESTED.load(Ordering::SeqCst);
+    tlb::flush_all();
+    FLUSHED.of(cpu).store(requested, Ordering::SeqCst);
+}
+
+// makes every other ready CPU flush its TLB and waits until they did. Nothing to do while only
+// one CPU is online. Called with the memory manager's lock held
+pub(super) fn shoot_down() {
+    if cpu::count() == 1 {
+        return;
+    }
+    let running = cpu::id().unwrap_or(0);
+    let requested = REQUESTED.fetch_add(1, Ordering::SeqCst) + 1;
+    let others = READY.load(Ordering::SeqCst) & !(1 << running);
+    let targets = || (0..MAX_CPUS).filter(move |cpu| others & 1 << cpu != 0);
+    for cpu in targets() {
+        if let Some(apic_id) = cpu::apic_id_of(cpu) {
+            apic::send_ipi(apic_id, apic::TLB_SHOOTDOWN_VECTOR);
+        }
+    }
+    for cpu in targets() {
+        while FLUSHED.of(cpu).load(Ordering::SeqCst) < requested {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+const BATCH: usize = 64;
+
+// frame deallocator for frames that were just unmapped: keeps up to BATCH of them and frees them
+// after a shootdown, when the batch is full and when it's dropped
+pub struct FreeAfterShootdown<'a> {
+    allocator: &'a mut FreeListFrameAllocator,
+    frames: [Option<PhysFrame>; BATCH],
+    len: usize,
+}
+
+impl<'a> FreeAfterShootdown<'a> {
+    pub fn new(allocator: &'a mut FreeListFrameAllocator) -> Self {
+        FreeAfterShootdown {
+            allocator,
+            frames: [None; BATCH],
+            len: 0,
+        }
+    }
+
+    fn release(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        shoot_down();
+        for frame in self.frames[..self.len].iter_mut() {
+            if let Some(frame) = frame.take() {
+                unsafe { self.allocator.deallocate_frame(frame) };
+            }
+        }
+        self.len = 0;
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FreeAfterShootdown<'_> {
+    // the frame must have been allocated by the frame allocator and must not be mapped anymore
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        if self.len == BATCH {
+            self.release();
+        }
+        self.frames[self.len] = Some(frame);
+        self.len += 1;
+    }
+}
+
+impl Drop for FreeAfterShootdown<'_> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
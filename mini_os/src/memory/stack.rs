@@ -0,0 +1,143 @@
+// kernel stacks with guard pages: every stack gets a slot of STACK_SLOT_SIZE bytes of which only the
+// top "pages" pages are mapped. The rest of the slot below the stack (at least one page) is never
+// mapped, so running over the end of a stack page faults instead of silently overwriting whatever
+// lies below it. The fault handlers look the address up with "overflowed_stack" and report whose
+// stack it was.
+use super::shootdown::FreeAfterShootdown;
+use super::{with_memory, MemoryManager, PagingError};
+use core::arch::asm;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameDeallocator, Mapper, Page, PageTableFlags as Flags};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+// in the level 4 slot of the heap (behind its 16MiB limit), so address spaces created after the
+// heap is in use share the stacks created later
+pub const STACKS_START: u64 = 0x_4444_8000_0000;
+pub const STACK_SLOT_SIZE: u64 = 64 * 1024;
+pub const MAX_STACK_PAGES: u64 = STACK_SLOT_SIZE / PAGE_SIZE - 1; // leaves one guard page
+pub const MAX_STACKS: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    owner: &'static str,
+    pages: u64,
+}
+
+// fixed size array, stacks are also made before the heap exists
+static SLOTS: Mutex<[Option<Slot>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(STACKS_START + slot as u64 * STACK_SLOT_SIZE)
+}
+
+fn stack_pages(slot: usize, pages: u64) -> impl Iterator<Item = Page> {
+    let top = slot_start(slot) + STACK_SLOT_SIZE;
+    Page::range(
+        Page::containing_address(top - pages * PAGE_SIZE),
+        Page::containing_address(top),
+    )
+}
+
+// unmaps "pages" and frees their frames, the page tables stay for the next stack in the slot
+fn unmap(memory: &mut MemoryManager, pages: impl Iterator<Item = Page>) {
+    let mut frames = FreeAfterShootdown::new(&mut memory.frame_allocator);
+    for page in pages {
+        if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+            flush.flush();
+            unsafe { frames.deallocate_frame(frame) };
+        }
+    }
+}
+
+pub struct KernelStack {
+    slot: usize,
+    pages: u64,
+}
+
+impl KernelStack {
+    // maps a zeroed stack of "pages" pages with a guard below it. "owner" names the task using it
+    // in overflow reports. Panics if "pages" is 0 or more than MAX_STACK_PAGES
+    pub fn new(owner: &'static str, pages: u64) -> Result<KernelStack, PagingError> {
+        assert!(
+            pages > 0 && pages <= MAX_STACK_PAGES,
+            "kernel stacks have 1 to {} pages",
+            MAX_STACK_PAGES
+        );
+        let slot = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let slot = slots
+                .iter()
+                .position(|slot| slot.is_none())
+                .ok_or(PagingError::TooManyStacks)?;
+            slots[slot] = Some(Slot { owner, pages });
+            Ok(slot)
+        })?;
+        let stack = KernelStack { slot, pages };
+        // on failure the drop of "stack" unmaps what was mapped and frees the slot
+        with_memory(|memory| {
+            let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
+            stack_pages(slot, pages).try_for_each(|page| memory.map_zeroed(page, flags))
+        })
+        .ok_or(PagingError::NotInitialized)??;
+        Ok(stack)
+    }
+
+    // initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        slot_start(self.slot) + STACK_SLOT_SIZE
+    }
+
+    // lowest mapped address, the guard is below
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.pages * PAGE_SIZE
+    }
+
+    // calls "f" with the stack pointer at the top of this stack, returns when "f" does
+    /// # Safety
+    /// the stack must not be in use already (no nested "run" on the same stack)
+    pub unsafe fn run(&self, f: extern "C" fn()) {
+        // r12 is callee-saved, it holds the old stack pointer over the call. The top is page
+        // aligned, so "f" starts with the alignment the calling convention wants
+        asm!(
+            "xchg rsp, r12",
+            "call {f}",
+            "mov rsp, r12",
+            f = in(reg) f,
+            inout("r12") self.top().as_u64() => _,
+            clobber_abi("C"),
+        );
+    }
+
+    // the stack stays mapped forever (e.g. the stacks in the TSS), returns its top
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let (slot, pages) = (self.slot, self.pages);
+        let _ = with_memory(|memory| unmap(memory, stack_pages(slot, pages)));
+        x86_64::instructions::interrupts::without_interrupts(|| SLOTS.lock()[slot] = None);
+    }
+}
+
+// owner of the stack whose guard "addr" is in, None if it isn't in a guard. Called by the fault
+// handlers, so it gives up if the registry is locked
+pub fn overflowed_stack(addr: VirtAddr) -> Option<&'static str> {
+    let offset = addr.as_u64().checked_sub(STACKS_START)?;
+    let slot = (offset / STACK_SLOT_SIZE) as usize;
+    if slot >= MAX_STACKS {
+        return None;
+    }
+    let info = SLOTS.try_lock()?[slot]?;
+    let bottom = slot_start(slot) + (STACK_SLOT_SIZE - info.pages * PAGE_SIZE);
+    if addr < bottom {
+        Some(info.owner)
+    } else {
+        None
+    }
+}
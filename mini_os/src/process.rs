@@ -0,0 +1,209 @@
+// user mode processes. A process runs in ring 3 in its own address space until it exits or faults,
+// only then "run" returns (one process per CPU at a time, the kernel waits for it). Entering user mode is
+// an "iretq" with a ring 3 frame; leaving it works like longjmp: "user_enter" saves the kernel
+// stack pointer and callee-saved registers, the exit syscall and the fault handlers jump back there
+// and drop whatever was on the privilege stack.
+pub mod elf;
+pub mod syscall;
+
+use crate::cpu::{PerCpu, MAX_CPUS};
+use crate::gdt;
+use crate::memory::address_space::{AddressSpace, Backing};
+use crate::memory::PagingError;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags as Flags;
+use x86_64::VirtAddr;
+
+// where "from_code" puts code and stack, both in level 4 slots the kernel doesn't use
+pub const USER_CODE_START: u64 = 0x0000_1000_0000_0000;
+pub const USER_STACK_TOP: u64 = 0x0000_7000_0000_0000;
+pub const USER_STACK_SIZE: u64 = 4096 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(u64),         // exit syscall with this code
+    PageFault(VirtAddr), // access to unmapped or kernel memory
+    GeneralProtection,   // e.g. a privileged instruction
+    Exception(u8),       // any other CPU exception, its vector
+}
+
+pub struct Process {
+    pid: u64,
+    address_space: AddressSpace,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+}
+
+// pid and end of the process that is running right now
+struct Running {
+    pid: u64,
+    status: Option<ExitStatus>,
+}
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+// a process stays on the CPU it was started on (interrupts and syscalls return to it), so every CPU
+// can run one
+static RUNNING: PerCpu<Mutex<Option<Running>>> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const NONE: Mutex<Option<Running>> = Mutex::new(None);
+    [NONE; MAX_CPUS]
+});
+// kernel stack pointer saved by "user_enter", "user_return" continues there
+static KERNEL_RSP: PerCpu<AtomicU64> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+});
+
+extern "C" {
+    fn user_enter(entry: u64, stack: u64, code_selector: u64, data_selector: u64, rsp: *mut u64);
+    fn user_return(rsp: u64) -> !;
+}
+
+global_asm!(
+    r#"
+.global user_enter
+user_enter:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [r8], rsp
+    // interrupt stack frame for ring 3: ss, rsp, rflags (interrupts on), cs, rip
+    push rcx
+    push rsi
+    push 0x202
+    push rdx
+    push rdi
+    // don't leak kernel values to the user program
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+
+.global user_return
+user_return:
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+impl Process {
+    // a process that starts at "entry" with the stack pointer at "stack_top", both have to be
+    // mapped user accessible in "address_space"
+    pub fn new(address_space: AddressSpace, entry: VirtAddr, stack_top: VirtAddr) -> Process {
+        Process {
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            address_space,
+            entry,
+            stack_top,
+        }
+    }
+
+    // a process running raw (position independent) machine code, mapped read only at
+    // USER_CODE_START, with a stack below USER_STACK_TOP
+    pub fn from_code(code: &[u8]) -> Result<Process, PagingError> {
+        let mut space = AddressSpace::new()?;
+        let code_start = VirtAddr::new(USER_CODE_START);
+        let code_len = (code.len() as u64 + 4095) & !4095;
+        space.map(
+            code_start,
+            code_len.max(4096),
+            Flags::USER_ACCESSIBLE,
+            Backing::Anonymous,
+        )?;
+        space.write(code_start, code)?;
+        let stack_top = VirtAddr::new(USER_STACK_TOP);
+        space.map(
+            stack_top - USER_STACK_SIZE,
+            USER_STACK_SIZE,
+            Flags::USER_ACCESSIBLE | Flags::WRITABLE | Flags::NO_EXECUTE,
+            Backing::Anonymous,
+        )?;
+        Ok(Process::new(space, code_start, stack_top))
+    }
+
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    // runs the process in ring 3 until it exits or faults
+    pub fn run(&mut self) -> ExitStatus {
+        use x86_64::instructions::interrupts;
+        use x86_64::registers::control::Cr3;
+
+        let (code_selector, data_selector) = gdt::user_selectors();
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable(); // "iretq" turns them on again for the user program
+        {
+            let mut running = RUNNING.get().lock();
+            assert!(running.is_none(), "another process is running on this CPU");
+            *running = Some(Running {
+                pid: self.pid,
+                status: None,
+            });
+        }
+        let (kernel_table, flags) = Cr3::read();
+        unsafe {
+            self.address_space.activate();
+            user_enter(
+                self.entry.as_u64(),
+                self.stack_top.as_u64(),
+                code_selector.0.into(),
+                data_selector.0.into(),
+                KERNEL_RSP.get().as_ptr(),
+            );
+            Cr3::write(kernel_table, flags);
+        }
+        let status = RUNNING
+            .get()
+            .lock()
+            .take()
+            .and_then(|running| running.status);
+        if interrupts_enabled {
+            interrupts::enable();
+        }
+        status.expect("process stopped without an exit status")
+    }
+}
+
+// pid of the process running on this CPU
+pub fn current_pid() -> Option<u64> {
+    RUNNING.get().lock().as_ref().map(|running| running.pid)
+}
+
+// ends the running process, its "run" call returns "status". Called by the exit syscall and the
+// exception handlers (with interrupts disabled), whatever they had on the stack is dropped
+pub(crate) fn stop_current(status: ExitStatus) -> ! {
+    {
+        let mut running = RUNNING.get().lock();
+        running.as_mut().expect("no process is running").status = Some(status);
+    }
+    unsafe { user_return(KERNEL_RSP.get().load(Ordering::SeqCst)) }
+}
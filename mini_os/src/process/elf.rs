@@ -0,0 +1,244 @@
+// ELF64 loader for user programs: statically linked x86_64 executables (ET_EXEC) are mapped into a
+// fresh address space, one VM region per PT_LOAD segment. Pages get the segment's permissions
+// (PF_W -> WRITABLE, no PF_X -> NO_EXECUTE), file data is copied in and the rest up to p_memsz
+// (.bss) is zeroed. A page shared by two segments gets the flags of both.
+use super::{Process, USER_STACK_SIZE, USER_STACK_TOP};
+use crate::memory::address_space::{AddressSpace, Backing};
+use crate::memory::PagingError;
+use x86_64::structures::paging::PageTableFlags as Flags;
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,           // file ends inside a header or segment
+    NotElf,              // wrong magic
+    Unsupported,         // not a little endian ELF64 x86_64 executable
+    BadSegment,          // p_filesz > p_memsz, overflow or misaligned
+    NoLoadableSegments,  // nothing to map
+    EntryNotExecutable,  // entry point outside of every executable segment
+    Paging(PagingError), // mapping failed (e.g. segment in kernel space)
+}
+
+impl From<PagingError> for ElfError {
+    fn from(error: PagingError) -> Self {
+        ElfError::Paging(error)
+    }
+}
+
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ElfError::Truncated => f.write_str("file is truncated"),
+            ElfError::NotElf => f.write_str("not an ELF file"),
+            ElfError::Unsupported => f.write_str("not a little endian ELF64 x86_64 executable"),
+            ElfError::BadSegment => f.write_str("invalid program header"),
+            ElfError::NoLoadableSegments => f.write_str("no PT_LOAD segments"),
+            ElfError::EntryNotExecutable => f.write_str("entry point is not executable"),
+            ElfError::Paging(error) => write!(f, "mapping a segment failed: {}", error),
+        }
+    }
+}
+
+// a PT_LOAD program header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub flags: u32, // PF_X, PF_W, PF_R
+}
+
+impl Segment {
+    fn page_flags(&self) -> Flags {
+        let mut flags = Flags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= Flags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= Flags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+// validated headers of an ELF file, segments are borrowed from the file
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: usize, // file offset
+    program_header_count: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    let mut buffer = [0; 4];
+    buffer.copy_from_slice(bytes);
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    let mut buffer = [0; 8];
+    buffer.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(buffer))
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != CLASS_64
+            || data[5] != LITTLE_ENDIAN
+            || read_u16(data, 16)? != TYPE_EXEC
+            || read_u16(data, 18)? != MACHINE_X86_64
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24)?,
+            program_headers: read_u64(data, 32)? as usize,
+            program_header_count: read_u16(data, 56)? as usize,
+        };
+        if read_u16(data, 54)? as usize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        let table_size = elf.program_header_count * PROGRAM_HEADER_SIZE;
+        match elf.program_headers.checked_add(table_size) {
+            Some(end) if end <= data.len() => {}
+            _ => return Err(ElfError::Truncated),
+        }
+        for segment in elf.segments() {
+            let segment = segment?;
+            let file_end = segment.offset.checked_add(segment.file_size);
+            if file_end.map_or(true, |end| end > data.len() as u64) {
+                return Err(ElfError::Truncated);
+            }
+            if segment.file_size > segment.mem_size
+                || segment.vaddr.checked_add(segment.mem_size).is_none()
+            {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.entry)
+    }
+
+    // PT_LOAD segments in file order (ascending addresses, the ELF spec requires it)
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment, ElfError>> + '_ {
+        (0..self.program_header_count)
+            .map(move |i| self.program_headers + i * PROGRAM_HEADER_SIZE)
+            .filter(move |&header| read_u32(self.data, header) == Ok(PT_LOAD))
+            .map(move |header| {
+                Ok(Segment {
+                    flags: read_u32(self.data, header + 4)?,
+                    offset: read_u64(self.data, header + 8)?,
+                    vaddr: read_u64(self.data, header + 16)?,
+                    file_size: read_u64(self.data, header + 32)?,
+                    mem_size: read_u64(self.data, header + 40)?,
+                })
+            })
+    }
+
+    // maps all loadable segments into "space" and copies their contents
+    pub fn load(&self, space: &mut AddressSpace) -> Result<(), ElfError> {
+        let mut loaded = false;
+        let mut entry_ok = false;
+        for segment in self.segments() {
+            let segment = segment?;
+            if segment.mem_size == 0 {
+                continue;
+            }
+            let start = VirtAddr::try_new(segment.vaddr).map_err(|_| ElfError::BadSegment)?;
+            let end = VirtAddr::try_new(segment.vaddr + segment.mem_size)
+                .map_err(|_| ElfError::BadSegment)?;
+            let mut page = start.align_down(PAGE_SIZE);
+            let page_end = end.align_up(PAGE_SIZE);
+            let flags = segment.page_flags();
+
+            // the first page may already belong to the previous segment: merge the permissions
+            let shared = space
+                .regions()
+                .iter()
+                .find(|r| r.start <= page && page < r.end())
+                .map(|r| r.flags);
+            if let Some(existing) = shared {
+                let mut merged = existing | flags;
+                if !(existing.contains(Flags::NO_EXECUTE) && flags.contains(Flags::NO_EXECUTE)) {
+                    merged.remove(Flags::NO_EXECUTE);
+                }
+                space.protect(page, PAGE_SIZE, merged)?;
+                page += PAGE_SIZE;
+            }
+            if page < page_end {
+                space.map(page, page_end - page, flags, Backing::Anonymous)?;
+            }
+
+            let file = &self.data[segment.offset as usize..][..segment.file_size as usize];
+            space.write(start, file)?;
+            // .bss: fresh pages are zero already, but a shared first page is not
+            space.zero(
+                start + segment.file_size,
+                segment.mem_size - segment.file_size,
+            )?;
+
+            loaded = true;
+            if segment.flags & PF_X != 0
+                && start.as_u64() <= self.entry
+                && self.entry < end.as_u64()
+            {
+                entry_ok = true;
+            }
+        }
+        if !loaded {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        if !entry_ok {
+            return Err(ElfError::EntryNotExecutable);
+        }
+        Ok(())
+    }
+}
+
+impl Process {
+    // a process running the ELF executable "data", with a stack below USER_STACK_TOP
+    pub fn from_elf(data: &[u8]) -> Result<Process, ElfError> {
+        let elf = Elf::parse(data)?;
+        let mut space = AddressSpace::new()?;
+        elf.load(&mut space)?;
+        let stack_top = VirtAddr::new(USER_STACK_TOP);
+        space.map(
+            stack_top - USER_STACK_SIZE,
+            USER_STACK_SIZE,
+            Flags::USER_ACCESSIBLE | Flags::WRITABLE | Flags::NO_EXECUTE,
+            Backing::Anonymous,
+        )?;
+        Ok(Process::new(space, elf.entry(), stack_top))
+    }
+}
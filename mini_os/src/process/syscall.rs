@@ -0,0 +1,128 @@
+// syscalls: user programs put the number into rax and the arguments into rdi, rsi and rdx, then
+// execute "int 0x80". The result comes back in rax (ERROR on failure), all other registers are
+// preserved
+use super::ExitStatus;
+use crate::interrupts;
+use crate::memory;
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+pub const INTERRUPT: u8 = 0x80;
+
+pub const WRITE: u64 = 0; // write(fd, buffer, len) -> bytes written, fd 1 and 2 go to the screen
+pub const EXIT: u64 = 1; // exit(code) -> never returns
+pub const YIELD: u64 = 2; // yield() -> 0
+pub const SLEEP: u64 = 3; // sleep(milliseconds) -> 0
+pub const GETPID: u64 = 4; // getpid() -> pid
+pub const ERROR: u64 = u64::MAX;
+
+type Handler = fn(u64, u64, u64) -> u64;
+
+// indexed by syscall number
+static TABLE: [Handler; 5] = [write, exit, yield_now, sleep, getpid];
+
+extern "C" {
+    fn syscall_entry();
+}
+
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    cld
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    // the CPU pushed 5 words, 8 more were pushed above: realign to 16 bytes for the call
+    sub rsp, 8
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    call syscall_dispatch
+    add rsp, 8
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    iretq
+"#
+);
+
+// address of the assembly entry point, for the IDT
+pub fn entry_address() -> VirtAddr {
+    VirtAddr::new(syscall_entry as usize as u64)
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(number: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    match TABLE.get(number as usize) {
+        Some(handler) => handler(arg1, arg2, arg3),
+        None => ERROR,
+    }
+}
+
+fn write(fd: u64, buffer: u64, len: u64) -> u64 {
+    if fd != 1 && fd != 2 {
+        return ERROR;
+    }
+    let start = match VirtAddr::try_new(buffer) {
+        Ok(start) => start,
+        Err(_) => return ERROR,
+    };
+    if !memory::user_accessible(start, len, false) {
+        return ERROR;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), len as usize) };
+    match core::str::from_utf8(bytes) {
+        Ok(text) => {
+            crate::print!("{}", text);
+            crate::serial_print!("{}", text);
+        }
+        Err(_) => {
+            for &byte in bytes {
+                crate::print!("{}", byte as char);
+                crate::serial_print!("{}", byte as char);
+            }
+        }
+    }
+    len
+}
+
+fn exit(code: u64, _: u64, _: u64) -> u64 {
+    if super::current_pid().is_none() {
+        return ERROR; // "int 0x80" from kernel code
+    }
+    super::stop_current(ExitStatus::Exited(code))
+}
+
+// only one process runs at a time, so there is nobody to give the CPU to
+fn yield_now(_: u64, _: u64, _: u64) -> u64 {
+    0
+}
+
+fn sleep(milliseconds: u64, _: u64, _: u64) -> u64 {
+    let micros = milliseconds.saturating_mul(1000);
+    let ticks =
+        micros.saturating_add(interrupts::TIMER_TICK_MICROS - 1) / interrupts::TIMER_TICK_MICROS;
+    let until = interrupts::ticks() + ticks;
+    while interrupts::ticks() < until {
+        // the syscall gate disabled interrupts, the timer has to get through
+        x86_64::instructions::interrupts::enable_and_hlt();
+        x86_64::instructions::interrupts::disable();
+    }
+    0
+}
+
+fn getpid(_: u64, _: u64, _: u64) -> u64 {
+    super::current_pid().unwrap_or(ERROR)
+}
@@ -11,6 +11,67 @@ lazy_static! {
     };
 }
 
+// RECEIVING: COM1 raises IRQ 4 when data arrives ("init" of uart_16550 enables that interrupt), its
+// handler moves the bytes into INPUT and wakes the task waiting for them (task::serial)
+const INPUT_SIZE: usize = 256;
+
+struct Input {
+    bytes: [u8; INPUT_SIZE],
+    start: usize,
+    len: usize,
+}
+
+static INPUT: Mutex<Input> = Mutex::new(Input {
+    bytes: [0; INPUT_SIZE],
+    start: 0,
+    len: 0,
+});
+
+// called by the serial interrupt handler: reads everything the UART received, bytes that don't fit
+// into INPUT are dropped. The data and line status ports are read directly, the interrupt may
+// arrive while SERIAL1 is locked for a write
+pub(crate) fn receive() {
+    use x86_64::instructions::port::Port;
+
+    let mut data = Port::<u8>::new(0x3f8);
+    let mut line_status = Port::<u8>::new(0x3fd);
+    let mut input = INPUT.lock();
+    while unsafe { line_status.read() } & 1 != 0 {
+        let byte = unsafe { data.read() };
+        if input.len < INPUT_SIZE {
+            let end = (input.start + input.len) % INPUT_SIZE;
+            input.bytes[end] = byte;
+            input.len += 1;
+        }
+    }
+    if input.len > 0 {
+        INPUT_WAKER.wake();
+    }
+}
+
+use core::task::Waker;
+use futures_util::task::AtomicWaker;
+static INPUT_WAKER: AtomicWaker = AtomicWaker::new();
+
+// "waker" is woken once bytes arrive, check "read_byte" again after registering
+pub fn register_input_waker(waker: &Waker) {
+    INPUT_WAKER.register(waker);
+}
+
+// oldest byte received on COM1 that wasn't read yet
+pub fn read_byte() -> Option<u8> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        if input.len == 0 {
+            return None;
+        }
+        let byte = input.bytes[input.start];
+        input.start = (input.start + 1) % INPUT_SIZE;
+        input.len -= 1;
+        Some(byte)
+    })
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -0,0 +1,310 @@
+// SMP: the application processors (every enabled processor of the MADT but the bootstrap one)
+// are started one after the other with INIT-SIPI-SIPI. They begin in real mode at a trampoline
+// copied to memory::low_frame, which switches straight to long mode with the bootstrap processor's
+// page table and calls "ap_main" on a kernel stack of the CPU's own. There every CPU loads its own
+// IDT, GDT and TSS, enables its local APIC and runs an Executor, which also picks up the tasks of
+// task::executor::spawn_shared.
+use crate::acpi::{self, AcpiError};
+use crate::cpu::{self, MAX_CPUS};
+use crate::interrupts::{self, apic};
+use crate::memory::stack::{KernelStack, MAX_STACK_PAGES};
+use crate::memory::{self, PagingError};
+use crate::task::executor::Executor;
+use crate::{gdt, serial_println};
+use core::arch::global_asm;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    NoApic, // apic::init didn't switch to the local APIC
+    Acpi(AcpiError),
+    NoLowMemory,        // no frame below 1MiB for the trampoline
+    TrampolineOccupied, // the trampoline's address is mapped to something else
+    Mapping(PagingError),
+}
+
+impl From<AcpiError> for SmpError {
+    fn from(error: AcpiError) -> Self {
+        SmpError::Acpi(error)
+    }
+}
+
+impl From<PagingError> for SmpError {
+    fn from(error: PagingError) -> Self {
+        SmpError::Mapping(error)
+    }
+}
+
+impl core::fmt::Display for SmpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            SmpError::NoApic => f.write_str("the local APIC is not active"),
+            SmpError::Acpi(error) => write!(f, "{}", error),
+            SmpError::NoLowMemory => f.write_str("no free frame below 1MiB for the trampoline"),
+            SmpError::TrampolineOccupied => f.write_str("the trampoline's address is in use"),
+            SmpError::Mapping(error) => write!(f, "mapping the trampoline failed: {}", error),
+        }
+    }
+}
+
+// names of the CPUs' kernel stacks in overflow reports
+const STACK_NAMES: [&str; MAX_CPUS] = [
+    "cpu 0", "cpu 1", "cpu 2", "cpu 3", "cpu 4", "cpu 5", "cpu 6", "cpu 7",
+];
+
+// what the trampoline needs, at "smp_trampoline_data" (offset 8) in its copy. The offsets are hard
+// coded in the assembly below
+#[repr(C)]
+struct TrampolineData {
+    gdt: [u64; 3],       // 0: null, 64 bit code, data
+    gdtr: [u16; 4],      // 24: limit, linear base (low, high)
+    long_mode: [u32; 2], // 32: far pointer to smp_trampoline_long_mode: offset, selector
+    cr3: u64,            // 40: below 4GiB, it's loaded in real mode
+    cr0: u64,            // 48
+    cr4: u64,            // 56
+    efer: u64,           // 64
+    stack: u64,          // 72
+    entry: u64,          // 80: extern "C" fn(cpu: usize) -> !
+    cpu: u64,            // 88
+}
+
+const _: () = assert!(core::mem::size_of::<TrampolineData>() == 96);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_long_mode: u8;
+    static smp_trampoline_data: u8;
+    static smp_trampoline_end: u8;
+}
+
+// real mode (CS = page of the copy, IP = 0, so everything is addressed relative to the start; the
+// data comes first for constant offsets), then long mode directly: PAE, page table, EFER.LME, then paging and protection at once. The far
+// jump loads the 64 bit code segment of the trampoline's GDT
+global_asm!(
+    r#"
+.pushsection .rodata.smp_trampoline, "a"
+.global smp_trampoline_start
+.global smp_trampoline_long_mode
+.global smp_trampoline_data
+.global smp_trampoline_end
+.code16
+smp_trampoline_start:
+    jmp smp_trampoline_real_mode
+.balign 8
+smp_trampoline_data:
+    .fill 96, 1, 0
+smp_trampoline_real_mode:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    lgdt [8 + 24]
+    mov eax, [8 + 56]
+    mov cr4, eax
+    mov eax, [8 + 40]
+    mov cr3, eax
+    mov eax, [8 + 64]
+    xor edx, edx
+    mov ecx, 0xc0000080
+    wrmsr
+    mov eax, [8 + 48]
+    mov cr0, eax
+    // jmp far dword [8 + 32]
+    .byte 0x66, 0xff, 0x2e
+    .word 8 + 32
+.code64
+smp_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor eax, eax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, [rip + smp_trampoline_data + 72]
+    mov rdi, [rip + smp_trampoline_data + 88]
+    mov rax, [rip + smp_trampoline_data + 80]
+    call rax
+    ud2
+smp_trampoline_end:
+.popsection
+"#
+);
+
+// the processor that is being started: WAITING until it enters "ap_main" (it doesn't need the
+// trampoline from there on) and ONLINE once it's set up. One that is late gets ABANDONED instead,
+// the compare exchange decides which of the two happened
+static STATE: AtomicU8 = AtomicU8::new(WAITING);
+const WAITING: u8 = 0;
+const ENTERED: u8 = 1;
+const ONLINE: u8 = 2;
+const ABANDONED: u8 = 3;
+
+// right after turning on paging the trampoline runs at its physical address. Returns whether the
+// identity mapping was made here (and has to be removed again)
+fn map_trampoline(frame: PhysFrame) -> Result<bool, SmpError> {
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    memory::with_memory(
+        |memory| match memory.mapper.translate_addr(page.start_address()) {
+            Some(phys) if phys == frame.start_address() => Ok(false),
+            Some(_) => Err(SmpError::TrampolineOccupied),
+            None => {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                unsafe {
+                    memory
+                        .mapper
+                        .map_to(page, frame, flags, &mut memory.frame_allocator)
+                        .map_err(|_| PagingError::MapFailed)?
+                        .flush();
+                }
+                Ok(true)
+            }
+        },
+    )
+    .ok_or(PagingError::NotInitialized)?
+}
+
+fn unmap_trampoline(frame: PhysFrame) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    memory::with_memory(|memory| {
+        if let Ok((_, flush)) = memory.mapper.unmap(page) {
+            flush.flush();
+        }
+    });
+}
+
+// copies the trampoline into "frame" and fills in what is the same for every processor
+fn install_trampoline(frame: PhysFrame) -> Result<&'static mut TrampolineData, SmpError> {
+    let start = unsafe { addr_of!(smp_trampoline_start) } as usize;
+    let long_mode = unsafe { addr_of!(smp_trampoline_long_mode) } as usize - start;
+    let data_offset = unsafe { addr_of!(smp_trampoline_data) } as usize - start;
+    let len = unsafe { addr_of!(smp_trampoline_end) } as usize - start;
+    assert!(len <= 4096, "the trampoline doesn't fit into a frame");
+    assert_eq!(data_offset, 8, "the trampoline's data moved");
+
+    let phys = frame.start_address().as_u64();
+    let copy = memory::phys_to_virt(frame.start_address()).ok_or(PagingError::NotInitialized)?;
+    let (cr3, _) = Cr3::read();
+    let cr3 = cr3.start_address().as_u64();
+    assert!(cr3 < 1 << 32, "the level 4 table has to be below 4GiB");
+    let gdt = phys + data_offset as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, copy.as_mut_ptr::<u8>(), len);
+        let data = &mut *(copy + data_offset).as_mut_ptr::<TrampolineData>();
+        *data = TrampolineData {
+            gdt: [0, 0x00af_9a00_0000_ffff, 0x00cf_9200_0000_ffff],
+            gdtr: [23, gdt as u16, (gdt >> 16) as u16, 0],
+            long_mode: [(phys + long_mode as u64) as u32, 0x08],
+            cr3,
+            cr0: Cr0::read_raw(),
+            // PCIDs can only be turned on in long mode
+            cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+            efer: (Efer::read()
+                & (EferFlags::LONG_MODE_ENABLE
+                    | EferFlags::NO_EXECUTE_ENABLE
+                    | EferFlags::SYSTEM_CALL_EXTENSIONS))
+                .bits(),
+            stack: 0,
+            entry: ap_main as usize as u64,
+            cpu: 0,
+        };
+        Ok(data)
+    }
+}
+
+// starts every other enabled processor of the MADT (up to MAX_CPUS in total) and returns how many
+// CPUs are online. Needs the heap, the new CPUs run executors. A processor that doesn't reach
+// "ap_main" within 100ms is sent back to wait-for-SIPI and left out
+pub fn start_application_processors() -> Result<usize, SmpError> {
+    if !apic::is_active() {
+        return Err(SmpError::NoApic);
+    }
+    let madt = acpi::madt()?;
+    let frame = memory::low_frame().ok_or(SmpError::NoLowMemory)?;
+    let identity_mapped = map_trampoline(frame)?;
+    let bsp = cpu::apic_id();
+    let result = install_trampoline(frame).and_then(|data| {
+        let page = (frame.start_address().as_u64() >> 12) as u8;
+        madt.processors()
+            .filter(|processor| processor.apic_id != bsp)
+            .try_for_each(|processor| start(processor.apic_id, page, data))
+    });
+    if identity_mapped {
+        unmap_trampoline(frame);
+    }
+    result.map(|()| cpu::count())
+}
+
+// INIT, 10ms, startup IPI, 200us, another startup IPI unless the first one worked
+fn start(apic_id: u8, page: u8, data: &mut TrampolineData) -> Result<(), SmpError> {
+    let cpu = match cpu::add(apic_id) {
+        Some(cpu) => cpu,
+        None => return Ok(()), // MAX_CPUS are online
+    };
+    let stack = match KernelStack::new(STACK_NAMES[cpu], MAX_STACK_PAGES) {
+        Ok(stack) => stack,
+        Err(error) => {
+            cpu::remove(cpu);
+            return Err(error.into());
+        }
+    };
+    data.stack = stack.top().as_u64();
+    data.cpu = cpu as u64;
+    STATE.store(WAITING, Ordering::SeqCst);
+
+    apic::send_init(apic_id);
+    apic::delay_micros(10_000);
+    apic::send_startup(apic_id, page);
+    apic::delay_micros(200);
+    if STATE.load(Ordering::SeqCst) == WAITING {
+        apic::send_startup(apic_id, page);
+    }
+    for _ in 0..100 {
+        if STATE.load(Ordering::SeqCst) != WAITING {
+            break;
+        }
+        apic::delay_micros(1000);
+    }
+    let abandoned = STATE
+        .compare_exchange(WAITING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok();
+    if abandoned {
+        // it may still be in the trampoline, which gets rewritten for the next processor or
+        // unmapped. INIT parks it in wait-for-SIPI before its number is handed out again
+        apic::send_init(apic_id);
+        serial_println!("cpu {} (APIC id {}) didn't start", cpu, apic_id);
+        cpu::remove(cpu);
+    } else {
+        // it's in "ap_main" and owns the number now, wait until it's set up
+        while STATE.load(Ordering::SeqCst) != ONLINE {
+            core::hint::spin_loop();
+        }
+    }
+    // the processor runs on it, or might have until the INIT
+    stack.leak();
+    Ok(())
+}
+
+// first Rust code on an application processor, "cpu" is its number (cpu::add)
+extern "C" fn ap_main(cpu: usize) -> ! {
+    if STATE
+        .compare_exchange(WAITING, ENTERED, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        // too late, "start" gave up on this processor and sends it an INIT
+        crate::hlt_loop();
+    }
+    cpu::init_ap(cpu);
+    interrupts::init_idt();
+    gdt::init_cpu(cpu).expect("mapping the exception stacks failed");
+    apic::init_local_apic();
+    memory::shootdown::cpu_ready(cpu);
+    STATE.store(ONLINE, Ordering::SeqCst);
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run()
+}
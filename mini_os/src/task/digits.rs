@@ -0,0 +1,48 @@
+use alloc::vec::Vec;
+use rust_cnn::inference::Model;
+
+use crate::println;
+
+// digit classifier trained with rust_cnn (see rust_cnn/examples/digit_blob.rs) and embedded into
+// the kernel image. rust_cnn is built without "std" here, only its forward passes are used...
+pub static MODEL_BLOB: &[u8] = include_bytes!("../../assets/digits.rcnn");
+
+// 12x12 built-in sample, '#' pixels are 1.0 and everything else 0.0
+#[rustfmt::skip]
+pub const SAMPLE: [&str; 12] = [
+    "............",
+    "............",
+    "...#####....",
+    ".......#....",
+    "......#.....",
+    ".....#......",
+    "....#.....#.",
+    "....#.......",
+    "....#.......",
+    "............",
+    "............",
+    "............",
+];
+pub const SAMPLE_LABEL: usize = 7;
+
+pub fn sample_pixels() -> Vec<f32> {
+    SAMPLE
+        .iter()
+        .flat_map(|row| row.bytes().map(|p| if p == b'#' { 1.0 } else { 0.0 }))
+        .collect()
+}
+
+// predicted digit of the built-in sample. the model is parsed from the blob on every call (it's
+// small), so no global state is needed
+pub fn classify_sample() -> usize {
+    let model = Model::from_bytes(MODEL_BLOB).expect("embedded model blob is invalid");
+    model.predict(&sample_pixels())
+}
+
+// demo task for the executor
+pub async fn classify() {
+    for row in SAMPLE.iter() {
+        println!("{}", row);
+    }
+    println!("digit classifier: the sample is a {}", classify_sample());
+}
@@ -1,7 +1,12 @@
 #![allow(clippy::new_ret_no_self)]
 // to silence "method named 'new' generally returns Self"
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use crate::cpu::{self, PerCpu, MAX_CPUS};
+use crate::interrupts::apic;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 use x86_64::instructions::interrupts::{self, enable_and_hlt};
@@ -13,7 +18,9 @@ pub struct Executor {
 }
 
 impl Executor {
+    // one per CPU: its own tasks plus the shared ones
     pub fn new() -> Self {
+        shared_queue();
         Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
@@ -60,6 +67,7 @@ impl Executor {
     pub fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
+            run_shared_tasks();
             self.sleep_if_idle();
         }
     }
@@ -69,11 +77,16 @@ impl Executor {
         // x86_64::instructions::hlt();
         // }
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        // marked idle before looking at the queues: a shared task queued after the look sends
+        // the wakeup interrupt, which ends the "hlt"
+        let idle = 1 << cpu::id().unwrap_or(0);
+        IDLE.fetch_or(idle, Ordering::SeqCst);
+        if self.task_queue.is_empty() && shared_queue().is_empty() {
             enable_and_hlt();
         } else {
             interrupts::enable();
         }
+        IDLE.fetch_and(!idle, Ordering::SeqCst);
     }
 }
 impl Default for Executor {
@@ -107,3 +120,100 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+// SHARED TASKS
+// tasks every CPU's executor runs, whichever gets to them first, so their futures have to be Send.
+// A task is in the queue at most once ("queued"); woken while a CPU polls it, it goes back into
+// the queue and the next CPU waits for the poll to finish
+struct SharedTask {
+    queued: AtomicBool,
+    future: spin::Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+}
+
+const SHARED_QUEUE_SIZE: usize = 100;
+static SHARED_QUEUE: spin::Once<ArrayQueue<Arc<SharedTask>>> = spin::Once::new();
+// bit n: CPU n is halted in "sleep_if_idle"
+static IDLE: AtomicU64 = AtomicU64::new(0);
+// shared task polls by CPU
+static SHARED_POLLS: PerCpu<AtomicU64> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+});
+
+fn shared_queue() -> &'static ArrayQueue<Arc<SharedTask>> {
+    SHARED_QUEUE.call_once(|| ArrayQueue::new(SHARED_QUEUE_SIZE))
+}
+
+// runs "future" on any CPU
+pub fn spawn_shared(future: impl Future<Output = ()> + Send + 'static) {
+    schedule(Arc::new(SharedTask {
+        queued: AtomicBool::new(false),
+        future: spin::Mutex::new(Some(Box::pin(future))),
+    }));
+}
+
+// polls of shared tasks on CPU "cpu"
+pub fn shared_polls(cpu: usize) -> u64 {
+    SHARED_POLLS.of(cpu).load(Ordering::Relaxed)
+}
+
+fn schedule(task: Arc<SharedTask>) {
+    if task.queued.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if shared_queue().push(task).is_err() {
+        panic!("shared task queue full");
+    }
+    wake_idle_cpu();
+}
+
+// a halted CPU other than the running one gets the wakeup interrupt. Its idle bit is cleared here,
+// so the next task of a burst goes to another CPU
+fn wake_idle_cpu() {
+    let running = cpu::id().unwrap_or(0);
+    loop {
+        let idle = IDLE.load(Ordering::SeqCst) & !(1 << running);
+        if idle == 0 {
+            return;
+        }
+        let cpu = idle.trailing_zeros() as usize;
+        if IDLE.fetch_and(!(1 << cpu), Ordering::SeqCst) & 1 << cpu != 0 {
+            if let Some(apic_id) = cpu::apic_id_of(cpu) {
+                apic::send_ipi(apic_id, apic::WAKEUP_VECTOR);
+            }
+            return;
+        }
+    }
+}
+
+// polls the shared tasks that were queued when it started (a task that keeps waking itself can't
+// hold the CPU forever)
+fn run_shared_tasks() {
+    let queue = shared_queue();
+    for _ in 0..queue.len() {
+        let task = match queue.pop() {
+            Ok(task) => task,
+            Err(_) => break,
+        };
+        task.queued.store(false, Ordering::SeqCst);
+        let waker = Waker::from(task.clone());
+        let mut context = Context::from_waker(&waker);
+        let mut future = task.future.lock();
+        if let Some(running) = future.as_mut() {
+            if running.as_mut().poll(&mut context).is_ready() {
+                *future = None; // done, later wakeups find nothing to poll
+            }
+        }
+        SHARED_POLLS.get().fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Wake for SharedTask {
+    fn wake(self: Arc<Self>) {
+        schedule(self);
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        schedule(self.clone());
+    }
+}
@@ -78,19 +78,33 @@ static WAKER: AtomicWaker = AtomicWaker::new();
 // concurrently
 
 // KEYBOARD TASK
+use super::shell;
 use crate::print;
+use alloc::string::String;
 use futures_util::stream::StreamExt;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
 pub async fn key_presses() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut line = String::new(); // typed so far, run as a shell command on enter
 
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
+                    DecodedKey::Unicode('\n') => {
+                        print!("\n");
+                        shell::execute(&line);
+                        line.clear();
+                    }
+                    DecodedKey::Unicode('\u{8}') => {
+                        line.pop(); // backspace (the screen can't erase yet)
+                    }
+                    DecodedKey::Unicode(character) => {
+                        line.push(character);
+                        print!("{}", character)
+                    }
                     DecodedKey::RawKey(key) => print!("{:?}", key),
                 }
             }
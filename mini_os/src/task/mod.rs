@@ -1,5 +1,8 @@
+pub mod digits;
 pub mod executor;
 pub mod keyboard;
+pub mod serial;
+pub mod shell;
 pub mod simple_executor;
 use alloc::boxed::Box;
 
@@ -0,0 +1,64 @@
+// commands over COM1: the same shell the keyboard has, for a host that is only connected through
+// the serial port (e.g. "-serial stdio" in QEMU). Lines end with CR or LF
+use super::shell;
+use crate::serial;
+use alloc::string::String;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::stream::{Stream, StreamExt};
+
+// bytes received on COM1, the serial interrupt handler wakes the task that waits for them
+pub struct SerialStream {
+    _private: (),
+}
+impl SerialStream {
+    pub fn new() -> Self {
+        SerialStream { _private: () }
+    }
+}
+impl Default for SerialStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u8>> {
+        if let Some(byte) = serial::read_byte() {
+            return Poll::Ready(Some(byte));
+        }
+        serial::register_input_waker(cx.waker());
+        match serial::read_byte() {
+            Some(byte) => Poll::Ready(Some(byte)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+// adds a received byte to "line" and runs the line once it's complete
+pub fn add_byte(line: &mut String, byte: u8) {
+    match byte {
+        b'\r' | b'\n' => {
+            shell::execute(line);
+            line.clear();
+        }
+        0x08 | 0x7f => {
+            line.pop(); // backspace or delete
+        }
+        byte if byte.is_ascii() && !byte.is_ascii_control() => line.push(byte as char),
+        _ => {}
+    }
+}
+
+// SERIAL TASK
+pub async fn commands() {
+    let mut bytes = SerialStream::new();
+    let mut line = String::new();
+    while let Some(byte) = bytes.next().await {
+        add_byte(&mut line, byte);
+    }
+}
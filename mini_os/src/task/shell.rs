@@ -0,0 +1,28 @@
+// a few debug commands, typed on the keyboard or sent over COM1 and run by the keyboard or serial
+// task at the end of the line
+use crate::{allocator, memory, println};
+
+pub fn execute(line: &str) {
+    match line.trim() {
+        "" => {}
+        "help" => println!(
+            "commands: help, heap (allocator statistics), allocs (live allocations to serial), \
+             mem (physical memory)"
+        ),
+        "heap" => println!("{}", allocator::stats()),
+        "allocs" => {
+            allocator::dump_live_allocations();
+            println!("live allocations written to serial");
+        }
+        "mem" => match memory::frame_stats() {
+            Some(stats) => println!(
+                "physical memory: {} KiB used, {} KiB free of {} KiB",
+                stats.used / 1024,
+                stats.free() / 1024,
+                stats.total / 1024
+            ),
+            None => println!("memory manager is not initialized"),
+        },
+        command => println!("unknown command: {} (try help)", command),
+    }
+}
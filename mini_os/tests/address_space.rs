@@ -0,0 +1,164 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+use mini_os::memory::address_space::{AddressSpace, Backing, MMAP_END, MMAP_START};
+use mini_os::memory::{self, PagingError};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTableFlags as Flags, Translate};
+use x86_64::VirtAddr;
+
+const PAGE: u64 = 4096;
+const FIXED: VirtAddr = VirtAddr::new_truncate(0x0000_3000_0000_0000);
+
+fn rw() -> Flags {
+    Flags::WRITABLE
+}
+
+#[test_case]
+fn mapping_is_private_to_the_address_space() {
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map(FIXED, 2 * PAGE, rw(), Backing::Anonymous)
+        .unwrap();
+    assert!(space.translate(FIXED).is_some());
+    let in_kernel = memory::with_memory(|m| m.mapper.translate_addr(FIXED)).unwrap();
+    assert_eq!(in_kernel, None);
+
+    let (kernel_table, flags) = Cr3::read();
+    unsafe {
+        space.activate();
+        // kernel code, stack and heap are shared, only the new region is different
+        let ptr: *mut u64 = (FIXED + PAGE).as_mut_ptr();
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+        Cr3::write(kernel_table, flags);
+    }
+}
+
+#[test_case]
+fn mmap_picks_free_ranges() {
+    let mut space = AddressSpace::new().unwrap();
+    let a = space.mmap(3 * PAGE, rw(), Backing::Anonymous).unwrap();
+    let b = space
+        .mmap(PAGE, Flags::empty(), Backing::Anonymous)
+        .unwrap();
+    assert!(a.as_u64() >= MMAP_START && b.as_u64() < MMAP_END);
+    assert!(a + 3 * PAGE <= b || b + PAGE <= a);
+    assert_eq!(space.regions().len(), 2);
+}
+
+#[test_case]
+fn munmap_splits_regions() {
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map(FIXED, 4 * PAGE, rw(), Backing::Anonymous)
+        .unwrap();
+    space.munmap(FIXED + PAGE, 2 * PAGE).unwrap();
+
+    let regions = space.regions();
+    assert_eq!(regions.len(), 2);
+    assert_eq!((regions[0].start, regions[0].len), (FIXED, PAGE));
+    assert_eq!((regions[1].start, regions[1].len), (FIXED + 3 * PAGE, PAGE));
+    assert!(space.translate(FIXED).is_some());
+    assert!(space.translate(FIXED + PAGE).is_none());
+    assert!(space.translate(FIXED + 2 * PAGE).is_none());
+    assert!(space.translate(FIXED + 3 * PAGE).is_some());
+    assert_eq!(
+        space.munmap(FIXED + PAGE, PAGE),
+        Err(PagingError::NotMapped)
+    );
+}
+
+#[test_case]
+fn protect_changes_flags() {
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map(FIXED, 3 * PAGE, rw(), Backing::Anonymous)
+        .unwrap();
+    space.protect(FIXED + PAGE, PAGE, Flags::empty()).unwrap();
+
+    let (_, flags) = space.translate(FIXED + PAGE).unwrap();
+    assert!(!flags.contains(Flags::WRITABLE));
+    let (_, flags) = space.translate(FIXED + 2 * PAGE).unwrap();
+    assert!(flags.contains(Flags::WRITABLE));
+    assert_eq!(space.regions().len(), 3);
+    let result = space.protect(FIXED + 2 * PAGE, 2 * PAGE, rw());
+    assert_eq!(result, Err(PagingError::NotMapped));
+}
+
+#[test_case]
+fn physical_backing_maps_the_given_frames() {
+    use x86_64::PhysAddr;
+
+    let mut space = AddressSpace::new().unwrap();
+    let vga = PhysAddr::new(0xb8000);
+    space
+        .map(FIXED, 2 * PAGE, rw(), Backing::Physical(vga))
+        .unwrap();
+    assert_eq!(
+        space.translate(FIXED + PAGE + 8u64).unwrap().0,
+        vga + PAGE + 8u64
+    );
+}
+
+#[test_case]
+fn invalid_ranges_are_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let unaligned = space.map(FIXED + 1u64, PAGE, rw(), Backing::Anonymous);
+    assert_eq!(unaligned, Err(PagingError::Unaligned));
+    // the heap lives in a level 4 entry shared with the kernel
+    let heap = VirtAddr::new(mini_os::allocator::HEAP_START as u64);
+    let kernel = space.map(heap, PAGE, rw(), Backing::Anonymous);
+    assert_eq!(kernel, Err(PagingError::KernelSpace));
+    space
+        .map(FIXED, 2 * PAGE, rw(), Backing::Anonymous)
+        .unwrap();
+    let overlap = space.map(FIXED + PAGE, 2 * PAGE, rw(), Backing::Anonymous);
+    assert_eq!(overlap, Err(PagingError::OverlappingRegion));
+}
+
+#[test_case]
+fn dropping_frees_all_frames() {
+    let populate = || {
+        let mut space = AddressSpace::new().unwrap();
+        space
+            .map(FIXED, 16 * PAGE, rw(), Backing::Anonymous)
+            .unwrap();
+        space.mmap(8 * PAGE, rw(), Backing::Anonymous).unwrap();
+        space
+    };
+    drop(populate()); // warm up: heap pages touched by the region list stay mapped
+    let before = memory::frame_stats().unwrap();
+    let space = populate();
+    assert!(memory::frame_stats().unwrap().used > before.used);
+    drop(space);
+    assert_eq!(memory::frame_stats().unwrap().used, before.used);
+}
@@ -0,0 +1,197 @@
+// compares the fixed size block allocator behind its lock with the same allocator behind per-CPU
+// magazines: cycles and lock acquisitions per alloc/dealloc pair, and how many acquisitions had to
+// wait (contention). First on the bootstrap processor alone, then on all the others at once (QEMU
+// runs with "-smp 4"). The numbers are printed over serial.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use mini_os::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use mini_os::allocator::magazine::Magazines;
+use mini_os::allocator::{LockStats, Locked};
+use mini_os::task::executor;
+use mini_os::{cpu, interrupts, serial_println, smp};
+
+entry_point!(main);
+
+// two heaps of their own (demand paged), one per variant
+const LOCKED_START: u64 = 0x_5555_0000_0000;
+const CACHED_START: u64 = 0x_5555_1000_0000;
+const POOL_SIZE: usize = 256 * 1024;
+static LOCKED: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static CACHED: Magazines<Locked<FixedSizeBlockAllocator>> =
+    Magazines::new(Locked::new(FixedSizeBlockAllocator::new()));
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    mini_os::interrupts::apic::init().expect("switching to the APICs failed");
+    allocator::init_heap().expect("heap initialization failed");
+    for &start in [LOCKED_START, CACHED_START].iter() {
+        memory::register_lazy_region(VirtAddr::new(start), POOL_SIZE as u64, Flags::WRITABLE)
+            .expect("pool registration failed");
+    }
+    unsafe {
+        LOCKED.lock().init(LOCKED_START as *mut u8, POOL_SIZE);
+        CACHED
+            .inner()
+            .lock()
+            .init(CACHED_START as *mut u8, POOL_SIZE);
+    }
+    smp::start_application_processors().expect("starting the other CPUs failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+const ROUNDS: usize = 200;
+const BATCH: usize = 12; // live at once, fits in a magazine
+const SIZES: [usize; 3] = [16, 64, 256];
+
+struct Result {
+    cycles_per_pair: u64,
+    lock: LockStats,
+}
+
+// ROUNDS times: allocate BATCH blocks of every size, then free them again
+fn run(heap: &impl GlobalAlloc, lock: &dyn Fn() -> LockStats) -> Result {
+    let mut blocks = [core::ptr::null_mut(); BATCH];
+    let before = lock();
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    for _ in 0..ROUNDS {
+        for &size in SIZES.iter() {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            for block in blocks.iter_mut() {
+                *block = unsafe { heap.alloc(layout) };
+                assert!(!block.is_null());
+            }
+            for &block in blocks.iter() {
+                unsafe { heap.dealloc(block, layout) };
+            }
+        }
+    }
+    let cycles = unsafe { core::arch::x86_64::_rdtsc() } - start;
+    let after = lock();
+    let pairs = (ROUNDS * SIZES.len() * BATCH) as u64;
+    Result {
+        cycles_per_pair: cycles / pairs,
+        lock: LockStats {
+            acquisitions: after.acquisitions - before.acquisitions,
+            contended: after.contended - before.contended,
+        },
+    }
+}
+
+#[test_case]
+fn magazines_take_the_lock_less_often() {
+    let pairs = (ROUNDS * SIZES.len() * BATCH) as u64;
+    let locked = run(&LOCKED, &|| LOCKED.lock_stats());
+    let cached = run(&CACHED, &|| CACHED.inner().lock_stats());
+    serial_println!(
+        "\n  locked:    {} cycles per alloc+dealloc, {} lock acquisitions ({} contended)",
+        locked.cycles_per_pair,
+        locked.lock.acquisitions,
+        locked.lock.contended
+    );
+    serial_println!(
+        "  magazines: {} cycles per alloc+dealloc, {} lock acquisitions ({} contended), {:?}",
+        cached.cycles_per_pair,
+        cached.lock.acquisitions,
+        cached.lock.contended,
+        CACHED.stats()
+    );
+    // every alloc and dealloc locks without magazines
+    assert_eq!(locked.lock.acquisitions, 2 * pairs);
+    // with them only the first batch of each size does
+    assert!(cached.lock.acquisitions <= (SIZES.len() * BATCH) as u64);
+}
+
+// the same rounds on every application processor at once, each one's executor runs one task
+static CYCLES: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static DONE: AtomicU64 = AtomicU64::new(0);
+
+async fn run_both() {
+    let locked = run(&LOCKED, &|| LOCKED.lock_stats());
+    let cached = run(&CACHED, &|| CACHED.inner().lock_stats());
+    CYCLES[0].fetch_add(locked.cycles_per_pair, Ordering::SeqCst);
+    CYCLES[1].fetch_add(cached.cycles_per_pair, Ordering::SeqCst);
+    DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn magazines_on_every_cpu() {
+    let others = cpu::count() as u64 - 1;
+    assert!(others > 0, "QEMU has to run with more than one CPU");
+    let before = [LOCKED.lock_stats(), CACHED.inner().lock_stats()];
+    for _ in 0..others {
+        executor::spawn_shared(run_both());
+    }
+    let start = interrupts::ticks();
+    while DONE.load(Ordering::SeqCst) < others {
+        assert!(
+            interrupts::ticks() - start < 200,
+            "benchmark tasks didn't finish"
+        );
+        x86_64::instructions::hlt();
+    }
+    let after = [LOCKED.lock_stats(), CACHED.inner().lock_stats()];
+    let [locked, cached] = [0, 1].map(|i| LockStats {
+        acquisitions: after[i].acquisitions - before[i].acquisitions,
+        contended: after[i].contended - before[i].contended,
+    });
+    serial_println!(
+        "\n  {} CPUs, locked:    {} cycles per alloc+dealloc, {} lock acquisitions ({} contended)",
+        others,
+        CYCLES[0].load(Ordering::SeqCst) / others,
+        locked.acquisitions,
+        locked.contended
+    );
+    serial_println!(
+        "  {} CPUs, magazines: {} cycles per alloc+dealloc, {} lock acquisitions ({} contended)",
+        others,
+        CYCLES[1].load(Ordering::SeqCst) / others,
+        cached.acquisitions,
+        cached.contended
+    );
+    let pairs = (ROUNDS * SIZES.len() * BATCH) as u64;
+    assert_eq!(locked.acquisitions, 2 * pairs * others);
+    // at most the first batch of each size, once per CPU
+    assert!(cached.acquisitions <= (SIZES.len() * BATCH) as u64 * others);
+}
+
+#[test_case]
+fn lock_disables_interrupts() {
+    use x86_64::instructions::interrupts;
+
+    assert!(interrupts::are_enabled());
+    {
+        let _guard = LOCKED.lock();
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    // nested in a section without interrupts they stay off
+    interrupts::without_interrupts(|| {
+        drop(LOCKED.lock());
+        assert!(!interrupts::are_enabled());
+    });
+}
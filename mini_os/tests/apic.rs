@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::interrupts::{self, apic};
+use mini_os::{acpi, cpu, serial, serial_print};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::{allocator, memory};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    apic::init().expect("switching to the APICs failed");
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_the_boot_processor() {
+    let madt = acpi::madt().expect("no MADT");
+    assert!(madt
+        .processors()
+        .any(|processor| processor.apic_id == cpu::apic_id()));
+    assert!(madt.io_apics().count() >= 1);
+    // QEMU wires the PIT (ISA IRQ 0) to input 2
+    assert_eq!(madt.isa_irq(0).0, 2);
+}
+
+#[test_case]
+fn apics_replace_the_pics() {
+    assert!(apic::is_active());
+    assert_eq!(apic::id(), Some(cpu::apic_id()));
+    assert_eq!(apic::pic_masks(), [0xff, 0xff]);
+}
+
+#[test_case]
+fn keyboard_and_serial_go_through_the_io_apic() {
+    let bsp = cpu::apic_id();
+    assert_eq!(apic::isa_route(apic::KEYBOARD_IRQ), Some((33, bsp)));
+    assert_eq!(apic::isa_route(apic::SERIAL_IRQ), Some((36, bsp)));
+    // the APIC timer replaces the PIT
+    assert_eq!(apic::isa_route(0), None);
+}
+
+#[test_case]
+fn timer_ticks() {
+    assert!(apic::timer_count().is_some());
+    // every tick needs the EOI of the previous one
+    let start = interrupts::ticks();
+    while interrupts::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn timer_keeps_the_pit_period() {
+    use x86_64::instructions::port::Port;
+
+    // PIT channel 2 counts down from 0xffff during one tick (mode 0: its output goes high at 0,
+    // the count wraps around and goes on). A tick of the PIT was 65536 counts
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    let start = interrupts::ticks();
+    while interrupts::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    let (remaining, wrapped) = unsafe {
+        let saved = control.read();
+        control.write((saved & !0b10) | 0b01);
+        command.write(0b1011_0000);
+        channel_2.write(0xff);
+        channel_2.write(0xff);
+        while interrupts::ticks() == start + 1 {
+            x86_64::instructions::hlt();
+        }
+        // latch the count, then read it
+        command.write(0b1000_0000);
+        let low = channel_2.read();
+        let high = channel_2.read();
+        let wrapped = control.read() & 0x20 != 0;
+        control.write(saved);
+        (u64::from(low) | u64::from(high) << 8, wrapped)
+    };
+    let elapsed = 0xffff - remaining + if wrapped { 0x1_0000 } else { 0 };
+    // 10% off is fine for an emulated clock
+    assert!(
+        elapsed > 65536 * 9 / 10 && elapsed < 65536 * 11 / 10,
+        "one tick took {} PIT counts",
+        elapsed
+    );
+}
+
+// COM1 in loopback mode: what it sends comes back in as received data, with its IRQ. Nothing
+// reaches the host meanwhile, so switch it off before asserting
+fn serial_loopback(on: bool) {
+    use x86_64::instructions::port::Port;
+
+    // DTR, RTS and OUT2 (the interrupt line) as "init" of uart_16550 sets them, plus loopback
+    let modem_control = if on { 0x1b } else { 0x0b };
+    unsafe { Port::<u8>::new(0x3fc).write(modem_control) };
+}
+
+// bytes read from the serial input buffer within "ticks" timer ticks, at most "buffer.len()"
+fn read_serial(buffer: &mut [u8], ticks: u64) -> usize {
+    let start = interrupts::ticks();
+    let mut read = 0;
+    while read < buffer.len() && interrupts::ticks() < start + ticks {
+        match serial::read_byte() {
+            Some(byte) => {
+                buffer[read] = byte;
+                read += 1;
+            }
+            None => x86_64::instructions::hlt(),
+        }
+    }
+    read
+}
+
+#[test_case]
+fn serial_input_arrives_through_the_io_apic() {
+    while serial::read_byte().is_some() {}
+    serial_loopback(true);
+    serial_print!("ping");
+    let mut received = [0; 8];
+    let read = read_serial(&mut received, 10);
+    serial_loopback(false);
+    assert_eq!(&received[..read], b"ping");
+}
+
+#[test_case]
+fn allocs_sent_over_serial_dumps_the_live_allocations() {
+    use alloc::string::String;
+    use mini_os::task::serial::add_byte;
+
+    while serial::read_byte().is_some() {}
+    serial_loopback(true);
+    serial_print!("allocs\r");
+    let mut command = [0; 7];
+    let read = read_serial(&mut command, 10);
+    // the serial task's line handling runs the command, its dump comes back in through the
+    // loopback. The UART's FIFO only keeps the start of it while the dump has interrupts disabled
+    let mut line = String::new();
+    for &byte in &command[..read] {
+        add_byte(&mut line, byte);
+    }
+    let mut dump = [0; 12];
+    let dumped = read_serial(&mut dump, 10);
+    serial_loopback(false);
+    assert_eq!(&command[..read], b"allocs\r");
+    assert!(line.is_empty());
+    assert_eq!(&dump[..dumped], b"heap debug: ");
+}
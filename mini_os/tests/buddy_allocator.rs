@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::allocator::buddy::{self, BuddyAllocator, MAX_ORDER, PAGE_SIZE};
+use mini_os::allocator::{KernelHeap, Locked};
+
+entry_point!(main);
+
+// a pool of its own (demand paged like the heap), aligned to the biggest block
+const POOL_START: u64 = 0x_5555_0000_0000;
+const POOL_SIZE: usize = 4 * 1024 * 1024;
+static POOL: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    let start = VirtAddr::new(POOL_START);
+    memory::register_lazy_region(start, POOL_SIZE as u64, Flags::WRITABLE)
+        .expect("pool registration failed");
+    unsafe { POOL.lock().init(POOL_START as usize, POOL_SIZE) };
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn pool_starts_as_one_block() {
+    let pool = POOL.lock();
+    assert_eq!(pool.heap_size(), POOL_SIZE);
+    assert_eq!(pool.free_bytes(), POOL_SIZE);
+    assert_eq!(pool.largest_free_block(), buddy::block_size(MAX_ORDER));
+    assert_eq!(pool.free_blocks(MAX_ORDER), 1);
+}
+
+#[test_case]
+fn blocks_are_aligned_to_their_size() {
+    let mut pool = POOL.lock();
+    for order in 0..=3 {
+        let block = pool.allocate(order).expect("allocation failed");
+        assert_eq!(block.as_ptr() as usize % buddy::block_size(order), 0);
+        // the memory is usable
+        unsafe { block.as_ptr().write_bytes(0xab, buddy::block_size(order)) };
+        unsafe { pool.deallocate(block, order) };
+    }
+    assert_eq!(pool.free_bytes(), POOL_SIZE);
+}
+
+#[test_case]
+fn split_and_merge() {
+    let mut pool = POOL.lock();
+    let first = pool.allocate(0).unwrap();
+    let second = pool.allocate(0).unwrap();
+    // the second page is the buddy of the first
+    assert_eq!(
+        first.as_ptr() as usize ^ PAGE_SIZE,
+        second.as_ptr() as usize
+    );
+    // splitting left one free block of every order in between
+    for order in 1..MAX_ORDER {
+        assert_eq!(pool.free_blocks(order), 1);
+    }
+    assert!(pool.allocate(MAX_ORDER).is_none());
+    assert_eq!(pool.free_bytes(), POOL_SIZE - 2 * PAGE_SIZE);
+
+    unsafe {
+        pool.deallocate(first, 0);
+        pool.deallocate(second, 0);
+    }
+    // everything merged back
+    assert_eq!(pool.free_blocks(MAX_ORDER), 1);
+    assert_eq!(pool.largest_free_block(), POOL_SIZE);
+}
+
+#[test_case]
+fn exhaust_and_reuse() {
+    let mut pool = POOL.lock();
+    let mut count = 0;
+    let mut last = None;
+    while let Some(block) = pool.allocate(6) {
+        count += 1;
+        last = Some(block);
+    }
+    assert_eq!(count, POOL_SIZE / buddy::block_size(6));
+    assert_eq!(pool.free_bytes(), 0);
+    assert!(pool.allocate(0).is_none());
+
+    // a freed block is handed out again
+    let last = last.unwrap();
+    unsafe { pool.deallocate(last, 6) };
+    assert_eq!(pool.allocate(6), Some(last));
+
+    // give everything back, the blocks are consecutive
+    for index in 0..count {
+        let addr = POOL_START as usize + index * buddy::block_size(6);
+        unsafe { pool.deallocate(core::ptr::NonNull::new(addr as *mut u8).unwrap(), 6) };
+    }
+    assert_eq!(pool.free_bytes(), POOL_SIZE);
+    assert_eq!(pool.free_blocks(MAX_ORDER), 1);
+}
+
+#[test_case]
+fn global_alloc_rounds_up_to_pages() {
+    use alloc::alloc::{GlobalAlloc, Layout};
+
+    let layout = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+    let ptr = unsafe { POOL.alloc(layout) };
+    assert!(!ptr.is_null());
+    // 4 pages taken
+    assert_eq!(POOL.lock().free_bytes(), POOL_SIZE - 4 * PAGE_SIZE);
+    unsafe { POOL.dealloc(ptr, layout) };
+    assert_eq!(POOL.lock().free_bytes(), POOL_SIZE);
+
+    let too_big = Layout::from_size_align(2 * POOL_SIZE, 8).unwrap();
+    assert!(unsafe { POOL.alloc(too_big) }.is_null());
+}
@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    memory::register_lazy_region(LAZY_START, LAZY_SIZE, PageTableFlags::WRITABLE)
+        .expect("registering the test region failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use mini_os::allocator::{HEAP_SIZE, HEAP_START};
+use mini_os::memory::{self, PagingError};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+const LAZY_START: VirtAddr = VirtAddr::new_truncate(0x_5555_0000_0000);
+const LAZY_SIZE: u64 = 16 * 4096;
+
+fn is_mapped(address: VirtAddr) -> bool {
+    memory::with_memory(|memory| memory.mapper.translate_addr(address).is_some())
+        .expect("memory manager not initialized")
+}
+
+#[test_case]
+fn lazy_pages_are_mapped_on_first_touch() {
+    let page_3 = LAZY_START + 3 * 4096u64;
+    assert!(!is_mapped(page_3));
+    let ptr: *mut u64 = page_3.as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0); // fresh pages are zeroed
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(is_mapped(page_3));
+    // only the touched page got a frame
+    assert!(!is_mapped(page_3 + 4096u64));
+    assert!(!is_mapped(page_3 - 4096u64));
+}
+
+#[test_case]
+fn lazy_pages_keep_their_contents() {
+    let ptr: *mut u8 = (LAZY_START + 8 * 4096u64).as_mut_ptr();
+    for i in 0..3 * 4096 {
+        unsafe { ptr.add(i).write_volatile(i as u8) };
+    }
+    for i in 0..3 * 4096 {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, i as u8);
+    }
+}
+
+#[test_case]
+fn heap_grows_on_demand() {
+    let last_page = VirtAddr::new(HEAP_START as u64 + HEAP_SIZE as u64 - 1);
+    assert!(!is_mapped(last_page));
+    // the fixed size block allocator falls back to the linked list heap for big blocks
+    let mut big: Vec<u8> = Vec::with_capacity(HEAP_SIZE / 2);
+    big.resize(HEAP_SIZE / 2, 1);
+    assert!(big.iter().all(|&b| b == 1));
+}
+
+#[test_case]
+fn faults_outside_lazy_regions_are_rejected() {
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    let outside = LAZY_START + LAZY_SIZE;
+    let result = memory::handle_page_fault(outside, PageFaultErrorCode::empty());
+    assert_eq!(result, Err(PagingError::NotLazy));
+    let present = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    assert_eq!(
+        memory::handle_page_fault(LAZY_START, present),
+        Err(PagingError::AlreadyPresent)
+    );
+}
+
+#[test_case]
+fn overlapping_regions_are_rejected() {
+    let result = memory::register_lazy_region(LAZY_START + 4096u64, 4096, PageTableFlags::WRITABLE);
+    assert_eq!(result, Err(PagingError::OverlappingRegion));
+}
+
+#[test_case]
+fn empty_and_overflowing_regions_are_rejected() {
+    let flags = PageTableFlags::WRITABLE;
+    let free = VirtAddr::new(0x_6666_0000_0000);
+    assert_eq!(
+        memory::register_lazy_region(free, 0, flags),
+        Err(PagingError::Unaligned)
+    );
+    // the end would be past the lower half's canonical addresses
+    assert_eq!(
+        memory::register_lazy_region(free, 0x_4000_0000_0000, flags),
+        Err(PagingError::OutOfAddressSpace)
+    );
+    assert_eq!(
+        memory::register_lazy_region(VirtAddr::new(u64::MAX - 4095), 8192, flags),
+        Err(PagingError::OutOfAddressSpace)
+    );
+}
+
+static MAPPED: u64 = 0; // part of the kernel image, always mapped
+
+#[test_case]
+fn growth_stops_at_stacks_and_mappings() {
+    use mini_os::memory::stack::STACKS_START;
+
+    let flags = PageTableFlags::WRITABLE;
+    let below_stacks = VirtAddr::new(STACKS_START - 2 * 4096);
+    memory::register_lazy_region(below_stacks, 4096, flags).unwrap();
+    memory::grow_lazy_region(below_stacks, 4096).unwrap();
+    // the first stack slot (its guard page) comes next
+    assert_eq!(
+        memory::grow_lazy_region(below_stacks, 4096),
+        Err(PagingError::OverlappingRegion)
+    );
+
+    let kernel_page = VirtAddr::from_ptr(&MAPPED).align_down(4096u64);
+    let below_kernel = kernel_page - 0x_10_0000u64;
+    memory::register_lazy_region(below_kernel, 4096, flags).unwrap();
+    assert_eq!(
+        memory::grow_lazy_region(below_kernel, 0x_10_0000),
+        Err(PagingError::OverlappingRegion)
+    );
+}
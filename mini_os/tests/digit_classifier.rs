@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+use mini_os::task::digits::{self, MODEL_BLOB, SAMPLE_LABEL};
+use rust_cnn::inference::Model;
+
+#[test_case]
+fn embedded_model_loads() {
+    let model = Model::from_bytes(MODEL_BLOB).expect("blob should parse");
+    assert_eq!(model.input_shape(), &[1, 12, 12]);
+    let output = model.forward(&digits::sample_pixels());
+    assert_eq!(output.len(), 10);
+    let total: f32 = output.iter().sum(); // softmax output
+    assert!((total - 1.0).abs() < 1e-4);
+}
+
+#[test_case]
+fn classifies_builtin_sample() {
+    assert_eq!(digits::classify_sample(), SAMPLE_LABEL);
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use mini_os::memory::address_space::AddressSpace;
+use mini_os::memory::PagingError;
+use mini_os::process::elf::{Elf, ElfError};
+use mini_os::process::{ExitStatus, Process};
+use x86_64::structures::paging::PageTableFlags as Flags;
+use x86_64::VirtAddr;
+
+// built from assets/user/hello.s: .text at 0x1000_0000_0000, .data + 8KiB .bss on the next page
+static HELLO: &[u8] = include_bytes!("../assets/user/hello.elf");
+const TEXT: u64 = 0x1000_0000_0000;
+const DATA: u64 = TEXT + 0x1000;
+
+fn patched(offset: usize, value: &[u8]) -> Vec<u8> {
+    let mut elf = HELLO.to_vec();
+    elf[offset..offset + value.len()].copy_from_slice(value);
+    elf
+}
+
+#[test_case]
+fn parses_headers() {
+    let elf = Elf::parse(HELLO).unwrap();
+    assert_eq!(elf.entry(), VirtAddr::new(TEXT));
+    let segments: Vec<_> = elf.segments().map(Result::unwrap).collect();
+    assert_eq!(segments.len(), 2);
+    assert_eq!((segments[0].vaddr, segments[0].flags), (TEXT, 5)); // R X
+    assert_eq!((segments[1].vaddr, segments[1].flags), (DATA, 6)); // R W
+    assert!(segments[1].mem_size > segments[1].file_size); // .bss
+}
+
+#[test_case]
+fn segments_get_their_permissions() {
+    let mut space = AddressSpace::new().unwrap();
+    Elf::parse(HELLO).unwrap().load(&mut space).unwrap();
+
+    let (_, text) = space.translate(VirtAddr::new(TEXT)).unwrap();
+    assert!(text.contains(Flags::USER_ACCESSIBLE));
+    assert!(!text.contains(Flags::WRITABLE) && !text.contains(Flags::NO_EXECUTE));
+    for page in [DATA, DATA + 0x1000, DATA + 0x2000].iter() {
+        let (_, data) = space.translate(VirtAddr::new(*page)).unwrap();
+        assert!(data.contains(Flags::WRITABLE | Flags::NO_EXECUTE | Flags::USER_ACCESSIBLE));
+    }
+}
+
+#[test_case]
+fn runs_the_binary() {
+    let mut process = Process::from_elf(HELLO).unwrap();
+    // the program checks its .bss is zeroed, then exits with the result of its write syscall
+    let message = "hello from an ELF binary\n";
+    assert_eq!(process.run(), ExitStatus::Exited(message.len() as u64));
+}
+
+#[test_case]
+fn rejects_broken_files() {
+    assert_eq!(Elf::parse(&HELLO[..40]).err(), Some(ElfError::Truncated));
+    assert_eq!(
+        Elf::parse(&patched(0, b"\x7fELG")).err(),
+        Some(ElfError::NotElf)
+    );
+    // e_machine = 183 (aarch64)
+    let arm = patched(18, &183u16.to_le_bytes());
+    assert_eq!(Elf::parse(&arm).err(), Some(ElfError::Unsupported));
+    // program header table pointing past the end of the file
+    let headers = patched(32, &(HELLO.len() as u64).to_le_bytes());
+    assert_eq!(Elf::parse(&headers).err(), Some(ElfError::Truncated));
+}
+
+#[test_case]
+fn rejects_unsafe_layouts() {
+    // entry point inside the (non executable) data segment
+    let entry = patched(24, &DATA.to_le_bytes());
+    let result = Process::from_elf(&entry).err();
+    assert_eq!(result, Some(ElfError::EntryNotExecutable));
+
+    // text segment moved onto the kernel heap (first program header starts at byte 64)
+    let heap = mini_os::allocator::HEAP_START as u64;
+    let kernel = patched(64 + 16, &heap.to_le_bytes());
+    let result = Process::from_elf(&kernel).err();
+    assert_eq!(result, Some(ElfError::Paging(PagingError::KernelSpace)));
+}
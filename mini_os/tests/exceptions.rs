@@ -0,0 +1,417 @@
+// every exception handler: the exception is raised inside "exceptions::catch", which hands back the
+// frame the entry stub saved, and the decoded report is checked. Alignment checks only happen in
+// ring 3, a user program raises them. Exceptions that can't be raised under QEMU at all (no task
+// switches, CET, SEV or SVM) are entered the way the CPU would enter them: interrupt frame and
+// error code on the stack, jump to the entry stub
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use core::arch::{asm, global_asm};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use mini_os::interrupts::exceptions::{self, catch, ExceptionFrame};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    mini_os::gdt::init_guarded_stacks().expect("mapping the exception stacks failed");
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+// the exception "f" raises, with its report
+fn raise(f: extern "C" fn(), vector: u8) -> (ExceptionFrame, alloc::string::String) {
+    let frame = catch(f).expect("no exception was raised");
+    assert_eq!(frame.vector, vector.into());
+    let report = format!("{}", frame);
+    let (mnemonic, name) = exceptions::name(vector).unwrap();
+    assert!(report.starts_with(&format!("EXCEPTION: {} ({}", name, mnemonic)));
+    assert!(report.contains("rax 0x"));
+    (frame, report)
+}
+
+static SIMULATED_VECTOR: AtomicU64 = AtomicU64::new(0);
+static SIMULATED_ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn enter_simulated() {
+    let vector = SIMULATED_VECTOR.load(Ordering::SeqCst) as u8;
+    let entry = exceptions::entry_address(vector).unwrap().as_u64();
+    let error_code = SIMULATED_ERROR_CODE.load(Ordering::SeqCst);
+    // the handlers all end in "catch", nothing comes back here
+    unsafe {
+        asm!(
+            "mov rax, rsp",
+            "and rsp, -16",
+            "mov ecx, ss",
+            "push rcx",
+            "push rax",
+            "pushfq",
+            "mov ecx, cs",
+            "push rcx",
+            "lea rcx, [rip]",
+            "push rcx",
+            "push rsi",
+            "jmp rdx",
+            in("rdx") entry,
+            in("rsi") error_code,
+            options(noreturn),
+        );
+    }
+}
+
+fn simulate(vector: u8, error_code: u64) -> (ExceptionFrame, alloc::string::String) {
+    SIMULATED_VECTOR.store(vector.into(), Ordering::SeqCst);
+    SIMULATED_ERROR_CODE.store(error_code, Ordering::SeqCst);
+    let (frame, report) = raise(enter_simulated, vector);
+    assert_eq!(frame.error_code, error_code);
+    (frame, report)
+}
+
+#[test_case]
+fn divide_error() {
+    extern "C" fn f() {
+        unsafe { asm!("xor ecx, ecx", "div ecx", out("eax") _, out("ecx") _, out("edx") _) };
+    }
+    let (_, report) = raise(f, exceptions::DIVIDE_ERROR);
+    assert!(report.contains("division by zero"));
+}
+
+#[test_case]
+fn debug() {
+    extern "C" fn f() {
+        unsafe { asm!(".byte 0xf1") }; // int1
+    }
+    let (_, report) = raise(f, exceptions::DEBUG);
+    assert!(report.contains("debug status"));
+}
+
+#[test_case]
+fn non_maskable_interrupt() {
+    extern "C" fn f() {
+        unsafe { asm!("int 2") };
+    }
+    raise(f, exceptions::NON_MASKABLE_INTERRUPT);
+}
+
+#[test_case]
+fn breakpoint() {
+    extern "C" fn f() {
+        x86_64::instructions::interrupts::int3();
+    }
+    let (frame, report) = raise(f, exceptions::BREAKPOINT);
+    assert!(report.contains(&format!("int3 at {:#x}", frame.rip - 1)));
+}
+
+#[test_case]
+fn overflow() {
+    extern "C" fn f() {
+        unsafe { asm!("int 4") };
+    }
+    raise(f, exceptions::OVERFLOW);
+}
+
+#[test_case]
+fn bound_range_exceeded() {
+    extern "C" fn f() {
+        unsafe { asm!("int 5") };
+    }
+    raise(f, exceptions::BOUND_RANGE_EXCEEDED);
+}
+
+#[test_case]
+fn invalid_opcode() {
+    extern "C" fn f() {
+        unsafe { asm!("ud2") };
+    }
+    let (_, report) = raise(f, exceptions::INVALID_OPCODE);
+    assert!(report.contains("bytes 0f 0b"));
+}
+
+#[test_case]
+fn device_not_available() {
+    extern "C" fn f() {
+        unsafe { asm!("fnop") };
+    }
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+    let frame = catch(f);
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED)) };
+    let frame = frame.expect("no exception was raised");
+    assert_eq!(frame.vector, exceptions::DEVICE_NOT_AVAILABLE.into());
+}
+
+#[test_case]
+fn double_fault() {
+    use mini_os::memory::stack::KernelStack;
+
+    #[allow(unconditional_recursion)]
+    extern "C" fn overflow() {
+        overflow();
+        volatile::Volatile::new(0).read();
+    }
+    // the page fault in the guard page can't push its frame either
+    extern "C" fn f() {
+        let stack = KernelStack::new("exceptions test", 2).unwrap();
+        unsafe { stack.run(overflow) };
+    }
+    let (_, report) = raise(f, exceptions::DOUBLE_FAULT);
+    assert!(report.contains("stack overflow in task exceptions test"));
+}
+
+#[test_case]
+fn invalid_tss() {
+    let (_, report) = simulate(exceptions::INVALID_TSS, 5 << 3);
+    assert!(report.contains("selector: GDT index 5"));
+}
+
+#[test_case]
+fn segment_not_present() {
+    // the IDT gate of 0x99 isn't present
+    extern "C" fn f() {
+        unsafe { asm!("int 0x99") };
+    }
+    let (frame, report) = raise(f, exceptions::SEGMENT_NOT_PRESENT);
+    assert_eq!(frame.error_code, 0x99 << 3 | 2);
+    assert!(report.contains("selector: IDT vector 0x99"));
+}
+
+#[test_case]
+fn stack_segment_fault() {
+    use x86_64::instructions::tables::lgdt;
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    // loads SS with a writable ring 0 data segment that isn't present. It's appended to a copy of
+    // the GDT, which is loaded meanwhile
+    static mut GDT: [u64; 16] = [0; 16];
+    static SELECTOR: AtomicU64 = AtomicU64::new(0);
+    extern "C" fn f() {
+        let selector = SELECTOR.load(Ordering::SeqCst);
+        unsafe { asm!("mov ss, {:x}", in(reg) selector) };
+    }
+
+    let mut kernel_gdt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe { asm!("sgdt [{}]", in(reg) &mut kernel_gdt, options(nostack, preserves_flags)) };
+    let entries = (usize::from(kernel_gdt.limit) + 1) / 8;
+    let copy = unsafe { &mut *core::ptr::addr_of_mut!(GDT) };
+    assert!(entries < copy.len());
+    let base = kernel_gdt.base;
+    unsafe { core::ptr::copy_nonoverlapping(base.as_ptr(), copy.as_mut_ptr(), entries) };
+    copy[entries] = 0x00cf_1200_0000_ffff; // like the kernel data segment but without P
+    SELECTOR.store(entries as u64 * 8, Ordering::SeqCst);
+    let test_gdt = DescriptorTablePointer {
+        limit: (entries * 8 + 7) as u16,
+        base: VirtAddr::from_ptr(copy.as_ptr()),
+    };
+
+    unsafe { lgdt(&test_gdt) };
+    let caught = catch(f);
+    unsafe { lgdt(&kernel_gdt) };
+    let frame = caught.expect("no exception was raised");
+    assert_eq!(frame.vector, exceptions::STACK_SEGMENT_FAULT.into());
+    assert_eq!(frame.error_code, entries as u64 * 8);
+    let report = format!("{}", frame);
+    assert!(report.contains(&format!("selector: GDT index {}", entries)));
+}
+
+#[test_case]
+fn general_protection_fault() {
+    // GDT entry 100 is far behind the end of the GDT
+    extern "C" fn f() {
+        unsafe { asm!("mov ax, 100 << 3", "mov ds, ax", out("ax") _) };
+    }
+    let (frame, report) = raise(f, exceptions::GENERAL_PROTECTION_FAULT);
+    assert_eq!(frame.error_code, 100 << 3);
+    assert!(report.contains("selector: GDT index 100"));
+}
+
+#[test_case]
+fn page_fault() {
+    const ADDRESS: u64 = 0x_dead_0000_0000;
+    extern "C" fn f() {
+        unsafe { (ADDRESS as *const u64).read_volatile() };
+    }
+    let (frame, report) = raise(f, exceptions::PAGE_FAULT);
+    assert_eq!(frame.cr2, ADDRESS);
+    assert!(report.contains(&format!("kernel read at {:#x}: page not present", ADDRESS)));
+}
+
+#[test_case]
+fn x87_floating_point() {
+    // 1 / 0 with the zero divide exception unmasked, reported by the next fwait
+    extern "C" fn f() {
+        unsafe {
+            asm!(
+                "fninit",
+                "push 0x037b",
+                "fldcw [rsp]",
+                "add rsp, 8",
+                "fld1",
+                "fldz",
+                "fdivp",
+                "fwait",
+            )
+        };
+    }
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::NUMERIC_ERROR)) };
+    let (frame, _) = raise(f, exceptions::X87_FLOATING_POINT);
+    unsafe { asm!("fninit") };
+    // the status word is the one of the exception, not the one after "fninit"
+    assert!(format!("{}", frame).contains("division by zero"));
+}
+
+// sets RFLAGS.AC and loads from an odd address, position independent like the programs of
+// tests/user_mode.rs
+global_asm!(
+    r#"
+.pushsection .rodata.user_programs, "a"
+misaligned_load_start:
+    pushfq
+    or dword ptr [rsp], 1 << 18
+    popfq
+    mov rax, [rsp + 1]
+    mov edi, 0
+    mov eax, 1
+    int 0x80
+misaligned_load_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static misaligned_load_start: u8;
+    static misaligned_load_end: u8;
+}
+
+#[test_case]
+fn alignment_check() {
+    use core::ptr::addr_of;
+    use mini_os::process::{ExitStatus, Process};
+
+    let start = unsafe { addr_of!(misaligned_load_start) };
+    let end = unsafe { addr_of!(misaligned_load_end) };
+    let code = unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) };
+    let mut process = Process::from_code(code).expect("process setup failed");
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::ALIGNMENT_MASK)) };
+    let status = process.run();
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::ALIGNMENT_MASK)) };
+    assert_eq!(status, ExitStatus::Exception(exceptions::ALIGNMENT_CHECK));
+}
+
+#[test_case]
+fn machine_check() {
+    extern "C" fn f() {
+        unsafe { asm!("int 18") };
+    }
+    raise(f, exceptions::MACHINE_CHECK);
+}
+
+#[test_case]
+fn simd_floating_point() {
+    // 1.0 / 0.0 with the zero divide exception unmasked in MXCSR
+    extern "C" fn f() {
+        unsafe {
+            asm!(
+                "push 0x1d80",
+                "ldmxcsr [rsp]",
+                "add rsp, 8",
+                "mov eax, 1",
+                "cvtsi2ss xmm0, eax",
+                "xorps xmm1, xmm1",
+                "divss xmm0, xmm1",
+                out("eax") _,
+            )
+        };
+    }
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+    let (frame, _) = raise(f, exceptions::SIMD_FLOATING_POINT);
+    unsafe { asm!("push 0x1f80", "ldmxcsr [rsp]", "add rsp, 8") };
+    // MXCSR as it was when the exception happened
+    assert!(format!("{}", frame).contains("division by zero"));
+}
+
+#[test_case]
+fn virtualization() {
+    extern "C" fn f() {
+        unsafe { asm!("int 20") };
+    }
+    raise(f, exceptions::VIRTUALIZATION);
+}
+
+#[test_case]
+fn control_protection() {
+    let (_, report) = simulate(exceptions::CONTROL_PROTECTION, 1);
+    assert!(report.contains("near return"));
+}
+
+#[test_case]
+fn hypervisor_injection() {
+    extern "C" fn f() {
+        unsafe { asm!("int 28") };
+    }
+    raise(f, exceptions::HYPERVISOR_INJECTION);
+}
+
+#[test_case]
+fn vmm_communication() {
+    let (_, report) = simulate(exceptions::VMM_COMMUNICATION, 0x72);
+    assert!(report.contains("exit code 0x72"));
+}
+
+#[test_case]
+fn security() {
+    let (_, report) = simulate(exceptions::SECURITY, 1);
+    assert!(report.contains("security violation"));
+}
+
+#[test_case]
+fn backtrace_follows_frame_pointers() {
+    #[inline(never)]
+    extern "C" fn inner() {
+        unsafe { asm!("ud2") };
+    }
+    extern "C" fn f() {
+        inner();
+    }
+    let (frame, _) = raise(f, exceptions::INVALID_OPCODE);
+    let callers = exceptions::Backtrace::new(frame.rbp).count();
+    // at least f and catch
+    assert!(callers >= 2);
+}
+
+#[test_case]
+fn nothing_to_catch() {
+    extern "C" fn f() {}
+    assert!(catch(f).is_none());
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
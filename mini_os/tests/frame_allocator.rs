@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+use mini_os::memory::{self, MemoryManager};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
+
+fn with_memory<R>(f: impl FnOnce(&mut MemoryManager) -> R) -> R {
+    memory::with_memory(f).expect("memory manager not initialized")
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    with_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+        let frame: PhysFrame<Size4KiB> = allocator.allocate_frame().expect("out of frames");
+        unsafe { allocator.deallocate_frame(frame) };
+        let again: PhysFrame<Size4KiB> = allocator.allocate_frame().expect("out of frames");
+        assert_eq!(frame, again);
+        unsafe { allocator.deallocate_frame(again) };
+    });
+}
+
+#[test_case]
+fn frames_are_distinct() {
+    with_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+        let mut frames: [Option<PhysFrame<Size4KiB>>; 64] = [None; 64];
+        for slot in frames.iter_mut() {
+            *slot = Some(allocator.allocate_frame().expect("out of frames"));
+        }
+        for (i, a) in frames.iter().enumerate() {
+            assert!(frames[i + 1..].iter().all(|b| b != a));
+        }
+        for frame in frames.iter().flatten() {
+            unsafe { allocator.deallocate_frame(*frame) };
+        }
+    });
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    with_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+        let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("no huge frame left");
+        assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+        unsafe { allocator.deallocate_frame(frame) };
+    });
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    with_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+        let before = allocator.stats();
+        assert!(before.total > 0);
+        assert_eq!(before.used + before.free(), before.total);
+
+        let small: PhysFrame<Size4KiB> = allocator.allocate_frame().expect("out of frames");
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("no huge frame left");
+        let during = allocator.stats();
+        assert_eq!(during.used, before.used + Size4KiB::SIZE + Size2MiB::SIZE);
+        assert_eq!(
+            during.free(),
+            before.free() - Size4KiB::SIZE - Size2MiB::SIZE
+        );
+
+        unsafe {
+            allocator.deallocate_frame(small);
+            allocator.deallocate_frame(huge);
+        }
+        assert_eq!(allocator.stats(), before);
+    });
+}
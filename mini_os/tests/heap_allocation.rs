@@ -13,14 +13,13 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use mini_os::allocator;
-    use mini_os::memory::{self, BootInfoFrameAllocator};
+    use mini_os::memory;
     use x86_64::VirtAddr;
 
     mini_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
 
     test_main();
     mini_os::hlt_loop()
@@ -53,7 +52,7 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
-use mini_os::allocator::HEAP_SIZE;
+use mini_os::allocator::{self, HEAP_SIZE};
 
 #[test_case]
 fn many_boxes() {
@@ -73,3 +72,71 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn stats_track_live_allocations() {
+    let before = allocator::stats();
+    let small = Box::new([0u8; 24]); // 32 byte class
+    let big = Vec::<u8>::with_capacity(4000); // bigger than every class
+    let during = allocator::stats();
+    assert_eq!(during.live_allocations, before.live_allocations + 2);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 24 + 4000);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    assert_eq!(during.size_classes[2], before.size_classes[2] + 1);
+    let bigger = during.size_classes.len() - 1;
+    assert_eq!(during.size_classes[bigger], before.size_classes[bigger] + 1);
+    assert!(during.allocations >= before.allocations + 2);
+    drop(small);
+    drop(big);
+    let after = allocator::stats();
+    assert_eq!(after.live_allocations, before.live_allocations);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert!(after.free_bytes <= after.heap_size);
+    assert!(after.largest_free_block <= after.free_bytes);
+    assert!(after.fragmentation_percent() <= 100);
+}
+
+// only the fixed size block allocator grows its heap
+#[cfg(feature = "alloc-fixed-size-block")]
+#[test_case]
+fn heap_grows_when_exhausted() {
+    let big = Vec::<u8>::with_capacity(HEAP_SIZE * 2);
+    let grown = allocator::stats();
+    assert!(grown.heap_size > HEAP_SIZE * 2);
+    assert!(grown.heap_size <= allocator::heap_limit());
+    // the new pages are usable
+    let mut big = big;
+    big.resize(HEAP_SIZE * 2, 0xab);
+    assert!(big.iter().all(|&byte| byte == 0xab));
+}
+
+#[cfg(feature = "alloc-fixed-size-block")]
+#[test_case]
+fn heap_stops_growing_at_the_limit() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    let before = allocator::stats();
+    allocator::set_heap_limit(before.heap_size);
+    let layout = Layout::from_size_align(before.heap_size, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    allocator::set_heap_limit(allocator::HEAP_MAX_SIZE);
+    assert!(ptr.is_null());
+    let after = allocator::stats();
+    assert_eq!(after.heap_size, before.heap_size);
+    assert_eq!(after.failures, before.failures + 1);
+
+    // with the limit raised again the same allocation works
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { dealloc(ptr, layout) };
+}
+
+#[test_case]
+fn heap_limit_stops_before_the_kernel_stacks() {
+    use mini_os::memory::stack::STACKS_START;
+
+    allocator::set_heap_limit(usize::MAX);
+    let limit = allocator::heap_limit();
+    allocator::set_heap_limit(allocator::HEAP_MAX_SIZE);
+    assert_eq!(limit as u64, STACKS_START - allocator::HEAP_START as u64);
+}
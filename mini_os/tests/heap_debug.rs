@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::allocator::debug::{Debugged, HeapError, POISON_BYTE, REDZONE, REDZONE_BYTE};
+use mini_os::allocator::linked_list::LinkedListAllocator;
+use mini_os::allocator::Locked;
+
+entry_point!(main);
+
+// a debugged heap of its own, independent of the "heap-debug" feature
+const HEAP_START: u64 = 0x_5555_0000_0000;
+const HEAP_SIZE: usize = 64 * 1024;
+static HEAP: Debugged<Locked<LinkedListAllocator>> =
+    Debugged::new(Locked::new(LinkedListAllocator::new()));
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    let start = VirtAddr::new(HEAP_START);
+    memory::register_lazy_region(start, HEAP_SIZE as u64, Flags::WRITABLE)
+        .expect("heap registration failed");
+    unsafe { HEAP.inner().lock().init(HEAP_START as usize, HEAP_SIZE) };
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+fn live_allocations() -> usize {
+    let mut count = 0;
+    HEAP.for_each_live(|_| count += 1);
+    count
+}
+
+#[test_case]
+fn allocations_are_surrounded_by_redzones() {
+    let ptr = unsafe { HEAP.alloc(layout(40)) };
+    assert!(!ptr.is_null());
+    for i in 1..=REDZONE {
+        assert_eq!(unsafe { *ptr.sub(i) }, REDZONE_BYTE);
+        assert_eq!(unsafe { *ptr.add(40 + i - 1) }, REDZONE_BYTE);
+    }
+    assert_eq!(unsafe { HEAP.validate(ptr, layout(40)) }, Ok(()));
+    unsafe { HEAP.dealloc(ptr, layout(40)) };
+}
+
+#[test_case]
+fn freed_memory_is_poisoned() {
+    let ptr = unsafe { HEAP.alloc(layout(64)) };
+    unsafe { ptr.write_bytes(0x11, 64) };
+    unsafe { HEAP.dealloc(ptr, layout(64)) };
+    assert!((0..64).all(|i| unsafe { *ptr.add(i) } == POISON_BYTE));
+}
+
+#[test_case]
+fn double_free_is_detected() {
+    let ptr = unsafe { HEAP.alloc(layout(32)) };
+    unsafe { HEAP.dealloc(ptr, layout(32)) };
+    assert_eq!(
+        unsafe { HEAP.validate(ptr, layout(32)) },
+        Err(HeapError::DoubleFree)
+    );
+}
+
+#[test_case]
+fn wrong_layout_is_detected() {
+    let ptr = unsafe { HEAP.alloc(layout(32)) };
+    assert_eq!(
+        unsafe { HEAP.validate(ptr, layout(48)) },
+        Err(HeapError::WrongLayout(layout(32)))
+    );
+    let aligned = Layout::from_size_align(32, 16).unwrap();
+    assert_eq!(
+        unsafe { HEAP.validate(ptr, aligned) },
+        Err(HeapError::WrongLayout(layout(32)))
+    );
+    unsafe { HEAP.dealloc(ptr, layout(32)) };
+}
+
+#[test_case]
+fn overflow_and_underflow_are_detected() {
+    let ptr = unsafe { HEAP.alloc(layout(24)) };
+    unsafe {
+        *ptr.add(24) = 0; // one byte too far
+        assert_eq!(HEAP.validate(ptr, layout(24)), Err(HeapError::Overflow));
+        *ptr.add(24) = REDZONE_BYTE;
+        *ptr.sub(1) = 0;
+        assert_eq!(HEAP.validate(ptr, layout(24)), Err(HeapError::Underflow));
+        *ptr.sub(1) = REDZONE_BYTE;
+        HEAP.dealloc(ptr, layout(24));
+    }
+}
+
+#[test_case]
+fn unknown_pointers_are_detected() {
+    let ptr = unsafe { HEAP.alloc(layout(128)) };
+    unsafe { ptr.write_bytes(0, 128) };
+    let inside = unsafe { ptr.add(64) };
+    assert_eq!(
+        unsafe { HEAP.validate(inside, layout(8)) },
+        Err(HeapError::UnknownPointer)
+    );
+    unsafe { HEAP.dealloc(ptr, layout(128)) };
+}
+
+#[test_case]
+fn registry_tracks_live_allocations() {
+    let before = live_allocations();
+    let a = unsafe { HEAP.alloc(layout(16)) };
+    let b = unsafe { HEAP.alloc(layout(200)) };
+    assert_eq!(live_allocations(), before + 2);
+
+    let mut found = None;
+    let untracked = HEAP.for_each_live(|allocation| {
+        if allocation.ptr == b as usize {
+            found = Some(*allocation);
+        }
+    });
+    assert_eq!(untracked, 0);
+    let found = found.expect("allocation not registered");
+    assert_eq!(found.size, 200);
+    assert_eq!(found.align, 8);
+    assert_ne!(found.callers[0], 0);
+    HEAP.dump();
+
+    unsafe {
+        HEAP.dealloc(a, layout(16));
+        HEAP.dealloc(b, layout(200));
+    }
+    assert_eq!(live_allocations(), before);
+}
@@ -0,0 +1,53 @@
+// a double free on a debugged heap must panic in dealloc
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::allocator::debug::Debugged;
+use mini_os::allocator::linked_list::LinkedListAllocator;
+use mini_os::allocator::Locked;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitcode};
+
+entry_point!(main);
+
+const HEAP_START: u64 = 0x_5555_0000_0000;
+const HEAP_SIZE: usize = 16 * 1024;
+static HEAP: Debugged<Locked<LinkedListAllocator>> =
+    Debugged::new(Locked::new(LinkedListAllocator::new()));
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::{allocator, memory};
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use x86_64::VirtAddr;
+
+    serial_print!("heap_double_free::double_free_panics...\t");
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    memory::register_lazy_region(VirtAddr::new(HEAP_START), HEAP_SIZE as u64, Flags::WRITABLE)
+        .expect("heap registration failed");
+    unsafe { HEAP.inner().lock().init(HEAP_START as usize, HEAP_SIZE) };
+
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = HEAP.alloc(layout);
+        HEAP.dealloc(ptr, layout);
+        HEAP.dealloc(ptr, layout);
+    }
+
+    serial_println!("[test did not panic!]");
+    exit_qemu(QemuExitcode::Failure);
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitcode::Success);
+    mini_os::hlt_loop()
+}
@@ -0,0 +1,45 @@
+// an allocation beyond the heap limit must end up in the alloc error handler (which panics)
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::allocator::{self, HEAP_SIZE};
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitcode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory;
+    use x86_64::VirtAddr;
+
+    serial_print!("heap_exhausted::allocation_beyond_limit...\t");
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    allocator::set_heap_limit(HEAP_SIZE * 4);
+    let too_big: Vec<u8> = Vec::with_capacity(HEAP_SIZE * 4);
+    drop(too_big);
+
+    serial_println!("[test did not panic!]");
+    exit_qemu(QemuExitcode::Failure);
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    // the panic has to come from the failed allocation, not from somewhere else
+    if allocator::stats().failures == 1 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitcode::Success);
+    } else {
+        serial_println!("[failed]");
+        exit_qemu(QemuExitcode::Failure);
+    }
+    mini_os::hlt_loop()
+}
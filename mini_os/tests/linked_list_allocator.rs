@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::allocator::linked_list::{FitStrategy, LinkedListAllocator};
+use mini_os::allocator::{KernelHeap, Locked};
+
+entry_point!(main);
+
+// a heap of its own (demand paged like the kernel heap), the global allocator stays as it is
+const HEAP_START: u64 = 0x_5555_0000_0000;
+const HEAP_SIZE: usize = 256 * 1024;
+static HEAP: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    let start = VirtAddr::new(HEAP_START);
+    memory::register_lazy_region(start, HEAP_SIZE as u64, Flags::WRITABLE)
+        .expect("heap registration failed");
+    unsafe { HEAP.lock().init(HEAP_START as usize, HEAP_SIZE) };
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+// every test leaves the heap as one free region again
+fn assert_recovered() {
+    let heap = HEAP.lock();
+    assert_eq!(heap.free_regions(), 1);
+    assert_eq!(heap.free_bytes(), HEAP_SIZE);
+    assert_eq!(heap.largest_free_block(), HEAP_SIZE);
+}
+
+#[test_case]
+fn neighbours_are_merged() {
+    let (a, b, c) = unsafe {
+        (
+            HEAP.alloc(layout(64)),
+            HEAP.alloc(layout(64)),
+            HEAP.alloc(layout(64)),
+        )
+    };
+    assert_eq!(b as usize, a as usize + 64);
+    assert_eq!(c as usize, b as usize + 64);
+    unsafe {
+        HEAP.dealloc(a, layout(64));
+        HEAP.dealloc(c, layout(64)); // merges with the rest of the heap
+        assert_eq!(HEAP.lock().free_regions(), 2);
+        HEAP.dealloc(b, layout(64)); // closes the gap
+    }
+    assert_recovered();
+}
+
+#[test_case]
+fn fragmentation_stress() {
+    const COUNT: usize = 400;
+    let mut blocks = [(core::ptr::null_mut::<u8>(), 0usize); COUNT];
+    let mut seed = 0x2545_f491_u32;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as usize
+    };
+    for round in 0..4 {
+        for block in blocks.iter_mut() {
+            let size = 8 + random() % 400;
+            let ptr = unsafe { HEAP.alloc(layout(size)) };
+            assert!(!ptr.is_null(), "allocation failed in round {}", round);
+            unsafe { ptr.write_bytes(round as u8, size) };
+            *block = (ptr, size);
+        }
+        // free every other block: lots of holes
+        for block in blocks.iter().step_by(2) {
+            unsafe { HEAP.dealloc(block.0, layout(block.1)) };
+        }
+        assert!(HEAP.lock().free_regions() > COUNT / 4);
+        // free the rest in a scrambled order
+        for index in 0..COUNT / 2 {
+            let block = blocks[(index * 7919) % (COUNT / 2) * 2 + 1];
+            unsafe { HEAP.dealloc(block.0, layout(block.1)) };
+        }
+        assert_recovered();
+    }
+}
+
+#[test_case]
+fn best_fit_takes_the_smallest_hole() {
+    // holes of 256 and 64 bytes in front of the rest of the heap
+    let sizes = [256, 32, 64, 32];
+    let blocks = sizes.map(|size| unsafe { HEAP.alloc(layout(size)) });
+    unsafe {
+        HEAP.dealloc(blocks[0], layout(256));
+        HEAP.dealloc(blocks[2], layout(64));
+    }
+
+    HEAP.lock().set_strategy(FitStrategy::BestFit);
+    let best = unsafe { HEAP.alloc(layout(48)) };
+    HEAP.lock().set_strategy(FitStrategy::FirstFit);
+    let first = unsafe { HEAP.alloc(layout(48)) };
+    assert_eq!(best, blocks[2]);
+    assert_eq!(first, blocks[0]);
+
+    unsafe {
+        HEAP.dealloc(best, layout(48));
+        HEAP.dealloc(first, layout(48));
+        HEAP.dealloc(blocks[1], layout(32));
+        HEAP.dealloc(blocks[3], layout(32));
+    }
+    assert_recovered();
+}
+
+#[test_case]
+fn next_fit_continues_behind_the_last_allocation() {
+    HEAP.lock().set_strategy(FitStrategy::NextFit);
+    let a = unsafe { HEAP.alloc(layout(64)) };
+    let b = unsafe { HEAP.alloc(layout(64)) };
+    unsafe { HEAP.dealloc(a, layout(64)) };
+    // first fit would reuse "a"
+    let c = unsafe { HEAP.alloc(layout(64)) };
+    assert_eq!(c as usize, b as usize + 64);
+    HEAP.lock().set_strategy(FitStrategy::FirstFit);
+    unsafe {
+        HEAP.dealloc(b, layout(64));
+        HEAP.dealloc(c, layout(64));
+    }
+    assert_recovered();
+}
+
+#[test_case]
+fn realloc_grows_in_place() {
+    let ptr = unsafe { HEAP.alloc(layout(64)) };
+    unsafe { ptr.write_bytes(0x42, 64) };
+    // the rest of the heap is free behind it
+    let grown = unsafe { HEAP.realloc(ptr, layout(64), 4096) };
+    assert_eq!(grown, ptr);
+    let shrunk = unsafe { HEAP.realloc(grown, layout(4096), 128) };
+    assert_eq!(shrunk, ptr);
+    assert!((0..64).all(|i| unsafe { *shrunk.add(i) } == 0x42));
+
+    // blocked by another allocation: moved, contents copied
+    let blocker = unsafe { HEAP.alloc(layout(64)) };
+    assert_eq!(blocker as usize, ptr as usize + 128);
+    let moved = unsafe { HEAP.realloc(shrunk, layout(128), 256) };
+    assert_ne!(moved, shrunk);
+    assert!((0..64).all(|i| unsafe { *moved.add(i) } == 0x42));
+    unsafe {
+        HEAP.dealloc(moved, layout(256));
+        HEAP.dealloc(blocker, layout(64));
+    }
+    assert_recovered();
+}
@@ -0,0 +1,163 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mini_os::allocator::buddy::BuddyAllocator;
+use mini_os::allocator::slab::SlabCache;
+use mini_os::allocator::{KernelHeap, Locked};
+
+entry_point!(main);
+
+// the slabs come from a buddy pool of their own (demand paged like the heap)
+const POOL_START: u64 = 0x_5555_0000_0000;
+const POOL_SIZE: usize = 1024 * 1024;
+static POOL: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+    let start = VirtAddr::new(POOL_START);
+    memory::register_lazy_region(start, POOL_SIZE as u64, Flags::WRITABLE)
+        .expect("pool registration failed");
+    unsafe { POOL.lock().init(POOL_START as usize, POOL_SIZE) };
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+struct Object {
+    id: u64,
+    payload: [u8; 40],
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn new_object() -> Object {
+    Object {
+        id: 7,
+        payload: [0x5a; 40],
+    }
+}
+
+#[test_case]
+fn objects_are_constructed() {
+    let mut cache = SlabCache::new("object", &POOL, new_object);
+    let object = cache.alloc().expect("allocation failed");
+    let value = unsafe { object.as_ref() };
+    assert_eq!(value.id, 7);
+    assert!(value.payload.iter().all(|&byte| byte == 0x5a));
+    assert_eq!(
+        object.as_ptr() as usize % core::mem::align_of::<Object>(),
+        0
+    );
+
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    unsafe { cache.free(object) };
+    assert_eq!(DROPPED.load(Ordering::Relaxed), dropped + 1);
+}
+
+#[test_case]
+fn stats_follow_allocations() {
+    let mut cache = SlabCache::new("object", &POOL, new_object);
+    let per_slab = cache.stats().objects_per_slab;
+    assert!(per_slab >= 8);
+    assert_eq!(cache.stats().slabs, 0);
+
+    let mut objects = alloc::vec::Vec::new();
+    for _ in 0..per_slab + 1 {
+        objects.push(cache.alloc().unwrap());
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.slabs, 2);
+    assert_eq!(stats.live_objects, per_slab + 1);
+    assert_eq!(stats.free_objects, per_slab - 1);
+    assert_eq!(stats.allocations, per_slab as u64 + 1);
+    assert!(stats.object_size >= core::mem::size_of::<Object>());
+
+    for object in objects {
+        unsafe { cache.free(object) };
+    }
+    assert_eq!(cache.stats().live_objects, 0);
+    assert_eq!(cache.stats().free_objects, 2 * per_slab);
+}
+
+#[test_case]
+fn freed_objects_are_reused() {
+    let mut cache = SlabCache::new("object", &POOL, new_object);
+    let first = cache.alloc().unwrap();
+    let second = cache.alloc().unwrap();
+    assert_ne!(first, second);
+    unsafe { cache.free(first) };
+    let again = cache.alloc().unwrap();
+    assert_eq!(again, first);
+    assert_eq!(cache.stats().slabs, 1);
+    unsafe {
+        cache.free(again);
+        cache.free(second);
+    }
+}
+
+#[test_case]
+fn shrink_returns_empty_slabs() {
+    let free_before = POOL.lock().free_bytes();
+    let mut cache = SlabCache::new("object", &POOL, new_object);
+    let per_slab = cache.stats().objects_per_slab;
+    let mut objects = alloc::vec::Vec::new();
+    for _ in 0..3 * per_slab {
+        objects.push(cache.alloc().unwrap());
+    }
+    assert_eq!(cache.stats().slabs, 3);
+    let kept = objects.pop().unwrap();
+    for object in objects {
+        unsafe { cache.free(object) };
+    }
+    // the slab holding "kept" stays
+    assert_eq!(cache.shrink(), 2);
+    assert_eq!(cache.stats().slabs, 1);
+    assert_eq!(unsafe { kept.as_ref() }.id, 7);
+    unsafe { cache.free(kept) };
+    drop(cache);
+    assert_eq!(POOL.lock().free_bytes(), free_before);
+}
+
+#[test_case]
+fn out_of_pages() {
+    let mut cache = SlabCache::new("big", &POOL, || [0u64; 512]); // 4KiB objects
+    let mut count = 0;
+    let mut objects = alloc::vec::Vec::new();
+    while let Some(object) = cache.alloc() {
+        objects.push(object);
+        count += 1;
+    }
+    assert!(count > 0);
+    assert_eq!(cache.stats().failures, 1);
+    assert!(POOL.lock().largest_free_block() < cache.stats().slab_size);
+    for object in objects {
+        unsafe { cache.free(object) };
+    }
+}
@@ -0,0 +1,232 @@
+// needs more than one processor, the test runner starts QEMU with "-smp 4"
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use mini_os::cpu::{self, PerCpu, MAX_CPUS};
+use mini_os::interrupts;
+use mini_os::task::executor;
+use mini_os::{acpi, smp};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    mini_os::interrupts::apic::init().expect("switching to the APICs failed");
+    allocator::init_heap().expect("heap initialization failed");
+    smp::start_application_processors().expect("starting the other CPUs failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn every_processor_comes_online() {
+    let processors = acpi::madt().unwrap().processors().count();
+    assert!(processors >= 2, "QEMU has to run with more than one CPU");
+    assert_eq!(cpu::count(), processors.min(MAX_CPUS));
+    // different APIC ids, so "cpu::id" tells them apart
+    for cpu in 1..cpu::count() {
+        let apic_id = cpu::apic_id_of(cpu).unwrap();
+        assert!((0..cpu).all(|other| cpu::apic_id_of(other) != Some(apic_id)));
+    }
+}
+
+// the bootstrap processor doesn't run an executor here, so all of these run on the others
+static RAN_ON: PerCpu<AtomicU64> = PerCpu::new({
+    #[allow(clippy::declare_interior_mutable_const)] // only copied into the array
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CPUS]
+});
+static DONE: AtomicU64 = AtomicU64::new(0);
+
+async fn busy_task() {
+    RAN_ON.get().fetch_add(1, Ordering::SeqCst);
+    // long enough for the other CPUs to pick up tasks meanwhile
+    let start = interrupts::ticks();
+    while interrupts::ticks() < start + 1 {
+        core::hint::spin_loop();
+    }
+    DONE.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn shared_tasks_run_on_several_cpus() {
+    const TASKS: u64 = 12;
+    for _ in 0..TASKS {
+        executor::spawn_shared(busy_task());
+    }
+    let start = interrupts::ticks();
+    while DONE.load(Ordering::SeqCst) < TASKS {
+        assert!(
+            interrupts::ticks() - start < 100,
+            "shared tasks didn't finish"
+        );
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(RAN_ON.of(0).load(Ordering::SeqCst), 0);
+    let cpus = RAN_ON
+        .iter()
+        .filter(|ran| ran.load(Ordering::SeqCst) > 0)
+        .count();
+    assert!(cpus >= 2, "all tasks ran on one CPU");
+    let polls: u64 = (0..cpu::count()).map(executor::shared_polls).sum();
+    assert!(polls >= TASKS);
+}
+
+// a task woken from another CPU's interrupt handler (the BSP's timer) keeps running
+#[test_case]
+fn shared_task_wakes_up_again() {
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+
+    // pending until the tick count changed, woken by the next poll of the executor
+    struct NextTick(u64);
+    impl Future for NextTick {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if interrupts::ticks() > self.0 {
+                Poll::Ready(())
+            } else {
+                context.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    static WOKEN: AtomicU64 = AtomicU64::new(0);
+    executor::spawn_shared(async {
+        for _ in 0..3 {
+            NextTick(interrupts::ticks()).await;
+            WOKEN.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    let start = interrupts::ticks();
+    while WOKEN.load(Ordering::SeqCst) < 3 {
+        assert!(interrupts::ticks() - start < 100, "the task got stuck");
+        x86_64::instructions::hlt();
+    }
+}
+
+// every application processor fills a fresh part of the heap at once, the first touch of each page
+// faults and often two CPUs fault on the same page
+#[test_case]
+fn heap_faults_on_every_cpu() {
+    use alloc::vec;
+
+    const LEN: usize = 256 * 1024;
+    static FILLED: AtomicU64 = AtomicU64::new(0);
+    let others = cpu::count() as u64 - 1;
+    for task in 0..others {
+        executor::spawn_shared(async move {
+            let mut pages = vec![0u8; LEN];
+            for (i, byte) in pages.iter_mut().enumerate().step_by(512) {
+                *byte = (i as u64 + task) as u8;
+            }
+            let ok = pages
+                .iter()
+                .enumerate()
+                .step_by(512)
+                .all(|(i, &byte)| byte == (i as u64 + task) as u8);
+            assert!(ok);
+            FILLED.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    let start = interrupts::ticks();
+    while FILLED.load(Ordering::SeqCst) < others {
+        assert!(
+            interrupts::ticks() - start < 100,
+            "heap tasks didn't finish"
+        );
+        x86_64::instructions::hlt();
+    }
+}
+
+// every CPU maps and frees kernel stacks at once, each free waits for the others' TLB flushes
+#[test_case]
+fn stacks_freed_on_every_cpu() {
+    use mini_os::memory::{self, stack::KernelStack};
+
+    const ROUNDS: u64 = 50;
+    static FREED: AtomicU64 = AtomicU64::new(0);
+    fn churn() {
+        for _ in 0..ROUNDS {
+            let stack = KernelStack::new("shootdown test", 4).expect("mapping a stack failed");
+            unsafe { stack.bottom().as_mut_ptr::<u64>().write_volatile(1) };
+        }
+    }
+
+    let used = memory::frame_stats().unwrap().used;
+    let others = cpu::count() as u64 - 1;
+    for _ in 0..others {
+        executor::spawn_shared(async {
+            churn();
+            FREED.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    churn();
+    let start = interrupts::ticks();
+    while FREED.load(Ordering::SeqCst) < others {
+        assert!(
+            interrupts::ticks() - start < 100,
+            "stack tasks didn't finish"
+        );
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(memory::frame_stats().unwrap().used, used);
+}
+
+// every CPU catches exceptions at the same time, each "catch" gets its own CPU's exception back
+#[test_case]
+fn catch_on_every_cpu() {
+    use core::arch::asm;
+    use mini_os::interrupts::exceptions;
+
+    const ROUNDS: u64 = 100;
+    static CAUGHT: AtomicU64 = AtomicU64::new(0);
+    extern "C" fn f() {
+        unsafe { asm!("ud2") };
+    }
+    fn catch_all() {
+        for _ in 0..ROUNDS {
+            let frame = exceptions::catch(f).expect("ud2 wasn't caught");
+            assert_eq!(frame.vector, u64::from(exceptions::INVALID_OPCODE));
+        }
+    }
+
+    let others = cpu::count() as u64 - 1;
+    for _ in 0..others {
+        executor::spawn_shared(async {
+            catch_all();
+            CAUGHT.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    catch_all();
+    let start = interrupts::ticks();
+    while CAUGHT.load(Ordering::SeqCst) < others {
+        assert!(
+            interrupts::ticks() - start < 100,
+            "catch tasks didn't finish"
+        );
+        x86_64::instructions::hlt();
+    }
+}
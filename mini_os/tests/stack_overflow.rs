@@ -2,15 +2,33 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
 
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use mini_os::serial_print;
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow...\t");
 
-    mini_os::gdt::init();
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::{allocator, memory};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    mini_os::gdt::init_guarded_stacks().expect("mapping the exception stacks failed");
+    allocator::init_heap().expect("heap initialization failed");
+
+    serial_print!("stack_overflow::task_stack_overflow...\t");
+    task_stack_overflow();
+    serial_println!("[ok]");
+
+    serial_print!("stack_overflow::stack_overflow...\t");
+    // the timer would hit the test IDT's missing handlers
+    x86_64::instructions::interrupts::disable();
     init_test_idt();
-    // we call this function instead of interrupts::init_idt function because we want to register
+    // we load this IDT instead of the one of interrupts::init_idt because we want to register
     // a custom double fault that exits qemu [exit_qemu(QemuExitCode::Success)] instead of
     // panicking...
 
@@ -25,16 +43,38 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 #[allow(unconditional_recursion)] // silence compiler warning that function recurses endlessly
-fn stack_overflow() {
+extern "C" fn stack_overflow() {
     stack_overflow(); // for each recursion, return address is pushed
     volatile::Volatile::new(0).read();
     // to prevent tail recursion optimizations, we add dummy volatile read statement
     // which compiler is not allowed to remove hence making this non-tail recursive.
 }
 
+// overflows the stack of a task: the guard page below it has to stop it and the kernel's own
+// double fault report has to tell whose stack it was
+fn task_stack_overflow() {
+    use alloc::format;
+    use mini_os::interrupts::exceptions::{self, catch};
+    use mini_os::memory::stack::KernelStack;
+
+    extern "C" fn overflowing_task() {
+        let task_stack =
+            KernelStack::new("stack_overflow test task", 4).expect("mapping the task stack failed");
+        unsafe { task_stack.run(stack_overflow) };
+    }
+    let frame = catch(overflowing_task).expect("the task's stack didn't overflow");
+    assert_eq!(frame.vector, exceptions::DOUBLE_FAULT.into());
+    let report = format!("{}", frame);
+    assert!(
+        report.contains("stack overflow in task stack_overflow test task"),
+        "{}",
+        report
+    );
+}
+
 // we disable test harness for integration test like this because we can't continue execution after
 // double fault. So there is no point of test runner (default and custom). We run directly from
-// start() function.
+// main() function.
 
 use lazy_static::lazy_static;
 use x86_64::structures::idt::InterruptDescriptorTable;
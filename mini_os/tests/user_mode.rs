@@ -0,0 +1,200 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory;
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_global(phys_mem_offset, &boot_info.memory_map) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    mini_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+use core::arch::global_asm;
+use core::ptr::addr_of;
+use mini_os::allocator::HEAP_START;
+use mini_os::interrupts;
+use mini_os::process::{syscall, ExitStatus, Process};
+use x86_64::VirtAddr;
+
+// tiny position independent user programs, copied into the user code pages by Process::from_code
+// (syscall numbers: 0 write, 1 exit, 2 yield, 3 sleep, 4 getpid)
+global_asm!(
+    r#"
+.pushsection .rodata.user_programs, "a"
+hello_start:
+    mov eax, 0
+    mov edi, 1
+    lea rsi, [rip + hello_message]
+    mov edx, hello_end - hello_message
+    int 0x80
+    mov rdi, rax
+    mov eax, 1
+    int 0x80
+hello_message:
+    .ascii "hello from ring 3\n"
+hello_end:
+
+read_kernel_start:
+    movabs rax, 0x444444440000
+    mov rax, [rax]
+    mov edi, 0
+    mov eax, 1
+    int 0x80
+read_kernel_end:
+
+write_kernel_start:
+    mov eax, 0
+    mov edi, 1
+    movabs rsi, 0x444444440000
+    mov edx, 16
+    int 0x80
+    mov rdi, rax
+    mov eax, 1
+    int 0x80
+write_kernel_end:
+
+privileged_start:
+    cli
+    mov edi, 0
+    mov eax, 1
+    int 0x80
+privileged_end:
+
+invalid_opcode_start:
+    ud2
+invalid_opcode_end:
+
+getpid_start:
+    mov eax, 4
+    int 0x80
+    mov rdi, rax
+    mov eax, 1
+    int 0x80
+getpid_end:
+
+sleep_start:
+    mov eax, 2
+    int 0x80
+    mov eax, 3
+    mov edi, 200
+    int 0x80
+    mov rdi, rax
+    mov eax, 1
+    int 0x80
+sleep_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static hello_start: u8;
+    static hello_end: u8;
+    static read_kernel_start: u8;
+    static read_kernel_end: u8;
+    static write_kernel_start: u8;
+    static write_kernel_end: u8;
+    static privileged_start: u8;
+    static privileged_end: u8;
+    static invalid_opcode_start: u8;
+    static invalid_opcode_end: u8;
+    static getpid_start: u8;
+    static getpid_end: u8;
+    static sleep_start: u8;
+    static sleep_end: u8;
+}
+
+fn program(start: *const u8, end: *const u8) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+fn run(code: &[u8]) -> ExitStatus {
+    Process::from_code(code)
+        .expect("process setup failed")
+        .run()
+}
+
+#[test_case]
+fn user_program_prints_through_syscall() {
+    let code = program(unsafe { addr_of!(hello_start) }, unsafe {
+        addr_of!(hello_end)
+    });
+    // exit code is the result of the write syscall
+    assert_eq!(run(code), ExitStatus::Exited(18));
+}
+
+#[test_case]
+fn kernel_memory_access_faults() {
+    let code = program(unsafe { addr_of!(read_kernel_start) }, unsafe {
+        addr_of!(read_kernel_end)
+    });
+    let heap = VirtAddr::new(HEAP_START as u64);
+    assert_eq!(run(code), ExitStatus::PageFault(heap));
+}
+
+#[test_case]
+fn kernel_pointers_are_rejected_by_syscalls() {
+    let code = program(unsafe { addr_of!(write_kernel_start) }, unsafe {
+        addr_of!(write_kernel_end)
+    });
+    assert_eq!(run(code), ExitStatus::Exited(syscall::ERROR));
+}
+
+#[test_case]
+fn privileged_instruction_faults() {
+    let code = program(unsafe { addr_of!(privileged_start) }, unsafe {
+        addr_of!(privileged_end)
+    });
+    assert_eq!(run(code), ExitStatus::GeneralProtection);
+}
+
+#[test_case]
+fn other_exceptions_end_the_process() {
+    let code = program(unsafe { addr_of!(invalid_opcode_start) }, unsafe {
+        addr_of!(invalid_opcode_end)
+    });
+    assert_eq!(run(code), ExitStatus::Exception(6));
+}
+
+#[test_case]
+fn getpid_returns_the_pid() {
+    let code = program(unsafe { addr_of!(getpid_start) }, unsafe {
+        addr_of!(getpid_end)
+    });
+    let mut first = Process::from_code(code).unwrap();
+    let mut second = Process::from_code(code).unwrap();
+    assert_ne!(first.pid(), second.pid());
+    assert_eq!(first.run(), ExitStatus::Exited(first.pid()));
+    assert_eq!(second.run(), ExitStatus::Exited(second.pid()));
+}
+
+#[test_case]
+fn sleep_waits_for_timer_ticks() {
+    let code = program(unsafe { addr_of!(sleep_start) }, unsafe {
+        addr_of!(sleep_end)
+    });
+    let before = interrupts::ticks();
+    assert_eq!(run(code), ExitStatus::Exited(0));
+    // 200ms are at least 3 ticks of ~55ms
+    assert!(interrupts::ticks() - before >= 3);
+}
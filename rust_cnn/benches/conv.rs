@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ndarray::Array4;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rust_cnn::conv_layer::ConvLayer;
+use rust_cnn::layers::Layer;
+
+// (name, batch, channels, size, filters) for MNIST sized layers
+const SHAPES: [(&str, usize, usize, usize, usize); 2] =
+    [("28x28x1->8", 32, 1, 28, 8), ("14x14x8->16", 32, 8, 14, 16)];
+
+fn conv(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    for &(name, batch, channels, size, filters) in &SHAPES {
+        let mut layer = ConvLayer::new(channels, filters, 3, 1, 1, &mut rng);
+        let input = Array4::from_shape_simple_fn((batch, channels, size, size), || {
+            rng.gen_range(-1.0f32..1.0)
+        });
+        let grad = Array4::from_shape_simple_fn((batch, filters, size, size), || {
+            rng.gen_range(-1.0f32..1.0)
+        });
+        let dyn_input = input.clone().into_dyn();
+        let dyn_grad = grad.clone().into_dyn();
+
+        let mut group = c.benchmark_group("conv forward+backward");
+        group.sample_size(10);
+        group.bench_function(BenchmarkId::new("naive", name), |b| {
+            b.iter(|| {
+                layer.naive_forward(&input);
+                layer.naive_backward(&input, &grad)
+            })
+        });
+        group.bench_function(BenchmarkId::new("im2col", name), |b| {
+            b.iter(|| {
+                layer.forward(&dyn_input);
+                layer.backward(&dyn_grad)
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, conv);
+criterion_main!(benches);
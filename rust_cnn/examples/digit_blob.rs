@@ -0,0 +1,123 @@
+// trains a small digit classifier on shifted, noisy 5x7 glyphs (12x12 images) and writes it
+// as an inference blob, the one embedded into mini_os:
+//   cargo run --release --example digit_blob -- ../mini_os/assets/digits.rcnn
+use ndarray::{Array1, Array4};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rust_cnn::activation::Activation;
+use rust_cnn::cnn::{Cnn, TrainConfig};
+use rust_cnn::conv_layer::ConvLayer;
+use rust_cnn::dense_layer::DenseLayer;
+use rust_cnn::inference::{self, Model};
+use rust_cnn::layers::{ActivationLayer, FlattenLayer};
+use rust_cnn::loss::LossFunction;
+use rust_cnn::optimizer::Optimizer;
+use rust_cnn::pool_layer::PoolLayer;
+use rust_cnn::util::Dataset;
+use std::error::Error;
+
+const SIZE: usize = 12;
+
+#[rustfmt::skip]
+const GLYPHS: [[&str; 7]; 10] = [
+    [".###.", "#...#", "#..##", "#.#.#", "##..#", "#...#", ".###."],
+    ["..#..", ".##..", "..#..", "..#..", "..#..", "..#..", ".###."],
+    [".###.", "#...#", "....#", "...#.", "..#..", ".#...", "#####"],
+    ["#####", "...#.", "..#..", "...#.", "....#", "#...#", ".###."],
+    ["...#.", "..##.", ".#.#.", "#..#.", "#####", "...#.", "...#."],
+    ["#####", "#....", "####.", "....#", "....#", "#...#", ".###."],
+    ["..##.", ".#...", "#....", "####.", "#...#", "#...#", ".###."],
+    ["#####", "....#", "...#.", "..#..", ".#...", ".#...", ".#..."],
+    [".###.", "#...#", "#...#", ".###.", "#...#", "#...#", ".###."],
+    [".###.", "#...#", "#...#", ".####", "....#", "...#.", ".##.."],
+];
+
+// the sample mini_os classifies (mini_os/src/task/digits.rs), a 7 with a stray pixel
+#[rustfmt::skip]
+const SAMPLE: [&str; SIZE] = [
+    "............",
+    "............",
+    "...#####....",
+    ".......#....",
+    "......#.....",
+    ".....#......",
+    "....#.....#.",
+    "....#.......",
+    "....#.......",
+    "............",
+    "............",
+    "............",
+];
+const SAMPLE_LABEL: usize = 7;
+
+fn render(digit: usize, rng: &mut StdRng) -> Vec<f32> {
+    let (dx, dy) = (rng.gen_range(0..=SIZE - 5), rng.gen_range(0..=SIZE - 7));
+    let mut image = vec![0.0; SIZE * SIZE];
+    for (y, row) in GLYPHS[digit].iter().enumerate() {
+        for (x, pixel) in row.bytes().enumerate() {
+            if pixel == b'#' {
+                image[(y + dy) * SIZE + x + dx] = rng.gen_range(0.7..1.0);
+            }
+        }
+    }
+    for pixel in image.iter_mut() {
+        if rng.gen_bool(0.02) {
+            *pixel = 1.0 - *pixel; // salt and pepper
+        }
+    }
+    image
+}
+
+fn dataset(samples: usize, seed: u64) -> Dataset {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let labels: Vec<usize> = (0..samples).map(|_| rng.gen_range(0..10)).collect();
+    let pixels: Vec<f32> = labels.iter().flat_map(|&d| render(d, &mut rng)).collect();
+    let inputs = Array4::from_shape_vec((samples, 1, SIZE, SIZE), pixels).unwrap();
+    Dataset::new(inputs.into_dyn(), Array1::from(labels), 10)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let output = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "digits.rcnn".to_string());
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut model = Cnn::new(
+        LossFunction::CategoricalCrossEntropy { from_logits: false },
+        Optimizer::adam(0.005),
+    )
+    .add_layer(ConvLayer::new(1, 6, 3, 1, 1, &mut rng))
+    .add_layer(ActivationLayer::new(Activation::ReLu))
+    .add_layer(PoolLayer::new(2, 2))
+    .add_layer(FlattenLayer::new())
+    .add_layer(DenseLayer::new(6 * 6 * 6, 10, &mut rng))
+    .add_layer(ActivationLayer::new(Activation::Softmax));
+
+    let config = TrainConfig {
+        epochs: 15,
+        batch_size: 32,
+        seed: 1,
+        checkpoint: None,
+    };
+    let test = dataset(500, 2);
+    model.fit(&dataset(4000, 1), Some(&test), &config, 0, |stats| {
+        let test = stats.validation.as_ref().unwrap();
+        println!(
+            "epoch {}: loss {:.4}  test accuracy {:.4}",
+            stats.epoch,
+            stats.loss,
+            test.accuracy()
+        );
+    })?;
+
+    let blob = inference::export(&model, &[1, SIZE, SIZE])?;
+    let sample: Vec<f32> = SAMPLE
+        .iter()
+        .flat_map(|row| row.bytes().map(|p| if p == b'#' { 1.0 } else { 0.0 }))
+        .collect();
+    let predicted = Model::from_bytes(&blob)?.predict(&sample);
+    if predicted != SAMPLE_LABEL {
+        return Err(format!("the sample is classified as {}", predicted).into());
+    }
+    std::fs::write(&output, &blob)?;
+    println!("{} bytes written to {}", blob.len(), output);
+    Ok(())
+}
@@ -1,47 +1,282 @@
-use ndarray::Array1;
+#[cfg(feature = "std")]
+use ndarray::{Array, Axis, Dimension, Zip};
+#[cfg(feature = "std")]
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+// every derivative is computed from the cached pre-activation (the input of forward),
+// never from the activation output...
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Activation {
-    ReLu,    // linear regression
-    Sigmoid, // for classification purposes
-    Softmax, // for determining probability of variable class
+    ReLu,           // linear regression
+    LeakyReLu(f32), // ReLu with a small slope (alpha) for negative inputs
+    Sigmoid,        // for classification purposes
+    Tanh,           // zero centered squashing
+    Gelu,           // smooth ReLu (tanh approximation)
+    Softmax,        // for determining probability of variable class (along the last axis)
+    Identity,       // no activation
 }
+
+pub(crate) const GELU_COEFF: f32 = 0.044_715;
+pub(crate) const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+
+#[cfg(feature = "std")] // the no_std forward pass lives in `inference`
 impl Activation {
-    pub fn forward(x: Array1<f32>, activation: Activation) -> Array1<f32> {
+    pub fn forward<D: Dimension>(x: Array<f32, D>, activation: Activation) -> Array<f32, D> {
         match activation {
             Activation::ReLu => Activation::relu(x),
+            Activation::LeakyReLu(alpha) => Activation::leaky_relu(x, alpha),
             Activation::Sigmoid => Activation::sigmoid(x),
+            Activation::Tanh => x.mapv(f32::tanh),
+            Activation::Gelu => Activation::gelu(x),
             Activation::Softmax => Activation::softmax(x),
+            Activation::Identity => x,
         }
     }
-    pub fn backward(x: Array1<f32>, activation: Activation) -> Array1<f32> {
+
+    // "x" is the pre-activation, "grad" is dL/d(output); returns dL/dx...
+    pub fn backward<D: Dimension>(
+        x: Array<f32, D>,
+        grad: Array<f32, D>,
+        activation: Activation,
+    ) -> Array<f32, D> {
+        match activation {
+            Activation::Softmax => Activation::softmax_backward(x, grad),
+            _ => Activation::derivative(x, activation) * grad,
+        }
+    }
+
+    // element-wise derivative f'(x); softmax has no element-wise derivative so its
+    // jacobian diagonal is returned, use "backward" for the full gradient...
+    pub fn derivative<D: Dimension>(x: Array<f32, D>, activation: Activation) -> Array<f32, D> {
         match activation {
             Activation::ReLu => Activation::relu_derivative(x),
+            Activation::LeakyReLu(alpha) => Activation::leaky_relu_derivative(x, alpha),
             Activation::Sigmoid => Activation::sigmoid_derivative(x),
-            Activation::Softmax => Activation::softmax_derivative(x),
+            Activation::Tanh => x.mapv(|xi| 1.0 - xi.tanh().powi(2)),
+            Activation::Gelu => Activation::gelu_derivative(x),
+            Activation::Softmax => Activation::softmax(x).mapv(|si| si * (1.0 - si)),
+            Activation::Identity => x.mapv(|_| 1.0),
         }
     }
 
-    fn relu(x: Array1<f32>) -> Array1<f32> {
+    // fused softmax + categorical cross-entropy gradient w.r.t. the logits, averaged over
+    // every softmax lane (the batch). Much more stable than chaining both backward passes...
+    pub fn softmax_cross_entropy_backward<D: Dimension>(
+        logits: Array<f32, D>,
+        targets: &Array<f32, D>,
+    ) -> Array<f32, D> {
+        assert_eq!(
+            logits.shape(),
+            targets.shape(),
+            "logits and targets shape mismatch"
+        );
+        let lanes = Activation::lane_count(&logits);
+        (Activation::softmax(logits) - targets) / lanes as f32
+    }
+
+    fn relu<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
         x.mapv(|xi| if xi > 0.0 { xi } else { 0.0 })
     }
-    fn relu_derivative(x: Array1<f32>) -> Array1<f32> {
+    fn relu_derivative<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
         x.mapv(|xi| if xi > 0.0 { 1.0 } else { 0.0 })
     }
-    fn sigmoid(x: Array1<f32>) -> Array1<f32> {
+    fn leaky_relu<D: Dimension>(x: Array<f32, D>, alpha: f32) -> Array<f32, D> {
+        x.mapv(|xi| if xi > 0.0 { xi } else { alpha * xi })
+    }
+    fn leaky_relu_derivative<D: Dimension>(x: Array<f32, D>, alpha: f32) -> Array<f32, D> {
+        x.mapv(|xi| if xi > 0.0 { 1.0 } else { alpha })
+    }
+    fn sigmoid<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
         x.mapv(|xi| 1.0 / (1.0 + (-xi).exp()))
     }
-    fn sigmoid_derivative(x: Array1<f32>) -> Array1<f32> {
-        x.mapv(|xi| xi * (1.0 - xi))
+    fn sigmoid_derivative<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
+        // s'(x) = s(x) * (1 - s(x)), "x" being the pre-activation...
+        Activation::sigmoid(x).mapv(|si| si * (1.0 - si))
+    }
+    fn gelu<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
+        x.mapv(|xi| {
+            let inner = SQRT_2_OVER_PI * (xi + GELU_COEFF * xi.powi(3));
+            0.5 * xi * (1.0 + inner.tanh())
+        })
+    }
+    fn gelu_derivative<D: Dimension>(x: Array<f32, D>) -> Array<f32, D> {
+        x.mapv(|xi| {
+            let inner = SQRT_2_OVER_PI * (xi + GELU_COEFF * xi.powi(3));
+            let t = inner.tanh();
+            let d_inner = SQRT_2_OVER_PI * (1.0 + 3.0 * GELU_COEFF * xi.powi(2));
+            0.5 * (1.0 + t) + 0.5 * xi * (1.0 - t * t) * d_inner
+        })
     }
-    fn softmax(x: Array1<f32>) -> Array1<f32> {
-        let max = x.fold(x[0], |acc, &xi| if xi > acc { xi } else { acc });
-        let exps = x.mapv(|xi| (xi - max).exp());
-        let sums = exps.sum();
-        exps / sums
+    fn softmax<D: Dimension>(mut x: Array<f32, D>) -> Array<f32, D> {
+        let last = match Activation::last_axis(&x) {
+            Some(last) => last,
+            None => return x.mapv(|_| 1.0),
+        };
+        for mut lane in x.lanes_mut(last) {
+            let max = lane.fold(f32::NEG_INFINITY, |acc, &xi| acc.max(xi));
+            lane.mapv_inplace(|xi| (xi - max).exp());
+            let sums = lane.sum();
+            lane /= sums;
+        }
+        x
+    }
+    fn softmax_backward<D: Dimension>(x: Array<f32, D>, mut grad: Array<f32, D>) -> Array<f32, D> {
+        // jacobian-vector product per lane: s * (g - <g, s>)
+        let last = match Activation::last_axis(&x) {
+            Some(last) => last,
+            None => return grad.mapv(|_| 0.0), // s = 1, so g - <g, s> = 0
+        };
+        let s = Activation::softmax(x);
+        Zip::from(grad.lanes_mut(last))
+            .and(s.lanes(last))
+            .for_each(|mut g, s| {
+                let dot = g.dot(&s);
+                Zip::from(&mut g)
+                    .and(&s)
+                    .for_each(|gi, &si| *gi = si * (*gi - dot));
+            });
+        grad
+    }
+    fn lane_count<D: Dimension>(x: &Array<f32, D>) -> usize {
+        match Activation::last_axis(x) {
+            Some(last) => x.len() / x.len_of(last).max(1),
+            None => 1,
+        }
+    }
+    // the softmax axis; a 0-d array has none and is treated as a single lane of one element
+    pub(crate) fn last_axis<D: Dimension>(x: &Array<f32, D>) -> Option<Axis> {
+        x.ndim().checked_sub(1).map(Axis)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use ndarray::{array, Array1, Array2};
+
+    const EPS: f32 = 1e-2;
+    const TOL: f32 = 1e-2;
+
+    // L = sum(w * f(x)) so that dL/d(output) = w
+    fn objective<D: Dimension>(
+        x: &Array<f32, D>,
+        w: &Array<f32, D>,
+        activation: Activation,
+    ) -> f32 {
+        (Activation::forward(x.clone(), activation) * w).sum()
+    }
+
+    fn check_gradient<D: Dimension>(x: Array<f32, D>, activation: Activation) {
+        let w = x.map(|xi| (xi * 3.7 + 0.3).sin());
+        let analytic = Activation::backward(x.clone(), w.clone(), activation);
+        for (i, &a) in analytic.iter().enumerate() {
+            let mut plus = x.clone();
+            let mut minus = x.clone();
+            *plus.iter_mut().nth(i).unwrap() += EPS;
+            *minus.iter_mut().nth(i).unwrap() -= EPS;
+            let numeric = (objective(&plus, &w, activation) - objective(&minus, &w, activation))
+                / (2.0 * EPS);
+            assert!(
+                (numeric - a).abs() <= TOL * (1.0 + numeric.abs()),
+                "{:?}: index {} numeric {} analytic {}",
+                activation,
+                i,
+                numeric,
+                a
+            );
+        }
     }
-    fn softmax_derivative(x: Array1<f32>) -> Array1<f32> {
-        Array1::ones(x.len())
+
+    // keeps clear of the ReLu kink at zero
+    fn sample() -> Array1<f32> {
+        array![-2.1, -0.7, -0.3, 0.2, 0.9, 1.6, 3.0]
+    }
+
+    #[test]
+    fn relu_gradient() {
+        check_gradient(sample(), Activation::ReLu);
+    }
+
+    #[test]
+    fn leaky_relu_gradient() {
+        check_gradient(sample(), Activation::LeakyReLu(0.01));
+        check_gradient(sample(), Activation::LeakyReLu(0.2));
+    }
+
+    #[test]
+    fn sigmoid_gradient() {
+        check_gradient(sample(), Activation::Sigmoid);
+    }
+
+    #[test]
+    fn tanh_gradient() {
+        check_gradient(sample(), Activation::Tanh);
+    }
+
+    #[test]
+    fn gelu_gradient() {
+        check_gradient(sample(), Activation::Gelu);
+    }
+
+    #[test]
+    fn identity_gradient() {
+        check_gradient(sample(), Activation::Identity);
+    }
+
+    #[test]
+    fn softmax_gradient() {
+        check_gradient(sample(), Activation::Softmax);
+        let batch = Array2::from_shape_fn((3, 4), |(i, j)| (i as f32 - j as f32) * 0.4);
+        check_gradient(batch, Activation::Softmax);
+    }
+
+    #[test]
+    fn softmax_of_a_scalar() {
+        let x = ndarray::arr0(2.5_f32);
+        assert_eq!(Activation::forward(x.clone(), Activation::Softmax)[()], 1.0);
+        check_gradient(x.clone(), Activation::Softmax);
+        let grad = Activation::softmax_cross_entropy_backward(x, &ndarray::arr0(1.0));
+        assert_eq!(grad[()], 0.0);
+    }
+
+    #[test]
+    fn sigmoid_derivative_uses_pre_activation() {
+        let d = Activation::derivative(array![0.0_f32], Activation::Sigmoid);
+        assert!((d[0] - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn softmax_rows_sum_to_one() {
+        let x = array![[1.0_f32, 2.0, 3.0], [1000.0, 1000.0, 1000.0]];
+        let s = Activation::forward(x, Activation::Softmax);
+        for row in s.rows() {
+            assert!((row.sum() - 1.0).abs() < 1e-6);
+        }
+        assert!((s[[1, 0]] - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fused_softmax_cross_entropy_gradient() {
+        let logits = array![[0.5_f32, -1.2, 2.0], [0.1, 0.3, -0.4]];
+        let targets = array![[0.0_f32, 0.0, 1.0], [1.0, 0.0, 0.0]];
+        let loss = |z: &Array2<f32>| {
+            let s = Activation::forward(z.clone(), Activation::Softmax);
+            -(s.mapv(f32::ln) * &targets).sum() / z.nrows() as f32
+        };
+        let analytic = Activation::softmax_cross_entropy_backward(logits.clone(), &targets);
+        for ((i, j), &a) in analytic.indexed_iter() {
+            let mut plus = logits.clone();
+            let mut minus = logits.clone();
+            plus[[i, j]] += EPS;
+            minus[[i, j]] -= EPS;
+            let numeric = (loss(&plus) - loss(&minus)) / (2.0 * EPS);
+            assert!(
+                (numeric - a).abs() <= TOL,
+                "numeric {} analytic {}",
+                numeric,
+                a
+            );
+        }
     }
 }
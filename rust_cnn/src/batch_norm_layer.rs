@@ -0,0 +1,216 @@
+use crate::layers::{Layer, Param};
+use ndarray::{Array1, Array2, ArrayD, Axis, Ix1, Ix2, IxDyn};
+use serde::{Deserialize, Serialize};
+
+// [N, C] stays as is, [N, C, H, W] becomes [N * H * W, C]
+fn to_rows(x: &ArrayD<f32>) -> Array2<f32> {
+    match x.ndim() {
+        2 => x.view().into_dimensionality::<Ix2>().unwrap().to_owned(),
+        4 => {
+            let channels = x.shape()[1];
+            let rows = x.len() / channels;
+            x.view()
+                .permuted_axes(IxDyn(&[0, 2, 3, 1]))
+                .as_standard_layout()
+                .into_owned()
+                .into_shape((rows, channels))
+                .unwrap()
+        }
+        n => panic!("batch norm expects 2 or 4 dimensional input, got {}", n),
+    }
+}
+
+fn from_rows(rows: Array2<f32>, shape: &[usize]) -> ArrayD<f32> {
+    match shape.len() {
+        2 => rows.into_dyn(),
+        _ => rows
+            .into_shape(IxDyn(&[shape[0], shape[2], shape[3], shape[1]]))
+            .unwrap()
+            .permuted_axes(IxDyn(&[0, 3, 1, 2]))
+            .as_standard_layout()
+            .into_owned(),
+    }
+}
+
+// batch normalization over [N, features] or, per channel, over [N, channels, height, width].
+// training mode normalizes with the batch statistics and updates the running ones,
+// eval mode only uses the running statistics...
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BatchNormLayer {
+    pub gamma: Param, // scale
+    pub beta: Param,  // shift
+    pub running_mean: Array1<f32>,
+    pub running_var: Array1<f32>,
+    pub momentum: f32, // weight of the newest batch in the running statistics
+    pub epsilon: f32,
+    #[serde(skip, default = "crate::layers::training_default")]
+    training: bool,
+    #[serde(skip)]
+    cache: Option<(Array2<f32>, Array1<f32>, Vec<usize>)>, // (normalized input, 1/std, input shape)
+}
+
+impl BatchNormLayer {
+    pub fn new(features: usize) -> BatchNormLayer {
+        Self {
+            gamma: Param::new(ArrayD::ones(vec![features])),
+            beta: Param::new(ArrayD::zeros(vec![features])),
+            running_mean: Array1::zeros(features),
+            running_var: Array1::ones(features),
+            momentum: 0.1,
+            epsilon: 1e-5,
+            training: true,
+            cache: None,
+        }
+    }
+
+    pub fn features(&self) -> usize {
+        self.running_mean.len()
+    }
+
+    fn gamma(&self) -> Array1<f32> {
+        self.gamma
+            .value
+            .clone()
+            .into_dimensionality::<Ix1>()
+            .unwrap()
+    }
+    fn beta(&self) -> Array1<f32> {
+        self.beta
+            .value
+            .clone()
+            .into_dimensionality::<Ix1>()
+            .unwrap()
+    }
+}
+
+impl Layer for BatchNormLayer {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        let x = to_rows(input);
+        assert_eq!(x.ncols(), self.features(), "batch norm feature mismatch");
+        let (mean, var) = if self.training {
+            let mean = x.mean_axis(Axis(0)).unwrap();
+            let var = (&x - &mean).mapv(|d| d * d).mean_axis(Axis(0)).unwrap();
+            // running variance is unbiased
+            let count = x.nrows() as f32;
+            let unbiased = if count > 1.0 {
+                &var * (count / (count - 1.0))
+            } else {
+                var.clone()
+            };
+            let m = self.momentum;
+            self.running_mean = &self.running_mean * (1.0 - m) + &mean * m;
+            self.running_var = &self.running_var * (1.0 - m) + unbiased * m;
+            (mean, var)
+        } else {
+            (self.running_mean.clone(), self.running_var.clone())
+        };
+        let inv_std = var.mapv(|v| 1.0 / (v + self.epsilon).sqrt());
+        let normalized = (x - &mean) * &inv_std;
+        let output = &normalized * &self.gamma() + self.beta();
+        self.cache = Some((normalized, inv_std, input.shape().to_vec()));
+        from_rows(output, input.shape())
+    }
+
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        let (normalized, inv_std, shape) =
+            self.cache.take().expect("backward called before forward");
+        let grad = to_rows(grad);
+        self.gamma.grad = (&grad * &normalized).sum_axis(Axis(0)).into_dyn();
+        self.beta.grad = grad.sum_axis(Axis(0)).into_dyn();
+
+        let grad_normalized = grad * &self.gamma();
+        let input_grad = if self.training {
+            // the batch statistics depend on every sample of the batch too
+            let count = normalized.nrows() as f32;
+            let sum = grad_normalized.sum_axis(Axis(0));
+            let dot = (&grad_normalized * &normalized).sum_axis(Axis(0));
+            (grad_normalized * count - sum - normalized * dot) * (inv_std / count)
+        } else {
+            grad_normalized * inv_std
+        };
+        from_rows(input_grad, &shape)
+    }
+
+    fn params(&mut self) -> Vec<&mut Param> {
+        vec![&mut self.gamma, &mut self.beta]
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradient_check::GradientCheck;
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random(shape: Vec<usize>, rng: &mut StdRng) -> ArrayD<f32> {
+        ArrayD::from_shape_simple_fn(shape, || rng.gen_range(-2.0..2.0))
+    }
+
+    #[test]
+    fn training_output_is_normalized() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut layer = BatchNormLayer::new(3);
+        let output = layer.forward(&(random(vec![64, 3], &mut rng) * 5.0 + 3.0));
+        let rows = output.into_dimensionality::<Ix2>().unwrap();
+        for column in rows.columns() {
+            assert!(column.mean().unwrap().abs() < 1e-4);
+            assert!((column.mapv(|v| v * v).mean().unwrap() - 1.0).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn eval_uses_running_statistics() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut layer = BatchNormLayer::new(2);
+        for _ in 0..200 {
+            layer.forward(&(random(vec![32, 2, 3, 3], &mut rng) + 4.0));
+        }
+        assert!((layer.running_mean[0] - 4.0).abs() < 0.1);
+        assert!((layer.running_var[1] - 4.0 / 3.0).abs() < 0.1); // uniform(-2, 2)
+
+        layer.set_training(false);
+        let running_mean = layer.running_mean.clone();
+        let single = ArrayD::from_elem(vec![1, 2, 1, 1], 4.0);
+        let output = layer.forward(&single);
+        assert_eq!(layer.running_mean, running_mean);
+        assert!(output.iter().all(|v| v.abs() < 0.1));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+        #[test]
+        fn gradients_match_finite_differences(
+            batch in 2usize..5,
+            features in 1usize..4,
+            spatial in proptest::option::of(1usize..4),
+            training in any::<bool>(),
+            seed in any::<u64>(),
+        ) {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let shape = match spatial {
+                Some(size) => vec![batch, features, size, size + 1],
+                None => vec![batch, features],
+            };
+            let mut layer = BatchNormLayer::new(features);
+            layer.gamma = Param::new(random(vec![features], &mut rng));
+            layer.beta = Param::new(random(vec![features], &mut rng));
+            layer.running_var = Array1::from_elem(features, 0.7);
+            layer.set_training(training);
+            let input = random(shape, &mut rng);
+            // nearly constant batches make the normalization too steep for finite differences
+            prop_assume!(!training || to_rows(&input).std_axis(Axis(0), 0.0).iter().all(|&s| s > 0.2));
+            let check = GradientCheck { epsilon: 5e-3, tolerance: 2e-2 };
+            let result = check.check_layer(&mut layer, &input);
+            prop_assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+}
@@ -0,0 +1,745 @@
+use crate::activation::Activation;
+use crate::batch_norm_layer::BatchNormLayer;
+use crate::conv_layer::ConvLayer;
+use crate::dense_layer::DenseLayer;
+use crate::dropout_layer::DropoutLayer;
+use crate::layers::{ActivationLayer, FlattenLayer, Layer, LayerType, Param};
+use crate::loss::{Loss, LossFunction};
+use crate::metrics::{accuracy, ConfusionMatrix};
+use crate::optimizer::{Optimizer, OptimizerKind};
+use crate::pool_layer::PoolLayer;
+use crate::util::{argmax, Dataset};
+use ndarray::{Array1, ArrayD, Ix2};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+// architecture, weights and optimizer state, everything needed to resume training...
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Cnn {
+    pub layers: Vec<LayerType>,
+    pub loss: LossFunction,
+    pub optimizer: Optimizer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelFormat {
+    Json,   // human readable
+    Binary, // compact (bincode)
+}
+impl ModelFormat {
+    // ".json" files are JSON, everything else is binary
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ModelFormat {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ModelFormat::Json,
+            _ => ModelFormat::Binary,
+        }
+    }
+}
+
+fn invalid_data<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+// writes to a temporary file first so an interrupted save never leaves a broken file
+fn save_to<T: Serialize, P: AsRef<Path>>(value: &T, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        match ModelFormat::from_path(path) {
+            ModelFormat::Json => serde_json::to_writer(&mut writer, value).map_err(invalid_data)?,
+            ModelFormat::Binary => {
+                bincode::serialize_into(&mut writer, value).map_err(invalid_data)?
+            }
+        }
+        io::Write::flush(&mut writer)?;
+    }
+    fs::rename(tmp, path)
+}
+
+fn load_from<T: for<'de> Deserialize<'de>, P: AsRef<Path>>(path: P) -> io::Result<T> {
+    let reader = BufReader::new(File::open(path.as_ref())?);
+    match ModelFormat::from_path(path) {
+        ModelFormat::Json => serde_json::from_reader(reader).map_err(invalid_data),
+        ModelFormat::Binary => bincode::deserialize_from(reader).map_err(invalid_data),
+    }
+}
+
+impl Cnn {
+    pub fn new(loss: LossFunction, optimizer: Optimizer) -> Cnn {
+        Self {
+            layers: Vec::new(),
+            loss,
+            optimizer,
+        }
+    }
+
+    pub fn add_layer<L: Into<LayerType>>(mut self, layer: L) -> Cnn {
+        self.layers.push(layer.into());
+        self
+    }
+
+    pub fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        self.layers
+            .iter_mut()
+            .fold(input.clone(), |x, layer| layer.forward(&x))
+    }
+
+    pub fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        self.layers
+            .iter_mut()
+            .rev()
+            .fold(grad.clone(), |g, layer| layer.backward(&g))
+    }
+
+    // training mode: dropout is active and batch norm uses (and updates) batch statistics.
+    // fit switches to it on its own, evaluate and predict switch to eval mode
+    pub fn train(&mut self) {
+        self.layers
+            .iter_mut()
+            .for_each(|layer| layer.set_training(true));
+    }
+    pub fn eval(&mut self) {
+        self.layers
+            .iter_mut()
+            .for_each(|layer| layer.set_training(false));
+    }
+
+    // inference on a batch, in eval mode
+    pub fn predict(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        self.eval();
+        self.forward(input)
+    }
+
+    pub fn params(&mut self) -> Vec<&mut Param> {
+        self.layers
+            .iter_mut()
+            .flat_map(|layer| layer.params())
+            .collect()
+    }
+
+    // softmax followed by cross-entropy: the fused gradient is used on the logits
+    fn fused_softmax_cross_entropy(&self) -> bool {
+        let softmax_output = match self.layers.last() {
+            Some(LayerType::Activation(layer)) => layer.activation == Activation::Softmax,
+            _ => false,
+        };
+        softmax_output && self.loss == LossFunction::CategoricalCrossEntropy { from_logits: false }
+    }
+
+    // one optimizer step on a batch, returns the batch loss and the predictions
+    pub fn train_batch(
+        &mut self,
+        inputs: &ArrayD<f32>,
+        targets: &ArrayD<f32>,
+    ) -> (f32, ArrayD<f32>) {
+        let predictions = self.forward(inputs);
+        let (loss, grad) = self.loss.compute(&predictions, targets);
+
+        if self.fused_softmax_cross_entropy() {
+            let last = self.layers.len() - 1;
+            let logits = match &mut self.layers[last] {
+                LayerType::Activation(layer) => layer.input.take().unwrap(),
+                _ => unreachable!(),
+            };
+            let grad = Activation::softmax_cross_entropy_backward(logits, targets);
+            self.layers[..last]
+                .iter_mut()
+                .rev()
+                .fold(grad, |g, layer| layer.backward(&g));
+        } else {
+            self.backward(&grad);
+        }
+
+        let params = self
+            .layers
+            .iter_mut()
+            .flat_map(|layer| layer.params())
+            .collect();
+        self.optimizer.step(params);
+        (loss, predictions)
+    }
+
+    // loss and confusion matrix over a whole dataset
+    pub fn evaluate(&mut self, data: &Dataset, batch_size: usize) -> Evaluation {
+        let mut confusion = ConfusionMatrix::new(data.classes);
+        let mut total = 0.0;
+        for batch in data.batches(batch_size, None) {
+            let predictions = self.predict(&batch.inputs);
+            let (loss, _) = self.loss.compute(&predictions, &batch.targets.into_dyn());
+            total += loss * batch.labels.len() as f32;
+            confusion.add(&batch.labels, &predicted_classes(predictions));
+        }
+        Evaluation {
+            loss: total / data.len().max(1) as f32,
+            confusion,
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        save_to(self, path)
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Cnn> {
+        load_from(path)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("model is always serializable")
+    }
+    pub fn from_json(json: &str) -> io::Result<Cnn> {
+        serde_json::from_str(json).map_err(invalid_data)
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("model is always serializable")
+    }
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Cnn> {
+        bincode::deserialize(bytes).map_err(invalid_data)
+    }
+
+    // shape of a single sample's output for a single sample of "input_shape". Fails like
+    // ModelSpec::build when a layer doesn't fit what it gets, or when its weights were made for
+    // a different number of channels or features
+    pub fn output_shape(&self, input_shape: &[usize]) -> io::Result<Vec<usize>> {
+        let mut shape = input_shape.to_vec();
+        for (i, layer) in self.layers.iter().enumerate() {
+            let (spec, inputs) = layer_spec(layer);
+            check_layer(&spec, &shape)
+                .and_then(|()| match inputs {
+                    Some(inputs) if inputs != shape[0] => {
+                        Err("weights were made for a different input size")
+                    }
+                    _ => Ok(()),
+                })
+                .map_err(|msg| {
+                    invalid_data(format!("layer {} ({:?}) on {:?}: {}", i, spec, shape, msg))
+                })?;
+            shape = layer.output_shape(&shape);
+        }
+        Ok(shape)
+    }
+
+    // trains from "start_epoch" up to "config.epochs", "on_epoch" sees the stats of every epoch.
+    // epoch "e" is shuffled with "seed + e" (wrapping) so a resumed run sees the same batches...
+    pub fn fit<F: FnMut(&EpochStats)>(
+        &mut self,
+        train: &Dataset,
+        validation: Option<&Dataset>,
+        config: &TrainConfig,
+        start_epoch: usize,
+        mut on_epoch: F,
+    ) -> io::Result<Vec<EpochStats>> {
+        let mut history = Vec::new();
+        for epoch in start_epoch..config.epochs {
+            self.train(); // the validation pass of the previous epoch left it in eval mode
+            let (mut total, mut correct) = (0.0, 0.0);
+            for batch in train.batches(
+                config.batch_size,
+                Some(config.seed.wrapping_add(epoch as u64)),
+            ) {
+                let batch_len = batch.labels.len() as f32;
+                let (loss, predictions) =
+                    self.train_batch(&batch.inputs, &batch.targets.into_dyn());
+                total += loss * batch_len;
+                correct += accuracy(&batch.labels, &predicted_classes(predictions)) * batch_len;
+            }
+            let samples = train.len().max(1) as f32;
+            let stats = EpochStats {
+                epoch: epoch + 1,
+                loss: total / samples,
+                accuracy: correct / samples,
+                validation: validation.map(|data| self.evaluate(data, config.batch_size)),
+            };
+            on_epoch(&stats);
+            history.push(stats);
+
+            if let Some(path) = &config.checkpoint {
+                Checkpoint {
+                    epoch: epoch + 1,
+                    model: self.clone(),
+                }
+                .save(path)?;
+            }
+        }
+        Ok(history)
+    }
+}
+
+// class with the highest score for every sample of a [batch, classes] output
+fn predicted_classes(predictions: ArrayD<f32>) -> Array1<usize> {
+    argmax(&predictions.into_dimensionality::<Ix2>().unwrap())
+}
+
+#[derive(Clone, Debug)]
+pub struct Evaluation {
+    pub loss: f32,
+    pub confusion: ConfusionMatrix,
+}
+impl Evaluation {
+    pub fn accuracy(&self) -> f32 {
+        self.confusion.accuracy()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EpochStats {
+    pub epoch: usize, // 1 based
+    pub loss: f32,
+    pub accuracy: f32, // on the training batches, measured while training
+    pub validation: Option<Evaluation>,
+}
+
+// architecture description read by the training binary, e.g.
+// {"input_shape": [1, 28, 28], "layers": [{"Conv": {"filters": 8, "kernel": 3, "stride": 1,
+// "padding": 1}}, {"Activation": "ReLu"}, {"Pool": {"size": 2, "stride": 2}}, "Flatten",
+// {"Dense": {"units": 10}}, {"Activation": "Softmax"}], "loss": ..., "optimizer": "Sgd"}
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ModelSpec {
+    pub input_shape: Vec<usize>, // single sample, without the batch axis
+    pub layers: Vec<LayerSpec>,
+    pub loss: LossFunction,
+    pub optimizer: OptimizerKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LayerSpec {
+    Conv {
+        filters: usize,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+    },
+    Pool {
+        size: usize,
+        stride: usize,
+    },
+    Dense {
+        units: usize,
+    },
+    Activation(Activation),
+    Flatten,
+    BatchNorm,
+    Dropout {
+        rate: f32,
+    },
+}
+
+impl ModelSpec {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ModelSpec> {
+        serde_json::from_reader(BufReader::new(File::open(path)?)).map_err(invalid_data)
+    }
+
+    // input sizes of every layer are inferred from "input_shape", weights are seeded.
+    // fails when a layer doesn't fit the shape it receives or the model doesn't end
+    // with one output per class
+    pub fn build(&self, classes: usize, learning_rate: f32, seed: u64) -> io::Result<Cnn> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut model = Cnn::new(self.loss, Optimizer::new(self.optimizer, learning_rate));
+        let mut shape = self.input_shape.clone();
+        for (i, spec) in self.layers.iter().enumerate() {
+            check_layer(spec, &shape).map_err(|msg| {
+                invalid_data(format!("layer {} ({:?}) on {:?}: {}", i, spec, shape, msg))
+            })?;
+            let layer: LayerType = match *spec {
+                LayerSpec::Conv {
+                    filters,
+                    kernel,
+                    stride,
+                    padding,
+                } => ConvLayer::new(shape[0], filters, kernel, stride, padding, &mut rng).into(),
+                LayerSpec::Pool { size, stride } => PoolLayer::new(size, stride).into(),
+                LayerSpec::Dense { units } => DenseLayer::new(shape[0], units, &mut rng).into(),
+                LayerSpec::Activation(activation) => ActivationLayer::new(activation).into(),
+                LayerSpec::Flatten => FlattenLayer::new().into(),
+                LayerSpec::BatchNorm => BatchNormLayer::new(shape[0]).into(),
+                LayerSpec::Dropout { rate } => DropoutLayer::new(rate, rng.gen()).into(),
+            };
+            shape = layer.output_shape(&shape);
+            model.layers.push(layer);
+        }
+        if shape != [classes] {
+            return Err(invalid_data(format!(
+                "the model outputs {:?} but the data has {} classes",
+                shape, classes
+            )));
+        }
+        Ok(model)
+    }
+}
+
+// the spec "layer" could have been built from, plus the channels or features its weights expect
+fn layer_spec(layer: &LayerType) -> (LayerSpec, Option<usize>) {
+    match layer {
+        LayerType::Conv(conv) => (
+            LayerSpec::Conv {
+                filters: conv.filters(),
+                kernel: conv.kernel(),
+                stride: conv.stride,
+                padding: conv.padding,
+            },
+            Some(conv.channels()),
+        ),
+        LayerType::Pool(pool) => (
+            LayerSpec::Pool {
+                size: pool.size,
+                stride: pool.stride,
+            },
+            None,
+        ),
+        LayerType::Dense(dense) => (
+            LayerSpec::Dense {
+                units: dense.outputs(),
+            },
+            Some(dense.inputs()),
+        ),
+        LayerType::Activation(layer) => (LayerSpec::Activation(layer.activation), None),
+        LayerType::Flatten(_) => (LayerSpec::Flatten, None),
+        LayerType::BatchNorm(norm) => (LayerSpec::BatchNorm, Some(norm.features())),
+        LayerType::Dropout(dropout) => (LayerSpec::Dropout { rate: dropout.rate }, None),
+    }
+}
+
+// what the layer constructors and forward passes would otherwise panic on,
+// shape is a single sample without the batch axis
+fn check_layer(spec: &LayerSpec, shape: &[usize]) -> Result<(), &'static str> {
+    let image = |window: usize, padding: usize| match *shape {
+        [_, h, w] if h + 2 * padding >= window && w + 2 * padding >= window => Ok(()),
+        [_, _, _] => Err("window larger than the input"),
+        _ => Err("expects [channels, height, width] input"),
+    };
+    match *spec {
+        LayerSpec::Conv {
+            filters,
+            kernel,
+            stride,
+            padding,
+        } => {
+            if filters == 0 || kernel == 0 || stride == 0 {
+                return Err("filters, kernel and stride must be positive");
+            }
+            image(kernel, padding)
+        }
+        LayerSpec::Pool { size, stride } => {
+            if size == 0 || stride == 0 {
+                return Err("pool size and stride must be positive");
+            }
+            image(size, 0)
+        }
+        LayerSpec::Dense { units } => match shape {
+            _ if units == 0 => Err("units must be positive"),
+            [_] => Ok(()),
+            _ => Err("expects flat input, add a Flatten layer first"),
+        },
+        LayerSpec::BatchNorm => match shape.len() {
+            1 | 3 => Ok(()),
+            _ => Err("expects [features] or [channels, height, width] input"),
+        },
+        LayerSpec::Dropout { rate } if !(0.0..1.0).contains(&rate) => {
+            Err("dropout rate must be in [0, 1)")
+        }
+        LayerSpec::Activation(_) | LayerSpec::Flatten | LayerSpec::Dropout { .. } => Ok(()),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TrainConfig {
+    pub epochs: usize,
+    pub batch_size: usize,
+    pub seed: u64,
+    pub checkpoint: Option<PathBuf>, // rewritten after every epoch
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Checkpoint {
+    pub epoch: usize, // number of completed epochs
+    pub model: Cnn,
+}
+impl Checkpoint {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        save_to(self, path)
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Checkpoint> {
+        load_from(path)
+    }
+
+    // continues training where the checkpoint stopped, fails if "train" doesn't fit the model
+    pub fn resume<F: FnMut(&EpochStats)>(
+        mut self,
+        train: &Dataset,
+        validation: Option<&Dataset>,
+        config: &TrainConfig,
+        on_epoch: F,
+    ) -> io::Result<(Cnn, Vec<EpochStats>)> {
+        let output = self.model.output_shape(&train.inputs.shape()[1..])?;
+        if output != [train.classes] {
+            return Err(invalid_data(format!(
+                "the model outputs {:?} but the data has {} classes",
+                output, train.classes
+            )));
+        }
+        let history = self
+            .model
+            .fit(train, validation, config, self.epoch, on_epoch)?;
+        Ok((self.model, history))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array4;
+
+    fn model() -> Cnn {
+        let mut rng = StdRng::seed_from_u64(3);
+        Cnn::new(
+            LossFunction::CategoricalCrossEntropy { from_logits: false },
+            Optimizer::adam(0.01),
+        )
+        .add_layer(ConvLayer::new(1, 2, 3, 1, 1, &mut rng))
+        .add_layer(ActivationLayer::new(Activation::ReLu))
+        .add_layer(PoolLayer::new(2, 2))
+        .add_layer(FlattenLayer::new())
+        .add_layer(DenseLayer::new(8, 3, &mut rng))
+        .add_layer(ActivationLayer::new(Activation::Softmax))
+    }
+
+    fn dataset() -> Dataset {
+        let inputs = Array4::from_shape_fn((6, 1, 4, 4), |(n, _, i, j)| {
+            ((n * 7 + i * 3 + j) % 5) as f32 / 5.0
+        });
+        Dataset::new(inputs.into_dyn(), Array1::from(vec![0, 1, 2, 0, 1, 2]), 3)
+    }
+
+    fn config(checkpoint: Option<PathBuf>) -> TrainConfig {
+        TrainConfig {
+            epochs: 3,
+            batch_size: 4,
+            seed: 11,
+            checkpoint,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_cnn_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn training_reduces_loss() {
+        let config = TrainConfig {
+            epochs: 30,
+            ..config(None)
+        };
+        let history = model()
+            .fit(&dataset(), Some(&dataset()), &config, 0, |_| {})
+            .unwrap();
+        let last = history.last().unwrap();
+        assert!(last.loss < history[0].loss);
+        assert!(last.validation.as_ref().unwrap().accuracy() > 0.5);
+    }
+
+    #[test]
+    fn any_seed_is_accepted() {
+        let config = TrainConfig {
+            seed: u64::MAX,
+            ..config(None)
+        };
+        let history = model().fit(&dataset(), None, &config, 0, |_| {}).unwrap();
+        assert_eq!(history.len(), 3);
+    }
+
+    #[test]
+    fn json_and_binary_round_trip() {
+        let mut trained = model();
+        trained
+            .fit(&dataset(), None, &config(None), 0, |_| {})
+            .unwrap();
+        let input = dataset().inputs;
+        let expected = trained.forward(&input);
+
+        let mut from_json = Cnn::from_json(&trained.to_json()).unwrap();
+        let mut from_bytes = Cnn::from_bytes(&trained.to_bytes()).unwrap();
+        assert_eq!(from_json.forward(&input), expected);
+        assert_eq!(from_bytes.forward(&input), expected);
+        assert_eq!(from_bytes.optimizer, trained.optimizer);
+        assert!(trained.to_bytes().len() < trained.to_json().len());
+    }
+
+    #[test]
+    fn save_and_load_files() {
+        let mut trained = model();
+        for name in ["model.json", "model.bin"] {
+            let path = temp_path(name);
+            trained.save(&path).unwrap();
+            let mut loaded = Cnn::load(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(
+                loaded.forward(&dataset().inputs),
+                trained.forward(&dataset().inputs)
+            );
+        }
+        assert!(Cnn::from_bytes(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn resuming_from_checkpoint_matches_uninterrupted_training() {
+        let mut straight = model();
+        straight
+            .fit(&dataset(), None, &config(None), 0, |_| {})
+            .unwrap();
+
+        // stop after the first epoch, then resume from the file
+        let path = temp_path("checkpoint.bin");
+        let first = TrainConfig {
+            epochs: 1,
+            ..config(Some(path.clone()))
+        };
+        model().fit(&dataset(), None, &first, 0, |_| {}).unwrap();
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.epoch, 1);
+        let (mut resumed, history) = checkpoint
+            .resume(&dataset(), None, &config(None), |_| {})
+            .unwrap();
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].epoch, 2);
+        assert_eq!(resumed.optimizer.steps(), straight.optimizer.steps());
+        assert_eq!(
+            resumed.forward(&dataset().inputs),
+            straight.forward(&dataset().inputs)
+        );
+    }
+
+    #[test]
+    fn resuming_rejects_data_that_doesnt_fit() {
+        let checkpoint = Checkpoint {
+            epoch: 1,
+            model: model(),
+        };
+        let four_classes = Dataset {
+            classes: 4,
+            ..dataset()
+        };
+        let bigger = Dataset::new(Array4::zeros((2, 1, 6, 6)).into_dyn(), Array1::zeros(2), 3);
+        let two_channels =
+            Dataset::new(Array4::zeros((2, 2, 4, 4)).into_dyn(), Array1::zeros(2), 3);
+        for data in [four_classes, bigger, two_channels] {
+            let err = checkpoint
+                .clone()
+                .resume(&data, None, &config(None), |_| {})
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(model().output_shape(&[1, 4, 4]).unwrap(), [3]);
+    }
+
+    #[test]
+    fn spec_builds_model() {
+        let json = r#"{
+            "input_shape": [1, 4, 4],
+            "layers": [
+                {"Conv": {"filters": 2, "kernel": 3, "stride": 1, "padding": 1}},
+                {"Activation": "ReLu"},
+                {"Pool": {"size": 2, "stride": 2}},
+                "Flatten",
+                {"Dense": {"units": 3}},
+                {"Activation": "Softmax"}
+            ],
+            "loss": {"CategoricalCrossEntropy": {"from_logits": false}},
+            "optimizer": {"Adam": {"beta1": 0.9, "beta2": 0.999, "epsilon": 1e-8}}
+        }"#;
+        let spec: ModelSpec = serde_json::from_str(json).unwrap();
+        let mut built = spec.build(3, 0.01, 3).unwrap();
+        assert_eq!(built.to_json(), model().to_json());
+        assert_eq!(built.forward(&dataset().inputs).shape(), &[6, 3]);
+    }
+
+    #[test]
+    fn spec_rejects_layers_that_dont_fit() {
+        let spec = |layers: Vec<LayerSpec>| ModelSpec {
+            input_shape: vec![1, 8, 8],
+            layers,
+            loss: LossFunction::CategoricalCrossEntropy { from_logits: true },
+            optimizer: OptimizerKind::Sgd,
+        };
+        let dense = |units| LayerSpec::Dense { units };
+        let rejected = [
+            vec![LayerSpec::Flatten, dense(5)],
+            vec![
+                LayerSpec::Pool {
+                    size: 16,
+                    stride: 1,
+                },
+                LayerSpec::Flatten,
+                dense(10),
+            ],
+            vec![
+                LayerSpec::Conv {
+                    filters: 2,
+                    kernel: 11,
+                    stride: 1,
+                    padding: 1,
+                },
+                LayerSpec::Flatten,
+                dense(10),
+            ],
+            vec![
+                LayerSpec::Flatten,
+                LayerSpec::Pool { size: 2, stride: 2 },
+                dense(10),
+            ],
+            vec![dense(10)],
+        ];
+        for layers in rejected {
+            let err = spec(layers.clone()).build(10, 0.01, 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", layers);
+        }
+        assert!(
+            spec(vec![LayerSpec::Flatten, LayerSpec::BatchNorm, dense(10)])
+                .build(10, 0.01, 1)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn train_and_eval_modes() {
+        let spec = ModelSpec {
+            input_shape: vec![1, 4, 4],
+            layers: vec![
+                LayerSpec::Conv {
+                    filters: 2,
+                    kernel: 3,
+                    stride: 1,
+                    padding: 1,
+                },
+                LayerSpec::BatchNorm,
+                LayerSpec::Activation(Activation::ReLu),
+                LayerSpec::Flatten,
+                LayerSpec::Dropout { rate: 0.5 },
+                LayerSpec::Dense { units: 3 },
+                LayerSpec::BatchNorm,
+                LayerSpec::Activation(Activation::Softmax),
+            ],
+            loss: LossFunction::CategoricalCrossEntropy { from_logits: false },
+            optimizer: OptimizerKind::Sgd,
+        };
+        let mut model = spec.build(3, 0.05, 5).unwrap();
+        let history = model
+            .fit(&dataset(), None, &config(None), 0, |_| {})
+            .unwrap();
+        assert!(history.iter().all(|stats| stats.loss.is_finite()));
+
+        // dropout draws a new mask on every training pass, eval is deterministic
+        let inputs = dataset().inputs;
+        model.train();
+        assert_ne!(model.forward(&inputs), model.forward(&inputs));
+        let expected = model.predict(&inputs);
+        assert_eq!(model.forward(&inputs), expected);
+
+        // running statistics are persisted, the mode is not
+        let mut loaded = Cnn::from_bytes(&model.to_bytes()).unwrap();
+        assert_eq!(loaded.predict(&inputs), expected);
+    }
+}
@@ -0,0 +1,379 @@
+use crate::layers::{Layer, Param};
+use ndarray::{
+    s, Array1, Array2, Array3, Array4, ArrayD, ArrayView2, ArrayView3, ArrayViewMut2,
+    ArrayViewMut3, Axis, Ix1, Ix4, Zip,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// runs a Zip over the samples of a batch, on every core with the "parallel" feature
+macro_rules! for_each_sample {
+    ($zip:expr, $body:expr) => {{
+        #[cfg(feature = "parallel")]
+        $zip.par_for_each($body);
+        #[cfg(not(feature = "parallel"))]
+        $zip.for_each($body);
+    }};
+}
+
+// 2D convolution (cross-correlation) over [N, channels, height, width] inputs,
+// kernels: [filters, channels, kernel, kernel], bias: [filters].
+// every sample gets unrolled into columns (im2col) so the convolution becomes one GEMM...
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConvLayer {
+    pub kernels: Param,
+    pub bias: Param,
+    pub stride: usize,
+    pub padding: usize, // zero padding on every side
+    #[serde(skip)]
+    cols: Option<Array3<f32>>, // cached im2col matrices, [N, channels * kernel^2, out_h * out_w]
+    #[serde(skip)]
+    input_dim: (usize, usize, usize, usize), // padded input
+}
+
+impl ConvLayer {
+    pub fn new<R: Rng>(
+        channels: usize,
+        filters: usize,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+        rng: &mut R,
+    ) -> ConvLayer {
+        assert!(
+            kernel > 0 && stride > 0,
+            "kernel and stride must be positive"
+        );
+        let fan_in = channels * kernel * kernel;
+        Self {
+            kernels: Param::he_uniform(&[filters, channels, kernel, kernel], fan_in, rng),
+            bias: Param::new(ArrayD::zeros(vec![filters])),
+            stride,
+            padding,
+            cols: None,
+            input_dim: (0, 0, 0, 0),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.kernels.value.shape()[1]
+    }
+    pub fn filters(&self) -> usize {
+        self.kernels.value.shape()[0]
+    }
+    pub fn kernel(&self) -> usize {
+        self.kernels.value.shape()[2]
+    }
+
+    // (height, width) of the output for a (height, width) input
+    pub fn output_size(&self, height: usize, width: usize) -> (usize, usize) {
+        let (k, p, s) = (self.kernel(), self.padding, self.stride);
+        assert!(
+            height + 2 * p >= k && width + 2 * p >= k,
+            "input smaller than the kernel"
+        );
+        ((height + 2 * p - k) / s + 1, (width + 2 * p - k) / s + 1)
+    }
+
+    fn pad(&self, input: Array4<f32>) -> Array4<f32> {
+        if self.padding == 0 {
+            return input;
+        }
+        let (n, c, h, w) = input.dim();
+        let p = self.padding;
+        let mut padded = Array4::zeros((n, c, h + 2 * p, w + 2 * p));
+        padded
+            .slice_mut(s![.., .., p..p + h, p..p + w])
+            .assign(&input);
+        padded
+    }
+
+    // kernels as a [filters, channels * kernel^2] matrix
+    fn kernel_matrix(&self) -> Array2<f32> {
+        let rows = self.filters();
+        let cols = self.kernels.value.len() / rows;
+        self.kernels
+            .value
+            .to_shape((rows, cols))
+            .unwrap()
+            .into_owned()
+    }
+
+    // one column per output position, one row per (channel, kernel row, kernel col)
+    fn im2col(&self, sample: ArrayView3<f32>, mut cols: ArrayViewMut2<f32>, out: (usize, usize)) {
+        let (k, s) = (self.kernel(), self.stride);
+        let (out_h, out_w) = out;
+        for c in 0..sample.len_of(Axis(0)) {
+            for ki in 0..k {
+                for kj in 0..k {
+                    let row = (c * k + ki) * k + kj;
+                    let window = sample.slice(s![
+                        c,
+                        ki..ki + s * (out_h - 1) + 1;s,
+                        kj..kj + s * (out_w - 1) + 1;s
+                    ]);
+                    let mut target = cols.row_mut(row);
+                    let mut target = target.view_mut().into_shape((out_h, out_w)).unwrap();
+                    target.assign(&window);
+                }
+            }
+        }
+    }
+
+    // inverse of im2col, overlapping windows are summed up
+    fn col2im(&self, cols: ArrayView2<f32>, mut sample: ArrayViewMut3<f32>, out: (usize, usize)) {
+        let (k, s) = (self.kernel(), self.stride);
+        let (out_h, out_w) = out;
+        for c in 0..sample.len_of(Axis(0)) {
+            for ki in 0..k {
+                for kj in 0..k {
+                    let row = (c * k + ki) * k + kj;
+                    let source = cols.row(row).into_shape((out_h, out_w)).unwrap();
+                    let mut window = sample.slice_mut(s![
+                        c,
+                        ki..ki + s * (out_h - 1) + 1;s,
+                        kj..kj + s * (out_w - 1) + 1;s
+                    ]);
+                    window += &source;
+                }
+            }
+        }
+    }
+
+    // straightforward nested loop version, kept as the reference implementation
+    pub fn naive_forward(&self, input: &Array4<f32>) -> Array4<f32> {
+        let padded = self.pad(input.clone());
+        let kernels = self
+            .kernels
+            .value
+            .view()
+            .into_dimensionality::<Ix4>()
+            .unwrap();
+        let bias = self.bias.value.view().into_dimensionality::<Ix1>().unwrap();
+        let (k, s) = (self.kernel(), self.stride);
+        let (out_h, out_w) = self.output_size(input.shape()[2], input.shape()[3]);
+        let batch = padded.len_of(Axis(0));
+        let mut output = Array4::zeros((batch, self.filters(), out_h, out_w));
+        for n in 0..batch {
+            for f in 0..self.filters() {
+                let kernel = kernels.index_axis(Axis(0), f);
+                for i in 0..out_h {
+                    for j in 0..out_w {
+                        let window = padded.slice(s![n, .., i * s..i * s + k, j * s..j * s + k]);
+                        output[[n, f, i, j]] = (&window * &kernel).sum() + bias[f];
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    // reference backward pass: (input grad, kernel grad, bias grad)
+    pub fn naive_backward(
+        &self,
+        input: &Array4<f32>,
+        grad: &Array4<f32>,
+    ) -> (Array4<f32>, Array4<f32>, Array1<f32>) {
+        let padded = self.pad(input.clone());
+        let kernels = self
+            .kernels
+            .value
+            .view()
+            .into_dimensionality::<Ix4>()
+            .unwrap();
+        let (k, s, p) = (self.kernel(), self.stride, self.padding);
+        let (batch, _, out_h, out_w) = grad.dim();
+
+        let mut kernel_grad = Array4::<f32>::zeros(kernels.raw_dim());
+        let mut input_grad = Array4::<f32>::zeros(padded.raw_dim());
+        for n in 0..batch {
+            for f in 0..self.filters() {
+                let kernel = kernels.index_axis(Axis(0), f);
+                for i in 0..out_h {
+                    for j in 0..out_w {
+                        let g = grad[[n, f, i, j]];
+                        let window = padded.slice(s![n, .., i * s..i * s + k, j * s..j * s + k]);
+                        kernel_grad
+                            .index_axis_mut(Axis(0), f)
+                            .scaled_add(g, &window);
+                        input_grad
+                            .slice_mut(s![n, .., i * s..i * s + k, j * s..j * s + k])
+                            .scaled_add(g, &kernel);
+                    }
+                }
+            }
+        }
+        let bias_grad = grad.sum_axis(Axis(3)).sum_axis(Axis(2)).sum_axis(Axis(0));
+        let (h, w) = (input.shape()[2], input.shape()[3]);
+        let input_grad = input_grad.slice(s![.., .., p..p + h, p..p + w]).to_owned();
+        (input_grad, kernel_grad, bias_grad)
+    }
+}
+
+impl Layer for ConvLayer {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        let input = input
+            .clone()
+            .into_dimensionality::<Ix4>()
+            .expect("conv layer expects [batch, channels, height, width]");
+        assert_eq!(
+            input.shape()[1],
+            self.channels(),
+            "conv layer channel mismatch"
+        );
+        let out = self.output_size(input.shape()[2], input.shape()[3]);
+        let padded = self.pad(input);
+        let batch = padded.len_of(Axis(0));
+        let kernels = self.kernel_matrix();
+        let bias = self
+            .bias
+            .value
+            .view()
+            .into_dimensionality::<Ix1>()
+            .unwrap()
+            .insert_axis(Axis(1));
+
+        let mut cols = Array3::zeros((batch, kernels.ncols(), out.0 * out.1));
+        let mut output = Array3::zeros((batch, self.filters(), out.0 * out.1));
+        let this = &*self;
+        for_each_sample!(
+            Zip::from(cols.outer_iter_mut())
+                .and(output.outer_iter_mut())
+                .and(padded.outer_iter()),
+            |mut col, mut out_n, sample| {
+                this.im2col(sample, col.view_mut(), out);
+                out_n.assign(&(kernels.dot(&col) + bias));
+            }
+        );
+        self.cols = Some(cols);
+        self.input_dim = padded.dim();
+        output
+            .into_shape((batch, self.filters(), out.0, out.1))
+            .unwrap()
+            .into_dyn()
+    }
+
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        let cols = self.cols.take().expect("backward called before forward");
+        let (batch, channels, h, w) = self.input_dim;
+        let grad = grad.view().into_dimensionality::<Ix4>().unwrap();
+        let out = (grad.shape()[2], grad.shape()[3]);
+        let grad = grad
+            .to_shape((batch, self.filters(), out.0 * out.1))
+            .unwrap();
+        let kernels = self.kernel_matrix();
+
+        // per sample kernel gradients, summed up afterwards
+        let mut kernel_grads = Array3::zeros((batch, kernels.nrows(), kernels.ncols()));
+        let mut input_grad = Array4::zeros((batch, channels, h, w));
+        let this = &*self;
+        for_each_sample!(
+            Zip::from(kernel_grads.outer_iter_mut())
+                .and(input_grad.outer_iter_mut())
+                .and(grad.outer_iter())
+                .and(cols.outer_iter()),
+            |mut kernel_grad, input_grad, g, col| {
+                kernel_grad.assign(&g.dot(&col.t()));
+                this.col2im(kernels.t().dot(&g).view(), input_grad, out);
+            }
+        );
+        self.kernels.grad = kernel_grads
+            .sum_axis(Axis(0))
+            .into_shape(self.kernels.value.raw_dim())
+            .unwrap();
+        self.bias.grad = grad.sum_axis(Axis(2)).sum_axis(Axis(0)).into_dyn();
+
+        let p = self.padding;
+        input_grad
+            .slice(s![.., .., p..h - p, p..w - p])
+            .to_owned()
+            .into_dyn()
+    }
+
+    fn params(&mut self) -> Vec<&mut Param> {
+        vec![&mut self.kernels, &mut self.bias]
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let (out_h, out_w) = self.output_size(input_shape[1], input_shape[2]);
+        vec![self.filters(), out_h, out_w]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradient_check::GradientCheck;
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_input(shape: (usize, usize, usize, usize), rng: &mut StdRng) -> Array4<f32> {
+        Array4::from_shape_simple_fn(shape, || rng.gen_range(-1.0..1.0))
+    }
+
+    fn assert_close<D: ndarray::Dimension>(a: &ndarray::Array<f32, D>, b: &ndarray::Array<f32, D>) {
+        assert_eq!(a.shape(), b.shape());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() <= 1e-4 * (1.0 + y.abs()), "{} vs {}", x, y);
+        }
+    }
+
+    #[test]
+    fn im2col_matches_naive_reference() {
+        let mut rng = StdRng::seed_from_u64(5);
+        // (batch, channels, height, width, filters, kernel, stride, padding)
+        let cases = [
+            (2, 1, 6, 6, 3, 3, 1, 0),
+            (3, 2, 7, 5, 4, 3, 2, 1),
+            (1, 3, 5, 5, 2, 1, 1, 0),
+            (2, 2, 8, 8, 2, 5, 3, 2),
+        ];
+        for &(n, c, h, w, f, k, s, p) in &cases {
+            let mut layer = ConvLayer::new(c, f, k, s, p, &mut rng);
+            layer.bias = Param::new(ArrayD::from_shape_simple_fn(vec![f], || {
+                rng.gen_range(-1.0..1.0)
+            }));
+            let input = random_input((n, c, h, w), &mut rng);
+            let output = layer.forward(&input.clone().into_dyn());
+            let expected = layer.naive_forward(&input);
+            assert_close(&output, &expected.clone().into_dyn());
+
+            let grad =
+                Array4::from_shape_simple_fn(expected.raw_dim(), || rng.gen_range(-1.0..1.0));
+            let input_grad = layer.backward(&grad.clone().into_dyn());
+            let (naive_input, naive_kernel, naive_bias) = layer.naive_backward(&input, &grad);
+            assert_close(&input_grad, &naive_input.into_dyn());
+            assert_close(&layer.kernels.grad, &naive_kernel.into_dyn());
+            assert_close(&layer.bias.grad, &naive_bias.into_dyn());
+        }
+    }
+
+    #[test]
+    fn output_shape_follows_stride_and_padding() {
+        let layer = ConvLayer::new(3, 4, 3, 2, 1, &mut StdRng::seed_from_u64(0));
+        assert_eq!(layer.output_shape(&[3, 28, 28]), vec![4, 14, 14]);
+        assert_eq!(layer.output_shape(&[3, 5, 6]), vec![4, 3, 3]);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(24))]
+        #[test]
+        fn gradients_match_finite_differences(
+            batch in 1usize..3,
+            channels in 1usize..3,
+            filters in 1usize..3,
+            kernel in 1usize..4,
+            stride in 1usize..3,
+            padding in 0usize..2,
+            extra in 0usize..4,
+            seed in any::<u64>(),
+        ) {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut layer = ConvLayer::new(channels, filters, kernel, stride, padding, &mut rng);
+            let size = kernel + extra;
+            let input = random_input((batch, channels, size, size + 1), &mut rng).into_dyn();
+            let result = GradientCheck::default().check_layer(&mut layer, &input);
+            prop_assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+}
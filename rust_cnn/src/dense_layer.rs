@@ -0,0 +1,114 @@
+use crate::layers::{Layer, Param};
+use ndarray::{Array1, Array2, ArrayD, Axis, Ix1, Ix2};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// y = x . W + b with x: [N, inputs], W: [inputs, outputs], b: [outputs]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DenseLayer {
+    pub weights: Param,
+    pub bias: Param,
+    #[serde(skip)]
+    input: Option<Array2<f32>>,
+}
+
+impl DenseLayer {
+    pub fn new<R: Rng>(inputs: usize, outputs: usize, rng: &mut R) -> DenseLayer {
+        Self {
+            weights: Param::he_uniform(&[inputs, outputs], inputs, rng),
+            bias: Param::new(ArrayD::zeros(vec![outputs])),
+            input: None,
+        }
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.weights.value.shape()[0]
+    }
+    pub fn outputs(&self) -> usize {
+        self.weights.value.shape()[1]
+    }
+
+    fn weights(&self) -> Array2<f32> {
+        self.weights
+            .value
+            .clone()
+            .into_dimensionality::<Ix2>()
+            .unwrap()
+    }
+    fn bias(&self) -> Array1<f32> {
+        self.bias
+            .value
+            .clone()
+            .into_dimensionality::<Ix1>()
+            .unwrap()
+    }
+}
+
+impl Layer for DenseLayer {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        let input = input
+            .clone()
+            .into_dimensionality::<Ix2>()
+            .expect("dense layer expects [batch, features], add a flatten layer first");
+        assert_eq!(
+            input.ncols(),
+            self.inputs(),
+            "dense layer input size mismatch"
+        );
+        let output = input.dot(&self.weights()) + self.bias();
+        self.input = Some(input);
+        output.into_dyn()
+    }
+
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        let input = self.input.take().expect("backward called before forward");
+        let grad = grad.clone().into_dimensionality::<Ix2>().unwrap();
+        self.weights.grad = input.t().dot(&grad).into_dyn();
+        self.bias.grad = grad.sum_axis(Axis(0)).into_dyn();
+        grad.dot(&self.weights().t()).into_dyn()
+    }
+
+    fn params(&mut self) -> Vec<&mut Param> {
+        vec![&mut self.weights, &mut self.bias]
+    }
+
+    fn output_shape(&self, _input_shape: &[usize]) -> Vec<usize> {
+        vec![self.outputs()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradient_check::GradientCheck;
+    use ndarray::array;
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn forward_is_affine() {
+        let mut layer = DenseLayer::new(2, 2, &mut StdRng::seed_from_u64(0));
+        layer.weights = Param::new(array![[1.0, 2.0], [3.0, 4.0]].into_dyn());
+        layer.bias = Param::new(array![0.5, -0.5].into_dyn());
+        let output = layer.forward(&array![[1.0, 1.0], [0.0, 2.0]].into_dyn());
+        assert_eq!(output, array![[4.5, 5.5], [6.5, 7.5]].into_dyn());
+        assert_eq!(layer.output_shape(&[2]), vec![2]);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(24))]
+        #[test]
+        fn gradients_match_finite_differences(
+            batch in 1usize..4,
+            inputs in 1usize..7,
+            outputs in 1usize..5,
+            seed in any::<u64>(),
+        ) {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut layer = DenseLayer::new(inputs, outputs, &mut rng);
+            let input = ArrayD::from_shape_simple_fn(vec![batch, inputs], || rng.gen_range(-1.0..1.0));
+            let result = GradientCheck::default().check_layer(&mut layer, &input);
+            prop_assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::layers::Layer;
+use ndarray::ArrayD;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+// inverted dropout: while training every input is zeroed with probability "rate" and the
+// survivors are scaled by 1 / (1 - rate), so eval mode is just the identity.
+// the mask of forward pass "n" comes from "seed + n", so runs are reproducible...
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DropoutLayer {
+    pub rate: f32,
+    pub seed: u64,
+    passes: u64, // persisted, a resumed run draws the same masks
+    #[serde(skip, default = "crate::layers::training_default")]
+    training: bool,
+    #[serde(skip)]
+    mask: Option<ArrayD<f32>>, // already scaled
+}
+
+impl DropoutLayer {
+    pub fn new(rate: f32, seed: u64) -> DropoutLayer {
+        assert!((0.0..1.0).contains(&rate), "dropout rate must be in [0, 1)");
+        Self {
+            rate,
+            seed,
+            passes: 0,
+            training: true,
+            mask: None,
+        }
+    }
+}
+
+impl Layer for DropoutLayer {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        if !self.training {
+            self.mask = None;
+            return input.clone();
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(self.passes));
+        self.passes += 1;
+        let scale = 1.0 / (1.0 - self.rate);
+        let mask = input.mapv(|_| {
+            if rng.gen::<f32>() < self.rate {
+                0.0
+            } else {
+                scale
+            }
+        });
+        let output = input * &mask;
+        self.mask = Some(mask);
+        output
+    }
+
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        match self.mask.take() {
+            Some(mask) => grad * &mask,
+            None => grad.clone(), // eval mode
+        }
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradient_check::GradientCheck;
+
+    #[test]
+    fn masks_are_seeded_and_scaled() {
+        let input = ArrayD::ones(vec![100, 100]);
+        let mut first = DropoutLayer::new(0.25, 9);
+        let mut second = DropoutLayer::new(0.25, 9);
+        let output = first.forward(&input);
+        assert_eq!(output, second.forward(&input));
+        assert_ne!(output, first.forward(&input)); // a new mask every pass
+
+        let dropped = output.iter().filter(|&&v| v == 0.0).count() as f32 / output.len() as f32;
+        assert!((dropped - 0.25).abs() < 0.02);
+        assert!(output
+            .iter()
+            .all(|&v| v == 0.0 || (v - 1.0 / 0.75).abs() < 1e-6));
+        assert!((output.mean().unwrap() - 1.0).abs() < 0.05); // expectation is preserved
+    }
+
+    #[test]
+    fn backward_reuses_the_forward_mask() {
+        let mut layer = DropoutLayer::new(0.5, 3);
+        let input = ArrayD::from_shape_fn(vec![4, 8], |i| (i[0] * 8 + i[1]) as f32 + 1.0);
+        let output = layer.forward(&input);
+        let grad = layer.backward(&ArrayD::ones(vec![4, 8]));
+        assert_eq!(grad * &input, output);
+    }
+
+    #[test]
+    fn eval_is_identity() {
+        let mut layer = DropoutLayer::new(0.9, 1);
+        layer.set_training(false);
+        let input = ArrayD::from_shape_fn(vec![3, 5], |i| i[1] as f32 - 2.0);
+        assert_eq!(layer.forward(&input), input);
+        let result = GradientCheck::default().check_layer(&mut layer, &input);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+    }
+}
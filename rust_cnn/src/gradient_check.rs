@@ -0,0 +1,202 @@
+use crate::layers::Layer;
+use crate::loss::Loss;
+use ndarray::ArrayD;
+use std::fmt;
+
+// finite difference gradient checking, any Layer or Loss can be run through it.
+// layers are checked on the scalar objective L = sum(w * forward(x)) with fixed weights w,
+// so dL/d(output) = w is what gets fed to backward...
+#[derive(Clone, Copy, Debug)]
+pub struct GradientCheck {
+    pub epsilon: f32,   // central difference step
+    pub tolerance: f32, // allowed |analytic - numeric| / max(1, |analytic| + |numeric|)
+}
+
+impl Default for GradientCheck {
+    fn default() -> GradientCheck {
+        // f32 only has ~7 digits, the step can't be much smaller than this
+        Self {
+            epsilon: 1e-2,
+            tolerance: 1e-2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GradientMismatch {
+    pub target: String, // "input" or "param <n>"
+    pub index: usize,   // flat index into the target
+    pub analytic: f32,
+    pub numeric: f32,
+}
+
+impl fmt::Display for GradientMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gradient mismatch for {} at index {}: analytic {} numeric {}",
+            self.target, self.index, self.analytic, self.numeric
+        )
+    }
+}
+
+// deterministic upstream gradient with the shape of "output"
+fn objective_weights(output: &ArrayD<f32>) -> ArrayD<f32> {
+    let mut i = 0.0f32;
+    output.mapv(|_| {
+        i += 1.0;
+        (i * 1.618).sin()
+    })
+}
+
+fn weighted_sum(output: &ArrayD<f32>, weights: &ArrayD<f32>) -> f64 {
+    output
+        .iter()
+        .zip(weights)
+        .map(|(&o, &w)| o as f64 * w as f64)
+        .sum()
+}
+
+fn nudge(value: &mut ArrayD<f32>, index: usize, delta: f32) {
+    let slice = value
+        .as_slice_mut()
+        .expect("parameters are kept in standard layout");
+    slice[index] += delta;
+}
+
+impl GradientCheck {
+    fn compare(
+        &self,
+        target: String,
+        analytic: &ArrayD<f32>,
+        mut numeric: impl FnMut(usize) -> f32,
+    ) -> Result<(), GradientMismatch> {
+        for (index, &a) in analytic.iter().enumerate() {
+            let n = numeric(index);
+            if (a - n).abs() > self.tolerance * (a.abs() + n.abs()).max(1.0) {
+                return Err(GradientMismatch {
+                    target,
+                    index,
+                    analytic: a,
+                    numeric: n,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // checks dL/d(input) and the gradient of every parameter
+    pub fn check_layer<L: Layer + ?Sized>(
+        &self,
+        layer: &mut L,
+        input: &ArrayD<f32>,
+    ) -> Result<(), GradientMismatch> {
+        let weights = objective_weights(&layer.forward(input));
+        let input_grad = layer.backward(&weights);
+        let param_grads: Vec<ArrayD<f32>> = layer.params().iter().map(|p| p.grad.clone()).collect();
+        let eps = self.epsilon;
+
+        // flat indices follow the logical order, so everything perturbed is in standard layout
+        let mut perturbed = input.as_standard_layout().into_owned();
+        self.compare("input".to_string(), &input_grad, |i| {
+            let original = perturbed.as_slice().unwrap()[i];
+            let mut objective = |value: f32| {
+                perturbed.as_slice_mut().unwrap()[i] = value;
+                weighted_sum(&layer.forward(&perturbed), &weights)
+            };
+            let numeric =
+                (objective(original + eps) - objective(original - eps)) / (2.0 * eps as f64);
+            perturbed.as_slice_mut().unwrap()[i] = original;
+            numeric as f32
+        })?;
+
+        for (p, analytic) in param_grads.iter().enumerate() {
+            self.compare(format!("param {}", p), analytic, |i| {
+                let mut objective = |delta: f32| {
+                    nudge(&mut layer.params()[p].value, i, delta);
+                    let value = weighted_sum(&layer.forward(input), &weights);
+                    nudge(&mut layer.params()[p].value, i, -delta);
+                    value
+                };
+                ((objective(eps) - objective(-eps)) / (2.0 * eps as f64)) as f32
+            })?;
+        }
+        Ok(())
+    }
+
+    // checks dL/d(predictions)
+    pub fn check_loss<L: Loss + ?Sized>(
+        &self,
+        loss: &L,
+        predictions: &ArrayD<f32>,
+        targets: &ArrayD<f32>,
+    ) -> Result<(), GradientMismatch> {
+        let (_, analytic) = loss.compute(predictions, targets);
+        let mut perturbed = predictions.as_standard_layout().into_owned();
+        let eps = self.epsilon;
+        self.compare("predictions".to_string(), &analytic, |i| {
+            let original = perturbed.as_slice().unwrap()[i];
+            let mut objective = |value: f32| {
+                perturbed.as_slice_mut().unwrap()[i] = value;
+                loss.compute(&perturbed, targets).0 as f64
+            };
+            let numeric =
+                (objective(original + eps) - objective(original - eps)) / (2.0 * eps as f64);
+            perturbed.as_slice_mut().unwrap()[i] = original;
+            numeric as f32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::Param;
+    use crate::loss::MeanSquaredError;
+    use ndarray::array;
+
+    // y = w * x with a deliberately wrong parameter gradient
+    struct Broken {
+        weight: Param,
+    }
+    impl Layer for Broken {
+        fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+            input * self.weight.value[[0]]
+        }
+        fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+            self.weight.grad = array![grad.sum() * 3.0].into_dyn();
+            grad * self.weight.value[[0]]
+        }
+        fn params(&mut self) -> Vec<&mut Param> {
+            vec![&mut self.weight]
+        }
+        fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+            input_shape.to_vec()
+        }
+    }
+
+    #[test]
+    fn reports_wrong_gradients() {
+        let mut layer = Broken {
+            weight: Param::new(array![2.0].into_dyn()),
+        };
+        let input = array![[1.0, 2.0]].into_dyn();
+        let mismatch = GradientCheck::default()
+            .check_layer(&mut layer, &input)
+            .unwrap_err();
+        assert_eq!(mismatch.target, "param 0");
+        assert_eq!(mismatch.index, 0);
+        // the input was left untouched and the parameter restored
+        assert!((layer.weight.value[[0]] - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn accepts_correct_loss_gradient() {
+        let predictions = array![[0.3, -1.0], [2.0, 0.5]].into_dyn();
+        let targets = array![[0.0, 1.0], [1.0, 0.0]].into_dyn();
+        assert_eq!(
+            GradientCheck::default().check_loss(&MeanSquaredError, &predictions, &targets),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,614 @@
+use crate::activation::{Activation, GELU_COEFF, SQRT_2_OVER_PI};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+// forward only models that build without std (alloc, no threads, libm for the float math),
+// e.g. inside mini_os. trained models are exported to a compact little endian blob:
+// "RCNN", u32 version, u32 rank + one u32 per dimension of a single input sample,
+// u32 layer count, then per layer a u8 tag followed by its u32 sizes and f32 weights...
+const MAGIC: &[u8; 4] = b"RCNN";
+const VERSION: u32 = 1;
+
+const TAG_CONV: u8 = 0; // filters, channels, kernel, stride, padding, kernels [F, C, K, K], bias [F]
+const TAG_POOL: u8 = 1; // size, stride (max pooling)
+const TAG_DENSE: u8 = 2; // inputs, outputs, weights [inputs, outputs], bias [outputs]
+const TAG_FLATTEN: u8 = 3;
+const TAG_ACTIVATION: u8 = 4; // u8 kind (+ f32 alpha for LeakyReLu)
+const TAG_AFFINE: u8 = 5; // features, scale, shift: batch norm in eval mode, per channel
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u32),
+    UnknownLayer(u8),
+    UnknownActivation(u8),
+    ShapeMismatch(usize), // index of the layer that doesn't fit its input
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Truncated => write!(f, "model blob is truncated"),
+            BlobError::BadMagic => write!(f, "not a rust_cnn model blob"),
+            BlobError::UnsupportedVersion(v) => write!(f, "unsupported blob version {}", v),
+            BlobError::UnknownLayer(tag) => write!(f, "unknown layer tag {}", tag),
+            BlobError::UnknownActivation(kind) => write!(f, "unknown activation {}", kind),
+            BlobError::ShapeMismatch(i) => write!(f, "layer {} doesn't fit its input", i),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl std::error::Error for BlobError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+    Conv {
+        filters: usize,
+        channels: usize,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+        kernels: Vec<f32>,
+        bias: Vec<f32>,
+    },
+    Pool {
+        size: usize,
+        stride: usize,
+    },
+    Dense {
+        inputs: usize,
+        outputs: usize,
+        weights: Vec<f32>,
+        bias: Vec<f32>,
+    },
+    Flatten,
+    Activation(Activation),
+    Affine {
+        scale: Vec<f32>,
+        shift: Vec<f32>,
+    },
+}
+
+impl Op {
+    // shape of a single sample after this op, None if the input doesn't fit
+    fn output_shape(&self, shape: &[usize]) -> Option<Vec<usize>> {
+        match self {
+            Op::Conv {
+                filters,
+                channels,
+                kernel,
+                stride,
+                padding,
+                ..
+            } => match *shape {
+                [c, h, w]
+                    if c == *channels
+                        && h + 2 * padding >= *kernel
+                        && w + 2 * padding >= *kernel =>
+                {
+                    Some(vec![
+                        *filters,
+                        (h + 2 * padding - kernel) / stride + 1,
+                        (w + 2 * padding - kernel) / stride + 1,
+                    ])
+                }
+                _ => None,
+            },
+            Op::Pool { size, stride } => match *shape {
+                [c, h, w] if h >= *size && w >= *size => {
+                    Some(vec![c, (h - size) / stride + 1, (w - size) / stride + 1])
+                }
+                _ => None,
+            },
+            Op::Dense {
+                inputs, outputs, ..
+            } => match shape {
+                [features] if features == inputs => Some(vec![*outputs]),
+                _ => None,
+            },
+            Op::Flatten => product(shape).map(|len| vec![len]),
+            Op::Activation(_) => Some(shape.to_vec()),
+            Op::Affine { scale, .. } => match shape.first() {
+                Some(&features) if features == scale.len() => Some(shape.to_vec()),
+                _ => None,
+            },
+        }
+    }
+
+    fn forward(&self, x: &[f32], shape: &[usize]) -> Vec<f32> {
+        match self {
+            Op::Conv {
+                filters,
+                channels,
+                kernel,
+                stride,
+                padding,
+                kernels,
+                bias,
+            } => {
+                let (h, w) = (shape[1] as isize, shape[2] as isize);
+                let (k, s, p) = (*kernel, *stride as isize, *padding as isize);
+                let out_h = (shape[1] + 2 * padding - k) / stride + 1;
+                let out_w = (shape[2] + 2 * padding - k) / stride + 1;
+                let mut out = vec![0.0; filters * out_h * out_w];
+                for f in 0..*filters {
+                    for oy in 0..out_h {
+                        for ox in 0..out_w {
+                            let mut sum = bias[f];
+                            for c in 0..*channels {
+                                for ky in 0..k {
+                                    let y = oy as isize * s + ky as isize - p;
+                                    if y < 0 || y >= h {
+                                        continue; // zero padding
+                                    }
+                                    for kx in 0..k {
+                                        let x_ = ox as isize * s + kx as isize - p;
+                                        if x_ < 0 || x_ >= w {
+                                            continue;
+                                        }
+                                        let input = x[(c * h as usize + y as usize) * w as usize
+                                            + x_ as usize];
+                                        sum +=
+                                            kernels[((f * channels + c) * k + ky) * k + kx] * input;
+                                    }
+                                }
+                            }
+                            out[(f * out_h + oy) * out_w + ox] = sum;
+                        }
+                    }
+                }
+                out
+            }
+            Op::Pool { size, stride } => {
+                let (c, h, w) = (shape[0], shape[1], shape[2]);
+                let out_h = (h - size) / stride + 1;
+                let out_w = (w - size) / stride + 1;
+                let mut out = vec![f32::NEG_INFINITY; c * out_h * out_w];
+                for ch in 0..c {
+                    for oy in 0..out_h {
+                        for ox in 0..out_w {
+                            let max = &mut out[(ch * out_h + oy) * out_w + ox];
+                            for dy in 0..*size {
+                                for dx in 0..*size {
+                                    let y = oy * stride + dy;
+                                    let x_ = ox * stride + dx;
+                                    *max = max.max(x[(ch * h + y) * w + x_]);
+                                }
+                            }
+                        }
+                    }
+                }
+                out
+            }
+            Op::Dense {
+                outputs,
+                weights,
+                bias,
+                ..
+            } => {
+                let mut out = bias.clone();
+                for (i, &xi) in x.iter().enumerate() {
+                    let row = &weights[i * outputs..(i + 1) * outputs];
+                    for (o, &w) in out.iter_mut().zip(row) {
+                        *o += xi * w;
+                    }
+                }
+                out
+            }
+            Op::Flatten => x.to_vec(),
+            Op::Activation(activation) => activate(x.to_vec(), *activation, shape),
+            Op::Affine { scale, shift } => {
+                let per_feature = x.len() / scale.len();
+                x.iter()
+                    .enumerate()
+                    .map(|(i, &xi)| {
+                        let feature = i / per_feature;
+                        xi * scale[feature] + shift[feature]
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// same formulas as `Activation::forward`, on a single sample
+fn activate(mut x: Vec<f32>, activation: Activation, shape: &[usize]) -> Vec<f32> {
+    let map = |x: &mut Vec<f32>, f: &dyn Fn(f32) -> f32| x.iter_mut().for_each(|xi| *xi = f(*xi));
+    match activation {
+        Activation::ReLu => map(&mut x, &|xi| if xi > 0.0 { xi } else { 0.0 }),
+        Activation::LeakyReLu(alpha) => map(&mut x, &|xi| if xi > 0.0 { xi } else { alpha * xi }),
+        Activation::Sigmoid => map(&mut x, &|xi| 1.0 / (1.0 + libm::expf(-xi))),
+        Activation::Tanh => map(&mut x, &libm::tanhf),
+        Activation::Gelu => map(&mut x, &|xi| {
+            let inner = SQRT_2_OVER_PI * (xi + GELU_COEFF * xi * xi * xi);
+            0.5 * xi * (1.0 + libm::tanhf(inner))
+        }),
+        Activation::Softmax => {
+            // along the last axis
+            let lane = shape.last().copied().unwrap_or(x.len()).max(1);
+            for lane in x.chunks_mut(lane) {
+                let max = lane.iter().fold(f32::NEG_INFINITY, |acc, &xi| acc.max(xi));
+                lane.iter_mut().for_each(|xi| *xi = libm::expf(*xi - max));
+                let sum: f32 = lane.iter().sum();
+                lane.iter_mut().for_each(|xi| *xi /= sum);
+            }
+        }
+        Activation::Identity => {}
+    }
+    x
+}
+
+// None on overflow, sizes come from the blob
+fn product(dims: &[usize]) -> Option<usize> {
+    dims.iter()
+        .try_fold(1usize, |len, &dim| len.checked_mul(dim))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BlobError> {
+        if self.bytes.len() < len {
+            return Err(BlobError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+    fn u8(&mut self) -> Result<u8, BlobError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, BlobError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn usize(&mut self) -> Result<usize, BlobError> {
+        Ok(self.u32()? as usize)
+    }
+    fn f32(&mut self) -> Result<f32, BlobError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+    fn f32s(&mut self, len: usize) -> Result<Vec<f32>, BlobError> {
+        let bytes = self.take(len.checked_mul(4).ok_or(BlobError::Truncated)?)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    fn activation(&mut self) -> Result<Activation, BlobError> {
+        Ok(match self.u8()? {
+            0 => Activation::ReLu,
+            1 => Activation::LeakyReLu(self.f32()?),
+            2 => Activation::Sigmoid,
+            3 => Activation::Tanh,
+            4 => Activation::Gelu,
+            5 => Activation::Softmax,
+            6 => Activation::Identity,
+            kind => return Err(BlobError::UnknownActivation(kind)),
+        })
+    }
+
+    fn op(&mut self) -> Result<Op, BlobError> {
+        Ok(match self.u8()? {
+            TAG_CONV => {
+                let (filters, channels, kernel) = (self.usize()?, self.usize()?, self.usize()?);
+                let (stride, padding) = (self.usize()?, self.usize()?);
+                Op::Conv {
+                    filters,
+                    channels,
+                    kernel,
+                    stride: stride.max(1),
+                    padding,
+                    kernels: self.f32s(
+                        // no blob is that long
+                        product(&[filters, channels, kernel, kernel])
+                            .ok_or(BlobError::Truncated)?,
+                    )?,
+                    bias: self.f32s(filters)?,
+                }
+            }
+            TAG_POOL => Op::Pool {
+                size: self.usize()?,
+                stride: self.usize()?.max(1),
+            },
+            TAG_DENSE => {
+                let (inputs, outputs) = (self.usize()?, self.usize()?);
+                Op::Dense {
+                    inputs,
+                    outputs,
+                    weights: self.f32s(product(&[inputs, outputs]).ok_or(BlobError::Truncated)?)?,
+                    bias: self.f32s(outputs)?,
+                }
+            }
+            TAG_FLATTEN => Op::Flatten,
+            TAG_ACTIVATION => Op::Activation(self.activation()?),
+            TAG_AFFINE => {
+                let features = self.usize()?;
+                Op::Affine {
+                    scale: self.f32s(features)?,
+                    shift: self.f32s(features)?,
+                }
+            }
+            tag => return Err(BlobError::UnknownLayer(tag)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Model {
+    input_shape: Vec<usize>,
+    ops: Vec<Op>,
+}
+
+impl Model {
+    // parses (and shape checks) a blob, e.g. one embedded with include_bytes!
+    pub fn from_bytes(bytes: &[u8]) -> Result<Model, BlobError> {
+        let mut reader = Reader { bytes };
+        if reader.take(4)? != MAGIC {
+            return Err(BlobError::BadMagic);
+        }
+        match reader.u32()? {
+            VERSION => {}
+            other => return Err(BlobError::UnsupportedVersion(other)),
+        }
+        let rank = reader.usize()?;
+        let input_shape = (0..rank)
+            .map(|_| reader.usize())
+            .collect::<Result<Vec<_>, _>>()?;
+        let count = reader.usize()?;
+        let mut ops = Vec::new();
+        let mut shape = input_shape.clone();
+        for i in 0..count {
+            let op = reader.op()?;
+            shape = op.output_shape(&shape).ok_or(BlobError::ShapeMismatch(i))?;
+            ops.push(op);
+        }
+        Ok(Model { input_shape, ops })
+    }
+
+    // shape of a single sample, without the batch axis
+    pub fn input_shape(&self) -> &[usize] {
+        &self.input_shape
+    }
+
+    // forward pass of a single sample laid out in row major order
+    pub fn forward(&self, input: &[f32]) -> Vec<f32> {
+        assert_eq!(
+            input.len(),
+            self.input_shape.iter().product::<usize>(),
+            "input doesn't match the model input shape"
+        );
+        let mut shape = self.input_shape.clone();
+        let mut x = input.to_vec();
+        for op in &self.ops {
+            x = op.forward(&x, &shape);
+            shape = op
+                .output_shape(&shape)
+                .expect("shapes are checked while loading");
+        }
+        x
+    }
+
+    // index of the highest output
+    pub fn predict(&self, input: &[f32]) -> usize {
+        let output = self.forward(input);
+        (0..output.len()).fold(0, |best, i| if output[i] > output[best] { i } else { best })
+    }
+}
+
+// ---- export (std only) ----
+
+#[cfg(feature = "std")]
+mod export {
+    use super::*;
+    use crate::cnn::Cnn;
+    use crate::layers::LayerType;
+    use std::io;
+
+    fn push_usize(blob: &mut Vec<u8>, value: usize) {
+        blob.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+    fn push_f32s<'a, I: IntoIterator<Item = &'a f32>>(blob: &mut Vec<u8>, values: I) {
+        values
+            .into_iter()
+            .for_each(|v| blob.extend_from_slice(&v.to_le_bytes()));
+    }
+
+    // blob for `Model::from_bytes`, the model is exported as in eval mode (dropout is dropped,
+    // batch norm becomes a per channel affine transform of its running statistics)
+    pub fn export(model: &Cnn, input_shape: &[usize]) -> io::Result<Vec<u8>> {
+        let mut blob = MAGIC.to_vec();
+        blob.extend_from_slice(&VERSION.to_le_bytes());
+        push_usize(&mut blob, input_shape.len());
+        input_shape.iter().for_each(|&d| push_usize(&mut blob, d));
+
+        let layers: Vec<&LayerType> = model
+            .layers
+            .iter()
+            .filter(|layer| !matches!(layer, LayerType::Dropout(_)))
+            .collect();
+        push_usize(&mut blob, layers.len());
+        for layer in layers {
+            match layer {
+                LayerType::Conv(conv) => {
+                    blob.push(TAG_CONV);
+                    let shape = conv.kernels.value.shape();
+                    for size in [shape[0], shape[1], shape[2], conv.stride, conv.padding] {
+                        push_usize(&mut blob, size);
+                    }
+                    push_f32s(&mut blob, conv.kernels.value.iter());
+                    push_f32s(&mut blob, conv.bias.value.iter());
+                }
+                LayerType::Pool(pool) => {
+                    blob.push(TAG_POOL);
+                    push_usize(&mut blob, pool.size);
+                    push_usize(&mut blob, pool.stride);
+                }
+                LayerType::Dense(dense) => {
+                    blob.push(TAG_DENSE);
+                    push_usize(&mut blob, dense.inputs());
+                    push_usize(&mut blob, dense.outputs());
+                    push_f32s(&mut blob, dense.weights.value.iter());
+                    push_f32s(&mut blob, dense.bias.value.iter());
+                }
+                LayerType::Flatten(_) => blob.push(TAG_FLATTEN),
+                LayerType::Activation(layer) => {
+                    blob.push(TAG_ACTIVATION);
+                    match layer.activation {
+                        Activation::ReLu => blob.push(0),
+                        Activation::LeakyReLu(alpha) => {
+                            blob.push(1);
+                            push_f32s(&mut blob, &[alpha]);
+                        }
+                        Activation::Sigmoid => blob.push(2),
+                        Activation::Tanh => blob.push(3),
+                        Activation::Gelu => blob.push(4),
+                        Activation::Softmax => blob.push(5),
+                        Activation::Identity => blob.push(6),
+                    }
+                }
+                LayerType::BatchNorm(norm) => {
+                    blob.push(TAG_AFFINE);
+                    let scale: Vec<f32> = norm
+                        .running_var
+                        .iter()
+                        .zip(&norm.gamma.value)
+                        .map(|(&var, &gamma)| gamma / (var + norm.epsilon).sqrt())
+                        .collect();
+                    let shift: Vec<f32> = (0..scale.len())
+                        .map(|i| norm.beta.value[i] - norm.running_mean[i] * scale[i])
+                        .collect();
+                    push_usize(&mut blob, scale.len());
+                    push_f32s(&mut blob, &scale);
+                    push_f32s(&mut blob, &shift);
+                }
+                LayerType::Dropout(_) => unreachable!("filtered out above"),
+            }
+        }
+        // the blob is only useful if it loads
+        Model::from_bytes(&blob)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(blob)
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(
+        model: &Cnn,
+        input_shape: &[usize],
+        path: P,
+    ) -> io::Result<()> {
+        std::fs::write(path, export(model, input_shape)?)
+    }
+}
+#[cfg(feature = "std")]
+pub use export::{export, save};
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::batch_norm_layer::BatchNormLayer;
+    use crate::cnn::Cnn;
+    use crate::conv_layer::ConvLayer;
+    use crate::dense_layer::DenseLayer;
+    use crate::dropout_layer::DropoutLayer;
+    use crate::layers::{ActivationLayer, FlattenLayer};
+    use crate::loss::LossFunction;
+    use crate::optimizer::Optimizer;
+    use crate::pool_layer::PoolLayer;
+    use crate::util::Dataset;
+    use ndarray::{Array1, Array4, ArrayD, Axis};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn model(hidden: Activation) -> Cnn {
+        let mut rng = StdRng::seed_from_u64(2);
+        Cnn::new(
+            LossFunction::CategoricalCrossEntropy { from_logits: false },
+            Optimizer::adam(0.01),
+        )
+        .add_layer(ConvLayer::new(2, 3, 3, 2, 1, &mut rng))
+        .add_layer(BatchNormLayer::new(3))
+        .add_layer(ActivationLayer::new(hidden))
+        .add_layer(PoolLayer::new(2, 1))
+        .add_layer(FlattenLayer::new())
+        .add_layer(DropoutLayer::new(0.3, 1))
+        .add_layer(DenseLayer::new(12, 4, &mut rng))
+        .add_layer(ActivationLayer::new(Activation::Softmax))
+    }
+
+    fn inputs() -> ArrayD<f32> {
+        Array4::from_shape_fn((6, 2, 6, 6), |(n, c, i, j)| {
+            ((n * 5 + c * 11 + i * 3 + j * 7) % 13) as f32 / 6.0 - 1.0
+        })
+        .into_dyn()
+    }
+
+    #[test]
+    fn matches_the_eval_mode_forward_pass() {
+        for hidden in [
+            Activation::ReLu,
+            Activation::LeakyReLu(0.1),
+            Activation::Sigmoid,
+            Activation::Tanh,
+            Activation::Gelu,
+            Activation::Identity,
+        ] {
+            // a few training steps so batch norm has running statistics
+            let mut cnn = model(hidden);
+            let data = Dataset::new(inputs(), Array1::from(vec![0, 1, 2, 3, 0, 1]), 4);
+            for batch in data.batches(3, Some(0)) {
+                cnn.train_batch(&batch.inputs, &batch.targets.into_dyn());
+            }
+
+            let inference = Model::from_bytes(&export(&cnn, &[2, 6, 6]).unwrap()).unwrap();
+            assert_eq!(inference.input_shape(), &[2, 6, 6]);
+            let expected = cnn.predict(&inputs());
+            for (sample, expected) in inputs().axis_iter(Axis(0)).zip(expected.outer_iter()) {
+                let sample: Vec<f32> = sample.iter().copied().collect();
+                let output = inference.forward(&sample);
+                for (a, b) in output.iter().zip(expected.iter()) {
+                    assert!((a - b).abs() < 1e-5, "{:?}: {} vs {}", hidden, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_broken_blobs() {
+        let blob = export(&model(Activation::ReLu), &[2, 6, 6]).unwrap();
+        assert_eq!(Model::from_bytes(b"ONNX"), Err(BlobError::BadMagic));
+        assert_eq!(
+            Model::from_bytes(&blob[..blob.len() - 1]),
+            Err(BlobError::Truncated)
+        );
+        let mut version = blob.clone();
+        version[4] = 9;
+        assert_eq!(
+            Model::from_bytes(&version),
+            Err(BlobError::UnsupportedVersion(9))
+        );
+        // sizes whose product overflows: one op, its tag and sizes, and no data
+        let header = |tag: u8, sizes: &[u32]| {
+            let mut blob = MAGIC.to_vec();
+            for word in [VERSION, 1, 1, 1] {
+                blob.extend_from_slice(&word.to_le_bytes());
+            }
+            blob.push(tag);
+            sizes
+                .iter()
+                .for_each(|size| blob.extend_from_slice(&size.to_le_bytes()));
+            blob
+        };
+        let conv = header(TAG_CONV, &[u32::MAX, u32::MAX, u32::MAX, 1, 0]);
+        assert_eq!(Model::from_bytes(&conv), Err(BlobError::Truncated));
+        let dense = header(TAG_DENSE, &[u32::MAX, u32::MAX]);
+        assert_eq!(Model::from_bytes(&dense), Err(BlobError::Truncated));
+        // wrong input shape for the dense layer
+        assert!(matches!(
+            export(&model(Activation::ReLu), &[2, 8, 8]),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput
+        ));
+    }
+}
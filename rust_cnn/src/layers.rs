@@ -0,0 +1,275 @@
+use crate::activation::Activation;
+use crate::batch_norm_layer::BatchNormLayer;
+use crate::conv_layer::ConvLayer;
+use crate::dense_layer::DenseLayer;
+use crate::dropout_layer::DropoutLayer;
+use crate::pool_layer::PoolLayer;
+use ndarray::{ArrayD, Axis, IxDyn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// every layer works on batches, samples are laid out along axis 0...
+pub trait Layer {
+    // caches whatever backward needs (pre-activations, inputs...)
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32>;
+    // "grad" is dL/d(output), stores the parameter gradients and returns dL/d(input)
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32>;
+    // trainable parameters, always in the same order
+    fn params(&mut self) -> Vec<&mut Param> {
+        Vec::new()
+    }
+    // switches between training and inference behaviour (dropout, batch statistics...)
+    fn set_training(&mut self, _training: bool) {}
+    // output shape for a single sample (without the batch axis)
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize>;
+}
+
+// serde default of the (not persisted) mode of layers with a training mode, loaded models start
+// out training like new ones
+pub(crate) fn training_default() -> bool {
+    true
+}
+
+// a trainable tensor together with the gradient of its last backward pass
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Param {
+    pub value: ArrayD<f32>,
+    #[serde(skip)]
+    pub grad: ArrayD<f32>, // not persisted, recomputed on every backward pass
+}
+impl Param {
+    pub fn new(value: ArrayD<f32>) -> Param {
+        let grad = ArrayD::zeros(value.raw_dim());
+        Self { value, grad }
+    }
+
+    // He initialization (uniform variant), suited to ReLu like activations
+    pub fn he_uniform<R: Rng>(shape: &[usize], fan_in: usize, rng: &mut R) -> Param {
+        let limit = (6.0 / fan_in as f32).sqrt();
+        Param::new(ArrayD::from_shape_simple_fn(IxDyn(shape), || {
+            rng.gen_range(-limit..limit)
+        }))
+    }
+}
+
+// serializable wrapper over every layer, the model is a Vec of these...
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum LayerType {
+    Conv(ConvLayer),             // CONVOLUTION
+    Pool(PoolLayer),             // MAX POOLING
+    Dense(DenseLayer),           // FULLY CONNECTED
+    Activation(ActivationLayer), // element wise (or softmax) activation
+    Flatten(FlattenLayer),       // [N, ...] -> [N, features]
+    BatchNorm(BatchNormLayer),   // per feature / per channel normalization
+    Dropout(DropoutLayer),       // inverted dropout, identity in eval mode
+}
+impl LayerType {
+    fn inner(&mut self) -> &mut dyn Layer {
+        match self {
+            LayerType::Conv(layer) => layer,
+            LayerType::Pool(layer) => layer,
+            LayerType::Dense(layer) => layer,
+            LayerType::Activation(layer) => layer,
+            LayerType::Flatten(layer) => layer,
+            LayerType::BatchNorm(layer) => layer,
+            LayerType::Dropout(layer) => layer,
+        }
+    }
+}
+impl Layer for LayerType {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        self.inner().forward(input)
+    }
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        self.inner().backward(grad)
+    }
+    fn params(&mut self) -> Vec<&mut Param> {
+        self.inner().params()
+    }
+    fn set_training(&mut self, training: bool) {
+        self.inner().set_training(training)
+    }
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        match self {
+            LayerType::Conv(layer) => layer.output_shape(input_shape),
+            LayerType::Pool(layer) => layer.output_shape(input_shape),
+            LayerType::Dense(layer) => layer.output_shape(input_shape),
+            LayerType::Activation(layer) => layer.output_shape(input_shape),
+            LayerType::Flatten(layer) => layer.output_shape(input_shape),
+            LayerType::BatchNorm(layer) => layer.output_shape(input_shape),
+            LayerType::Dropout(layer) => layer.output_shape(input_shape),
+        }
+    }
+}
+
+impl From<ConvLayer> for LayerType {
+    fn from(layer: ConvLayer) -> LayerType {
+        LayerType::Conv(layer)
+    }
+}
+impl From<PoolLayer> for LayerType {
+    fn from(layer: PoolLayer) -> LayerType {
+        LayerType::Pool(layer)
+    }
+}
+impl From<DenseLayer> for LayerType {
+    fn from(layer: DenseLayer) -> LayerType {
+        LayerType::Dense(layer)
+    }
+}
+impl From<ActivationLayer> for LayerType {
+    fn from(layer: ActivationLayer) -> LayerType {
+        LayerType::Activation(layer)
+    }
+}
+impl From<FlattenLayer> for LayerType {
+    fn from(layer: FlattenLayer) -> LayerType {
+        LayerType::Flatten(layer)
+    }
+}
+impl From<BatchNormLayer> for LayerType {
+    fn from(layer: BatchNormLayer) -> LayerType {
+        LayerType::BatchNorm(layer)
+    }
+}
+impl From<DropoutLayer> for LayerType {
+    fn from(layer: DropoutLayer) -> LayerType {
+        LayerType::Dropout(layer)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ActivationLayer {
+    pub activation: Activation,
+    #[serde(skip)]
+    pub(crate) input: Option<ArrayD<f32>>, // cached pre-activation
+}
+impl ActivationLayer {
+    pub fn new(activation: Activation) -> ActivationLayer {
+        Self {
+            activation,
+            input: None,
+        }
+    }
+}
+impl Layer for ActivationLayer {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        self.input = Some(input.clone());
+        Activation::forward(input.clone(), self.activation)
+    }
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        let input = self.input.take().expect("backward called before forward");
+        Activation::backward(input, grad.clone(), self.activation)
+    }
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FlattenLayer {
+    #[serde(skip)]
+    input_shape: Vec<usize>,
+}
+impl FlattenLayer {
+    pub fn new() -> FlattenLayer {
+        Self::default()
+    }
+}
+impl Layer for FlattenLayer {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        self.input_shape = input.shape().to_vec();
+        let batch = input.len_of(Axis(0));
+        let features = input.len() / batch.max(1);
+        input
+            .as_standard_layout()
+            .into_owned()
+            .into_shape(IxDyn(&[batch, features]))
+            .unwrap()
+    }
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        grad.as_standard_layout()
+            .into_owned()
+            .into_shape(IxDyn(&self.input_shape))
+            .unwrap()
+    }
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        vec![input_shape.iter().product()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradient_check::GradientCheck;
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const ACTIVATIONS: [Activation; 7] = [
+        Activation::ReLu,
+        Activation::LeakyReLu(0.1),
+        Activation::Sigmoid,
+        Activation::Tanh,
+        Activation::Gelu,
+        Activation::Softmax,
+        Activation::Identity,
+    ];
+
+    #[test]
+    fn he_uniform_stays_in_bounds() {
+        let param = Param::he_uniform(&[50, 20], 24, &mut StdRng::seed_from_u64(1));
+        assert!(param.value.iter().all(|w| w.abs() <= 0.5));
+        assert_eq!(param.grad.shape(), &[50, 20]);
+    }
+
+    #[test]
+    fn flatten_round_trips_shapes() {
+        let mut layer = FlattenLayer::new();
+        let input = ArrayD::from_shape_fn(vec![2, 3, 4, 5], |i| i[3] as f32);
+        let output = layer.forward(&input);
+        assert_eq!(output.shape(), &[2, 60]);
+        assert_eq!(layer.output_shape(&[3, 4, 5]), vec![60]);
+        assert_eq!(layer.backward(&output), input);
+    }
+
+    #[test]
+    fn layer_type_dispatches() {
+        let mut layer: LayerType = ActivationLayer::new(Activation::ReLu).into();
+        let input = ArrayD::from_shape_vec(vec![1, 2], vec![-1.0, 2.0]).unwrap();
+        assert_eq!(layer.forward(&input).as_slice().unwrap(), &[0.0, 2.0]);
+        assert!(layer.params().is_empty());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+        #[test]
+        fn activation_gradients_match_finite_differences(
+            batch in 1usize..4,
+            features in 1usize..6,
+            seed in any::<u64>(),
+        ) {
+            let mut rng = StdRng::seed_from_u64(seed);
+            // keep clear of the ReLu kink at zero
+            let input = ArrayD::from_shape_simple_fn(vec![batch, features], || {
+                let x: f32 = rng.gen_range(0.05..2.0);
+                if rng.gen() { x } else { -x }
+            });
+            for activation in ACTIVATIONS {
+                let mut layer = ActivationLayer::new(activation);
+                let result = GradientCheck::default().check_layer(&mut layer, &input);
+                prop_assert!(result.is_ok(), "{:?}: {}", activation, result.unwrap_err());
+            }
+        }
+
+        #[test]
+        fn flatten_gradients_match_finite_differences(
+            dims in proptest::collection::vec(1usize..4, 2..5),
+            seed in any::<u64>(),
+        ) {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let input = ArrayD::from_shape_simple_fn(dims, || rng.gen_range(-1.0..1.0));
+            let result = GradientCheck::default().check_layer(&mut FlattenLayer::new(), &input);
+            prop_assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+}
@@ -1,8 +1,32 @@
+// without the default "std" feature only forward passes are available (alloc, no threads)
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+
 pub mod activation;
+#[cfg(feature = "std")]
+pub mod batch_norm_layer; // NORMALIZATION LAYERS
+#[cfg(feature = "std")]
 pub mod cnn;
+#[cfg(feature = "std")]
 pub mod conv_layer; // CONVOLUTION LAYERS
+#[cfg(feature = "std")]
 pub mod dense_layer; // FULLY CONNECTED LAYERS
+#[cfg(feature = "std")]
+pub mod dropout_layer; // REGULARIZATION LAYERS
+#[cfg(feature = "std")]
+pub mod gradient_check;
+pub mod inference; // FORWARD ONLY (no_std)
+#[cfg(feature = "std")]
 pub mod layers;
+#[cfg(feature = "std")]
+pub mod loss; // LOSS FUNCTIONS
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod onnx; // ONNX EXPORT / IMPORT
+#[cfg(feature = "std")]
 pub mod optimizer;
+#[cfg(feature = "std")]
 pub mod pool_layer; // POOL LAYERS
+#[cfg(feature = "std")]
 pub mod util;
@@ -0,0 +1,290 @@
+use crate::activation::Activation;
+use ndarray::{Array1, Array2, ArrayD, Axis, Ix1, Ix2, Zip};
+use serde::{Deserialize, Serialize};
+
+// probabilities are clamped into [EPSILON, 1 - EPSILON] before taking logs...
+pub const EPSILON: f32 = 1e-7;
+
+// a 1-D array is a single sample, otherwise samples are laid out along axis 0.
+// MSE and BCE are element-wise means (over every output of every sample), categorical
+// cross-entropy is averaged over the samples. Gradients are w.r.t. the predictions...
+pub trait Loss {
+    fn compute(&self, predictions: &ArrayD<f32>, targets: &ArrayD<f32>) -> (f32, ArrayD<f32>);
+
+    fn sample(&self, predictions: &Array1<f32>, targets: &Array1<f32>) -> (f32, Array1<f32>) {
+        let (loss, grad) =
+            self.compute(&predictions.clone().into_dyn(), &targets.clone().into_dyn());
+        (loss, grad.into_dimensionality::<Ix1>().unwrap())
+    }
+    fn batch(&self, predictions: &Array2<f32>, targets: &Array2<f32>) -> (f32, Array2<f32>) {
+        let (loss, grad) =
+            self.compute(&predictions.clone().into_dyn(), &targets.clone().into_dyn());
+        (loss, grad.into_dimensionality::<Ix2>().unwrap())
+    }
+}
+
+// serializable choice of loss, used by model specs and checkpoints
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LossFunction {
+    MeanSquaredError,
+    BinaryCrossEntropy { from_logits: bool },
+    CategoricalCrossEntropy { from_logits: bool },
+}
+impl Loss for LossFunction {
+    fn compute(&self, predictions: &ArrayD<f32>, targets: &ArrayD<f32>) -> (f32, ArrayD<f32>) {
+        match *self {
+            LossFunction::MeanSquaredError => MeanSquaredError.compute(predictions, targets),
+            LossFunction::BinaryCrossEntropy { from_logits } => {
+                BinaryCrossEntropy { from_logits }.compute(predictions, targets)
+            }
+            LossFunction::CategoricalCrossEntropy { from_logits } => {
+                CategoricalCrossEntropy { from_logits }.compute(predictions, targets)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeanSquaredError; // for regression
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BinaryCrossEntropy {
+    pub from_logits: bool, // predictions are raw scores, sigmoid is applied internally
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CategoricalCrossEntropy {
+    pub from_logits: bool, // predictions are raw scores, softmax is applied internally
+}
+
+fn check_shapes(predictions: &ArrayD<f32>, targets: &ArrayD<f32>) {
+    assert_eq!(
+        predictions.shape(),
+        targets.shape(),
+        "predictions and targets shape mismatch"
+    );
+    assert!(predictions.ndim() > 0, "loss needs at least one dimension");
+}
+
+fn sample_count(x: &ArrayD<f32>) -> usize {
+    if x.ndim() == 1 {
+        1
+    } else {
+        x.len_of(Axis(0))
+    }
+}
+
+impl Loss for MeanSquaredError {
+    fn compute(&self, predictions: &ArrayD<f32>, targets: &ArrayD<f32>) -> (f32, ArrayD<f32>) {
+        check_shapes(predictions, targets);
+        let n = predictions.len() as f32;
+        let diff = predictions - targets;
+        let loss = diff.mapv(|d| d * d).sum() / n;
+        (loss, diff * (2.0 / n))
+    }
+}
+
+impl Loss for BinaryCrossEntropy {
+    fn compute(&self, predictions: &ArrayD<f32>, targets: &ArrayD<f32>) -> (f32, ArrayD<f32>) {
+        check_shapes(predictions, targets);
+        let n = predictions.len() as f32;
+        let mut loss = 0.0;
+        let mut grad = ArrayD::zeros(predictions.raw_dim());
+        if self.from_logits {
+            // max(z, 0) - z * t + ln(1 + e^-|z|) never overflows
+            Zip::from(&mut grad)
+                .and(predictions)
+                .and(targets)
+                .for_each(|g, &z, &t| {
+                    loss += z.max(0.0) - z * t + (-z.abs()).exp().ln_1p();
+                    *g = (1.0 / (1.0 + (-z).exp()) - t) / n;
+                });
+        } else {
+            Zip::from(&mut grad)
+                .and(predictions)
+                .and(targets)
+                .for_each(|g, &p, &t| {
+                    let p = p.clamp(EPSILON, 1.0 - EPSILON);
+                    loss -= t * p.ln() + (1.0 - t) * (1.0 - p).ln();
+                    *g = (p - t) / (p * (1.0 - p)) / n;
+                });
+        }
+        (loss / n, grad)
+    }
+}
+
+impl Loss for CategoricalCrossEntropy {
+    fn compute(&self, predictions: &ArrayD<f32>, targets: &ArrayD<f32>) -> (f32, ArrayD<f32>) {
+        check_shapes(predictions, targets);
+        let n = sample_count(predictions) as f32;
+        if self.from_logits {
+            // log-softmax with the max subtracted per lane
+            let last = Axis(predictions.ndim() - 1);
+            let mut loss = 0.0;
+            Zip::from(predictions.lanes(last))
+                .and(targets.lanes(last))
+                .for_each(|z, t| {
+                    let max = z.fold(f32::NEG_INFINITY, |acc, &zi| acc.max(zi));
+                    let log_sum = z.mapv(|zi| (zi - max).exp()).sum().ln() + max;
+                    loss -= Zip::from(&z)
+                        .and(&t)
+                        .fold(0.0, |acc, &zi, &ti| acc + ti * (zi - log_sum));
+                });
+            // averaged over the samples like the loss, not over every lane as in
+            // Activation::softmax_cross_entropy_backward (they differ beyond 2 dimensions)
+            let grad =
+                (Activation::forward(predictions.clone(), Activation::Softmax) - targets) / n;
+            (loss / n, grad)
+        } else {
+            let mut loss = 0.0;
+            let mut grad = ArrayD::zeros(predictions.raw_dim());
+            Zip::from(&mut grad)
+                .and(predictions)
+                .and(targets)
+                .for_each(|g, &p, &t| {
+                    let p = p.clamp(EPSILON, 1.0);
+                    loss -= t * p.ln();
+                    *g = -t / p / n;
+                });
+            (loss / n, grad)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradient_check::GradientCheck;
+    use ndarray::array;
+
+    fn check_gradient(loss: &dyn Loss, predictions: ArrayD<f32>, targets: ArrayD<f32>) {
+        let check = GradientCheck {
+            epsilon: 1e-3,
+            tolerance: 1e-2,
+        };
+        if let Err(mismatch) = check.check_loss(loss, &predictions, &targets) {
+            panic!("{}", mismatch);
+        }
+    }
+
+    fn probabilities() -> Array2<f32> {
+        array![[0.7, 0.2, 0.1], [0.25, 0.5, 0.25]]
+    }
+    fn one_hot() -> Array2<f32> {
+        array![[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]
+    }
+
+    #[test]
+    fn mse_value_and_gradient() {
+        let (loss, _) = MeanSquaredError.sample(&array![1.0, 2.0], &array![0.0, 4.0]);
+        assert!((loss - 2.5).abs() < 1e-6);
+        check_gradient(
+            &MeanSquaredError,
+            probabilities().into_dyn(),
+            one_hot().into_dyn(),
+        );
+    }
+
+    #[test]
+    fn bce_gradient() {
+        let targets = array![[1.0, 0.0, 1.0], [0.0, 1.0, 0.0]].into_dyn();
+        check_gradient(
+            &BinaryCrossEntropy::default(),
+            probabilities().into_dyn(),
+            targets.clone(),
+        );
+        let logits = array![[2.0, -1.0, 0.3], [-0.5, 1.5, 0.0]].into_dyn();
+        check_gradient(&BinaryCrossEntropy { from_logits: true }, logits, targets);
+    }
+
+    #[test]
+    fn cce_gradient() {
+        check_gradient(
+            &CategoricalCrossEntropy::default(),
+            probabilities().into_dyn(),
+            one_hot().into_dyn(),
+        );
+        let logits = array![[2.0, -1.0, 0.3], [-0.5, 1.5, 0.0]].into_dyn();
+        check_gradient(
+            &CategoricalCrossEntropy { from_logits: true },
+            logits,
+            one_hot().into_dyn(),
+        );
+    }
+
+    #[test]
+    fn cce_gradient_beyond_two_dimensions() {
+        // 2 samples of 2 positions with 3 classes each
+        let logits = ArrayD::from_shape_fn(vec![2, 2, 3], |i| {
+            (i[0] as f32 * 0.7 - i[1] as f32 * 1.3 + i[2] as f32 * 0.4).sin()
+        });
+        let targets = ArrayD::from_shape_fn(vec![2, 2, 3], |i| {
+            if (i[0] + i[1]) % 3 == i[2] {
+                1.0
+            } else {
+                0.0
+            }
+        });
+        check_gradient(
+            &CategoricalCrossEntropy { from_logits: true },
+            logits.clone(),
+            targets.clone(),
+        );
+        let probs = Activation::forward(logits.clone(), Activation::Softmax);
+        check_gradient(
+            &CategoricalCrossEntropy::default(),
+            probs.clone(),
+            targets.clone(),
+        );
+        let (a, _) = CategoricalCrossEntropy { from_logits: true }.compute(&logits, &targets);
+        let (b, _) = CategoricalCrossEntropy::default().compute(&probs, &targets);
+        assert!((a - b).abs() < 1e-5);
+    }
+
+    #[test]
+    fn logits_and_probabilities_agree() {
+        let logits = array![[2.0, -1.0, 0.3], [-0.5, 1.5, 0.0]];
+        let probs = Activation::forward(logits.clone(), Activation::Softmax);
+        let (a, _) = CategoricalCrossEntropy { from_logits: true }.batch(&logits, &one_hot());
+        let (b, _) = CategoricalCrossEntropy::default().batch(&probs, &one_hot());
+        assert!((a - b).abs() < 1e-5);
+    }
+
+    #[test]
+    fn batch_is_mean_of_samples() {
+        let cce = CategoricalCrossEntropy::default();
+        let (batch, _) = cce.batch(&probabilities(), &one_hot());
+        let first = cce.sample(
+            &probabilities().row(0).to_owned(),
+            &one_hot().row(0).to_owned(),
+        );
+        let second = cce.sample(
+            &probabilities().row(1).to_owned(),
+            &one_hot().row(1).to_owned(),
+        );
+        assert!((batch - (first.0 + second.0) / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn extreme_inputs_stay_finite() {
+        let (loss, grad) =
+            BinaryCrossEntropy::default().sample(&array![0.0, 1.0], &array![1.0, 0.0]);
+        assert!(loss.is_finite() && grad.iter().all(|g| g.is_finite()));
+        let (loss, grad) = BinaryCrossEntropy { from_logits: true }
+            .sample(&array![1000.0, -1000.0], &array![0.0, 1.0]);
+        assert!(loss.is_finite() && grad.iter().all(|g| g.is_finite()));
+        let (loss, grad) = CategoricalCrossEntropy { from_logits: true }
+            .sample(&array![1000.0, -1000.0, 0.0], &array![0.0, 1.0, 0.0]);
+        assert!((loss - 2000.0).abs() < 1.0 && grad.iter().all(|g| g.is_finite()));
+        let (loss, _) =
+            CategoricalCrossEntropy::default().sample(&array![0.0, 1.0], &array![1.0, 0.0]);
+        assert!(loss.is_finite());
+    }
+
+    #[test]
+    fn loss_function_enum_dispatches() {
+        let (a, _) = LossFunction::MeanSquaredError.batch(&probabilities(), &one_hot());
+        let (b, _) = MeanSquaredError.batch(&probabilities(), &one_hot());
+        assert_eq!(a, b);
+    }
+}
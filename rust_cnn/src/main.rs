@@ -1,3 +1,195 @@
+use rust_cnn::cnn::{Checkpoint, EpochStats, ModelSpec, TrainConfig};
+use rust_cnn::onnx;
+use rust_cnn::util::Dataset;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+const USAGE: &str = "usage: rust_cnn --spec <model.json> --data <mnist dir> [options]
+
+  --spec <file>        model spec (JSON), see specs/mnist.json
+  --data <dir>         directory with the decompressed (Fashion-)MNIST IDX files:
+                       train-images-idx3-ubyte, train-labels-idx1-ubyte,
+                       t10k-images-idx3-ubyte, t10k-labels-idx1-ubyte
+  --epochs <n>         default 5
+  --batch-size <n>     default 32
+  --lr <rate>          learning rate, default 0.001
+  --seed <n>           weight init and shuffling seed, default 42
+  --validation <frac>  part of the training set held out for validation, default 0.1
+  --output <file>      trained model, \".json\" or binary, default model.bin
+  --onnx <file>        also export the trained model to ONNX
+  --checkpoint <file>  checkpoint written after every epoch
+  --resume <file>      continue training from a checkpoint (--spec and --lr are ignored)";
+
+struct Args {
+    spec: Option<PathBuf>,
+    data: PathBuf,
+    epochs: usize,
+    batch_size: usize,
+    learning_rate: f32,
+    seed: u64,
+    validation: f32,
+    output: PathBuf,
+    onnx: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+    resume: Option<PathBuf>,
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        spec: None,
+        data: PathBuf::new(),
+        epochs: 5,
+        batch_size: 32,
+        learning_rate: 0.001,
+        seed: 42,
+        validation: 0.1,
+        output: PathBuf::from("model.bin"),
+        onnx: None,
+        checkpoint: None,
+        resume: None,
+    };
+    let mut data = None;
+    let mut argv = std::env::args().skip(1);
+    while let Some(flag) = argv.next() {
+        if flag == "-h" || flag == "--help" {
+            return Err(String::new());
+        }
+        let value = argv
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        match flag.as_str() {
+            "--spec" => args.spec = Some(PathBuf::from(&value)),
+            "--data" => data = Some(PathBuf::from(&value)),
+            "--epochs" => args.epochs = parse(&flag, &value)?,
+            "--batch-size" => args.batch_size = parse(&flag, &value)?,
+            "--lr" => args.learning_rate = parse(&flag, &value)?,
+            "--seed" => args.seed = parse(&flag, &value)?,
+            "--validation" => args.validation = parse(&flag, &value)?,
+            "--output" => args.output = PathBuf::from(&value),
+            "--onnx" => args.onnx = Some(PathBuf::from(&value)),
+            "--checkpoint" => args.checkpoint = Some(PathBuf::from(&value)),
+            "--resume" => args.resume = Some(PathBuf::from(&value)),
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+    args.data = data.ok_or("--data is required")?;
+    if args.spec.is_none() && args.resume.is_none() {
+        return Err("--spec (or --resume) is required".to_string());
+    }
+    if args.batch_size == 0 || !(0.0..1.0).contains(&args.validation) {
+        return Err("batch size must be positive and validation in [0, 1)".to_string());
+    }
+    Ok(args)
+}
+
+// (Fashion-)MNIST labels are digits / clothing categories 0..10
+const MNIST_CLASSES: usize = 10;
+
+fn load_mnist(dir: &Path, prefix: &str) -> Result<Dataset, Box<dyn Error>> {
+    let images = dir.join(format!("{}-images-idx3-ubyte", prefix));
+    let labels = dir.join(format!("{}-labels-idx1-ubyte", prefix));
+    Dataset::from_idx(&images, &labels, MNIST_CLASSES).map_err(|e| {
+        format!(
+            "cannot read {} / {}: {}",
+            images.display(),
+            labels.display(),
+            e
+        )
+        .into()
+    })
+}
+
+fn print_epoch(stats: &EpochStats) {
+    print!(
+        "epoch {:>3}: loss {:.4}  accuracy {:.4}",
+        stats.epoch, stats.loss, stats.accuracy
+    );
+    match &stats.validation {
+        Some(valid) => println!(
+            "  |  validation loss {:.4}  accuracy {:.4}",
+            valid.loss,
+            valid.accuracy()
+        ),
+        None => println!(),
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let train = load_mnist(&args.data, "train")?;
+    let test = load_mnist(&args.data, "t10k")?;
+    let (train, validation) = train.split(args.validation, args.seed);
+    println!(
+        "{} training, {} validation and {} test samples",
+        train.len(),
+        validation.len(),
+        test.len()
+    );
+    let validation = (!validation.is_empty()).then_some(&validation);
+
+    let config = TrainConfig {
+        epochs: args.epochs,
+        batch_size: args.batch_size,
+        seed: args.seed,
+        checkpoint: args.checkpoint.clone(),
+    };
+    let mut model = match (&args.resume, &args.spec) {
+        (Some(path), _) => {
+            let checkpoint = Checkpoint::load(path)?;
+            println!("resuming after epoch {}", checkpoint.epoch);
+            checkpoint
+                .resume(&train, validation, &config, print_epoch)?
+                .0
+        }
+        (None, Some(path)) => {
+            let spec = ModelSpec::load(path)?;
+            if spec.input_shape != train.inputs.shape()[1..] {
+                return Err(format!(
+                    "spec expects {:?} inputs but the data is {:?}",
+                    spec.input_shape,
+                    &train.inputs.shape()[1..]
+                )
+                .into());
+            }
+            let mut model = spec.build(train.classes, args.learning_rate, args.seed)?;
+            model.fit(&train, validation, &config, 0, print_epoch)?;
+            model
+        }
+        (None, None) => unreachable!("checked while parsing"),
+    };
+
+    let evaluation = model.evaluate(&test, args.batch_size);
+    println!(
+        "\ntest loss {:.4}\n{}",
+        evaluation.loss, evaluation.confusion
+    );
+
+    model.save(&args.output)?;
+    println!("model written to {}", args.output.display());
+    if let Some(path) = &args.onnx {
+        onnx::save(&model, &test.inputs.shape()[1..], path)?;
+        println!("ONNX model written to {}", path.display());
+    }
+    Ok(())
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args = parse_args().unwrap_or_else(|err| {
+        if !err.is_empty() {
+            eprintln!("error: {}\n", err);
+        }
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    });
+    if let Err(err) = run(args) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,115 @@
+use ndarray::{Array1, Array2};
+use std::fmt;
+
+// fraction of matching labels
+pub fn accuracy(actual: &Array1<usize>, predicted: &Array1<usize>) -> f32 {
+    assert_eq!(actual.len(), predicted.len(), "label count mismatch");
+    if actual.is_empty() {
+        return 0.0;
+    }
+    let correct = actual.iter().zip(predicted).filter(|(a, p)| a == p).count();
+    correct as f32 / actual.len() as f32
+}
+
+// rows are the actual classes, columns the predicted ones
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfusionMatrix {
+    pub counts: Array2<usize>,
+}
+
+impl ConfusionMatrix {
+    pub fn new(classes: usize) -> ConfusionMatrix {
+        Self {
+            counts: Array2::zeros((classes, classes)),
+        }
+    }
+
+    pub fn classes(&self) -> usize {
+        self.counts.nrows()
+    }
+
+    pub fn add(&mut self, actual: &Array1<usize>, predicted: &Array1<usize>) {
+        for (&a, &p) in actual.iter().zip(predicted) {
+            self.counts[[a, p]] += 1;
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.sum()
+    }
+
+    pub fn accuracy(&self) -> f32 {
+        let correct: usize = self.counts.diag().sum();
+        correct as f32 / self.total().max(1) as f32
+    }
+
+    // of everything predicted as "class", how much really was "class"
+    pub fn precision(&self, class: usize) -> f32 {
+        let predicted = self.counts.column(class).sum();
+        self.counts[[class, class]] as f32 / predicted.max(1) as f32
+    }
+
+    // of everything that really was "class", how much got predicted as such
+    pub fn recall(&self, class: usize) -> f32 {
+        let actual = self.counts.row(class).sum();
+        self.counts[[class, class]] as f32 / actual.max(1) as f32
+    }
+}
+
+impl fmt::Display for ConfusionMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .counts
+            .iter()
+            .max()
+            .map_or(1, |m| m.to_string().len())
+            .max(3);
+        write!(f, "actual\\pred")?;
+        for class in 0..self.classes() {
+            write!(f, " {:>width$}", class, width = width)?;
+        }
+        writeln!(f, " {:>7} {:>7}", "prec", "recall")?;
+        for (class, row) in self.counts.rows().into_iter().enumerate() {
+            write!(f, "{:>11}", class)?;
+            for count in row {
+                write!(f, " {:>width$}", count, width = width)?;
+            }
+            writeln!(
+                f,
+                " {:>7.3} {:>7.3}",
+                self.precision(class),
+                self.recall(class)
+            )?;
+        }
+        write!(
+            f,
+            "accuracy: {:.4} ({} samples)",
+            self.accuracy(),
+            self.total()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn confusion_matrix_counts() {
+        let mut matrix = ConfusionMatrix::new(3);
+        matrix.add(&array![0, 0, 1, 2, 2], &array![0, 1, 1, 2, 0]);
+        assert_eq!(matrix.total(), 5);
+        assert_eq!(matrix.counts[[0, 1]], 1);
+        assert!((matrix.accuracy() - 0.6).abs() < 1e-6);
+        assert!((matrix.precision(1) - 0.5).abs() < 1e-6);
+        assert!((matrix.recall(2) - 0.5).abs() < 1e-6);
+        assert!(matrix.to_string().contains("accuracy: 0.6000"));
+    }
+
+    #[test]
+    fn accuracy_of_labels() {
+        assert_eq!(accuracy(&array![1, 2, 3, 4], &array![1, 2, 0, 0]), 0.5);
+        assert_eq!(accuracy(&array![], &array![]), 0.0);
+    }
+}
@@ -0,0 +1,719 @@
+use crate::activation::Activation;
+use crate::cnn::Cnn;
+use crate::conv_layer::ConvLayer;
+use crate::dense_layer::DenseLayer;
+use crate::layers::{ActivationLayer, FlattenLayer, Layer, LayerType, Param};
+use crate::loss::LossFunction;
+use crate::optimizer::Optimizer;
+use crate::pool_layer::PoolLayer;
+use ndarray::{ArrayD, Ix2, IxDyn};
+use prost::Message;
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// ONNX export / import of plain feed forward graphs: Conv, MaxPool, Gemm, Flatten and the
+// activations. exported graphs use opset 20 (Gelu), the batch axis is left symbolic ("N")...
+const IR_VERSION: i64 = 9;
+const OPSET_VERSION: i64 = 20;
+const FLOAT: i32 = 1; // TensorProto.DataType
+
+// the subset of onnx.proto we read and write, field numbers are the official ones.
+// oneofs (TypeProto, Dimension) are flattened, they are the same on the wire
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelProto {
+        #[prost(int64, tag = "1")]
+        pub ir_version: i64,
+        #[prost(string, tag = "2")]
+        pub producer_name: String,
+        #[prost(string, tag = "3")]
+        pub producer_version: String,
+        #[prost(message, optional, tag = "7")]
+        pub graph: Option<GraphProto>,
+        #[prost(message, repeated, tag = "8")]
+        pub opset_import: Vec<OperatorSetIdProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct OperatorSetIdProto {
+        #[prost(string, tag = "1")]
+        pub domain: String,
+        #[prost(int64, tag = "2")]
+        pub version: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GraphProto {
+        #[prost(message, repeated, tag = "1")]
+        pub node: Vec<NodeProto>,
+        #[prost(string, tag = "2")]
+        pub name: String,
+        #[prost(message, repeated, tag = "5")]
+        pub initializer: Vec<TensorProto>,
+        #[prost(message, repeated, tag = "11")]
+        pub input: Vec<ValueInfoProto>,
+        #[prost(message, repeated, tag = "12")]
+        pub output: Vec<ValueInfoProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct NodeProto {
+        #[prost(string, repeated, tag = "1")]
+        pub input: Vec<String>,
+        #[prost(string, repeated, tag = "2")]
+        pub output: Vec<String>,
+        #[prost(string, tag = "3")]
+        pub name: String,
+        #[prost(string, tag = "4")]
+        pub op_type: String,
+        #[prost(message, repeated, tag = "5")]
+        pub attribute: Vec<AttributeProto>,
+        #[prost(string, tag = "7")]
+        pub domain: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AttributeProto {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        // optional like in onnx.proto (proto2), so zeros are written out too
+        #[prost(float, optional, tag = "2")]
+        pub f: Option<f32>,
+        #[prost(int64, optional, tag = "3")]
+        pub i: Option<i64>,
+        #[prost(bytes = "vec", optional, tag = "4")]
+        pub s: Option<Vec<u8>>,
+        #[prost(int64, repeated, packed = "false", tag = "8")]
+        pub ints: Vec<i64>,
+        #[prost(int32, tag = "20")]
+        pub r#type: i32,
+    }
+
+    // AttributeProto.AttributeType
+    pub const ATTRIBUTE_FLOAT: i32 = 1;
+    pub const ATTRIBUTE_INT: i32 = 2;
+    pub const ATTRIBUTE_STRING: i32 = 3;
+    pub const ATTRIBUTE_INTS: i32 = 7;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TensorProto {
+        #[prost(int64, repeated, packed = "false", tag = "1")]
+        pub dims: Vec<i64>,
+        #[prost(int32, tag = "2")]
+        pub data_type: i32,
+        #[prost(float, repeated, tag = "4")]
+        pub float_data: Vec<f32>,
+        #[prost(string, tag = "8")]
+        pub name: String,
+        #[prost(bytes = "vec", tag = "9")]
+        pub raw_data: Vec<u8>, // little endian
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ValueInfoProto {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(message, optional, tag = "2")]
+        pub r#type: Option<TypeProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TypeProto {
+        #[prost(message, optional, tag = "1")]
+        pub tensor_type: Option<TensorTypeProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TensorTypeProto {
+        #[prost(int32, tag = "1")]
+        pub elem_type: i32,
+        #[prost(message, optional, tag = "2")]
+        pub shape: Option<TensorShapeProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TensorShapeProto {
+        #[prost(message, repeated, tag = "1")]
+        pub dim: Vec<Dimension>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Dimension {
+        #[prost(int64, tag = "1")]
+        pub dim_value: i64,
+        #[prost(string, tag = "2")]
+        pub dim_param: String,
+    }
+}
+use proto::*;
+
+fn unsupported<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// ---- export ----
+
+fn ints(name: &str, values: &[usize]) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        ints: values.iter().map(|&v| v as i64).collect(),
+        r#type: ATTRIBUTE_INTS,
+        ..Default::default()
+    }
+}
+fn int(name: &str, value: i64) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        i: Some(value),
+        r#type: ATTRIBUTE_INT,
+        ..Default::default()
+    }
+}
+fn float(name: &str, value: f32) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        f: Some(value),
+        r#type: ATTRIBUTE_FLOAT,
+        ..Default::default()
+    }
+}
+fn string(name: &str, value: &str) -> AttributeProto {
+    AttributeProto {
+        name: name.to_string(),
+        s: Some(value.as_bytes().to_vec()),
+        r#type: ATTRIBUTE_STRING,
+        ..Default::default()
+    }
+}
+
+fn tensor(name: String, value: &ArrayD<f32>) -> TensorProto {
+    TensorProto {
+        dims: value.shape().iter().map(|&d| d as i64).collect(),
+        data_type: FLOAT,
+        name,
+        raw_data: value.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        ..Default::default()
+    }
+}
+
+// float tensor with a symbolic batch axis
+fn value_info(name: &str, sample_shape: &[usize]) -> ValueInfoProto {
+    let batch = Dimension {
+        dim_param: "N".to_string(),
+        ..Default::default()
+    };
+    let dims = sample_shape.iter().map(|&d| Dimension {
+        dim_value: d as i64,
+        ..Default::default()
+    });
+    ValueInfoProto {
+        name: name.to_string(),
+        r#type: Some(TypeProto {
+            tensor_type: Some(TensorTypeProto {
+                elem_type: FLOAT,
+                shape: Some(TensorShapeProto {
+                    dim: std::iter::once(batch).chain(dims).collect(),
+                }),
+            }),
+        }),
+    }
+}
+
+// (op_type, attributes) of an activation
+fn activation_op(activation: Activation) -> (&'static str, Vec<AttributeProto>) {
+    match activation {
+        Activation::ReLu => ("Relu", vec![]),
+        Activation::LeakyReLu(alpha) => ("LeakyRelu", vec![float("alpha", alpha)]),
+        Activation::Sigmoid => ("Sigmoid", vec![]),
+        Activation::Tanh => ("Tanh", vec![]),
+        Activation::Gelu => ("Gelu", vec![string("approximate", "tanh")]),
+        Activation::Softmax => ("Softmax", vec![int("axis", -1)]),
+        Activation::Identity => ("Identity", vec![]),
+    }
+}
+
+// serialized ModelProto, "input_shape" is the shape of a single sample
+pub fn export(model: &Cnn, input_shape: &[usize]) -> io::Result<Vec<u8>> {
+    let mut graph = GraphProto {
+        name: "rust_cnn".to_string(),
+        input: vec![value_info("input", input_shape)],
+        ..Default::default()
+    };
+    let mut shape = input_shape.to_vec();
+    let mut previous = "input".to_string();
+    for (i, layer) in model.layers.iter().enumerate() {
+        let name = format!("layer{}", i);
+        let output = if i + 1 == model.layers.len() {
+            "output".to_string()
+        } else {
+            format!("{}_out", name)
+        };
+        let mut inputs = vec![previous.clone()];
+        let (op_type, attribute) = match layer {
+            LayerType::Conv(conv) => {
+                let kernel = conv.kernels.value.shape()[2];
+                for (suffix, value) in [("weight", &conv.kernels.value), ("bias", &conv.bias.value)]
+                {
+                    inputs.push(format!("{}.{}", name, suffix));
+                    graph
+                        .initializer
+                        .push(tensor(format!("{}.{}", name, suffix), value));
+                }
+                let attributes = vec![
+                    ints("kernel_shape", &[kernel, kernel]),
+                    ints("strides", &[conv.stride, conv.stride]),
+                    ints("pads", &[conv.padding; 4]),
+                ];
+                ("Conv", attributes)
+            }
+            LayerType::Pool(pool) => {
+                let attributes = vec![
+                    ints("kernel_shape", &[pool.size, pool.size]),
+                    ints("strides", &[pool.stride, pool.stride]),
+                ];
+                ("MaxPool", attributes)
+            }
+            LayerType::Dense(dense) => {
+                // Y = X * W + b with W stored as [inputs, outputs], i.e. transB = 0
+                for (suffix, value) in [
+                    ("weight", &dense.weights.value),
+                    ("bias", &dense.bias.value),
+                ] {
+                    inputs.push(format!("{}.{}", name, suffix));
+                    graph
+                        .initializer
+                        .push(tensor(format!("{}.{}", name, suffix), value));
+                }
+                ("Gemm", vec![])
+            }
+            LayerType::Activation(layer) => activation_op(layer.activation),
+            LayerType::Flatten(_) => ("Flatten", vec![int("axis", 1)]),
+            LayerType::BatchNorm(_) | LayerType::Dropout(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("layer {} has no ONNX export (batch norm / dropout)", i),
+                ))
+            }
+        };
+        graph.node.push(NodeProto {
+            input: inputs,
+            output: vec![output.clone()],
+            name,
+            op_type: op_type.to_string(),
+            attribute,
+            ..Default::default()
+        });
+        shape = layer.output_shape(&shape);
+        previous = output;
+    }
+    graph.output.push(value_info(&previous, &shape));
+
+    let model = ModelProto {
+        ir_version: IR_VERSION,
+        producer_name: "rust_cnn".to_string(),
+        producer_version: env!("CARGO_PKG_VERSION").to_string(),
+        graph: Some(graph),
+        opset_import: vec![OperatorSetIdProto {
+            domain: String::new(),
+            version: OPSET_VERSION,
+        }],
+    };
+    Ok(model.encode_to_vec())
+}
+
+pub fn save<P: AsRef<Path>>(model: &Cnn, input_shape: &[usize], path: P) -> io::Result<()> {
+    fs::write(path, export(model, input_shape)?)
+}
+
+// ---- import ----
+
+fn to_array(tensor: &TensorProto) -> io::Result<ArrayD<f32>> {
+    if tensor.data_type != FLOAT {
+        return Err(unsupported(format!(
+            "tensor {} is not float (data type {})",
+            tensor.name, tensor.data_type
+        )));
+    }
+    let data = if tensor.raw_data.is_empty() {
+        tensor.float_data.clone()
+    } else {
+        tensor
+            .raw_data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    };
+    let shape: Vec<usize> = tensor.dims.iter().map(|&d| d as usize).collect();
+    ArrayD::from_shape_vec(IxDyn(&shape), data)
+        .map_err(|_| unsupported(format!("tensor {} doesn't match its dims", tensor.name)))
+}
+
+struct Node<'a> {
+    proto: &'a NodeProto,
+    initializers: &'a HashMap<&'a str, ArrayD<f32>>,
+}
+impl<'a> Node<'a> {
+    fn attribute(&self, name: &str) -> Option<&'a AttributeProto> {
+        self.proto.attribute.iter().find(|a| a.name == name)
+    }
+    fn int(&self, name: &str, default: i64) -> i64 {
+        self.attribute(name).and_then(|a| a.i).unwrap_or(default)
+    }
+    fn float(&self, name: &str, default: f32) -> f32 {
+        self.attribute(name).and_then(|a| a.f).unwrap_or(default)
+    }
+    fn ints(&self, name: &str) -> Option<&'a [i64]> {
+        self.attribute(name).map(|a| a.ints.as_slice())
+    }
+    fn string(&self, name: &str) -> Option<&'a [u8]> {
+        self.attribute(name).and_then(|a| a.s.as_deref())
+    }
+    // the n-th input, which has to be an initializer
+    fn weight(&self, n: usize) -> io::Result<Option<ArrayD<f32>>> {
+        match self.proto.input.get(n).filter(|name| !name.is_empty()) {
+            None => Ok(None),
+            Some(name) => match self.initializers.get(name.as_str()) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(self.error(&format!("input {} is not a constant", name))),
+            },
+        }
+    }
+    fn error(&self, message: &str) -> io::Error {
+        unsupported(format!(
+            "{} node {}: {}",
+            self.proto.op_type, self.proto.name, message
+        ))
+    }
+
+    // square 2d window with the same value on both axes
+    fn square(&self, name: &str, default: usize) -> io::Result<usize> {
+        match self.ints(name) {
+            None => Ok(default),
+            Some(&[a, b]) if a == b && a > 0 => Ok(a as usize),
+            Some(values) => Err(self.error(&format!("{} {:?} is not supported", name, values))),
+        }
+    }
+    fn padding(&self) -> io::Result<usize> {
+        let auto_pad = self.string("auto_pad").unwrap_or(b"NOTSET");
+        if auto_pad != b"NOTSET" {
+            return Err(self.error("auto_pad is not supported"));
+        }
+        match self.ints("pads") {
+            None => Ok(0),
+            Some(pads) if pads.len() == 4 && pads.iter().all(|&p| p == pads[0] && p >= 0) => {
+                Ok(pads[0] as usize)
+            }
+            Some(pads) => Err(self.error(&format!("pads {:?} are not supported", pads))),
+        }
+    }
+
+    fn layer(&self) -> io::Result<LayerType> {
+        let activation = |activation| Ok(ActivationLayer::new(activation).into());
+        match self.proto.op_type.as_str() {
+            "Conv" => {
+                let kernels = self
+                    .weight(1)?
+                    .ok_or_else(|| self.error("missing weights"))?;
+                if kernels.ndim() != 4 || kernels.shape()[2] != kernels.shape()[3] {
+                    return Err(self.error("only square 2d kernels are supported"));
+                }
+                if self.int("group", 1) != 1 || self.square("dilations", 1)? != 1 {
+                    return Err(self.error("groups and dilations are not supported"));
+                }
+                let (filters, channels, kernel) =
+                    (kernels.shape()[0], kernels.shape()[1], kernels.shape()[2]);
+                if self.square("kernel_shape", kernel)? != kernel {
+                    return Err(self.error("kernel_shape doesn't match the weights"));
+                }
+                let stride = self.square("strides", 1)?;
+                let padding = self.padding()?;
+                let bias = self
+                    .weight(2)?
+                    .unwrap_or_else(|| ArrayD::zeros(vec![filters]));
+                // the initial weights are overwritten right away
+                let mut rng = StdRng::seed_from_u64(0);
+                let mut conv = ConvLayer::new(channels, filters, kernel, stride, padding, &mut rng);
+                conv.kernels = Param::new(kernels.as_standard_layout().into_owned());
+                conv.bias = Param::new(
+                    bias.into_shape(vec![filters])
+                        .map_err(|_| self.error("bias doesn't match the filters"))?,
+                );
+                Ok(conv.into())
+            }
+            "MaxPool" => {
+                let size = self.square("kernel_shape", 0)?;
+                if size == 0 || self.padding()? != 0 || self.int("ceil_mode", 0) != 0 {
+                    return Err(self.error("only unpadded square windows are supported"));
+                }
+                Ok(PoolLayer::new(size, self.square("strides", 1)?).into())
+            }
+            "Gemm" => {
+                if self.int("transA", 0) != 0
+                    || self.float("alpha", 1.0) != 1.0
+                    || self.float("beta", 1.0) != 1.0
+                {
+                    return Err(self.error("only Y = A * B + C is supported"));
+                }
+                let weights = self
+                    .weight(1)?
+                    .ok_or_else(|| self.error("missing weights"))?;
+                let mut weights = weights
+                    .into_dimensionality::<Ix2>()
+                    .map_err(|_| self.error("weights are not a matrix"))?;
+                if self.int("transB", 0) != 0 {
+                    weights = weights.reversed_axes(); // e.g. PyTorch's Linear
+                }
+                let (inputs, outputs) = weights.dim();
+                let bias = self
+                    .weight(2)?
+                    .unwrap_or_else(|| ArrayD::zeros(vec![outputs]));
+                let mut rng = StdRng::seed_from_u64(0);
+                let mut dense = DenseLayer::new(inputs, outputs, &mut rng);
+                dense.weights = Param::new(weights.as_standard_layout().into_owned().into_dyn());
+                dense.bias = Param::new(
+                    bias.into_shape(vec![outputs])
+                        .map_err(|_| self.error("bias doesn't match the outputs"))?,
+                );
+                Ok(dense.into())
+            }
+            "Flatten" => match self.int("axis", 1) {
+                1 => Ok(FlattenLayer::new().into()),
+                _ => Err(self.error("only axis 1 is supported")),
+            },
+            "Relu" => activation(Activation::ReLu),
+            "LeakyRelu" => activation(Activation::LeakyReLu(self.float("alpha", 0.01))),
+            "Sigmoid" => activation(Activation::Sigmoid),
+            "Tanh" => activation(Activation::Tanh),
+            "Gelu" => match self.string("approximate") {
+                Some(b"tanh") => activation(Activation::Gelu),
+                _ => Err(self.error("only the tanh approximation is supported")),
+            },
+            // the input is [N, classes] here, so axis 1 is the last one too
+            "Softmax" => match self.int("axis", -1) {
+                -1 | 1 => activation(Activation::Softmax),
+                _ => Err(self.error("only the last axis is supported")),
+            },
+            "Identity" => activation(Activation::Identity),
+            _ => Err(self.error("operator is not supported")),
+        }
+    }
+}
+
+// rebuilds a model from a chain of supported ops, ONNX has no notion of loss or optimizer
+pub fn import(bytes: &[u8], loss: LossFunction, optimizer: Optimizer) -> io::Result<Cnn> {
+    let model = ModelProto::decode(bytes).map_err(|e| unsupported(e.to_string()))?;
+    let graph = model
+        .graph
+        .ok_or_else(|| unsupported("model has no graph"))?;
+    let initializers = graph
+        .initializer
+        .iter()
+        .map(|t| Ok((t.name.as_str(), to_array(t)?)))
+        .collect::<io::Result<HashMap<_, _>>>()?;
+    // older exporters list the initializers as graph inputs too
+    let input = graph
+        .input
+        .iter()
+        .find(|input| !initializers.contains_key(input.name.as_str()))
+        .ok_or_else(|| unsupported("graph has no input"))?;
+    let input_shape = sample_shape(input)?;
+    let mut current = input.name.as_str();
+
+    let mut cnn = Cnn::new(loss, optimizer);
+    for proto in &graph.node {
+        let node = Node {
+            proto,
+            initializers: &initializers,
+        };
+        if proto.input.first().map(String::as_str) != Some(current) || proto.output.len() != 1 {
+            return Err(node.error("only sequential graphs are supported"));
+        }
+        cnn.layers.push(node.layer()?);
+        current = &proto.output[0];
+    }
+    if graph.output.iter().all(|output| output.name != current) {
+        return Err(unsupported("the last node is not the graph output"));
+    }
+    // the layers have to fit the input and each other, forward would panic otherwise
+    cnn.output_shape(&input_shape)?;
+    Ok(cnn)
+}
+
+// dims of a graph input without the batch axis, every one of them has to be known
+fn sample_shape(input: &ValueInfoProto) -> io::Result<Vec<usize>> {
+    let dims = input
+        .r#type
+        .as_ref()
+        .and_then(|t| t.tensor_type.as_ref())
+        .and_then(|t| t.shape.as_ref())
+        .map(|shape| shape.dim.as_slice())
+        .ok_or_else(|| unsupported(format!("input {} has no shape", input.name)))?;
+    match dims.split_first() {
+        Some((_batch, sample)) if sample.iter().all(|d| d.dim_value > 0) => {
+            Ok(sample.iter().map(|d| d.dim_value as usize).collect())
+        }
+        _ => Err(unsupported(format!(
+            "input {} needs a batch axis and fixed sample dims",
+            input.name
+        ))),
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P, loss: LossFunction, optimizer: Optimizer) -> io::Result<Cnn> {
+    import(&fs::read(path)?, loss, optimizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dropout_layer::DropoutLayer;
+    use ndarray::{array, Array4};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const CCE: LossFunction = LossFunction::CategoricalCrossEntropy { from_logits: false };
+
+    fn model(hidden: Activation) -> Cnn {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut conv = ConvLayer::new(2, 3, 3, 2, 1, &mut rng);
+        conv.bias = Param::new(ArrayD::from_shape_simple_fn(vec![3], || {
+            rng.gen_range(-1.0..1.0)
+        }));
+        Cnn::new(CCE, Optimizer::sgd(0.1))
+            .add_layer(conv)
+            .add_layer(ActivationLayer::new(hidden))
+            .add_layer(PoolLayer::new(2, 1))
+            .add_layer(FlattenLayer::new())
+            .add_layer(DenseLayer::new(12, 4, &mut rng))
+            .add_layer(ActivationLayer::new(Activation::Softmax))
+    }
+
+    fn inputs() -> ArrayD<f32> {
+        Array4::from_shape_fn((5, 2, 6, 6), |(n, c, i, j)| {
+            ((n * 13 + c * 7 + i * 3 + j) % 11) as f32 / 5.0 - 1.0
+        })
+        .into_dyn()
+    }
+
+    #[test]
+    fn round_trip_keeps_outputs() {
+        for hidden in [
+            Activation::ReLu,
+            Activation::LeakyReLu(0.0), // zero attributes have to survive the encoding
+            Activation::Sigmoid,
+            Activation::Tanh,
+            Activation::Gelu,
+            Activation::Identity,
+        ] {
+            let mut original = model(hidden);
+            let bytes = export(&original, &[2, 6, 6]).unwrap();
+            let mut imported = import(&bytes, CCE, Optimizer::sgd(0.1)).unwrap();
+            assert_eq!(imported.to_json(), original.to_json(), "{:?}", hidden);
+            assert_eq!(imported.forward(&inputs()), original.forward(&inputs()));
+        }
+    }
+
+    #[test]
+    fn exported_graph_is_described() {
+        let bytes = export(&model(Activation::ReLu), &[2, 6, 6]).unwrap();
+        let model = ModelProto::decode(bytes.as_slice()).unwrap();
+        assert_eq!(model.opset_import[0].version, OPSET_VERSION);
+        let graph = model.graph.unwrap();
+        let ops: Vec<&str> = graph.node.iter().map(|n| n.op_type.as_str()).collect();
+        assert_eq!(
+            ops,
+            ["Conv", "Relu", "MaxPool", "Flatten", "Gemm", "Softmax"]
+        );
+        assert_eq!(graph.initializer.len(), 4);
+        let dims = |info: &ValueInfoProto| {
+            let shape = info.r#type.as_ref().unwrap().tensor_type.as_ref().unwrap();
+            let shape = shape.shape.as_ref().unwrap();
+            shape.dim.iter().map(|d| d.dim_value).collect::<Vec<_>>()
+        };
+        assert_eq!(dims(&graph.input[0]), [0, 2, 6, 6]); // 0: the symbolic batch axis
+        assert_eq!(dims(&graph.output[0]), [0, 4]);
+    }
+
+    // the way PyTorch writes a Linear layer: transposed weights as float_data
+    #[test]
+    fn imports_transposed_gemm() {
+        let weights = array![[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]; // [outputs, inputs]
+        let graph = GraphProto {
+            node: vec![NodeProto {
+                input: vec!["x".into(), "w".into(), "b".into()],
+                output: vec!["y".into()],
+                op_type: "Gemm".into(),
+                attribute: vec![int("transB", 1)],
+                ..Default::default()
+            }],
+            initializer: vec![
+                TensorProto {
+                    dims: vec![2, 3],
+                    data_type: FLOAT,
+                    float_data: weights.iter().copied().collect(),
+                    name: "w".into(),
+                    ..Default::default()
+                },
+                tensor("b".into(), &array![0.5, -0.5].into_dyn()),
+            ],
+            input: vec![value_info("x", &[3])],
+            output: vec![value_info("y", &[2])],
+            ..Default::default()
+        };
+        let bytes = ModelProto {
+            graph: Some(graph),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let mut cnn = import(&bytes, LossFunction::MeanSquaredError, Optimizer::sgd(0.1)).unwrap();
+        let output = cnn.forward(&array![[1.0, 0.0, -1.0]].into_dyn());
+        assert_eq!(output, array![[-1.5, -2.5]].into_dyn());
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_represent() {
+        let with_dropout = model(Activation::ReLu).add_layer(DropoutLayer::new(0.5, 1));
+        assert!(export(&with_dropout, &[2, 6, 6]).is_err());
+
+        let bytes = export(&model(Activation::ReLu), &[2, 6, 6]).unwrap();
+        let mut proto = ModelProto::decode(bytes.as_slice()).unwrap();
+        proto.graph.as_mut().unwrap().node[1].op_type = "Elu".into();
+        let err = import(&proto.encode_to_vec(), CCE, Optimizer::sgd(0.1)).unwrap_err();
+        assert!(err.to_string().contains("Elu"));
+
+        assert!(import(&[0xff, 0xff, 0xff], CCE, Optimizer::sgd(0.1)).is_err());
+    }
+
+    #[test]
+    fn rejects_layers_that_dont_fit() {
+        let with_input = |input: ValueInfoProto| {
+            let bytes = export(&model(Activation::ReLu), &[2, 6, 6]).unwrap();
+            let mut proto = ModelProto::decode(bytes.as_slice()).unwrap();
+            proto.graph.as_mut().unwrap().input[0] = input;
+            import(&proto.encode_to_vec(), CCE, Optimizer::sgd(0.1))
+        };
+        assert!(with_input(value_info("input", &[2, 6, 6])).is_ok());
+        // conv channels, the dense layer's inputs, the pool window and a flat input
+        for input_shape in [&[3, 6, 6][..], &[2, 8, 8], &[2, 2, 2], &[72]] {
+            let err = with_input(value_info("input", input_shape)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", input_shape);
+        }
+
+        let mut unknown = value_info("input", &[2, 6, 6]);
+        let tensor_type = unknown.r#type.as_mut().unwrap().tensor_type.as_mut();
+        tensor_type.unwrap().shape = None;
+        assert!(with_input(unknown).is_err());
+    }
+
+    #[test]
+    fn save_and_load_files() {
+        let mut original = model(Activation::Tanh);
+        let path = std::env::temp_dir().join(format!("rust_cnn_{}.onnx", std::process::id()));
+        save(&original, &[2, 6, 6], &path).unwrap();
+        let mut loaded = load(&path, CCE, Optimizer::sgd(0.1)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.forward(&inputs()), original.forward(&inputs()));
+    }
+}
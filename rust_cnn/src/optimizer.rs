@@ -0,0 +1,129 @@
+use crate::layers::Param;
+use ndarray::{ArrayD, Zip};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OptimizerKind {
+    Sgd,
+    Momentum {
+        beta: f32,
+    },
+    RmsProp {
+        beta: f32,
+        epsilon: f32,
+    },
+    Adam {
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+    },
+}
+
+// the optimizer owns one set of moment buffers per parameter (in the model's parameter
+// order), so it is saved together with the model to resume training exactly...
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Optimizer {
+    pub kind: OptimizerKind,
+    pub learning_rate: f32,
+    step: u64,
+    state: Vec<Vec<ArrayD<f32>>>,
+}
+
+impl Optimizer {
+    pub fn new(kind: OptimizerKind, learning_rate: f32) -> Optimizer {
+        Self {
+            kind,
+            learning_rate,
+            step: 0,
+            state: Vec::new(),
+        }
+    }
+    pub fn sgd(learning_rate: f32) -> Optimizer {
+        Optimizer::new(OptimizerKind::Sgd, learning_rate)
+    }
+    pub fn momentum(learning_rate: f32) -> Optimizer {
+        Optimizer::new(OptimizerKind::Momentum { beta: 0.9 }, learning_rate)
+    }
+    pub fn rms_prop(learning_rate: f32) -> Optimizer {
+        let kind = OptimizerKind::RmsProp {
+            beta: 0.9,
+            epsilon: 1e-8,
+        };
+        Optimizer::new(kind, learning_rate)
+    }
+    pub fn adam(learning_rate: f32) -> Optimizer {
+        let kind = OptimizerKind::Adam {
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+        };
+        Optimizer::new(kind, learning_rate)
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.step
+    }
+
+    // applies one update using the gradients stored in "params"
+    pub fn step(&mut self, params: Vec<&mut Param>) {
+        let buffers = match self.kind {
+            OptimizerKind::Sgd => 0,
+            OptimizerKind::Momentum { .. } | OptimizerKind::RmsProp { .. } => 1,
+            OptimizerKind::Adam { .. } => 2,
+        };
+        if self.state.len() != params.len() {
+            self.state = params
+                .iter()
+                .map(|p| vec![ArrayD::zeros(p.value.raw_dim()); buffers])
+                .collect();
+        }
+        self.step += 1;
+        let lr = self.learning_rate;
+
+        for (param, state) in params.into_iter().zip(self.state.iter_mut()) {
+            assert_eq!(
+                param.value.shape(),
+                param.grad.shape(),
+                "parameter has no gradient, run backward first"
+            );
+            match self.kind {
+                OptimizerKind::Sgd => param.value.scaled_add(-lr, &param.grad),
+                OptimizerKind::Momentum { beta } => {
+                    let velocity = &mut state[0];
+                    Zip::from(&mut *velocity)
+                        .and(&param.grad)
+                        .for_each(|v, &g| *v = beta * *v + g);
+                    param.value.scaled_add(-lr, velocity);
+                }
+                OptimizerKind::RmsProp { beta, epsilon } => {
+                    Zip::from(&mut param.value)
+                        .and(&mut state[0])
+                        .and(&param.grad)
+                        .for_each(|w, s, &g| {
+                            *s = beta * *s + (1.0 - beta) * g * g;
+                            *w -= lr * g / (s.sqrt() + epsilon);
+                        });
+                }
+                OptimizerKind::Adam {
+                    beta1,
+                    beta2,
+                    epsilon,
+                } => {
+                    // bias corrected first and second moments
+                    let t = self.step as i32;
+                    let (c1, c2) = (1.0 - beta1.powi(t), 1.0 - beta2.powi(t));
+                    let (m, v) = state.split_at_mut(1);
+                    Zip::from(&mut param.value)
+                        .and(&mut m[0])
+                        .and(&mut v[0])
+                        .and(&param.grad)
+                        .for_each(|w, m, v, &g| {
+                            *m = beta1 * *m + (1.0 - beta1) * g;
+                            *v = beta2 * *v + (1.0 - beta2) * g * g;
+                            *w -= lr * (*m / c1) / ((*v / c2).sqrt() + epsilon);
+                        });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::layers::Layer;
+use ndarray::{s, Array4, ArrayD, Ix4};
+use serde::{Deserialize, Serialize};
+
+// max pooling over [N, channels, height, width], every channel separately
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PoolLayer {
+    pub size: usize,
+    pub stride: usize,
+    #[serde(skip)]
+    input: Option<Array4<f32>>,
+}
+
+impl PoolLayer {
+    pub fn new(size: usize, stride: usize) -> PoolLayer {
+        assert!(
+            size > 0 && stride > 0,
+            "pool size and stride must be positive"
+        );
+        Self {
+            size,
+            stride,
+            input: None,
+        }
+    }
+
+    pub fn output_size(&self, height: usize, width: usize) -> (usize, usize) {
+        assert!(
+            height >= self.size && width >= self.size,
+            "input smaller than the pool window"
+        );
+        (
+            (height - self.size) / self.stride + 1,
+            (width - self.size) / self.stride + 1,
+        )
+    }
+
+    // position (row, col) of the maximum inside a window, first one wins on ties
+    fn argmax(
+        &self,
+        input: &Array4<f32>,
+        n: usize,
+        c: usize,
+        i: usize,
+        j: usize,
+    ) -> (usize, usize) {
+        let (k, s) = (self.size, self.stride);
+        let window = input.slice(s![n, c, i * s..i * s + k, j * s..j * s + k]);
+        let mut best = (0, 0);
+        for ((r, col), &v) in window.indexed_iter() {
+            if v > window[best] {
+                best = (r, col);
+            }
+        }
+        (i * s + best.0, j * s + best.1)
+    }
+}
+
+impl Layer for PoolLayer {
+    fn forward(&mut self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        let input = input
+            .clone()
+            .into_dimensionality::<Ix4>()
+            .expect("pool layer expects [batch, channels, height, width]");
+        let (batch, channels, h, w) = input.dim();
+        let (out_h, out_w) = self.output_size(h, w);
+        let mut output = Array4::zeros((batch, channels, out_h, out_w));
+        for ((n, c, i, j), out) in output.indexed_iter_mut() {
+            let (r, col) = self.argmax(&input, n, c, i, j);
+            *out = input[[n, c, r, col]];
+        }
+        self.input = Some(input);
+        output.into_dyn()
+    }
+
+    fn backward(&mut self, grad: &ArrayD<f32>) -> ArrayD<f32> {
+        let input = self.input.take().expect("backward called before forward");
+        let grad = grad.view().into_dimensionality::<Ix4>().unwrap();
+        // only the max of every window receives the gradient
+        let mut input_grad = Array4::zeros(input.raw_dim());
+        for ((n, c, i, j), &g) in grad.indexed_iter() {
+            let (r, col) = self.argmax(&input, n, c, i, j);
+            input_grad[[n, c, r, col]] += g;
+        }
+        input_grad.into_dyn()
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let (out_h, out_w) = self.output_size(input_shape[1], input_shape[2]);
+        vec![input_shape[0], out_h, out_w]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gradient_check::GradientCheck;
+    use ndarray::Array;
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    #[test]
+    fn max_of_every_window() {
+        #[rustfmt::skip]
+        let input = ArrayD::from_shape_vec(vec![1, 1, 4, 4], vec![
+            1.0, 2.0, 0.0, 1.0,
+            4.0, 3.0, 5.0, 0.0,
+            0.0, 0.0, 1.0, 1.0,
+            0.0, 9.0, 1.0, 2.0,
+        ])
+        .unwrap();
+        let mut layer = PoolLayer::new(2, 2);
+        let output = layer.forward(&input);
+        assert_eq!(output.shape(), &[1, 1, 2, 2]);
+        assert_eq!(output.as_slice().unwrap(), &[4.0, 5.0, 9.0, 2.0]);
+
+        // only the max positions get the gradient back
+        let grad = layer.backward(&Array::ones(output.raw_dim()));
+        assert_eq!(grad.sum(), 4.0);
+        assert_eq!(grad[[0, 0, 1, 0]], 1.0);
+        assert_eq!(grad[[0, 0, 0, 0]], 0.0);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(24))]
+        #[test]
+        fn gradients_match_finite_differences(
+            batch in 1usize..3,
+            channels in 1usize..3,
+            size in 1usize..4,
+            stride in 1usize..3,
+            extra in 0usize..4,
+            seed in any::<u64>(),
+        ) {
+            // distinct values far apart, so a finite difference step never changes the max
+            let shape = vec![batch, channels, size + extra, size + extra + 1];
+            let len: usize = shape.iter().product();
+            let mut values: Vec<f32> = (0..len).map(|v| v as f32 * 0.1).collect();
+            values.shuffle(&mut StdRng::seed_from_u64(seed));
+            let input = ArrayD::from_shape_vec(shape, values).unwrap();
+
+            let mut layer = PoolLayer::new(size, stride);
+            let result = GradientCheck::default().check_layer(&mut layer, &input);
+            prop_assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use ndarray::{Array1, Array2, ArrayD, Axis, IxDyn};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+// IDX file format (used by MNIST/Fashion-MNIST), files must be decompressed first:
+// [0, 0, type code, number of dimensions] then one big-endian u32 per dimension, then the data...
+const IDX_U8: u8 = 0x08;
+const IDX_I8: u8 = 0x09;
+const IDX_I16: u8 = 0x0B;
+const IDX_I32: u8 = 0x0C;
+const IDX_F32: u8 = 0x0D;
+const IDX_F64: u8 = 0x0E;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// reads any IDX tensor, every element type gets converted into f32
+pub fn read_idx<R: Read>(reader: R) -> io::Result<ArrayD<f32>> {
+    read_idx_within(reader, u64::MAX)
+}
+
+pub fn load_idx<P: AsRef<Path>>(path: P) -> io::Result<ArrayD<f32>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    read_idx_within(BufReader::new(file), size)
+}
+
+// the header's sizes are checked against "size" (the file length) before allocating anything
+fn read_idx_within<R: Read>(mut reader: R, size: u64) -> io::Result<ArrayD<f32>> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic[0] != 0 || magic[1] != 0 {
+        return Err(invalid_data(format!("bad IDX magic number {:?}", magic)));
+    }
+    let (type_code, ndim) = (magic[2], magic[3] as usize);
+
+    let mut shape = Vec::with_capacity(ndim);
+    for _ in 0..ndim {
+        let mut dim = [0u8; 4];
+        reader.read_exact(&mut dim)?;
+        shape.push(u32::from_be_bytes(dim) as usize);
+    }
+    let len = shape
+        .iter()
+        .try_fold(1usize, |len, &dim| len.checked_mul(dim))
+        .ok_or_else(|| invalid_data(format!("IDX shape {:?} overflows", shape)))?;
+
+    let width = match type_code {
+        IDX_U8 | IDX_I8 => 1,
+        IDX_I16 => 2,
+        IDX_I32 | IDX_F32 => 4,
+        IDX_F64 => 8,
+        other => {
+            return Err(invalid_data(format!(
+                "unknown IDX type code {:#04x}",
+                other
+            )))
+        }
+    };
+    let header = 4 + 4 * ndim as u64;
+    let data_len = len
+        .checked_mul(width)
+        .filter(|&data_len| (data_len as u64) <= size.saturating_sub(header))
+        .ok_or_else(|| {
+            invalid_data(format!(
+                "IDX shape {:?} needs more than the {} bytes of the file",
+                shape, size
+            ))
+        })?;
+    // grows with what is actually read, a stream shorter than its header says fails early
+    let mut bytes = Vec::new();
+    reader.take(data_len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != data_len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("IDX data ends after {} of {} bytes", bytes.len(), data_len),
+        ));
+    }
+
+    let data: Vec<f32> = bytes
+        .chunks_exact(width)
+        .map(|b| match type_code {
+            IDX_U8 => b[0] as f32,
+            IDX_I8 => b[0] as i8 as f32,
+            IDX_I16 => i16::from_be_bytes([b[0], b[1]]) as f32,
+            IDX_I32 => i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f32,
+            IDX_F32 => f32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+            _ => f64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32,
+        })
+        .collect();
+    ArrayD::from_shape_vec(IxDyn(&shape), data).map_err(|e| invalid_data(e.to_string()))
+}
+
+// (x - mean) / std, element wise
+pub fn normalize(x: ArrayD<f32>, mean: f32, std: f32) -> ArrayD<f32> {
+    x.mapv(|xi| (xi - mean) / std)
+}
+
+pub fn mean_std(x: &ArrayD<f32>) -> (f32, f32) {
+    let mean = x.mean().unwrap_or(0.0);
+    let var = x.mapv(|xi| (xi - mean).powi(2)).mean().unwrap_or(0.0);
+    (mean, var.sqrt().max(f32::EPSILON))
+}
+
+pub fn one_hot(labels: &Array1<usize>, classes: usize) -> Array2<f32> {
+    let mut encoded = Array2::zeros((labels.len(), classes));
+    for (row, &label) in labels.iter().enumerate() {
+        assert!(
+            label < classes,
+            "label {} out of range for {} classes",
+            label,
+            classes
+        );
+        encoded[[row, label]] = 1.0;
+    }
+    encoded
+}
+
+// index of the highest score per row, e.g. predicted class of a batch
+pub fn argmax(scores: &Array2<f32>) -> Array1<usize> {
+    scores
+        .rows()
+        .into_iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .fold((0, f32::NEG_INFINITY), |best, (i, &v)| {
+                    if v > best.1 {
+                        (i, v)
+                    } else {
+                        best
+                    }
+                })
+                .0
+        })
+        .collect()
+}
+
+// samples are laid out along axis 0 of "inputs"
+#[derive(Clone, Debug)]
+pub struct Dataset {
+    pub inputs: ArrayD<f32>,
+    pub labels: Array1<usize>,
+    pub classes: usize,
+}
+
+pub struct Batch {
+    pub inputs: ArrayD<f32>,
+    pub labels: Array1<usize>,
+    pub targets: Array2<f32>, // one-hot labels
+}
+
+impl Dataset {
+    pub fn new(inputs: ArrayD<f32>, labels: Array1<usize>, classes: usize) -> Dataset {
+        assert_eq!(
+            inputs.len_of(Axis(0)),
+            labels.len(),
+            "inputs and labels count mismatch"
+        );
+        Self {
+            inputs,
+            labels,
+            classes,
+        }
+    }
+
+    // images come out as [N, 1, rows, cols] scaled into [0, 1], every label must be an
+    // integer in 0..classes
+    pub fn from_idx<P: AsRef<Path>>(images: P, labels: P, classes: usize) -> io::Result<Dataset> {
+        let images = load_idx(images)?;
+        let labels = load_idx(labels)?;
+        if images.ndim() != 3 || labels.ndim() != 1 {
+            return Err(invalid_data(format!(
+                "expected [N, rows, cols] images and [N] labels, got {:?} and {:?}",
+                images.shape(),
+                labels.shape()
+            )));
+        }
+        if images.len_of(Axis(0)) != labels.len() {
+            return Err(invalid_data(format!(
+                "{} images but {} labels",
+                images.len_of(Axis(0)),
+                labels.len()
+            )));
+        }
+        let images = images.insert_axis(Axis(1)) / 255.0;
+        let labels = labels
+            .iter()
+            .map(|&l| {
+                if l >= 0.0 && l.fract() == 0.0 && l < classes as f32 {
+                    Ok(l as usize)
+                } else {
+                    Err(invalid_data(format!(
+                        "label {} is not a class in 0..{}",
+                        l, classes
+                    )))
+                }
+            })
+            .collect::<io::Result<Array1<usize>>>()?;
+        Ok(Dataset::new(images, labels, classes))
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn normalize(mut self, mean: f32, std: f32) -> Dataset {
+        self.inputs = normalize(self.inputs, mean, std);
+        self
+    }
+
+    pub fn select(&self, indices: &[usize]) -> Dataset {
+        Dataset {
+            inputs: self.inputs.select(Axis(0), indices),
+            labels: indices.iter().map(|&i| self.labels[i]).collect(),
+            classes: self.classes,
+        }
+    }
+
+    // shuffles with "seed" and keeps the last "validation_fraction" as validation set
+    pub fn split(&self, validation_fraction: f32, seed: u64) -> (Dataset, Dataset) {
+        assert!((0.0..=1.0).contains(&validation_fraction));
+        let mut indices: Vec<usize> = (0..self.len()).collect();
+        indices.shuffle(&mut StdRng::seed_from_u64(seed));
+        let validation = (self.len() as f32 * validation_fraction).round() as usize;
+        let (train, valid) = indices.split_at(self.len() - validation);
+        (self.select(train), self.select(valid))
+    }
+
+    // mini-batches in a seeded random order, "None" keeps the dataset order.
+    // the last batch may be smaller than "batch_size"...
+    pub fn batches(&self, batch_size: usize, seed: Option<u64>) -> Batches<'_> {
+        assert!(batch_size > 0, "batch size must be positive");
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        if let Some(seed) = seed {
+            order.shuffle(&mut StdRng::seed_from_u64(seed));
+        }
+        Batches {
+            dataset: self,
+            order,
+            batch_size,
+            position: 0,
+        }
+    }
+}
+
+pub struct Batches<'a> {
+    dataset: &'a Dataset,
+    order: Vec<usize>,
+    batch_size: usize,
+    position: usize,
+}
+
+impl<'a> Iterator for Batches<'a> {
+    type Item = Batch;
+
+    fn next(&mut self) -> Option<Batch> {
+        if self.position >= self.order.len() {
+            return None;
+        }
+        let end = (self.position + self.batch_size).min(self.order.len());
+        let indices = &self.order[self.position..end];
+        self.position = end;
+        let labels: Array1<usize> = indices.iter().map(|&i| self.dataset.labels[i]).collect();
+        Some(Batch {
+            inputs: self.dataset.inputs.select(Axis(0), indices),
+            targets: one_hot(&labels, self.dataset.classes),
+            labels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    // 4 images of 2x3 pixels and their labels, in IDX format
+    fn image_fixture() -> Vec<u8> {
+        let mut bytes = vec![0, 0, IDX_U8, 3];
+        for dim in [4u32, 2, 3] {
+            bytes.extend_from_slice(&dim.to_be_bytes());
+        }
+        bytes.extend((0..24).map(|p| (p * 10) as u8));
+        bytes
+    }
+    fn label_fixture() -> Vec<u8> {
+        let mut bytes = vec![0, 0, IDX_U8, 1];
+        bytes.extend_from_slice(&4u32.to_be_bytes());
+        bytes.extend([3, 1, 4, 1]);
+        bytes
+    }
+
+    // tests run in parallel, each call gets a directory of its own
+    fn idx_dataset(labels: Vec<u8>, classes: usize) -> io::Result<Dataset> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "rust_cnn_idx_{}_{}",
+            std::process::id(),
+            CALLS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (images, label_path) = (dir.join("images-idx3-ubyte"), dir.join("labels-idx1-ubyte"));
+        std::fs::write(&images, image_fixture()).unwrap();
+        std::fs::write(&label_path, labels).unwrap();
+        let dataset = Dataset::from_idx(&images, &label_path, classes);
+        std::fs::remove_dir_all(&dir).unwrap();
+        dataset
+    }
+
+    fn fixture_dataset() -> Dataset {
+        idx_dataset(label_fixture(), 10).unwrap()
+    }
+
+    #[test]
+    fn rejects_labels_outside_the_classes() {
+        assert_eq!(idx_dataset(label_fixture(), 5).unwrap().classes, 5);
+        assert!(idx_dataset(label_fixture(), 4).is_err()); // label 4
+
+        let float_labels = |labels: [f32; 4]| {
+            let mut bytes = vec![0, 0, IDX_F32, 1, 0, 0, 0, 4];
+            labels
+                .iter()
+                .for_each(|l| bytes.extend_from_slice(&l.to_be_bytes()));
+            bytes
+        };
+        assert!(idx_dataset(float_labels([0.0, 1.0, 1.0, 0.0]), 2).is_ok());
+        assert!(idx_dataset(float_labels([0.0, 1.5, 1.0, 0.0]), 2).is_err());
+        assert!(idx_dataset(float_labels([0.0, -1.0, 1.0, 0.0]), 2).is_err());
+    }
+
+    #[test]
+    fn reads_idx_files() {
+        let dataset = fixture_dataset();
+        assert_eq!(dataset.inputs.shape(), &[4, 1, 2, 3]);
+        assert_eq!(dataset.labels, array![3, 1, 4, 1]);
+        assert_eq!(dataset.classes, 10);
+        assert!((dataset.inputs[[1, 0, 0, 1]] - 70.0 / 255.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reads_wider_types() {
+        let mut bytes = vec![0, 0, IDX_I16, 1, 0, 0, 0, 2];
+        bytes.extend_from_slice(&(-300i16).to_be_bytes());
+        bytes.extend_from_slice(&7i16.to_be_bytes());
+        assert_eq!(
+            read_idx(&bytes[..]).unwrap(),
+            array![-300.0, 7.0].into_dyn()
+        );
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(read_idx(&[1u8, 0, 8, 1, 0, 0, 0, 1, 0][..]).is_err());
+        assert!(read_idx(&[0u8, 0, 0x42, 1, 0, 0, 0, 1, 0][..]).is_err());
+        assert!(read_idx(&[0u8, 0, 8, 1, 0, 0, 0, 5, 0][..]).is_err()); // truncated
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        // 2^32-1 x 2^32-1 x 2^32-1 elements overflow usize
+        let mut bytes = vec![0u8, 0, IDX_F64, 3];
+        bytes.extend([0xff; 12]);
+        assert!(read_idx(&bytes[..]).is_err());
+        // fits into usize but not into the file
+        let path = std::env::temp_dir().join(format!("rust_cnn_idx_big_{}", std::process::id()));
+        std::fs::write(&path, [0, 0, IDX_U8, 1, 0xff, 0xff, 0xff, 0xff, 1]).unwrap();
+        let error = load_idx(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn one_hot_and_argmax_round_trip() {
+        let labels = array![2, 0, 1];
+        let encoded = one_hot(&labels, 3);
+        assert_eq!(encoded.sum(), 3.0);
+        assert_eq!(argmax(&encoded), labels);
+    }
+
+    #[test]
+    fn normalization() {
+        let (mean, std) = mean_std(&fixture_dataset().inputs);
+        let normalized = fixture_dataset().normalize(mean, std);
+        let (mean, std) = mean_std(&normalized.inputs);
+        assert!(mean.abs() < 1e-5 && (std - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn batches_cover_dataset_once() {
+        let dataset = fixture_dataset();
+        let batches: Vec<Batch> = dataset.batches(3, Some(7)).collect();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].inputs.shape(), &[3, 1, 2, 3]);
+        assert_eq!(batches[1].targets.shape(), &[1, 10]);
+        let mut seen: Vec<usize> = batches.iter().flat_map(|b| b.labels.to_vec()).collect();
+        seen.sort();
+        assert_eq!(seen, vec![1, 1, 3, 4]);
+    }
+
+    #[test]
+    fn shuffling_is_seeded() {
+        let dataset = fixture_dataset();
+        let order = |seed| {
+            dataset
+                .batches(1, Some(seed))
+                .map(|b| b.inputs[[0, 0, 0, 0]])
+                .collect::<Vec<f32>>()
+        };
+        assert_eq!(order(42), order(42));
+        let unshuffled: Vec<usize> = dataset.batches(4, None).next().unwrap().labels.to_vec();
+        assert_eq!(unshuffled, vec![3, 1, 4, 1]);
+    }
+
+    #[test]
+    fn split_is_disjoint() {
+        let dataset = fixture_dataset();
+        let (train, valid) = dataset.split(0.25, 1);
+        assert_eq!((train.len(), valid.len()), (3, 1));
+        let mut firsts: Vec<f32> = train
+            .inputs
+            .outer_iter()
+            .chain(valid.inputs.outer_iter())
+            .map(|img| img[[0, 0, 0]])
+            .collect();
+        firsts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected: Vec<f32> = (0..4).map(|i| (i * 60) as f32 / 255.0).collect();
+        assert_eq!(firsts, expected);
+    }
+}